@@ -6,11 +6,13 @@
 #![no_main]
 use regalloc2::fuzzing::func::Func;
 use regalloc2::fuzzing::fuzz_target;
+use regalloc2::{Ctx, RegallocOptions};
 
 fuzz_target!(|func: Func| {
     let _ = env_logger::try_init();
     log::trace!("func:\n{:?}", func);
     let env = regalloc2::fuzzing::func::machine_env();
-    let _out =
-        regalloc2::fuzzing::ion::run(&func, &env, false, false).expect("regalloc did not succeed");
+    let options = RegallocOptions::default();
+    let _out = regalloc2::fuzzing::ion::run(&func, &env, &options, &mut Ctx::default())
+        .expect("regalloc did not succeed");
 });
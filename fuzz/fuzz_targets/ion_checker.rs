@@ -8,6 +8,7 @@ use regalloc2::fuzzing::arbitrary::{Arbitrary, Result, Unstructured};
 use regalloc2::fuzzing::checker::Checker;
 use regalloc2::fuzzing::func::{Func, Options};
 use regalloc2::fuzzing::fuzz_target;
+use regalloc2::{Ctx, RegallocOptions};
 
 #[derive(Clone, Debug)]
 struct TestCase {
@@ -25,6 +26,8 @@ impl Arbitrary<'_> for TestCase {
                     fixed_nonallocatable: true,
                     clobbers: true,
                     reftypes: true,
+                    redefs: true,
+                    reg_pairs: true,
                 },
             )?,
         })
@@ -36,10 +39,15 @@ fuzz_target!(|testcase: TestCase| {
     let _ = env_logger::try_init();
     log::trace!("func:\n{:?}", func);
     let env = regalloc2::fuzzing::func::machine_env();
-    let out =
-        regalloc2::fuzzing::ion::run(&func, &env, true, false).expect("regalloc did not succeed");
+    let options = RegallocOptions {
+        verbose_log: true,
+        ..RegallocOptions::default()
+    };
+    let out = regalloc2::fuzzing::ion::run(&func, &env, &options, &mut Ctx::default())
+        .expect("regalloc did not succeed");
 
     let mut checker = Checker::new(&func, &env);
+    checker.enable_stackmap_checks();
     checker.prepare(&out);
     checker.run().expect("checker failed");
 });
@@ -9,6 +9,7 @@ use regalloc2::fuzzing::cfg::CFGInfo;
 use regalloc2::fuzzing::func::{Func, Options};
 use regalloc2::fuzzing::fuzz_target;
 use regalloc2::ssa::validate_ssa;
+use regalloc2::validate_function;
 
 #[derive(Debug)]
 struct TestCase {
@@ -26,6 +27,8 @@ impl Arbitrary<'_> for TestCase {
                     fixed_nonallocatable: true,
                     clobbers: true,
                     reftypes: true,
+                    redefs: false,
+                    reg_pairs: false,
                 },
             )?,
         })
@@ -35,4 +38,5 @@ impl Arbitrary<'_> for TestCase {
 fuzz_target!(|t: TestCase| {
     let cfginfo = CFGInfo::new(&t.f).expect("could not create CFG info");
     validate_ssa(&t.f, &cfginfo).expect("invalid SSA");
+    validate_function(&t.f).expect("invalid function");
 });
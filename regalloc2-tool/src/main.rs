@@ -32,6 +32,8 @@ fn main() {
     let options = RegallocOptions {
         verbose_log: true,
         validate_ssa: true,
+        validate: true,
+        ..RegallocOptions::default()
     };
     let output = match regalloc2::run(&function, function.machine_env(), &options) {
         Ok(output) => output,
@@ -88,6 +90,19 @@ fn print_output(func: &SerializableFunction, output: &Output) {
                 InstOrEdit::Edit(Edit::Move { from, to }) => {
                     print!("    edit: move {to} <- {from}\n");
                 }
+                InstOrEdit::Edit(Edit::Remat { vreg, to }) => {
+                    print!("    edit: remat {to} <- {vreg}\n");
+                }
+                InstOrEdit::Edit(Edit::Sink { inst, vreg, to }) => {
+                    print!("    edit: sink {to} <- inst{} {vreg}\n", inst.index());
+                }
+                InstOrEdit::Edit(Edit::Reinterpret {
+                    alloc,
+                    from_class,
+                    to_class,
+                }) => {
+                    print!("    edit: reinterpret {alloc} {from_class:?} -> {to_class:?}\n");
+                }
             }
         }
     }
@@ -0,0 +1,708 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Allocating several functions together.
+//!
+//! A whole-program compiler for a small target can do better than
+//! the calling convention: `run_batch` takes a set of functions and
+//! the calls between them, picks one register for each value passed
+//! into or out of a callee that all of its callers and the callee
+//! itself agree on, and narrows the ABI clobbers of each call to the
+//! registers the callee actually writes. Each function is then
+//! allocated by the usual single-function pipeline, callees first, so
+//! values live across a call can stay in any register the callee
+//! leaves alone.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{
+    all_clobbers, ion, Block, Edit, Function, FxHashMap, FxHashSet, Inst, InstRange, MachineEnv,
+    Operand, OperandConstraint, OperandKind, OperandPos, Output, PReg, PRegSet, ProgPoint,
+    RegAllocError, RegClass, RegallocOptions, VReg,
+};
+
+/// A call from one function of a batch to another; see [`run_batch`].
+#[derive(Clone, Debug)]
+pub struct BatchCall {
+    /// The index of the calling function.
+    pub caller: usize,
+    /// The call instruction in the caller.
+    pub inst: Inst,
+    /// The index of the called function.
+    pub callee: usize,
+    /// The arguments, as `(caller vreg, callee vreg)`: the caller's
+    /// vreg is used by `inst`, and the callee's is one of its
+    /// `Function::entry_args`.
+    pub args: Vec<(VReg, VReg)>,
+    /// The results, as `(callee vreg, caller vreg)`: the callee's vreg
+    /// is used by its return instructions, and the caller's is
+    /// defined by `inst`.
+    pub rets: Vec<(VReg, VReg)>,
+}
+
+/// A function of a batch as it is allocated: the original with its
+/// transfer operands and entry args fixed to their shared registers,
+/// and its calls into already-allocated callees clobbering only what
+/// those callees write. The checker must be run against this rather
+/// than the original function.
+#[derive(Clone, Debug)]
+pub struct BatchFunction<'a, F: Function> {
+    func: &'a F,
+    operands: FxHashMap<Inst, Vec<Operand>>,
+    entry_args: Vec<(VReg, PReg)>,
+    call_clobbers: FxHashMap<Inst, PRegSet>,
+}
+
+impl<'a, F: Function> BatchFunction<'a, F> {
+    /// The original function.
+    pub fn func(&self) -> &'a F {
+        self.func
+    }
+}
+
+/// The result of allocating one function of a batch.
+#[derive(Clone, Debug)]
+pub struct BatchOutput<'a, F: Function> {
+    /// The function as it was allocated.
+    pub func: BatchFunction<'a, F>,
+    /// The allocation.
+    pub output: Output,
+}
+
+/// Allocate `funcs` together. Each value passed by one of `calls` is
+/// given a register of its class that is free at every instruction
+/// that hands it over, shared by the callee and all of its callers; a
+/// value for which there is none keeps its original constraints.
+/// Calls are narrowed to clobber only the registers of
+/// `MachineEnv::call_clobbers` that the callee (transitively) writes,
+/// while their own `Function::inst_clobbers` are kept as they are.
+/// Functions are allocated callees first, which is what makes this
+/// pay off for leaf-heavy programs; a call into a function that is
+/// not yet allocated because of recursion keeps its full clobbers.
+///
+/// The outputs are in the order of `funcs`.
+pub fn run_batch<'a, F: Function>(
+    funcs: &'a [F],
+    calls: &[BatchCall],
+    env: &MachineEnv,
+    options: &RegallocOptions,
+) -> Result<Vec<BatchOutput<'a, F>>, RegAllocError> {
+    for (i, call) in calls.iter().enumerate() {
+        if !call_is_valid(funcs, call) {
+            return Err(RegAllocError::BatchCall(i));
+        }
+    }
+
+    let shared = choose_shared_regs(funcs, calls, env, options);
+    let mut batch: Vec<BatchFunction<'a, F>> = funcs
+        .iter()
+        .enumerate()
+        .map(|(i, func)| pin_transfers(func, i, calls, &shared))
+        .collect();
+
+    // Allocate callees before their callers.
+    let mut written: Vec<Option<PRegSet>> = vec![None; funcs.len()];
+    let mut outputs: Vec<Option<Output>> = vec![None; funcs.len()];
+    for f in callees_first(funcs.len(), calls) {
+        for call in calls.iter().filter(|call| call.caller == f) {
+            if let Some(writes) = written[call.callee] {
+                let inst = call.inst;
+                let narrowed = funcs[f]
+                    .inst_clobbers(inst)
+                    .union(env.call_clobbers.intersection(writes));
+                batch[f].call_clobbers.insert(inst, narrowed);
+            }
+        }
+        let output = crate::run(&batch[f], env, options)?;
+        written[f] = Some(written_regs(&batch[f], env, &output));
+        outputs[f] = Some(output);
+    }
+
+    Ok(batch
+        .into_iter()
+        .zip(outputs)
+        .map(|(func, output)| BatchOutput {
+            func,
+            output: output.unwrap(),
+        })
+        .collect())
+}
+
+/// Does `call` name existing functions, instructions and vregs?
+fn call_is_valid<F: Function>(funcs: &[F], call: &BatchCall) -> bool {
+    let (caller, callee) = match (funcs.get(call.caller), funcs.get(call.callee)) {
+        (Some(caller), Some(callee)) => (caller, callee),
+        _ => return false,
+    };
+    if call.inst.index() >= caller.num_insts() {
+        return false;
+    }
+    let has_operand = |func: &F, inst: Inst, vreg: VReg, kind: OperandKind| {
+        func.inst_operands(inst)
+            .iter()
+            .any(|op| op.vreg() == vreg && op.kind() == kind)
+    };
+    let args_ok = call.args.iter().all(|&(from, to)| {
+        from.class() == to.class()
+            && has_operand(caller, call.inst, from, OperandKind::Use)
+            && callee.entry_args().iter().any(|&(vreg, _)| vreg == to)
+    });
+    let rets_ok = call.rets.iter().all(|&(from, to)| {
+        from.class() == to.class()
+            && has_operand(caller, call.inst, to, OperandKind::Def)
+            && (0..callee.num_insts()).map(Inst::new).any(|inst| {
+                callee.is_ret(inst) && has_operand(callee, inst, from, OperandKind::Use)
+            })
+    });
+    args_ok && rets_ok
+}
+
+/// The fixed registers of the operands at `inst` of `func` that do
+/// not take part in a transfer, plus the registers killed early.
+fn other_fixed_regs<F: Function>(
+    func: &F,
+    inst: Inst,
+    transfer: impl Fn(&Operand) -> bool,
+) -> PRegSet {
+    let mut regs = func.inst_early_kills(inst);
+    for op in func.inst_operands(inst) {
+        if let OperandConstraint::FixedReg(preg) = op.constraint() {
+            if !transfer(op) {
+                regs.add(preg);
+            }
+        }
+    }
+    regs
+}
+
+/// Pick a shared register for each callee vreg that a call passes a
+/// value into or out of, by function.
+fn choose_shared_regs<F: Function>(
+    funcs: &[F],
+    calls: &[BatchCall],
+    env: &MachineEnv,
+    options: &RegallocOptions,
+) -> Vec<FxHashMap<VReg, PReg>> {
+    let mut shared = vec![FxHashMap::default(); funcs.len()];
+    for (c, callee) in funcs.iter().enumerate() {
+        let calls: Vec<&BatchCall> = calls.iter().filter(|call| call.callee == c).collect();
+        if calls.is_empty() {
+            continue;
+        }
+        let ret_insts: Vec<Inst> = (0..callee.num_insts())
+            .map(Inst::new)
+            .filter(|&inst| callee.is_ret(inst))
+            .collect();
+        let candidates = |class: RegClass| {
+            let class = class as usize;
+            env.preferred_regs_by_class[class]
+                .iter()
+                .chain(&env.non_preferred_regs_by_class[class])
+                .copied()
+                .filter(|&preg| {
+                    !env.reserved_regs.contains(preg)
+                        && env.scratch_by_class[class] != Some(preg)
+                        && options
+                            .available_override
+                            .iter()
+                            .all(|available| available.contains(preg))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Arguments must avoid the other entry args and the other
+        // fixed registers at every call site.
+        let mut avoid = PRegSet::empty();
+        let mut arg_vregs = vec![];
+        for &(vreg, preg) in callee.entry_args() {
+            if calls
+                .iter()
+                .any(|call| call.args.iter().any(|&(_, to)| to == vreg))
+            {
+                arg_vregs.push(vreg);
+            } else {
+                avoid.add(preg);
+            }
+        }
+        let is_arg = |call: &BatchCall, op: &Operand| {
+            op.kind() == OperandKind::Use && call.args.iter().any(|&(from, _)| from == op.vreg())
+        };
+        let mut late_args = false;
+        for call in &calls {
+            let caller = &funcs[call.caller];
+            avoid.union_from(other_fixed_regs(caller, call.inst, |op| is_arg(call, op)));
+            late_args |= caller
+                .inst_operands(call.inst)
+                .iter()
+                .any(|op| is_arg(call, op) && op.pos() == OperandPos::Late);
+        }
+        for vreg in arg_vregs {
+            if let Some(preg) = candidates(vreg.class())
+                .into_iter()
+                .find(|&preg| !avoid.contains(preg))
+            {
+                avoid.add(preg);
+                shared[c].insert(vreg, preg);
+            }
+        }
+
+        // Results must avoid the other fixed registers at the
+        // callee's returns and at every call site, and the argument
+        // registers if an argument is still read late in the call.
+        let mut avoid = PRegSet::empty();
+        if late_args {
+            for &preg in shared[c].values() {
+                avoid.add(preg);
+            }
+        }
+        let mut ret_vregs: Vec<VReg> = vec![];
+        for call in &calls {
+            let caller = &funcs[call.caller];
+            avoid.union_from(other_fixed_regs(caller, call.inst, |op| {
+                op.kind() == OperandKind::Def && call.rets.iter().any(|&(_, to)| to == op.vreg())
+            }));
+            for &(vreg, _) in &call.rets {
+                if !ret_vregs.contains(&vreg) {
+                    ret_vregs.push(vreg);
+                }
+            }
+        }
+        for &inst in &ret_insts {
+            avoid.union_from(other_fixed_regs(callee, inst, |op| {
+                op.kind() == OperandKind::Use && ret_vregs.contains(&op.vreg())
+            }));
+        }
+        for vreg in ret_vregs {
+            if let Some(preg) = candidates(vreg.class())
+                .into_iter()
+                .find(|&preg| !avoid.contains(preg))
+            {
+                avoid.add(preg);
+                shared[c].insert(vreg, preg);
+            }
+        }
+    }
+    shared
+}
+
+/// Fix the operands and entry args of function `f` that take part in
+/// a transfer to their shared registers.
+fn pin_transfers<'a, F: Function>(
+    func: &'a F,
+    f: usize,
+    calls: &[BatchCall],
+    shared: &[FxHashMap<VReg, PReg>],
+) -> BatchFunction<'a, F> {
+    let mut operands: FxHashMap<Inst, Vec<Operand>> = FxHashMap::default();
+    let mut pinned: FxHashSet<(Inst, usize)> = FxHashSet::default();
+    let mut pin = |inst: Inst, vreg: VReg, kind: OperandKind, preg: PReg| {
+        let ops = operands
+            .entry(inst)
+            .or_insert_with(|| func.inst_operands(inst).to_vec());
+        // Each transfer takes the first operand of its vreg that no
+        // other transfer has taken yet.
+        let slot = (0..ops.len()).find(|&slot| {
+            ops[slot].vreg() == vreg && ops[slot].kind() == kind && !pinned.contains(&(inst, slot))
+        });
+        if let Some(slot) = slot {
+            pinned.insert((inst, slot));
+            let pos = ops[slot].pos();
+            ops[slot] = Operand::new(vreg, OperandConstraint::FixedReg(preg), kind, pos);
+        }
+    };
+
+    for call in calls.iter().filter(|call| call.caller == f) {
+        for &(from, to) in &call.args {
+            if let Some(&preg) = shared[call.callee].get(&to) {
+                pin(call.inst, from, OperandKind::Use, preg);
+            }
+        }
+        for &(from, to) in &call.rets {
+            if let Some(&preg) = shared[call.callee].get(&from) {
+                pin(call.inst, to, OperandKind::Def, preg);
+            }
+        }
+    }
+    for i in 0..func.num_insts() {
+        let inst = Inst::new(i);
+        if func.is_ret(inst) {
+            for op in func.inst_operands(inst) {
+                if op.kind() == OperandKind::Use {
+                    if let Some(&preg) = shared[f].get(&op.vreg()) {
+                        pin(inst, op.vreg(), OperandKind::Use, preg);
+                    }
+                }
+            }
+        }
+    }
+
+    let entry_args = func
+        .entry_args()
+        .iter()
+        .map(|&(vreg, preg)| (vreg, shared[f].get(&vreg).copied().unwrap_or(preg)))
+        .collect();
+    BatchFunction {
+        func,
+        operands,
+        entry_args,
+        call_clobbers: FxHashMap::default(),
+    }
+}
+
+/// The function indices in an order that visits callees before their
+/// callers, as far as recursion allows.
+fn callees_first(num_funcs: usize, calls: &[BatchCall]) -> Vec<usize> {
+    let mut order = Vec::with_capacity(num_funcs);
+    let mut visited = vec![false; num_funcs];
+    for root in 0..num_funcs {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        let mut stack = vec![(root, 0)];
+        while let Some(&mut (f, ref mut next)) = stack.last_mut() {
+            match calls[*next..].iter().position(|call| call.caller == f) {
+                Some(offset) => {
+                    let callee = calls[*next + offset].callee;
+                    *next += offset + 1;
+                    if !visited[callee] {
+                        visited[callee] = true;
+                        stack.push((callee, 0));
+                    }
+                }
+                None => {
+                    order.push(f);
+                    stack.pop();
+                }
+            }
+        }
+    }
+    order
+}
+
+/// The registers that running `func` may overwrite: those its
+/// operands, temps and edits write, plus its clobbers.
+fn written_regs<F: Function>(func: &F, env: &MachineEnv, output: &Output) -> PRegSet {
+    let mut regs = PRegSet::empty();
+    for i in 0..func.num_insts() {
+        let inst = Inst::new(i);
+        let ops = func.inst_operands(inst);
+        let allocs = output.inst_allocs(inst);
+        for (op, &alloc) in ops.iter().zip(allocs) {
+            if op.kind() == OperandKind::Def {
+                if let Some(preg) = alloc.as_reg() {
+                    regs.add(preg);
+                }
+                if op.constraint() == OperandConstraint::RegPair {
+                    if let Some(hi) = ion::pair_halves(func, env, alloc, op.class()).1.as_reg() {
+                        regs.add(hi);
+                    }
+                }
+            }
+        }
+        for &alloc in &allocs[ops.len()..] {
+            if let Some(preg) = alloc.as_reg() {
+                regs.add(preg);
+            }
+        }
+        regs.union_from(all_clobbers(func, env, inst));
+    }
+    for b in 0..func.num_blocks() {
+        let block = Block::new(b);
+        let last = func.block_insns(block).last();
+        for succ in 0..func.block_succs(block).len() {
+            for &(_, preg) in func.branch_edge_defs(block, last, succ) {
+                regs.add(preg);
+            }
+        }
+    }
+    for (_, edit) in &output.edits {
+        let to = match *edit {
+            Edit::Move { to, .. } | Edit::Remat { to, .. } | Edit::Sink { to, .. } => to,
+            Edit::Reinterpret { .. } => continue,
+        };
+        if let Some(preg) = to.as_reg() {
+            regs.add(preg);
+        }
+    }
+    regs
+}
+
+impl<'a, F: Function> Function for BatchFunction<'a, F> {
+    fn num_insts(&self) -> usize {
+        self.func.num_insts()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.func.num_blocks()
+    }
+
+    fn entry_block(&self) -> Block {
+        self.func.entry_block()
+    }
+
+    fn block_insns(&self, block: Block) -> InstRange {
+        self.func.block_insns(block)
+    }
+
+    fn block_succs(&self, block: Block) -> &[Block] {
+        self.func.block_succs(block)
+    }
+
+    fn block_preds(&self, block: Block) -> &[Block] {
+        self.func.block_preds(block)
+    }
+
+    fn block_params(&self, block: Block) -> &[VReg] {
+        self.func.block_params(block)
+    }
+
+    fn is_ret(&self, insn: Inst) -> bool {
+        self.func.is_ret(insn)
+    }
+
+    fn is_branch(&self, insn: Inst) -> bool {
+        self.func.is_branch(insn)
+    }
+
+    fn branch_blockparams(&self, block: Block, insn: Inst, succ_idx: usize) -> &[VReg] {
+        self.func.branch_blockparams(block, insn, succ_idx)
+    }
+
+    fn branch_edge_defs(&self, block: Block, insn: Inst, succ_idx: usize) -> &[(VReg, PReg)] {
+        self.func.branch_edge_defs(block, insn, succ_idx)
+    }
+
+    fn entry_args(&self) -> &[(VReg, PReg)] {
+        &self.entry_args
+    }
+
+    fn landing_pad_args(&self, block: Block) -> &[(VReg, PReg)] {
+        self.func.landing_pad_args(block)
+    }
+
+    fn block_frequency(&self, block: Block) -> Option<f32> {
+        self.func.block_frequency(block)
+    }
+
+    fn block_is_cold(&self, block: Block) -> bool {
+        self.func.block_is_cold(block)
+    }
+
+    fn block_available_regs(&self, block: Block) -> Option<PRegSet> {
+        self.func.block_available_regs(block)
+    }
+
+    fn inst_operands(&self, insn: Inst) -> &[Operand] {
+        match self.operands.get(&insn) {
+            Some(ops) => ops,
+            None => self.func.inst_operands(insn),
+        }
+    }
+
+    fn inst_clobbers(&self, insn: Inst) -> PRegSet {
+        match self.call_clobbers.get(&insn) {
+            Some(&clobbers) => clobbers,
+            None => self.func.inst_clobbers(insn),
+        }
+    }
+
+    fn is_call(&self, insn: Inst) -> bool {
+        // A narrowed call's clobbers already include what is left of
+        // the ABI's.
+        self.func.is_call(insn) && !self.call_clobbers.contains_key(&insn)
+    }
+
+    fn inst_soft_clobbers(&self, insn: Inst) -> PRegSet {
+        self.func.inst_soft_clobbers(insn)
+    }
+
+    fn inst_early_kills(&self, insn: Inst) -> PRegSet {
+        self.func.inst_early_kills(insn)
+    }
+
+    fn operand_preferred_reg(&self, insn: Inst, slot: usize) -> Option<PReg> {
+        self.func.operand_preferred_reg(insn, slot)
+    }
+
+    fn operand_class_view(&self, insn: Inst, slot: usize) -> Option<RegClass> {
+        self.func.operand_class_view(insn, slot)
+    }
+
+    fn operand_is_immediate(&self, insn: Inst, slot: usize) -> bool {
+        self.func.operand_is_immediate(insn, slot)
+    }
+
+    fn inst_requires_distinct_regs(&self, insn: Inst) -> bool {
+        self.func.inst_requires_distinct_regs(insn)
+    }
+
+    fn inst_temps(&self, insn: Inst) -> &[RegClass] {
+        self.func.inst_temps(insn)
+    }
+
+    fn is_move(&self, insn: Inst) -> Option<(VReg, VReg)> {
+        self.func.is_move(insn)
+    }
+
+    fn num_vregs(&self) -> usize {
+        self.func.num_vregs()
+    }
+
+    fn debug_value_labels(&self) -> &[(VReg, Inst, Inst, u32)] {
+        self.func.debug_value_labels()
+    }
+
+    fn unavailable_ranges(&self) -> &[(PReg, ProgPoint, ProgPoint)] {
+        self.func.unavailable_ranges()
+    }
+
+    fn split_hints(&self) -> &[(VReg, ProgPoint)] {
+        self.func.split_hints()
+    }
+
+    fn coalesce_hints(&self) -> &[(VReg, VReg)] {
+        self.func.coalesce_hints()
+    }
+
+    fn allocation_hints(&self) -> &[(VReg, PReg)] {
+        self.func.allocation_hints()
+    }
+
+    fn is_reference_vreg(&self, vreg: VReg) -> bool {
+        self.func.is_reference_vreg(vreg)
+    }
+
+    fn requires_stackmap(&self, insn: Inst) -> bool {
+        self.func.requires_stackmap(insn)
+    }
+
+    fn spillslot_size(&self, regclass: RegClass) -> usize {
+        self.func.spillslot_size(regclass)
+    }
+
+    fn never_spill(&self, vreg: VReg) -> bool {
+        self.func.never_spill(vreg)
+    }
+
+    fn can_remat(&self, vreg: VReg) -> bool {
+        self.func.can_remat(vreg)
+    }
+
+    fn inst_is_pure(&self, insn: Inst) -> bool {
+        self.func.inst_is_pure(insn)
+    }
+
+    fn inst_latency(&self, insn: Inst) -> u32 {
+        self.func.inst_latency(insn)
+    }
+
+    fn multi_spillslot_named_by_last_slot(&self) -> bool {
+        self.func.multi_spillslot_named_by_last_slot()
+    }
+
+    fn allow_multiple_vreg_defs(&self) -> bool {
+        self.func.allow_multiple_vreg_defs()
+    }
+
+    fn is_ssa(&self) -> bool {
+        self.func.is_ssa()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::checker::{check_output, CheckOutcome};
+    use crate::testing::{int_env, vreg, TestFunc};
+    use crate::Allocation;
+
+    #[test]
+    fn test_transfers_in_registers() {
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut env = int_env(4);
+        env.call_clobbers = (1..4).map(p).fold(PRegSet::empty(), PRegSet::with);
+
+        // The callee takes v0 in p0 and returns v1 in p0.
+        let mut callee = TestFunc::new();
+        callee.set_entry_args(&[(vreg(0), p(0))]);
+        callee.block(&[]);
+        callee.op(&[Operand::reg_use(vreg(0)), Operand::reg_def(vreg(1))]);
+        callee.ret(&[Operand::reg_fixed_use(vreg(1), p(0))]);
+
+        // The caller keeps v1 live across the call, which takes p0
+        // and clobbers every other register as far as the ABI is
+        // concerned.
+        let mut caller = TestFunc::new();
+        caller.block(&[]);
+        caller.op(&[Operand::reg_def(vreg(0))]);
+        caller.op(&[Operand::reg_def(vreg(1))]);
+        let call = caller.call(&[
+            Operand::reg_fixed_use(vreg(0), p(0)),
+            Operand::reg_fixed_def(vreg(2), p(0)),
+        ]);
+        caller.ret(&[Operand::reg_use(vreg(1)), Operand::reg_use(vreg(2))]);
+
+        let spills = |out: &Output| {
+            out.edits.iter().any(|(_, edit)| match *edit {
+                Edit::Move { from, to } => from.is_stack() || to.is_stack(),
+                _ => false,
+            })
+        };
+        let alone = crate::run(&caller, &env, &RegallocOptions::default()).unwrap();
+        assert!(spills(&alone));
+
+        let funcs = [callee, caller];
+        let calls = [BatchCall {
+            caller: 1,
+            inst: call,
+            callee: 0,
+            args: vec![(vreg(0), vreg(0))],
+            rets: vec![(vreg(1), vreg(2))],
+        }];
+        let options = RegallocOptions {
+            validate: true,
+            ..RegallocOptions::default()
+        };
+        let outs = run_batch(&funcs, &calls, &env, &options).unwrap();
+        for out in &outs {
+            assert_eq!(
+                check_output(&out.func, &env, &out.output),
+                CheckOutcome::Correct
+            );
+        }
+        assert!(!spills(&outs[1].output));
+
+        // Both sides of each transfer agree on its register.
+        let callee_arg = outs[0].func.entry_args()[0].1;
+        let caller_allocs = outs[1].output.inst_allocs(call);
+        assert_eq!(caller_allocs[0], Allocation::reg(callee_arg));
+        assert_eq!(
+            caller_allocs[1],
+            outs[0].output.inst_allocs(Inst::new(1))[0]
+        );
+    }
+
+    #[test]
+    fn test_invalid_call() {
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        let call = f.call(&[Operand::reg_use(vreg(0))]);
+        f.ret(&[]);
+        let calls = [BatchCall {
+            caller: 0,
+            inst: call,
+            callee: 0,
+            args: vec![(vreg(0), vreg(0))],
+            rets: vec![],
+        }];
+        // v0 is not one of the callee's entry args.
+        let funcs = [f];
+        let result = run_batch(&funcs, &calls, &int_env(2), &RegallocOptions::default());
+        assert!(matches!(result, Err(RegAllocError::BatchCall(0))));
+    }
+}
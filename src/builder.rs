@@ -0,0 +1,366 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! A ready-made `Function` implementation that is filled in
+//! incrementally.
+//!
+//! Implementing `Function` over a compiler's own IR usually means
+//! materializing operand, successor and predecessor slices anyway.
+//! `FunctionBuilder` lets an embedder stream its instructions into
+//! flat arrays instead, and `finish` turns them into a
+//! `BuiltFunction` that can be passed straight to `run`.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::{Block, Function, Inst, InstRange, Operand, PRegSet, RegClass, VReg};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+enum InstKind {
+    Op,
+    Branch,
+    Ret,
+}
+
+/// Streams a function into compact storage, block by block in layout
+/// order.
+///
+/// Each block is started with `block`, filled with `inst` (or
+/// `inst_with_clobbers`) and ended with either `ret` or `branch`; a
+/// `branch` is followed by one `succ` call per successor. The entry
+/// block is the first block. Well-formedness (terminators, edge
+/// arguments, SSA) is checked by the allocator, not by the builder.
+///
+/// ```
+/// use regalloc2::{Block, FunctionBuilder, Operand, RegClass, VReg};
+///
+/// let v0 = VReg::new(0, RegClass::Int);
+/// let v1 = VReg::new(1, RegClass::Int);
+/// let mut b = FunctionBuilder::new();
+/// b.block([]);
+/// b.inst([Operand::reg_def(v0)]);
+/// b.branch([]);
+/// b.succ(Block::new(1), [v0]);
+/// b.block([v1]);
+/// b.ret([Operand::reg_use(v1)]);
+/// let func = b.finish();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FunctionBuilder {
+    func: BuiltFunction,
+}
+
+impl FunctionBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a new block with the given params; subsequent
+    /// instructions are appended to it.
+    pub fn block(&mut self, params: impl IntoIterator<Item = VReg>) -> Block {
+        let f = &mut self.func;
+        let block = Block::new(f.block_starts.len());
+        f.block_starts.push(f.kinds.len() as u32);
+        f.param_starts.push(f.params.len() as u32);
+        f.succ_starts.push(f.succs.len() as u32);
+        for param in params {
+            f.note_vreg(param);
+            f.params.push(param);
+        }
+        block
+    }
+
+    /// Append a plain instruction to the current block.
+    pub fn inst(&mut self, operands: impl IntoIterator<Item = Operand>) -> Inst {
+        self.push(InstKind::Op, operands, PRegSet::empty())
+    }
+
+    /// Append a plain instruction that also clobbers `clobbers`; see
+    /// `Function::inst_clobbers`.
+    pub fn inst_with_clobbers(
+        &mut self,
+        operands: impl IntoIterator<Item = Operand>,
+        clobbers: PRegSet,
+    ) -> Inst {
+        self.push(InstKind::Op, operands, clobbers)
+    }
+
+    /// End the current block with a return.
+    pub fn ret(&mut self, operands: impl IntoIterator<Item = Operand>) -> Inst {
+        self.push(InstKind::Ret, operands, PRegSet::empty())
+    }
+
+    /// End the current block with a branch. Its successors are added
+    /// afterwards with `succ`.
+    pub fn branch(&mut self, operands: impl IntoIterator<Item = Operand>) -> Inst {
+        self.push(InstKind::Branch, operands, PRegSet::empty())
+    }
+
+    /// Add a successor to the branch ending the current block, passing
+    /// `args` to its block params. Successors are numbered in the
+    /// order they are added.
+    pub fn succ(&mut self, block: Block, args: impl IntoIterator<Item = VReg>) {
+        let f = &mut self.func;
+        debug_assert_eq!(f.kinds.last(), Some(&InstKind::Branch));
+        f.succs.push(block);
+        f.arg_starts.push(f.args.len() as u32);
+        for arg in args {
+            f.note_vreg(arg);
+            f.args.push(arg);
+        }
+    }
+
+    /// Record that `vreg` carries debug label `label` from `from`
+    /// (inclusive) to `to` (exclusive); see
+    /// `Function::debug_value_labels`. Labels may be added in any
+    /// order.
+    pub fn debug_value_label(&mut self, vreg: VReg, from: Inst, to: Inst, label: u32) {
+        self.func.debug_value_labels.push((vreg, from, to, label));
+    }
+
+    /// Set the number of spillslots a value of `class` occupies; see
+    /// `Function::spillslot_size`. Defaults to 1.
+    pub fn set_spillslot_size(&mut self, class: RegClass, size: usize) {
+        self.func.spillslot_size[class as usize] = size;
+    }
+
+    /// Finish the function: compute predecessor lists and seal the
+    /// per-block arrays.
+    pub fn finish(self) -> BuiltFunction {
+        let mut f = self.func;
+        let num_blocks = f.block_starts.len();
+        f.block_starts.push(f.kinds.len() as u32);
+        f.param_starts.push(f.params.len() as u32);
+        f.succ_starts.push(f.succs.len() as u32);
+        f.operand_starts.push(f.operands.len() as u32);
+        f.arg_starts.push(f.args.len() as u32);
+
+        // Bucket the edges by target, in source-block order.
+        let mut pred_starts = vec![0u32; num_blocks + 1];
+        for &succ in &f.succs {
+            pred_starts[succ.index() + 1] += 1;
+        }
+        for i in 0..num_blocks {
+            pred_starts[i + 1] += pred_starts[i];
+        }
+        let mut fill = pred_starts.clone();
+        f.preds = vec![Block::invalid(); f.succs.len()];
+        for block in 0..num_blocks {
+            for &succ in &f.succs[range(&f.succ_starts, block)] {
+                f.preds[fill[succ.index()] as usize] = Block::new(block);
+                fill[succ.index()] += 1;
+            }
+        }
+        f.pred_starts = pred_starts;
+
+        f.debug_value_labels.sort_unstable();
+        f
+    }
+
+    fn push(
+        &mut self,
+        kind: InstKind,
+        operands: impl IntoIterator<Item = Operand>,
+        clobbers: PRegSet,
+    ) -> Inst {
+        let f = &mut self.func;
+        debug_assert!(!f.block_starts.is_empty(), "instruction outside a block");
+        let inst = Inst::new(f.kinds.len());
+        f.kinds.push(kind);
+        f.operand_starts.push(f.operands.len() as u32);
+        for op in operands {
+            f.note_vreg(op.vreg());
+            f.operands.push(op);
+        }
+        if clobbers != PRegSet::empty() {
+            f.clobbers.push((inst, clobbers));
+        }
+        inst
+    }
+}
+
+/// A `Function` produced by `FunctionBuilder`.
+///
+/// All per-instruction and per-block lists live in a handful of flat
+/// arrays indexed through offset tables, so the overhead beyond the
+/// operands themselves is a few bytes per instruction, block and edge.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BuiltFunction {
+    kinds: Vec<InstKind>,
+    operands: Vec<Operand>,
+    operand_starts: Vec<u32>,
+    // Only instructions with a non-empty clobber set, in order.
+    clobbers: Vec<(Inst, PRegSet)>,
+    block_starts: Vec<u32>,
+    params: Vec<VReg>,
+    param_starts: Vec<u32>,
+    succs: Vec<Block>,
+    succ_starts: Vec<u32>,
+    preds: Vec<Block>,
+    pred_starts: Vec<u32>,
+    // Branch args, indexed by edge (position in `succs`).
+    args: Vec<VReg>,
+    arg_starts: Vec<u32>,
+    num_vregs: usize,
+    debug_value_labels: Vec<(VReg, Inst, Inst, u32)>,
+    spillslot_size: [usize; 3],
+}
+
+impl Default for BuiltFunction {
+    fn default() -> Self {
+        Self {
+            kinds: vec![],
+            operands: vec![],
+            operand_starts: vec![],
+            clobbers: vec![],
+            block_starts: vec![],
+            params: vec![],
+            param_starts: vec![],
+            succs: vec![],
+            succ_starts: vec![],
+            preds: vec![],
+            pred_starts: vec![],
+            args: vec![],
+            arg_starts: vec![],
+            num_vregs: 0,
+            debug_value_labels: vec![],
+            spillslot_size: [1; 3],
+        }
+    }
+}
+
+impl BuiltFunction {
+    fn note_vreg(&mut self, vreg: VReg) {
+        if vreg.vreg() != VReg::MAX {
+            self.num_vregs = core::cmp::max(self.num_vregs, vreg.vreg() + 1);
+        }
+    }
+}
+
+/// The `i`th range of a sealed offset table.
+fn range(starts: &[u32], i: usize) -> Range<usize> {
+    starts[i] as usize..starts[i + 1] as usize
+}
+
+impl Function for BuiltFunction {
+    fn num_insts(&self) -> usize {
+        self.kinds.len()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.block_starts.len() - 1
+    }
+
+    fn entry_block(&self) -> Block {
+        Block::new(0)
+    }
+
+    fn block_insns(&self, block: Block) -> InstRange {
+        let insts = range(&self.block_starts, block.index());
+        InstRange::new(Inst::new(insts.start), Inst::new(insts.end))
+    }
+
+    fn block_succs(&self, block: Block) -> &[Block] {
+        &self.succs[range(&self.succ_starts, block.index())]
+    }
+
+    fn block_preds(&self, block: Block) -> &[Block] {
+        &self.preds[range(&self.pred_starts, block.index())]
+    }
+
+    fn block_params(&self, block: Block) -> &[VReg] {
+        &self.params[range(&self.param_starts, block.index())]
+    }
+
+    fn is_ret(&self, insn: Inst) -> bool {
+        self.kinds[insn.index()] == InstKind::Ret
+    }
+
+    fn is_branch(&self, insn: Inst) -> bool {
+        self.kinds[insn.index()] == InstKind::Branch
+    }
+
+    fn branch_blockparams(&self, block: Block, _insn: Inst, succ_idx: usize) -> &[VReg] {
+        let edge = self.succ_starts[block.index()] as usize + succ_idx;
+        &self.args[range(&self.arg_starts, edge)]
+    }
+
+    fn inst_operands(&self, insn: Inst) -> &[Operand] {
+        &self.operands[range(&self.operand_starts, insn.index())]
+    }
+
+    fn inst_clobbers(&self, insn: Inst) -> PRegSet {
+        match self.clobbers.binary_search_by_key(&insn, |&(inst, _)| inst) {
+            Ok(i) => self.clobbers[i].1,
+            Err(_) => PRegSet::empty(),
+        }
+    }
+
+    fn num_vregs(&self) -> usize {
+        self.num_vregs
+    }
+
+    fn debug_value_labels(&self) -> &[(VReg, Inst, Inst, u32)] {
+        &self.debug_value_labels
+    }
+
+    fn spillslot_size(&self, regclass: RegClass) -> usize {
+        self.spillslot_size[regclass as usize]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FunctionBuilder;
+    use crate::checker::Checker;
+    use crate::testing::int_env;
+    use crate::{Block, Function, Operand, PReg, PRegSet, RegClass, RegallocOptions, VReg};
+
+    #[test]
+    fn test_builder_loop() {
+        let v = |i| VReg::new(i, RegClass::Int);
+        let mut b = FunctionBuilder::new();
+        b.block([]);
+        b.inst([Operand::reg_def(v(0))]);
+        b.branch([]);
+        b.succ(Block::new(1), [v(0)]);
+        b.block([v(1)]);
+        b.inst_with_clobbers(
+            [Operand::reg_use(v(1)), Operand::reg_def(v(2))],
+            PRegSet::empty().with(PReg::new(0, RegClass::Int)),
+        );
+        b.branch([]);
+        b.succ(Block::new(2), []);
+        b.succ(Block::new(3), []);
+        b.block([]);
+        b.branch([]);
+        b.succ(Block::new(1), [v(2)]);
+        b.block([]);
+        b.ret([Operand::reg_use(v(2))]);
+        let f = b.finish();
+
+        assert_eq!(f.num_blocks(), 4);
+        assert_eq!(f.num_vregs(), 3);
+        assert_eq!(f.block_preds(Block::new(1)), [Block::new(0), Block::new(2)]);
+        assert_eq!(f.block_succs(Block::new(1)), [Block::new(2), Block::new(3)]);
+        assert_eq!(
+            f.branch_blockparams(Block::new(2), f.block_insns(Block::new(2)).last(), 0),
+            [v(2)]
+        );
+        assert_eq!(
+            f.inst_clobbers(f.block_insns(Block::new(1)).first()),
+            PRegSet::empty().with(PReg::new(0, RegClass::Int))
+        );
+
+        let env = int_env(2);
+        let out = crate::run(&f, &env, &RegallocOptions::default()).unwrap();
+        let mut checker = Checker::new(&f, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+    }
+}
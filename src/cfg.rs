@@ -10,18 +10,23 @@ use alloc::vec;
 use alloc::vec::Vec;
 use smallvec::{smallvec, SmallVec};
 
+/// The CFG analyses the allocator runs on a function before
+/// allocating it: block layout, postorder, dominator tree and loop
+/// depth. A frontend that needs the same information can build one
+/// with `CFGInfo::new` rather than compute its own; it is read-only
+/// from the outside.
 #[derive(Clone, Debug)]
 pub struct CFGInfo {
     /// Postorder traversal of blocks.
-    pub postorder: Vec<Block>,
+    pub(crate) postorder: Vec<Block>,
     /// Domtree parents, indexed by block.
-    pub domtree: Vec<Block>,
+    pub(crate) domtree: Vec<Block>,
     /// For each instruction, the block it belongs to.
-    pub insn_block: Vec<Block>,
+    pub(crate) insn_block: Vec<Block>,
     /// For each block, the first instruction.
-    pub block_entry: Vec<ProgPoint>,
+    pub(crate) block_entry: Vec<ProgPoint>,
     /// For each block, the last instruction.
-    pub block_exit: Vec<ProgPoint>,
+    pub(crate) block_exit: Vec<ProgPoint>,
     /// For each block, what is the approximate loop depth?
     ///
     /// This measure is fully precise iff the input CFG is reducible
@@ -29,10 +34,15 @@ pub struct CFGInfo {
     /// those whose block target indices are less than their source
     /// indices. Otherwise, it will be approximate, but should still
     /// be usable for heuristic purposes.
-    pub approx_loop_depth: Vec<u32>,
+    pub(crate) approx_loop_depth: Vec<u32>,
+    /// For each block, whether the function marked it as cold.
+    pub(crate) block_is_cold: Vec<bool>,
 }
 
 impl CFGInfo {
+    /// Analyze `f`'s CFG. Fails if `f` has a critical edge or passes
+    /// arguments on a branch into a block with several predecessors,
+    /// which the allocator does not accept either.
     pub fn new<F: Function>(f: &F) -> Result<CFGInfo, RegAllocError> {
         let postorder = postorder::calculate(f.num_blocks(), f.entry_block(), |block| {
             f.block_succs(block)
@@ -126,10 +136,87 @@ impl CFGInfo {
             block_entry,
             block_exit,
             approx_loop_depth,
+            block_is_cold: (0..f.num_blocks())
+                .map(|block| f.block_is_cold(Block::new(block)))
+                .collect(),
         })
     }
 
+    /// Whether block `a` dominates block `b`; every block dominates
+    /// itself.
     pub fn dominates(&self, a: Block, b: Block) -> bool {
         domtree::dominates(&self.domtree[..], a, b)
     }
+
+    /// The immediate dominator of `block`, or `Block::invalid()` for
+    /// the entry block and unreachable blocks.
+    pub fn idom(&self, block: Block) -> Block {
+        self.domtree[block.index()]
+    }
+
+    /// The blocks reachable from the entry block, in postorder.
+    pub fn postorder(&self) -> &[Block] {
+        &self.postorder
+    }
+
+    /// The block that `inst` belongs to.
+    pub fn inst_block(&self, inst: Inst) -> Block {
+        self.insn_block[inst.index()]
+    }
+
+    /// The first program point of `block`: `Before` its first
+    /// instruction.
+    pub fn block_entry(&self, block: Block) -> ProgPoint {
+        self.block_entry[block.index()]
+    }
+
+    /// The last program point of `block`: `After` its last
+    /// instruction.
+    pub fn block_exit(&self, block: Block) -> ProgPoint {
+        self.block_exit[block.index()]
+    }
+
+    /// The approximate loop nesting depth of `block`, as used for
+    /// spill weights: exact for a reducible CFG with blocks in
+    /// reverse postorder, a heuristic otherwise.
+    pub fn approx_loop_depth(&self, block: Block) -> u32 {
+        self.approx_loop_depth[block.index()]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CFGInfo;
+    use crate::testing::TestFunc;
+    use crate::{Block, Inst, ProgPoint};
+
+    #[test]
+    fn test_accessors() {
+        // A diamond: block 0 branches to blocks 1 and 2, which both
+        // jump to block 3.
+        let b = Block::new;
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[]);
+        f.branch(&[(b(1), &[]), (b(2), &[])]);
+        f.block(&[]);
+        f.branch(&[(b(3), &[])]);
+        f.block(&[]);
+        f.branch(&[(b(3), &[])]);
+        f.block(&[]);
+        let ret = f.ret(&[]);
+
+        let cfg = CFGInfo::new(&f).unwrap();
+        assert_eq!(cfg.inst_block(Inst::new(1)), b(0));
+        assert_eq!(cfg.inst_block(ret), b(3));
+        assert_eq!(cfg.block_entry(b(0)), ProgPoint::before(Inst::new(0)));
+        assert_eq!(cfg.block_exit(b(0)), ProgPoint::after(Inst::new(1)));
+        assert_eq!(cfg.block_entry(b(3)), ProgPoint::before(ret));
+        assert_eq!(cfg.postorder().len(), 4);
+        assert_eq!(cfg.postorder().last(), Some(&b(0)));
+        assert_eq!(cfg.idom(b(3)), b(0));
+        assert!(cfg.idom(b(0)).is_invalid());
+        assert!(cfg.dominates(b(0), b(2)) && !cfg.dominates(b(1), b(3)));
+        assert_eq!(cfg.approx_loop_depth(b(3)), 0);
+    }
 }
@@ -67,6 +67,23 @@
 //!
 //!       A' = A[alloc_d → A[alloc_s]]
 //!
+//!   - `Edit::Remat` or `Edit::Sink` by RA: [ alloc_d := remat V_i ]
+//!
+//!       A' = A[alloc_d → { V_i }]
+//!
+//!   - `Edit::Reinterpret` inserted by RA: [ view alloc as class ]
+//!
+//!       A' = A
+//!
+//!   - safepoint with stackmap S, where R are the reference vregs
+//!     live across it               [ collect garbage ]
+//!
+//!       A' = A[A_k → A[A_k] \ R for all A_k ∉ S]
+//!
+//!     The collector may move any live reference, so only the copies
+//!     it knows about remain valid. (Only checked when enabled with
+//!     `Checker::enable_stackmap_checks`.)
+//!
 //!   - statement in pre-regalloc function [ V_i := op V_j, V_k, ... ]
 //!     with allocated form                [ A_i := op A_j, A_k, ... ]
 //!
@@ -91,14 +108,16 @@
 //! examine the dataflow solution at the preceding program point, and
 //! check that the allocation for each op arg (input/use) contains the
 //! symbol corresponding to the original virtual register specified
-//! for this arg.
+//! for this arg. At each safepoint, every allocation in the stackmap
+//! must hold a live reference, and every live reference must be in
+//! some allocation of the stackmap.
 
 #![allow(dead_code)]
 
 use crate::{
-    Allocation, AllocationKind, Block, Edit, Function, FxHashMap, FxHashSet, Inst, InstOrEdit,
-    InstPosition, MachineEnv, Operand, OperandConstraint, OperandKind, OperandPos, Output, PReg,
-    PRegSet, VReg,
+    all_clobbers, block_edge_defs, block_entry_args, Allocation, AllocationKind, Block, Edit,
+    Function, FxHashMap, FxHashSet, Inst, InstOrEdit, InstPosition, MachineEnv, Operand,
+    OperandConstraint, OperandKind, OperandPos, Output, PReg, PRegSet, ProgPoint, VReg,
 };
 use alloc::vec::Vec;
 use alloc::{format, vec};
@@ -146,6 +165,16 @@ pub enum CheckerError {
         op: Operand,
         alloc: Allocation,
     },
+    AllocationIsNotRegPair {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
+    AllocationIsNotInGroup {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
     AllocationIsNotFixedReg {
         inst: Inst,
         op: Operand,
@@ -162,6 +191,11 @@ pub enum CheckerError {
         op: Operand,
         alloc: Allocation,
     },
+    AllocationIsNotAligned {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
     ConflictedValueInStackmap {
         inst: Inst,
         alloc: Allocation,
@@ -171,6 +205,10 @@ pub enum CheckerError {
         alloc: Allocation,
         vregs: FxHashSet<VReg>,
     },
+    MissingRefInStackmap {
+        safepoint: ProgPoint,
+        vreg: VReg,
+    },
     StackToStackMove {
         into: Allocation,
         from: Allocation,
@@ -487,9 +525,12 @@ impl CheckerState {
                 // Skip Use-checks at the After point if there are any
                 // reused inputs: the Def which reuses the input
                 // happens early.
-                let has_reused_input = operands
-                    .iter()
-                    .any(|op| matches!(op.constraint(), OperandConstraint::Reuse(_)));
+                let has_reused_input = operands.iter().any(|op| {
+                    matches!(
+                        op.constraint(),
+                        OperandConstraint::Reuse(_) | OperandConstraint::ReuseStack(_)
+                    )
+                });
                 if has_reused_input && pos == InstPosition::After {
                     return Ok(());
                 }
@@ -497,13 +538,19 @@ impl CheckerState {
                 // For each operand, check (i) that the allocation
                 // contains the expected vreg, and (ii) that it meets
                 // the requirements of the OperandConstraint.
-                for (op, alloc) in operands.iter().zip(allocs.iter()) {
+                // Immediates (see `Function::operand_is_immediate`)
+                // are left unallocated. The high halves of register
+                // pairs come after the instruction's own operands.
+                let num_operands = checker.f.inst_operands(inst).len();
+                for (slot, (op, alloc)) in operands.iter().zip(allocs.iter()).enumerate() {
                     let is_here = match (op.pos(), pos) {
                         (OperandPos::Early, InstPosition::Before) => true,
                         (OperandPos::Late, InstPosition::After) => true,
                         _ => false,
                     };
-                    if !is_here {
+                    if !is_here
+                        || (slot < num_operands && checker.f.operand_is_immediate(inst, slot))
+                    {
                         continue;
                     }
 
@@ -519,7 +566,8 @@ impl CheckerState {
                 }
             }
             &CheckerInst::Move { into, from } => {
-                // Ensure that the allocator never returns stack-to-stack moves.
+                // Ensure that the allocator never returns stack-to-stack
+                // moves unless the target supports them.
                 let is_stack = |alloc: Allocation| {
                     if let Some(reg) = alloc.as_reg() {
                         checker.stack_pregs.contains(reg)
@@ -527,11 +575,49 @@ impl CheckerState {
                         alloc.is_stack()
                     }
                 };
-                if is_stack(into) && is_stack(from) {
+                if is_stack(into) && is_stack(from) && !checker.machine_env.stack_to_stack_moves {
                     return Err(CheckerError::StackToStackMove { into, from });
                 }
             }
-            &CheckerInst::ParallelMove { .. } => {
+            &CheckerInst::Safepoint {
+                inst,
+                ref stackmap,
+                ref live_refs,
+            } => {
+                if pos == InstPosition::After {
+                    return Ok(());
+                }
+                for &alloc in stackmap {
+                    match self.get_value(&alloc).unwrap_or(&default_val) {
+                        CheckerValue::Universe => {
+                            return Err(CheckerError::ConflictedValueInStackmap { inst, alloc });
+                        }
+                        CheckerValue::VRegs(vregs) => {
+                            if !vregs.iter().any(|vreg| live_refs.contains(vreg)) {
+                                return Err(CheckerError::NonRefValuesInStackmap {
+                                    inst,
+                                    alloc,
+                                    vregs: vregs.clone(),
+                                });
+                            }
+                        }
+                    }
+                }
+                for &vreg in live_refs {
+                    let in_stackmap = stackmap.iter().any(|alloc| {
+                        self.get_value(alloc)
+                            .and_then(|val| val.vregs())
+                            .is_some_and(|vregs| vregs.contains(&vreg))
+                    });
+                    if !in_stackmap {
+                        return Err(CheckerError::MissingRefInStackmap {
+                            safepoint: ProgPoint::before(inst),
+                            vreg,
+                        });
+                    }
+                }
+            }
+            &CheckerInst::ParallelMove { .. } | &CheckerInst::Remat { .. } => {
                 // This doesn't need verification; we just update
                 // according to the move semantics in the step
                 // function below.
@@ -563,6 +649,24 @@ impl CheckerState {
                     self.set_value(into, val);
                 }
             }
+            &CheckerInst::Remat { into, vreg } => {
+                self.set_value(into, CheckerValue::from_reg(vreg));
+            }
+            CheckerInst::Safepoint {
+                stackmap,
+                live_refs,
+                ..
+            } => {
+                // Copies of a live reference outside the stackmap
+                // may now point to where the object used to be.
+                for (alloc, value) in self.get_mappings_mut() {
+                    if !stackmap.contains(alloc) {
+                        for &vreg in live_refs {
+                            value.remove_vreg(vreg);
+                        }
+                    }
+                }
+            }
             &CheckerInst::ParallelMove { ref moves } => {
                 // First, build map of actions for each vreg in an
                 // alloc. If an alloc has a reg V_i before a parallel
@@ -610,16 +714,21 @@ impl CheckerState {
                 // vreg (and only that vreg), and (ii) update all
                 // other allocs in the checker state by removing this
                 // vreg, if defined (other defs are now stale).
-                for (op, alloc) in operands.iter().zip(allocs.iter()) {
-                    if op.kind() != OperandKind::Def {
-                        continue;
+                for op in operands {
+                    if op.kind() == OperandKind::Def {
+                        self.remove_vreg(op.vreg());
                     }
-                    self.remove_vreg(op.vreg());
-                    self.set_value(*alloc, CheckerValue::from_reg(op.vreg()));
                 }
+                // A register may be both clobbered and defined; the
+                // def is what it holds afterwards.
                 for clobber in clobbers {
                     self.remove_value(&Allocation::reg(*clobber));
                 }
+                for (op, alloc) in operands.iter().zip(allocs.iter()) {
+                    if op.kind() == OperandKind::Def {
+                        self.set_value(*alloc, CheckerValue::from_reg(op.vreg()));
+                    }
+                }
             }
         }
     }
@@ -649,6 +758,22 @@ impl CheckerState {
                 }
                 return Err(CheckerError::AllocationIsNotReg { inst, op, alloc });
             }
+            OperandConstraint::RegPair => {
+                if let Some(preg) = alloc.as_reg() {
+                    if preg.hw_enc() & 1 == 0
+                        && !checker.machine_env.fixed_stack_slots.contains(&preg)
+                    {
+                        return Ok(());
+                    }
+                }
+                return Err(CheckerError::AllocationIsNotRegPair { inst, op, alloc });
+            }
+            OperandConstraint::RegInGroup(idx) => {
+                let group = checker.machine_env.reg_groups[idx];
+                if !alloc.as_reg().is_some_and(|preg| group.contains(preg)) {
+                    return Err(CheckerError::AllocationIsNotInGroup { inst, op, alloc });
+                }
+            }
             OperandConstraint::FixedReg(preg) => {
                 if alloc != Allocation::reg(preg) {
                     return Err(CheckerError::AllocationIsNotFixedReg { inst, op, alloc });
@@ -667,6 +792,35 @@ impl CheckerState {
                     });
                 }
             }
+            OperandConstraint::Stack(align) => {
+                let slot = match alloc.as_stack() {
+                    Some(slot) => slot.index(),
+                    None => return Err(CheckerError::AllocationIsNotStack { inst, op, alloc }),
+                };
+                // The slot's offset is its lowest unit.
+                let offset = if checker.f.multi_spillslot_named_by_last_slot() {
+                    slot + 1
+                        - crate::ion::spillslot_size(checker.f, checker.machine_env, op.class())
+                } else {
+                    slot
+                };
+                if offset % align != 0 {
+                    return Err(CheckerError::AllocationIsNotAligned { inst, op, alloc });
+                }
+            }
+            OperandConstraint::ReuseStack(idx) => {
+                if alloc.kind() != AllocationKind::Stack {
+                    return Err(CheckerError::AllocationIsNotStack { inst, op, alloc });
+                }
+                if alloc != allocs[idx] {
+                    return Err(CheckerError::AllocationIsNotReuse {
+                        inst,
+                        op,
+                        alloc,
+                        expected_alloc: allocs[idx],
+                    });
+                }
+            }
         }
         Ok(())
     }
@@ -679,6 +833,10 @@ pub(crate) enum CheckerInst {
     /// spillslots).
     Move { into: Allocation, from: Allocation },
 
+    /// A rematerialization of a vreg inserted by RA. Copies of the
+    /// vreg elsewhere are still valid.
+    Remat { into: Allocation, vreg: VReg },
+
     /// A parallel move in the original program. Simultaneously moves
     /// from all source vregs to all corresponding dest vregs,
     /// permitting overlap in the src and dest sets and doing all
@@ -697,6 +855,14 @@ pub(crate) enum CheckerInst {
         allocs: Vec<Allocation>,
         clobbers: Vec<PReg>,
     },
+
+    /// A safepoint, with the stackmap the allocator reported for it
+    /// and the reference vregs that are actually live across it.
+    Safepoint {
+        inst: Inst,
+        stackmap: Vec<Allocation>,
+        live_refs: Vec<VReg>,
+    },
 }
 
 #[derive(Debug)]
@@ -707,6 +873,11 @@ pub struct Checker<'a, F: Function> {
     edge_insts: FxHashMap<(Block, Block), Vec<CheckerInst>>,
     machine_env: &'a MachineEnv,
     stack_pregs: PRegSet,
+    pair_vregs: Vec<bool>,
+    check_stackmaps: bool,
+    /// The reference vregs live across each safepoint, if
+    /// `check_stackmaps` is set.
+    safepoint_refs: FxHashMap<Inst, Vec<VReg>>,
 }
 
 impl<'a, F: Function> Checker<'a, F> {
@@ -742,16 +913,122 @@ impl<'a, F: Function> Checker<'a, F> {
             edge_insts,
             machine_env,
             stack_pregs,
+            pair_vregs: crate::ion::pair_vregs(f),
+            check_stackmaps: false,
+            safepoint_refs: FxHashMap::default(),
+        }
+    }
+
+    /// Also check the stackmaps of the safepoints (see
+    /// `Function::requires_stackmap` and `Output::stackmaps`): each
+    /// must list exactly the allocations holding the reference vregs
+    /// that are live across it. Must be called before `prepare()`.
+    pub fn enable_stackmap_checks(&mut self) {
+        self.check_stackmaps = true;
+    }
+
+    /// Compute the reference vregs live across each safepoint, i.e.
+    /// live after it and not defined by it, from the function alone.
+    fn compute_safepoint_refs(&self) -> FxHashMap<Inst, Vec<VReg>> {
+        let f = self.f;
+        let mut live_ins: Vec<FxHashSet<VReg>> = vec![FxHashSet::default(); f.num_blocks()];
+        let mut safepoint_refs = FxHashMap::default();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in (0..f.num_blocks()).rev() {
+                let block = Block::new(block);
+                let insns = f.block_insns(block);
+                let mut live = FxHashSet::default();
+                for &succ in f.block_succs(block) {
+                    live.extend(live_ins[succ.index()].iter().copied());
+                }
+                if f.is_branch(insns.last()) {
+                    for i in 0..f.block_succs(block).len() {
+                        for &arg in f.branch_blockparams(block, insns.last(), i) {
+                            if f.is_reference_vreg(arg) {
+                                live.insert(arg);
+                            }
+                        }
+                    }
+                }
+                for inst in insns.iter().rev() {
+                    let operands = f.inst_operands(inst);
+                    if !f.is_branch(inst) && f.requires_stackmap(inst) {
+                        let mut refs: Vec<VReg> = live
+                            .iter()
+                            .copied()
+                            .filter(|&vreg| {
+                                !operands
+                                    .iter()
+                                    .any(|op| op.kind() == OperandKind::Def && op.vreg() == vreg)
+                            })
+                            .collect();
+                        refs.sort_unstable();
+                        safepoint_refs.insert(inst, refs);
+                    }
+                    for pos in [OperandPos::Late, OperandPos::Early] {
+                        for (slot, op) in operands.iter().enumerate() {
+                            if op.pos() != pos
+                                || op.as_fixed_nonallocatable().is_some()
+                                || f.operand_is_immediate(inst, slot)
+                                || !f.is_reference_vreg(op.vreg())
+                            {
+                                continue;
+                            }
+                            match op.kind() {
+                                OperandKind::Use => live.insert(op.vreg()),
+                                OperandKind::Def => live.remove(&op.vreg()),
+                            };
+                        }
+                    }
+                }
+                for param in f.block_params(block) {
+                    live.remove(param);
+                }
+                if let Some((_, defs)) = block_edge_defs(f, block) {
+                    for (vreg, _) in defs {
+                        live.remove(vreg);
+                    }
+                }
+                if live != live_ins[block.index()] {
+                    live_ins[block.index()] = live;
+                    changed = true;
+                }
+            }
         }
+        safepoint_refs
     }
 
     /// Build the list of checker instructions based on the given func
     /// and allocation results.
     pub fn prepare(&mut self, out: &Output) {
         trace!("checker: out = {:?}", out);
+        if self.check_stackmaps {
+            self.safepoint_refs = self.compute_safepoint_refs();
+        }
         let mut last_inst = None;
         for block in 0..self.f.num_blocks() {
             let block = Block::new(block);
+            // The function's arguments and the values passed to a
+            // landing pad appear in their registers before anything
+            // else happens.
+            let args = block_entry_args(self.f, block);
+            if !args.is_empty() {
+                let checkinst = CheckerInst::Op {
+                    inst: self.f.block_insns(block).first(),
+                    operands: args
+                        .iter()
+                        .map(|&(vreg, preg)| Operand::reg_fixed_def(vreg, preg))
+                        .collect(),
+                    allocs: args
+                        .iter()
+                        .map(|&(_, preg)| Allocation::reg(preg))
+                        .collect(),
+                    clobbers: vec![],
+                };
+                self.bb_insts.get_mut(&block).unwrap().push(checkinst);
+            }
             for inst_or_edit in out.block_insts_and_edits(self.f, block) {
                 match inst_or_edit {
                     InstOrEdit::Inst(inst) => {
@@ -771,9 +1048,53 @@ impl<'a, F: Function> Checker<'a, F> {
         // not exist in post-regalloc code, and the edge-moves have to
         // be inserted before the branch rather than after.
         if !self.f.is_branch(inst) {
-            let operands: Vec<_> = self.f.inst_operands(inst).iter().cloned().collect();
-            let allocs: Vec<_> = out.inst_allocs(inst).iter().cloned().collect();
-            let clobbers: Vec<_> = self.f.inst_clobbers(inst).into_iter().collect();
+            let mut operands: Vec<_> = self.f.inst_operands(inst).iter().cloned().collect();
+            let mut allocs: Vec<_> = out.inst_allocs(inst).iter().cloned().collect();
+            // Track the high half of each register pair as an extra
+            // operand, so that both halves must hold the vreg.
+            for i in 0..operands.len() {
+                let op = operands[i];
+                if self.pair_vregs.get(op.vreg().vreg()) == Some(&true) {
+                    let (_, hi) =
+                        crate::ion::pair_halves(self.f, self.machine_env, allocs[i], op.class());
+                    operands.push(Operand::new(
+                        op.vreg(),
+                        OperandConstraint::Any,
+                        op.kind(),
+                        op.pos(),
+                    ));
+                    allocs.push(hi);
+                }
+            }
+            // Early kills empty their registers before the inputs are
+            // read, just like the clobbers of an operand-less
+            // instruction would. So do the instruction's temps, which
+            // must not hold any input or value live across it.
+            let mut kills = self.f.inst_early_kills(inst);
+            for temp in out.inst_temp_allocs(inst) {
+                match temp.as_reg() {
+                    Some(preg) => kills.add(preg),
+                    None => panic!("temp of {:?} not in a register: {}", inst, temp),
+                }
+            }
+            if kills != PRegSet::empty() {
+                let checkinst = CheckerInst::Op {
+                    inst,
+                    operands: vec![],
+                    allocs: vec![],
+                    clobbers: kills.into_iter().collect(),
+                };
+                self.bb_insts.get_mut(&block).unwrap().push(checkinst);
+            }
+            // A register taken over a range that the instruction
+            // overlaps holds nothing past it.
+            let mut clobbers = all_clobbers(self.f, self.machine_env, inst);
+            for &(preg, from, to) in self.f.unavailable_ranges() {
+                if from <= ProgPoint::after(inst) && ProgPoint::before(inst) < to {
+                    clobbers.add(preg);
+                }
+            }
+            let clobbers: Vec<_> = clobbers.into_iter().collect();
             let checkinst = CheckerInst::Op {
                 inst,
                 operands,
@@ -782,6 +1103,17 @@ impl<'a, F: Function> Checker<'a, F> {
             };
             trace!("checker: adding inst {:?}", checkinst);
             self.bb_insts.get_mut(&block).unwrap().push(checkinst);
+            // The instruction reads its operands before the collector
+            // runs, which cannot touch the values it defines or the
+            // registers it clobbers.
+            if let Some(live_refs) = self.safepoint_refs.get(&inst) {
+                let checkinst = CheckerInst::Safepoint {
+                    inst,
+                    stackmap: out.stackmap(inst).to_vec(),
+                    live_refs: live_refs.clone(),
+                };
+                self.bb_insts.get_mut(&block).unwrap().push(checkinst);
+            }
         }
         // Instead, if this is a branch, emit a ParallelMove on each
         // outgoing edge as necessary to handle blockparams.
@@ -805,6 +1137,27 @@ impl<'a, F: Function> Checker<'a, F> {
                         .unwrap()
                         .push(CheckerInst::ParallelMove { moves });
                 }
+                // Values defined on this edge only appear in their
+                // registers on it.
+                let edge_defs = self.f.branch_edge_defs(block, inst, i);
+                if !edge_defs.is_empty() {
+                    let checkinst = CheckerInst::Op {
+                        inst,
+                        operands: edge_defs
+                            .iter()
+                            .map(|&(vreg, preg)| Operand::reg_fixed_def(vreg, preg))
+                            .collect(),
+                        allocs: edge_defs
+                            .iter()
+                            .map(|&(_, preg)| Allocation::reg(preg))
+                            .collect(),
+                        clobbers: vec![],
+                    };
+                    self.edge_insts
+                        .get_mut(&(block, succ))
+                        .unwrap()
+                        .push(checkinst);
+                }
             }
         }
     }
@@ -818,6 +1171,14 @@ impl<'a, F: Function> Checker<'a, F> {
                     .unwrap()
                     .push(CheckerInst::Move { into: to, from });
             }
+            &Edit::Remat { vreg, to } | &Edit::Sink { vreg, to, .. } => {
+                self.bb_insts
+                    .get_mut(&block)
+                    .unwrap()
+                    .push(CheckerInst::Remat { into: to, vreg });
+            }
+            // The value stays where it is.
+            &Edit::Reinterpret { .. } => {}
         }
     }
 
@@ -960,6 +1321,21 @@ impl<'a, F: Function> Checker<'a, F> {
                     &CheckerInst::Move { from, into } => {
                         trace!("    {} -> {}", from, into);
                     }
+                    &CheckerInst::Remat { vreg, into } => {
+                        trace!("    remat {} -> {}", vreg, into);
+                    }
+                    &CheckerInst::Safepoint {
+                        inst,
+                        ref stackmap,
+                        ref live_refs,
+                    } => {
+                        trace!(
+                            "  safepoint inst{}: stackmap {:?} live refs {:?}",
+                            inst.index(),
+                            stackmap,
+                            live_refs
+                        );
+                    }
                     &CheckerInst::ParallelMove { .. } => {
                         panic!("unexpected parallel_move in body (non-edge)")
                     }
@@ -973,14 +1349,19 @@ impl<'a, F: Function> Checker<'a, F> {
                 let mut state = state.clone();
                 for edge_inst in self.edge_insts.get(&(bb, succ)).unwrap() {
                     match edge_inst {
-                        &CheckerInst::ParallelMove { ref moves } => {
+                        CheckerInst::ParallelMove { moves } => {
                             let moves = moves
                                 .iter()
                                 .map(|(dest, src)| format!("{} -> {}", src, dest))
                                 .collect::<Vec<_>>();
                             trace!("    parallel_move {}", moves.join(", "));
                         }
-                        _ => panic!("unexpected edge_inst: not a parallel move"),
+                        CheckerInst::Op {
+                            operands, allocs, ..
+                        } => {
+                            trace!("    edge defs {:?} ({:?})", operands, allocs);
+                        }
+                        _ => panic!("unexpected edge_inst: not a parallel move or edge defs"),
                     }
                     state.update(edge_inst);
                     print_state(&state);
@@ -991,3 +1372,340 @@ impl<'a, F: Function> Checker<'a, F> {
         result
     }
 }
+
+/// The result of [`check_output`]: whether an allocation is correct,
+/// in a form that is deterministic and can be compared between runs,
+/// e.g. to check two allocator configurations against each other in a
+/// differential fuzzer.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CheckOutcome {
+    /// The output preserves the function's dataflow and satisfies
+    /// every operand constraint.
+    Correct,
+    /// The output does not have the shape the function requires
+    /// (e.g. the wrong number of allocations for an instruction), so
+    /// it was not analyzed.
+    Malformed(&'static str),
+    /// The checker found the given discrepancies (at least one),
+    /// sorted.
+    Incorrect(Vec<Discrepancy>),
+}
+
+impl CheckOutcome {
+    /// Whether the output was found to be correct.
+    pub fn is_correct(&self) -> bool {
+        *self == CheckOutcome::Correct
+    }
+}
+
+/// A [`CheckerError`] with its sets of vregs sorted, so that it can
+/// be ordered and compared.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Discrepancy {
+    /// The instruction the error was found at, if any.
+    pub inst: Option<Inst>,
+    /// The name of the `CheckerError` variant.
+    pub kind: &'static str,
+    /// The operand involved, if any.
+    pub operand: Option<Operand>,
+    /// The allocations involved: the operand's allocation, then the
+    /// expected one for a reuse; or the destination and source of a
+    /// stack-to-stack move.
+    pub allocs: Vec<Allocation>,
+    /// The vregs found in the allocation, where the error has them,
+    /// or the reference missing from a stackmap.
+    pub vregs: Vec<VReg>,
+}
+
+impl From<&CheckerError> for Discrepancy {
+    fn from(error: &CheckerError) -> Self {
+        let sorted = |vregs: &FxHashSet<VReg>| {
+            let mut vregs: Vec<VReg> = vregs.iter().copied().collect();
+            vregs.sort_unstable();
+            vregs
+        };
+        let (kind, inst, operand, allocs, vregs) = match error {
+            CheckerError::MissingAllocation { inst, op } => {
+                ("MissingAllocation", Some(*inst), Some(*op), vec![], vec![])
+            }
+            CheckerError::UnknownValueInAllocation { inst, op, alloc } => (
+                "UnknownValueInAllocation",
+                Some(*inst),
+                Some(*op),
+                vec![*alloc],
+                vec![],
+            ),
+            CheckerError::ConflictedValueInAllocation { inst, op, alloc } => (
+                "ConflictedValueInAllocation",
+                Some(*inst),
+                Some(*op),
+                vec![*alloc],
+                vec![],
+            ),
+            CheckerError::IncorrectValuesInAllocation {
+                inst,
+                op,
+                alloc,
+                actual,
+            } => (
+                "IncorrectValuesInAllocation",
+                Some(*inst),
+                Some(*op),
+                vec![*alloc],
+                sorted(actual),
+            ),
+            CheckerError::ConstraintViolated { inst, op, alloc } => (
+                "ConstraintViolated",
+                Some(*inst),
+                Some(*op),
+                vec![*alloc],
+                vec![],
+            ),
+            CheckerError::AllocationIsNotReg { inst, op, alloc } => (
+                "AllocationIsNotReg",
+                Some(*inst),
+                Some(*op),
+                vec![*alloc],
+                vec![],
+            ),
+            CheckerError::AllocationIsNotRegPair { inst, op, alloc } => (
+                "AllocationIsNotRegPair",
+                Some(*inst),
+                Some(*op),
+                vec![*alloc],
+                vec![],
+            ),
+            CheckerError::AllocationIsNotInGroup { inst, op, alloc } => (
+                "AllocationIsNotInGroup",
+                Some(*inst),
+                Some(*op),
+                vec![*alloc],
+                vec![],
+            ),
+            CheckerError::AllocationIsNotFixedReg { inst, op, alloc } => (
+                "AllocationIsNotFixedReg",
+                Some(*inst),
+                Some(*op),
+                vec![*alloc],
+                vec![],
+            ),
+            CheckerError::AllocationIsNotReuse {
+                inst,
+                op,
+                alloc,
+                expected_alloc,
+            } => (
+                "AllocationIsNotReuse",
+                Some(*inst),
+                Some(*op),
+                vec![*alloc, *expected_alloc],
+                vec![],
+            ),
+            CheckerError::AllocationIsNotStack { inst, op, alloc } => (
+                "AllocationIsNotStack",
+                Some(*inst),
+                Some(*op),
+                vec![*alloc],
+                vec![],
+            ),
+            CheckerError::AllocationIsNotAligned { inst, op, alloc } => (
+                "AllocationIsNotAligned",
+                Some(*inst),
+                Some(*op),
+                vec![*alloc],
+                vec![],
+            ),
+            CheckerError::ConflictedValueInStackmap { inst, alloc } => (
+                "ConflictedValueInStackmap",
+                Some(*inst),
+                None,
+                vec![*alloc],
+                vec![],
+            ),
+            CheckerError::NonRefValuesInStackmap { inst, alloc, vregs } => (
+                "NonRefValuesInStackmap",
+                Some(*inst),
+                None,
+                vec![*alloc],
+                sorted(vregs),
+            ),
+            CheckerError::MissingRefInStackmap { safepoint, vreg } => (
+                "MissingRefInStackmap",
+                Some(safepoint.inst()),
+                None,
+                vec![],
+                vec![*vreg],
+            ),
+            CheckerError::StackToStackMove { into, from } => {
+                ("StackToStackMove", None, None, vec![*into, *from], vec![])
+            }
+        };
+        Discrepancy {
+            inst,
+            kind,
+            operand,
+            allocs,
+            vregs,
+        }
+    }
+}
+
+/// Check `output` against `func` with the symbolic checker, after
+/// making sure that it has the shape the checker relies on, so that a
+/// broken output is reported rather than causing a panic. The outcome
+/// only depends on `func`, `env` and `output`.
+pub fn check_output<F: Function>(func: &F, env: &MachineEnv, output: &Output) -> CheckOutcome {
+    if output.inst_alloc_offsets.len() != func.num_insts() {
+        return CheckOutcome::Malformed("inst_alloc_offsets does not cover every instruction");
+    }
+    let mut end = output.allocs.len();
+    for inst in (0..func.num_insts()).rev() {
+        let start = output.inst_alloc_offsets[inst] as usize;
+        let num_operands = func.inst_operands(Inst::new(inst)).len();
+        if start > end || end - start != num_operands {
+            return CheckOutcome::Malformed("wrong number of allocations for an instruction");
+        }
+        end = start;
+    }
+    if end != 0 {
+        return CheckOutcome::Malformed("allocations not assigned to any instruction");
+    }
+    if output.temp_allocs.windows(2).any(|w| w[0].0 > w[1].0)
+        || output.temp_allocs.iter().any(|&(_, alloc)| !alloc.is_reg())
+        || output.temp_allocs.len()
+            != (0..func.num_insts())
+                .map(|inst| func.inst_temps(Inst::new(inst)).len())
+                .sum::<usize>()
+        || (0..func.num_insts()).any(|inst| {
+            let inst = Inst::new(inst);
+            output.inst_temp_allocs(inst).count() != func.inst_temps(inst).len()
+        })
+    {
+        return CheckOutcome::Malformed("temp allocations do not match the instructions' temps");
+    }
+    let num_points = 2 * func.num_insts();
+    if output
+        .edits
+        .iter()
+        .any(|&(pos, _)| pos.to_index() as usize >= num_points)
+    {
+        return CheckOutcome::Malformed("edit outside the function");
+    }
+    if output.edits.windows(2).any(|w| w[0].0 > w[1].0) {
+        return CheckOutcome::Malformed("edits are not sorted by program point");
+    }
+
+    let mut checker = Checker::new(func, env);
+    checker.prepare(output);
+    match checker.run() {
+        Ok(()) => CheckOutcome::Correct,
+        Err(errors) => {
+            let mut discrepancies: Vec<Discrepancy> =
+                errors.errors.iter().map(Discrepancy::from).collect();
+            discrepancies.sort_unstable();
+            discrepancies.dedup();
+            CheckOutcome::Incorrect(discrepancies)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_output, CheckOutcome, Checker, CheckerError};
+    use crate::testing::{int_env, run_and_check, vreg, TestFunc};
+    use crate::{Operand, Output, ProgPoint};
+
+    #[test]
+    fn test_check_output() {
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        let use_both = f.op(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+        f.ret(&[]);
+        let env = int_env(2);
+        let out = run_and_check(&f, &env);
+        assert_eq!(check_output(&f, &env, &out), CheckOutcome::Correct);
+
+        // Swapping the two uses' registers reads the wrong values.
+        let mut swapped = out.clone();
+        let start = swapped.inst_alloc_offsets[use_both.index()] as usize;
+        swapped.allocs.swap(start, start + 1);
+        let outcome = check_output(&f, &env, &swapped);
+        assert_eq!(outcome, check_output(&f, &env, &swapped));
+        match outcome {
+            CheckOutcome::Incorrect(discrepancies) => {
+                // Only the first bad operand of an instruction is
+                // reported.
+                assert_eq!(discrepancies.len(), 1);
+                let d = &discrepancies[0];
+                assert_eq!(d.inst, Some(use_both));
+                assert_eq!(d.kind, "IncorrectValuesInAllocation");
+                assert_eq!(d.operand.map(|op| op.vreg()), Some(vreg(0)));
+                assert_eq!(d.vregs, [vreg(1)]);
+            }
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+
+        // A missing allocation is reported rather than indexed past.
+        let mut truncated = out.clone();
+        truncated.allocs.pop();
+        assert!(matches!(
+            check_output(&f, &env, &truncated),
+            CheckOutcome::Malformed(_)
+        ));
+    }
+
+    #[test]
+    fn test_stackmap_checks() {
+        // v0 and v2 are references; only v0 is live across the
+        // safepoint, which has to leave it on the stack.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0)), Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_def(vreg(2))]);
+        let safepoint = f.op(&[Operand::reg_use(vreg(1)), Operand::reg_use(vreg(2))]);
+        f.op(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+        f.ret(&[]);
+        f.set_reference_vregs(&[vreg(0), vreg(2)]);
+        f.set_safepoint(safepoint);
+        let env = int_env(2);
+        let out = run_and_check(&f, &env);
+        assert_eq!(out.stackmaps.len(), 1);
+        let (inst, ref stackmap) = out.stackmaps[0];
+        assert_eq!(inst, safepoint);
+        assert_eq!(stackmap.len(), 1);
+        assert!(stackmap[0].is_stack());
+
+        let check = |out: &Output| {
+            let mut checker = Checker::new(&f, &env);
+            checker.enable_stackmap_checks();
+            checker.prepare(out);
+            checker.run().map_err(|errors| errors.errors)
+        };
+        assert!(check(&out).is_ok());
+
+        // Leaving out v0 is caught at the safepoint...
+        let mut missing = out.clone();
+        missing.stackmaps[0].1.clear();
+        let errors = check(&missing).unwrap_err();
+        assert!(matches!(
+            errors[0],
+            CheckerError::MissingRefInStackmap { safepoint: pos, vreg: v }
+                if pos == ProgPoint::before(safepoint) && v == vreg(0)
+        ));
+
+        // ...and so is listing v2, which is dead after it, or v1,
+        // which is not a reference.
+        for alloc in out.inst_allocs(safepoint).iter().copied() {
+            let mut extra = out.clone();
+            extra.stackmaps[0].1.push(alloc);
+            let errors = check(&extra).unwrap_err();
+            assert!(matches!(
+                errors[0],
+                CheckerError::NonRefValuesInStackmap { inst, alloc: a, .. }
+                    if inst == safepoint && a == alloc
+            ));
+        }
+    }
+}
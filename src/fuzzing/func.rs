@@ -26,6 +26,7 @@ pub struct InstData {
     op: InstOpcode,
     operands: Vec<Operand>,
     clobbers: Vec<PReg>,
+    is_safepoint: bool,
 }
 
 impl InstData {
@@ -34,6 +35,7 @@ impl InstData {
             op: InstOpcode::Branch,
             operands: vec![],
             clobbers: vec![],
+            is_safepoint: false,
         }
     }
     pub fn ret() -> InstData {
@@ -41,6 +43,7 @@ impl InstData {
             op: InstOpcode::Ret,
             operands: vec![],
             clobbers: vec![],
+            is_safepoint: false,
         }
     }
 }
@@ -56,6 +59,8 @@ pub struct Func {
     num_vregs: usize,
     reftype_vregs: Vec<VReg>,
     debug_value_labels: Vec<(VReg, Inst, Inst, u32)>,
+    is_ssa: bool,
+    cold_blocks: Vec<bool>,
 }
 
 impl Function for Func {
@@ -100,6 +105,14 @@ impl Function for Func {
         &self.block_params_out[block.index()][succ][..]
     }
 
+    fn is_reference_vreg(&self, vreg: VReg) -> bool {
+        self.reftype_vregs.contains(&vreg)
+    }
+
+    fn requires_stackmap(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].is_safepoint
+    }
+
     fn debug_value_labels(&self) -> &[(VReg, Inst, Inst, u32)] {
         &self.debug_value_labels[..]
     }
@@ -120,6 +133,14 @@ impl Function for Func {
         self.num_vregs
     }
 
+    fn is_ssa(&self) -> bool {
+        self.is_ssa
+    }
+
+    fn block_is_cold(&self, block: Block) -> bool {
+        self.cold_blocks[block.index()]
+    }
+
     fn spillslot_size(&self, regclass: RegClass) -> usize {
         match regclass {
             // Test the case where 2 classes share the same
@@ -152,6 +173,8 @@ impl FuncBuilder {
                 num_vregs: 0,
                 reftype_vregs: vec![],
                 debug_value_labels: vec![],
+                is_ssa: true,
+                cold_blocks: vec![],
             },
             insts_per_block: vec![],
         }
@@ -166,6 +189,7 @@ impl FuncBuilder {
         self.f.block_succs.push(vec![]);
         self.f.block_params_in.push(vec![]);
         self.f.block_params_out.push(vec![]);
+        self.f.cold_blocks.push(false);
         self.insts_per_block.push(vec![]);
         b
     }
@@ -257,6 +281,8 @@ pub struct Options {
     pub fixed_nonallocatable: bool,
     pub clobbers: bool,
     pub reftypes: bool,
+    pub redefs: bool,
+    pub reg_pairs: bool,
 }
 
 impl core::default::Default for Options {
@@ -267,6 +293,8 @@ impl core::default::Default for Options {
             fixed_nonallocatable: false,
             clobbers: false,
             reftypes: false,
+            redefs: false,
+            reg_pairs: false,
         }
     }
 }
@@ -322,6 +350,8 @@ impl Func {
         for pred in out_blocks {
             let succ = *u.choose(&in_blocks[..])?;
             builder.add_edge(Block::new(pred), Block::new(succ));
+            // The side of a fork is a natural slow path.
+            builder.f.cold_blocks[pred] = bool::arbitrary(u)?;
         }
 
         builder.compute_doms();
@@ -502,7 +532,21 @@ impl Func {
                         63,
                         RegClass::arbitrary(u)?,
                     )));
+                } else if opts.redefs && !avail.is_empty() && bool::arbitrary(u)? {
+                    // Redefine an already-defined vreg, making the
+                    // function non-SSA. All uses here are Early, so a
+                    // Late redef never conflicts with them.
+                    let vreg = *u.choose(&avail[..])?;
+                    let constraint = OperandConstraint::arbitrary(u)?;
+                    operands.push(Operand::new(
+                        vreg,
+                        constraint,
+                        OperandKind::Def,
+                        OperandPos::Late,
+                    ));
+                    builder.f.is_ssa = false;
                 }
+                let is_safepoint = opts.reftypes && bool::arbitrary(u)?;
 
                 builder.add_inst(
                     Block::new(block),
@@ -510,6 +554,7 @@ impl Func {
                         op: InstOpcode::Op,
                         operands,
                         clobbers,
+                        is_safepoint,
                     },
                 );
                 avail.push(vreg);
@@ -556,6 +601,53 @@ impl Func {
             }
         }
 
+        if opts.reg_pairs {
+            // Put some vregs in register pairs. Pair-ness has to agree
+            // across blockparams, and pair vregs can only be
+            // constrained to `Any` or `RegPair`.
+            let mut pairs = vec![false; builder.f.num_vregs];
+            for pair in pairs.iter_mut() {
+                *pair = u.ratio(1, 4)?;
+            }
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for block in 0..num_blocks {
+                    for (succ, args) in builder.f.block_succs[block]
+                        .iter()
+                        .zip(&builder.f.block_params_out[block])
+                    {
+                        for (arg, param) in
+                            args.iter().zip(&builder.f.block_params_in[succ.index()])
+                        {
+                            if pairs[arg.vreg()] != pairs[param.vreg()] {
+                                pairs[arg.vreg()] = true;
+                                pairs[param.vreg()] = true;
+                                changed = true;
+                            }
+                        }
+                    }
+                }
+            }
+            for inst in builder.insts_per_block.iter_mut().flatten() {
+                let orig = inst.operands.clone();
+                for op in &mut inst.operands {
+                    if op.as_fixed_nonallocatable().is_some() {
+                        continue;
+                    }
+                    let constraint = match op.constraint() {
+                        OperandConstraint::Any => OperandConstraint::Any,
+                        _ if pairs[op.vreg().vreg()] => OperandConstraint::RegPair,
+                        OperandConstraint::Reuse(idx) if pairs[orig[idx].vreg().vreg()] => {
+                            OperandConstraint::Reg
+                        }
+                        constraint => constraint,
+                    };
+                    *op = Operand::new(op.vreg(), constraint, op.kind(), op.pos());
+                }
+            }
+        }
+
         builder.f.debug_value_labels.sort_unstable();
 
         Ok(builder.finalize())
@@ -595,17 +687,26 @@ impl core::fmt::Debug for Func {
                 .join(", ");
             write!(
                 f,
-                "  block{}({}): # succs:{:?} preds:{:?}\n",
-                i, params_in, succs, preds
+                "  block{}({}): # succs:{:?} preds:{:?}{}\n",
+                i,
+                params_in,
+                succs,
+                preds,
+                if self.cold_blocks[i] { " cold" } else { "" }
             )?;
             for inst in blockrange.iter() {
                 write!(
                     f,
-                    "    inst{}: {:?} ops:{:?} clobber:{:?}\n",
+                    "    inst{}: {:?} ops:{:?} clobber:{:?}{}\n",
                     inst.index(),
                     self.insts[inst.index()].op,
                     self.insts[inst.index()].operands,
-                    self.insts[inst.index()].clobbers
+                    self.insts[inst.index()].clobbers,
+                    if self.insts[inst.index()].is_safepoint {
+                        " safepoint"
+                    } else {
+                        ""
+                    }
                 )?;
                 if let InstOpcode::Branch = self.insts[inst.index()].op {
                     write!(f, "    params: {}\n", params_out)?;
@@ -645,7 +746,13 @@ pub fn machine_env() -> MachineEnv {
     MachineEnv {
         preferred_regs_by_class,
         non_preferred_regs_by_class,
+        probe_order_by_class: [None, None, None],
         scratch_by_class,
         fixed_stack_slots,
+        stack_to_stack_moves: false,
+        reserved_regs: PRegSet::empty(),
+        slot_size_by_class: [0; 3],
+        call_clobbers: PRegSet::empty(),
+        reg_groups: vec![],
     }
 }
@@ -3,7 +3,7 @@ macro_rules! define_index {
     ($ix:ident, $storage:ident, $elem:ident) => {
         define_index!($ix);
 
-        #[derive(Clone, Debug)]
+        #[derive(Clone, Debug, Default)]
         pub struct $storage {
             storage: Vec<$elem>,
         }
@@ -31,6 +31,15 @@ macro_rules! define_index {
                 self.storage.iter_mut()
             }
 
+            /// Take the storage out of `buf`, emptied, with room
+            /// for at least `n` elements.
+            pub fn reuse(buf: &mut Self, n: usize) -> Self {
+                let mut storage = core::mem::take(&mut buf.storage);
+                storage.clear();
+                storage.reserve(n);
+                Self { storage }
+            }
+
             #[inline(always)]
             pub fn push(&mut self, value: $elem) -> $ix {
                 let idx = $ix(self.storage.len() as u32);
@@ -13,12 +13,13 @@
 //! Data structures for backtracking allocator.
 
 use super::liveranges::SpillWeight;
+use super::moves::HalfMoves;
 use crate::cfg::CFGInfo;
 use crate::index::ContainerComparator;
 use crate::indexset::IndexSet;
 use crate::{
-    define_index, Allocation, Block, Edit, Function, FxHashSet, MachineEnv, Operand, PReg,
-    ProgPoint, RegClass, VReg,
+    define_index, Allocation, Block, Edit, Function, FxHashMap, FxHashSet, Inst, MachineEnv,
+    MoveCause, Operand, PReg, PRegSet, ProgPoint, RegAllocError, RegClass, SpillObserver, VReg,
 };
 use alloc::collections::BTreeMap;
 use alloc::string::String;
@@ -296,14 +297,35 @@ pub struct SpillSet {
     pub spill_bundle: LiveBundleIndex,
     pub required: bool,
     pub splits: u8,
-
-    /// The aggregate [`CodeRange`] of all involved [`LiveRange`]s. The effect of this abstraction
-    /// is that we attempt to allocate one spill slot for the extent of a bundle. For fragmented
-    /// bundles with lots of open space this abstraction is pessimistic, but when bundles are small
-    /// or dense this yields similar results to tracking individual live ranges.
+    /// Whether the vregs in this spillset live in register pairs.
+    pub pair: bool,
+    /// The alignment in spillslot units that the slot must have, from
+    /// `OperandConstraint::Stack` uses; 1 if there are none.
+    pub align: u32,
+    /// The vreg of this spillset if `Function::never_spill` holds for
+    /// it. Such a spillset holds no other vreg, and its spillslot is
+    /// never written: reloads from it become `Edit::Remat`s.
+    pub never_spill: Option<VReg>,
+    /// The pure instruction defining this spillset's vreg if it may be
+    /// sunk to its single use (see `VRegData::sink`). Such a spillset
+    /// also holds no other vreg and its spillslot is never written:
+    /// reloads from it become `Edit::Sink`s.
+    pub sink: Option<Inst>,
+
+    /// The aggregate [`CodeRange`] of all involved [`LiveRange`]s. Spillslot sharing looks at
+    /// the individual live ranges instead, so that fragmented spillsets can share a slot with
+    /// values that live in their gaps.
     pub range: CodeRange,
 }
 
+impl SpillSet {
+    /// Whether this spillset's slot is never written, so that reloads
+    /// from it recompute the value instead.
+    pub fn is_remat(&self) -> bool {
+        self.never_spill.is_some() || self.sink.is_some()
+    }
+}
+
 pub(crate) const MAX_SPLITS_PER_SPILLSET: u8 = 2;
 
 #[derive(Clone, Debug)]
@@ -312,6 +334,10 @@ pub struct VRegData {
     pub blockparam: Block,
     // We don't initially know the RegClass until we observe a use of the VReg.
     pub class: Option<RegClass>,
+    /// The pure instruction whose only operand defines this vreg, if
+    /// the vreg has a single use that the definition may be sunk to;
+    /// see `Function::inst_is_pure`.
+    pub sink: Option<Inst>,
 }
 
 #[derive(Clone, Debug)]
@@ -330,6 +356,17 @@ pub struct MultiFixedRegFixup {
     pub vreg: VRegIndex,
 }
 
+/// A copy of `vreg` from its allocation at `from_slot` into the
+/// separate register given to `to_slot`, for an instruction that
+/// requires distinct registers.
+#[derive(Clone, Debug)]
+pub struct DistinctRegCopy {
+    pub pos: ProgPoint,
+    pub from_slot: u8,
+    pub to_slot: u8,
+    pub vreg: VRegIndex,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum FixedRegFixupLevel {
     /// A fixup copy for the initial fixed reg; must come first.
@@ -438,6 +475,104 @@ impl core::ops::IndexMut<VReg> for VRegs {
     }
 }
 
+/// Buffers that the allocator keeps from one function to the next,
+/// so that a caller allocating many functions (e.g. a JIT) can hold
+/// on to one and pass it to each `run_with_ctx`, rather than have
+/// the allocator's large tables reallocated every time. Hand each
+/// `Output` back through `Ctx::recycle` once done with it to also
+/// reuse its buffers.
+///
+/// A `Ctx` holds no state that outlives a run, only capacity, so
+/// its results are exactly those of `run`. It is `Send`: a pool of
+/// them can be shared between compiler threads, with each run
+/// taking one out for its duration.
+#[derive(Clone, Debug, Default)]
+pub struct Ctx {
+    pub(crate) liveins: Vec<IndexSet>,
+    pub(crate) liveouts: Vec<IndexSet>,
+    pub(crate) blockparam_outs: Vec<BlockparamOut>,
+    pub(crate) blockparam_ins: Vec<BlockparamIn>,
+    pub(crate) blockparam_outs_by_dest: Vec<u32>,
+    pub(crate) ranges: LiveRanges,
+    pub(crate) bundles: LiveBundles,
+    pub(crate) spillsets: SpillSets,
+    pub(crate) vregs: VRegs,
+    pub(crate) pregs: Vec<PRegData>,
+    pub(crate) spilled_bundles: Vec<LiveBundleIndex>,
+    pub(crate) spillslots: Vec<SpillSlotData>,
+    pub(crate) multi_fixed_reg_fixups: Vec<MultiFixedRegFixup>,
+    pub(crate) distinct_reg_copies: Vec<DistinctRegCopy>,
+    pub(crate) soft_clobber_insts: Vec<Inst>,
+    pub(crate) clobber_insts: Vec<Inst>,
+    pub(crate) temp_insts: Vec<Inst>,
+    pub(crate) coalesced_moves: Vec<(VRegIndex, ProgPoint, VRegIndex, ProgPoint)>,
+    pub(crate) allocation_queue: PrioQueue,
+    pub(crate) block_grouped_bundles: FxHashSet<LiveBundleIndex>,
+    pub(crate) conflict_set: FxHashSet<LiveBundleIndex>,
+    pub(crate) half_moves: HalfMoves,
+    pub(crate) inserted_moves: Vec<InsertedMove>,
+    pub(crate) edits: Vec<(PosWithPrio, Edit, MoveCause, RegClass, Option<VReg>)>,
+    // Buffers that end up in the `Output`, back from `recycle`.
+    pub(crate) allocs: Vec<Allocation>,
+    pub(crate) inst_alloc_offsets: Vec<u32>,
+    pub(crate) output_edits: Vec<(ProgPoint, Edit)>,
+}
+
+impl Ctx {
+    /// Keep the buffers of `output`, which is no longer needed, for
+    /// the next run.
+    pub fn recycle(&mut self, output: crate::Output) {
+        self.allocs = output.allocs;
+        self.inst_alloc_offsets = output.inst_alloc_offsets;
+        self.output_edits = output.edits;
+    }
+
+    /// Take the buffers of `env` back after a run, leaving it with
+    /// empty ones.
+    pub(crate) fn reclaim<F: Function>(&mut self, env: &mut Env<'_, F>) {
+        use core::mem::take;
+        self.liveins = take(&mut env.liveins);
+        self.liveouts = take(&mut env.liveouts);
+        self.blockparam_outs = take(&mut env.blockparam_outs);
+        self.blockparam_ins = take(&mut env.blockparam_ins);
+        self.blockparam_outs_by_dest = take(&mut env.blockparam_outs_by_dest);
+        self.ranges = take(&mut env.ranges);
+        self.bundles = take(&mut env.bundles);
+        self.spillsets = take(&mut env.spillsets);
+        self.vregs = take(&mut env.vregs);
+        self.pregs = take(&mut env.pregs);
+        self.spilled_bundles = take(&mut env.spilled_bundles);
+        self.spillslots = take(&mut env.spillslots);
+        self.multi_fixed_reg_fixups = take(&mut env.multi_fixed_reg_fixups);
+        self.distinct_reg_copies = take(&mut env.distinct_reg_copies);
+        self.soft_clobber_insts = take(&mut env.soft_clobber_insts);
+        self.clobber_insts = take(&mut env.clobber_insts);
+        self.temp_insts = take(&mut env.temp_insts);
+        self.coalesced_moves = take(&mut env.coalesced_moves);
+        self.allocation_queue = take(&mut env.allocation_queue);
+        self.block_grouped_bundles = take(&mut env.block_grouped_bundles);
+        self.conflict_set = take(&mut env.conflict_set);
+        self.half_moves = take(&mut env.half_moves);
+        self.inserted_moves = take(&mut env.inserted_move_buf);
+        self.edits = take(&mut env.edit_buf);
+    }
+}
+
+/// Take a buffer out of a `Ctx`, emptied, with room for `n` elements.
+pub(crate) fn reuse<T>(buf: &mut Vec<T>, n: usize) -> Vec<T> {
+    let mut buf = core::mem::take(buf);
+    buf.clear();
+    buf.reserve(n);
+    buf
+}
+
+/// Take a set out of a `Ctx`, emptied.
+pub(crate) fn reuse_set<T>(set: &mut FxHashSet<T>) -> FxHashSet<T> {
+    let mut set = core::mem::take(set);
+    set.clear();
+    set
+}
+
 #[derive(Clone, Debug)]
 pub struct Env<'a, F: Function> {
     pub func: &'a F,
@@ -447,6 +582,8 @@ pub struct Env<'a, F: Function> {
     pub liveouts: Vec<IndexSet>,
     pub blockparam_outs: Vec<BlockparamOut>,
     pub blockparam_ins: Vec<BlockparamIn>,
+    // Indices into `blockparam_outs`, sorted by destination vreg.
+    pub blockparam_outs_by_dest: Vec<u32>,
 
     pub ranges: LiveRanges,
     pub bundles: LiveBundles,
@@ -472,21 +609,123 @@ pub struct Env<'a, F: Function> {
     // was to the approprate PReg.
     pub multi_fixed_reg_fixups: Vec<MultiFixedRegFixup>,
 
+    // Extra uses of a vreg at an instruction that requires distinct
+    // registers are given a temporary vreg of their own, numbered
+    // after the function's vregs; we copy the value into its
+    // register when we produce the final edit-list.
+    pub distinct_reg_copies: Vec<DistinctRegCopy>,
+
+    // Instructions with a non-empty `Function::inst_soft_clobbers`
+    // set, in order.
+    pub soft_clobber_insts: Vec<Inst>,
+
+    // Instructions with a non-empty `Function::inst_clobbers` or
+    // `Function::inst_early_kills` set, in order. Spilled bundles are split around these when they
+    // cannot be given a register as a whole.
+    pub clobber_insts: Vec<Inst>,
+
+    // Instructions with a non-empty `Function::inst_temps` list, in
+    // order. Their temps take the allocation slots after those of
+    // their operands, and are moved out into `temp_allocs` at the
+    // end.
+    pub temp_insts: Vec<Inst>,
+
     // Output:
     pub allocs: Vec<Allocation>,
     pub inst_alloc_offsets: Vec<u32>,
     pub num_spillslots: u32,
+    pub num_secondary_spillslots: u32,
     pub debug_locations: Vec<(u32, ProgPoint, ProgPoint, Allocation)>,
+    pub reuse_copies: Vec<(Inst, usize, usize)>,
 
     pub allocated_bundle_count: usize,
 
     pub stats: Stats,
+    /// The decisions made so far; see `Output::decision_log`.
+    #[cfg(feature = "decision-log")]
+    pub decision_log: Vec<crate::Decision>,
 
     // For debug output only: a list of textual annotations at every
     // ProgPoint to insert into the final allocated program listing.
     pub debug_annotations: hashbrown::HashMap<ProgPoint, Vec<String>>,
     pub annotations_enabled: bool,
 
+    // See `RegallocOptions::spill_once`.
+    pub spill_once: bool,
+
+    // See `RegallocOptions::reload_once_per_block`.
+    pub reload_once_per_block: bool,
+    // See `RegallocOptions::reload_reused_into_output`.
+    pub reload_reused_into_output: bool,
+    // Bundles built by `split_into_minimal_bundles` that span several
+    // uses in one block under `reload_once_per_block`.
+    pub block_grouped_bundles: FxHashSet<LiveBundleIndex>,
+
+    // See `RegallocOptions::forbid_spilling`.
+    pub forbid_spilling: bool,
+
+    // See `RegallocOptions::primary_spillslots`.
+    pub primary_spillslots: Option<u32>,
+
+    // See `RegallocOptions::minimize_regs`.
+    pub minimize_regs: bool,
+
+    // Whether any instruction has a latency above 1; see
+    // `Function::inst_latency`.
+    pub has_inst_latencies: bool,
+
+    // See `RegallocOptions::conservative_coalescing`.
+    pub conservative_coalescing: bool,
+    // The bundles each bundle interferes with, kept up to date across
+    // merges; only built for conservative coalescing.
+    pub interference: Vec<FxHashSet<LiveBundleIndex>>,
+
+    // See `RegallocOptions::spill_observer`.
+    pub spill_observer: Option<SpillObserver<'a>>,
+
+    // See `RegallocOptions::count_edits_only`.
+    pub count_edits_only: bool,
+
+    // See `RegallocOptions::cross_class_spills`.
+    pub cross_class_spills: bool,
+
+    // See `RegallocOptions::partial_spills`. The plan maps each store
+    // or reload of a partially spilled pair, by vreg and position, to
+    // the halves kept in a register, and lists the ranges spilled in
+    // part; the reservations hold the kept registers in their
+    // `btree`s while the other half is spilled.
+    pub partial_spills: bool,
+    pub partial_spill_moves: FxHashMap<(VReg, ProgPoint), PartialSpillMove>,
+    pub partial_spill_ranges: FxHashSet<LiveRangeIndex>,
+    pub partial_spill_reservations: Vec<(PRegIndex, CodeRange)>,
+
+    // Every register a bundle has been allocated to so far, including
+    // ones it was later evicted from.
+    pub used_pregs: PRegSet,
+
+    // Whether the function is a single block without arguments, for
+    // which move resolution skips the half-move machinery.
+    pub single_block: bool,
+
+    // See `RegallocOptions::move_prio_order` and
+    // `InsertMovePrio::ranks`.
+    pub move_prio_ranks: [u32; 6],
+
+    // Per-vreg flag for vregs that live in register pairs (see
+    // `OperandConstraint::RegPair`); empty if there are none.
+    pub pair_vregs: Vec<bool>,
+
+    // Vregs merged by `merge_vreg_bundles` that are connected by a
+    // program move or a blockparam, as (source, where it is read,
+    // destination, where it is written).
+    pub coalesced_moves: Vec<(VRegIndex, ProgPoint, VRegIndex, ProgPoint)>,
+
+    // Spare buffers for the moves and edits of `resolve_moves`, from
+    // a `Ctx` and returned to it afterwards.
+    pub half_moves: HalfMoves,
+    pub inserted_move_buf: Vec<InsertedMove>,
+    pub edit_buf: Vec<(PosWithPrio, Edit, MoveCause, RegClass, Option<VReg>)>,
+
     // Cached allocation for `try_to_allocate_bundle_to_reg` to avoid allocating
     // a new HashSet on every call.
     pub conflict_set: FxHashSet<LiveBundleIndex>,
@@ -503,18 +742,47 @@ impl<'a, F: Function> Env<'a, F> {
     }
 
     /// Record the class of a VReg. We learn this only when we observe
-    /// the VRegs in use.
-    pub fn observe_vreg_class(&mut self, vreg: VReg) {
-        let old_class = self.vregs[vreg].class.replace(vreg.class());
-        // We should never observe two different classes for two
-        // mentions of a VReg in the source program.
-        debug_assert!(old_class == None || old_class == Some(vreg.class()));
+    /// the VRegs in use. Two different classes for two mentions of a
+    /// VReg in the source program are an error.
+    pub fn observe_vreg_class(&mut self, vreg: VReg, inst: Inst) -> Result<(), RegAllocError> {
+        match self.vregs[vreg].class.replace(vreg.class()) {
+            Some(class) if class != vreg.class() => Err(RegAllocError::OperandClass(vreg, inst)),
+            _ => Ok(()),
+        }
     }
 
     /// Is this vreg actually used in the source program?
     pub fn is_vreg_used(&self, index: VRegIndex) -> bool {
         self.vregs[index].class.is_some()
     }
+
+    /// Does this vreg live in a register pair?
+    pub fn is_pair_vreg(&self, index: VRegIndex) -> bool {
+        self.pair_vregs.get(index.index()).copied().unwrap_or(false)
+    }
+
+    /// Does this bundle hold a vreg that lives in a register pair?
+    pub fn is_pair_bundle(&self, bundle: LiveBundleIndex) -> bool {
+        self.spillsets[self.bundles[bundle].spillset].pair
+    }
+
+    /// If `preg` can hold the low half of a register pair, return the
+    /// register holding the high half: the pair must be even-aligned
+    /// and both halves allocatable.
+    pub fn pair_high(&self, preg: PReg) -> Option<PReg> {
+        if preg.hw_enc() & 1 != 0 || preg.hw_enc() + 1 > PReg::MAX {
+            return None;
+        }
+        let hi = PReg::new(preg.hw_enc() + 1, preg.class());
+        let class = preg.class() as usize;
+        let allocatable = self.env.preferred_regs_by_class[class].contains(&hi)
+            || self.env.non_preferred_regs_by_class[class].contains(&hi);
+        if allocatable && !self.pregs[hi.index()].is_stack {
+            Some(hi)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -534,7 +802,18 @@ impl SpillSetRanges {
 pub struct SpillSlotData {
     pub ranges: SpillSetRanges,
     pub slots: u32,
+    // The largest alignment required by a spillset in the slot.
+    pub align: u32,
     pub alloc: Allocation,
+    pub tier: SpillTier,
+}
+
+/// The area of the frame a spillslot prefers to be placed in; see
+/// `RegallocOptions::primary_spillslots`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpillTier {
+    Primary,
+    Secondary,
 }
 
 #[derive(Clone, Debug)]
@@ -563,7 +842,7 @@ impl SpillSlotList {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct PrioQueue {
     pub heap: alloc::collections::BinaryHeap<PrioQueueEntry>,
 }
@@ -647,6 +926,13 @@ impl PrioQueue {
         }
     }
 
+    /// Take the queue out of `queue`, emptied.
+    pub fn reuse(queue: &mut Self) -> Self {
+        let mut queue = core::mem::take(queue);
+        queue.heap.clear();
+        queue
+    }
+
     #[inline(always)]
     pub fn insert(&mut self, bundle: LiveBundleIndex, prio: usize, reg_hint: PReg) {
         self.heap.push(PrioQueueEntry {
@@ -675,27 +961,149 @@ impl LiveRangeSet {
     }
 }
 
+/// A store or reload of a register pair of which only some halves
+/// move; see `RegallocOptions::partial_spills`.
+#[derive(Clone, Copy, Debug)]
+pub struct PartialSpillMove {
+    pub from: Allocation,
+    pub to: Allocation,
+    /// The register each half (low, high) stays in, if it does not
+    /// move.
+    pub kept: [Option<Allocation>; 2],
+}
+
 #[derive(Clone, Debug)]
 pub struct InsertedMove {
     pub pos_prio: PosWithPrio,
     pub from_alloc: Allocation,
     pub to_alloc: Allocation,
     pub to_vreg: VReg,
+    pub cause: MoveCause,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+/// The group a move inserted by the allocator belongs to. Moves at
+/// the same program point are emitted one group at a time, in the
+/// order of this type (see `RegallocOptions::move_prio_order`); the
+/// moves within a group form one parallel move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum InsertMovePrio {
+    /// Moves into blockparams and live-ins at the start of a block.
     InEdgeMoves,
+    /// Moves between the pieces of a split vreg.
     Regular,
+    /// Copies of a vreg into the first of several fixed registers it
+    /// is needed in at one instruction.
     MultiFixedRegInitial,
+    /// Copies from that first fixed register into the others, and of
+    /// a repeated input into the separate registers required by
+    /// `Function::inst_requires_distinct_regs`.
     MultiFixedRegSecondary,
+    /// Copies of an input into its output's allocation for a
+    /// `Reuse` constraint that could not be met in place.
     ReusedInput,
+    /// Moves into blockparams and live-ins at the end of a block.
     OutEdgeMoves,
 }
 
-#[derive(Debug, Default)]
+impl InsertMovePrio {
+    pub const ALL: [InsertMovePrio; 6] = [
+        InsertMovePrio::InEdgeMoves,
+        InsertMovePrio::Regular,
+        InsertMovePrio::MultiFixedRegInitial,
+        InsertMovePrio::MultiFixedRegSecondary,
+        InsertMovePrio::ReusedInput,
+        InsertMovePrio::OutEdgeMoves,
+    ];
+
+    /// The cause of a move in this group from `from` to `to` of a
+    /// value of `class`.
+    pub(crate) fn cause(self, from: Allocation, to: Allocation, class: RegClass) -> MoveCause {
+        match self {
+            InsertMovePrio::InEdgeMoves => MoveCause::EdgeIn,
+            InsertMovePrio::Regular if is_spill_location(to, class) => MoveCause::Spill,
+            InsertMovePrio::Regular if is_spill_location(from, class) => MoveCause::Reload,
+            InsertMovePrio::Regular => MoveCause::Split,
+            InsertMovePrio::MultiFixedRegInitial | InsertMovePrio::MultiFixedRegSecondary => {
+                MoveCause::FixedReg
+            }
+            InsertMovePrio::ReusedInput => MoveCause::ReuseInput,
+            InsertMovePrio::OutEdgeMoves => MoveCause::EdgeOut,
+        }
+    }
+
+    /// The pairs of groups `(a, b)` where `a` must be emitted before
+    /// `b` at a shared program point, as `b` reads or overwrites what
+    /// `a` writes: moves into a block come before anything else at
+    /// its start, and moves out of a block after anything else at its
+    /// end; the regular moves settle a vreg's allocation before it is
+    /// copied into fixed registers or a reused input's output; and the
+    /// first fixed register is written before it is copied on. A
+    /// custom order that violates one of these is rejected with
+    /// `RegAllocError::MovePrioOrder`.
+    pub const REQUIRED_ORDER: [(InsertMovePrio, InsertMovePrio); 12] = [
+        (InsertMovePrio::InEdgeMoves, InsertMovePrio::Regular),
+        (
+            InsertMovePrio::InEdgeMoves,
+            InsertMovePrio::MultiFixedRegInitial,
+        ),
+        (
+            InsertMovePrio::InEdgeMoves,
+            InsertMovePrio::MultiFixedRegSecondary,
+        ),
+        (InsertMovePrio::InEdgeMoves, InsertMovePrio::ReusedInput),
+        (InsertMovePrio::InEdgeMoves, InsertMovePrio::OutEdgeMoves),
+        (
+            InsertMovePrio::Regular,
+            InsertMovePrio::MultiFixedRegInitial,
+        ),
+        (InsertMovePrio::Regular, InsertMovePrio::ReusedInput),
+        (InsertMovePrio::Regular, InsertMovePrio::OutEdgeMoves),
+        (
+            InsertMovePrio::MultiFixedRegInitial,
+            InsertMovePrio::MultiFixedRegSecondary,
+        ),
+        (
+            InsertMovePrio::MultiFixedRegInitial,
+            InsertMovePrio::OutEdgeMoves,
+        ),
+        (
+            InsertMovePrio::MultiFixedRegSecondary,
+            InsertMovePrio::OutEdgeMoves,
+        ),
+        (InsertMovePrio::ReusedInput, InsertMovePrio::OutEdgeMoves),
+    ];
+
+    /// The position of each group in the order given by `cmp`, indexed
+    /// by the group. Groups that compare equal keep their default
+    /// order, so every group remains a parallel move of its own.
+    pub fn ranks(cmp: fn(InsertMovePrio, InsertMovePrio) -> core::cmp::Ordering) -> [u32; 6] {
+        let mut order = Self::ALL;
+        order.sort_by(|&a, &b| cmp(a, b));
+        let mut ranks = [0; 6];
+        for (rank, prio) in order.iter().enumerate() {
+            ranks[*prio as usize] = rank as u32;
+        }
+        ranks
+    }
+}
+
+/// Whether `alloc` holds a spilled value of `class`: a stack slot, or
+/// a register of another class (see
+/// `RegallocOptions::cross_class_spills`).
+fn is_spill_location(alloc: Allocation, class: RegClass) -> bool {
+    alloc.is_stack() || alloc.as_reg().is_some_and(|preg| preg.class() != class)
+}
+
+#[derive(Debug)]
 pub struct InsertedMoves {
     pub moves: Vec<InsertedMove>,
+    // The sort position of each `InsertMovePrio`; see
+    // `InsertMovePrio::ranks`.
+    pub prio_ranks: [u32; 6],
+    // Whether moves may involve registers of another class than the
+    // value; see `RegallocOptions::cross_class_spills`.
+    pub cross_class_spills: bool,
 }
 
 impl InsertedMoves {
@@ -719,27 +1127,48 @@ impl InsertedMoves {
             trace!(" -> skipping move with same source and  dest");
             return;
         }
-        if let Some(from) = from_alloc.as_reg() {
-            debug_assert_eq!(from.class(), to_vreg.class());
-        }
-        if let Some(to) = to_alloc.as_reg() {
-            debug_assert_eq!(to.class(), to_vreg.class());
+        if !self.cross_class_spills {
+            if let Some(from) = from_alloc.as_reg() {
+                debug_assert_eq!(from.class(), to_vreg.class());
+            }
+            if let Some(to) = to_alloc.as_reg() {
+                debug_assert_eq!(to.class(), to_vreg.class());
+            }
         }
         self.moves.push(InsertedMove {
             pos_prio: PosWithPrio {
                 pos,
-                prio: prio as u32,
+                prio: self.prio_ranks[prio as usize],
             },
             from_alloc,
             to_alloc,
             to_vreg,
+            cause: prio.cause(from_alloc, to_alloc, to_vreg.class()),
         });
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct Edits {
-    edits: Vec<(PosWithPrio, Edit)>,
+    edits: Vec<(PosWithPrio, Edit, MoveCause, RegClass, Option<VReg>)>,
+    /// Only count the edits rather than storing them.
+    count_only: bool,
+    /// The number of edits added that store a register to the stack.
+    pub spills: usize,
+    /// The number of edits added that load a register from the stack.
+    pub reloads: usize,
+    /// The number of all other edits added.
+    pub moves: usize,
+    /// The number of rematerializations added.
+    pub remats: usize,
+    /// The number of sunk definitions added.
+    pub sinks: usize,
+    /// The number of class-view switches added.
+    pub reinterprets: usize,
+    /// The spillslots of never-spilled and sunk vregs, by vreg and
+    /// sunk instruction: moves into them are dropped and moves out of
+    /// them become `Edit::Remat`s, or `Edit::Sink`s of the instruction.
+    pub remat_slots: FxHashMap<Allocation, (VReg, Option<Inst>)>,
 }
 
 impl Edits {
@@ -747,6 +1176,35 @@ impl Edits {
     pub fn with_capacity(n: usize) -> Self {
         Self {
             edits: Vec::with_capacity(n),
+            count_only: false,
+            spills: 0,
+            reloads: 0,
+            moves: 0,
+            remats: 0,
+            sinks: 0,
+            reinterprets: 0,
+            remat_slots: FxHashMap::default(),
+        }
+    }
+
+    /// Edits collected into `buf`, a spare buffer from a `Ctx`, with
+    /// room for `n` of them.
+    pub fn with_buffer(
+        buf: &mut Vec<(PosWithPrio, Edit, MoveCause, RegClass, Option<VReg>)>,
+        n: usize,
+    ) -> Self {
+        Self {
+            edits: reuse(buf, n),
+            ..Self::with_capacity(0)
+        }
+    }
+
+    /// Edits that are only counted; see
+    /// `RegallocOptions::count_edits_only`.
+    pub fn counting() -> Self {
+        Self {
+            count_only: true,
+            ..Self::with_capacity(0)
         }
     }
 
@@ -756,28 +1214,135 @@ impl Edits {
     }
 
     #[inline(always)]
-    pub fn iter(&self) -> impl Iterator<Item = &(PosWithPrio, Edit)> {
+    pub fn iter(
+        &self,
+    ) -> impl Iterator<Item = &(PosWithPrio, Edit, MoveCause, RegClass, Option<VReg>)> {
         self.edits.iter()
     }
 
-    #[inline(always)]
-    pub fn into_edits(self) -> impl Iterator<Item = (ProgPoint, Edit)> {
-        self.edits.into_iter().map(|(pos, edit)| (pos.pos, edit))
+    /// Move the edits in order to `edits`, which is cleared first, and
+    /// return the cause, register class and vreg of each if `causes`,
+    /// `classes` and `vregs` ask for them. The buffer that held the
+    /// edits is left empty, to be reused through `into_buffer`.
+    pub fn drain_into(
+        &mut self,
+        edits: &mut Vec<(ProgPoint, Edit)>,
+        causes: bool,
+        classes: bool,
+        vregs: bool,
+    ) -> (Vec<MoveCause>, Vec<RegClass>, Vec<Option<VReg>>) {
+        edits.clear();
+        edits.reserve(self.edits.len());
+        let mut cause_list = Vec::with_capacity(if causes { self.edits.len() } else { 0 });
+        let mut class_list = Vec::with_capacity(if classes { self.edits.len() } else { 0 });
+        let mut vreg_list = Vec::with_capacity(if vregs { self.edits.len() } else { 0 });
+        for (pos_prio, edit, cause, class, vreg) in self.edits.drain(..) {
+            edits.push((pos_prio.pos, edit));
+            if causes {
+                cause_list.push(cause);
+            }
+            if classes {
+                class_list.push(class);
+            }
+            if vregs {
+                vreg_list.push(vreg);
+            }
+        }
+        (cause_list, class_list, vreg_list)
+    }
+
+    /// The (emptied) buffer that held the edits.
+    pub fn into_buffer(self) -> Vec<(PosWithPrio, Edit, MoveCause, RegClass, Option<VReg>)> {
+        self.edits
     }
 
     /// Sort edits by the combination of their program position and priority. This is a stable sort
-    /// to preserve the order of the moves the parallel move resolver inserts.
+    /// to preserve the order of the moves the parallel move resolver inserts; debug builds check
+    /// that it is.
     #[inline(always)]
     pub fn sort(&mut self) {
-        self.edits.sort_by_key(|&(pos_prio, _)| pos_prio.key());
+        fn sort_by_pos_prio<T>(edits: &mut [T], pos_prio: impl Fn(&T) -> PosWithPrio) {
+            edits.sort_by_key(|edit| pos_prio(edit).key());
+        }
+
+        #[cfg(not(debug_assertions))]
+        sort_by_pos_prio(&mut self.edits, |edit| edit.0);
+
+        #[cfg(debug_assertions)]
+        {
+            // Tag each edit with its original index, and check that
+            // edits with the same key kept their relative order.
+            let mut tagged: Vec<_> = core::mem::take(&mut self.edits)
+                .into_iter()
+                .zip(0usize..)
+                .collect();
+            sort_by_pos_prio(&mut tagged, |(edit, _)| edit.0);
+            debug_assert!(
+                tagged.windows(2).all(|w| {
+                    let ((a, a_seq), (b, b_seq)) = (&w[0], &w[1]);
+                    a.0.key() != b.0.key() || a_seq < b_seq
+                }),
+                "edit sort reordered moves within a (pos, prio) group"
+            );
+            self.edits = tagged.into_iter().map(|(edit, _)| edit).collect();
+        }
     }
 
-    pub fn add(&mut self, pos_prio: PosWithPrio, from: Allocation, to: Allocation) {
+    pub fn add(
+        &mut self,
+        pos_prio: PosWithPrio,
+        from: Allocation,
+        to: Allocation,
+        cause: MoveCause,
+        class: RegClass,
+        vreg: Option<VReg>,
+    ) {
         if from != to {
-            if from.is_reg() && to.is_reg() {
-                debug_assert_eq!(from.as_reg().unwrap().class(), to.as_reg().unwrap().class());
+            let edit = if self.remat_slots.contains_key(&to) {
+                return;
+            } else if let Some(&(vreg, sink)) = self.remat_slots.get(&from) {
+                match sink {
+                    Some(inst) => {
+                        self.sinks += 1;
+                        Edit::Sink { inst, vreg, to }
+                    }
+                    None => {
+                        self.remats += 1;
+                        Edit::Remat { vreg, to }
+                    }
+                }
+            } else {
+                match (is_spill_location(from, class), is_spill_location(to, class)) {
+                    (false, true) => self.spills += 1,
+                    (true, false) => self.reloads += 1,
+                    _ => self.moves += 1,
+                }
+                Edit::Move { from, to }
+            };
+            if !self.count_only {
+                self.edits.push((pos_prio, edit, cause, class, vreg));
             }
-            self.edits.push((pos_prio, Edit::Move { from, to }));
+        }
+    }
+
+    /// Add a switch of `alloc`'s view from `from_class` to
+    /// `to_class`; see `Edit::Reinterpret`.
+    pub fn add_reinterpret(
+        &mut self,
+        pos_prio: PosWithPrio,
+        alloc: Allocation,
+        from_class: RegClass,
+        to_class: RegClass,
+    ) {
+        self.reinterprets += 1;
+        if !self.count_only {
+            let edit = Edit::Reinterpret {
+                alloc,
+                from_class,
+                to_class,
+            };
+            self.edits
+                .push((pos_prio, edit, MoveCause::Reinterpret, from_class, None));
         }
     }
 }
@@ -827,10 +1392,87 @@ pub struct Stats {
     pub spill_bundle_count: usize,
     pub spill_bundle_reg_probes: usize,
     pub spill_bundle_reg_success: usize,
+    /// Spillsets placed in a spillslot that was already used by other
+    /// spillsets whose live ranges they do not overlap.
+    pub spillslots_shared: usize,
+    /// The size of the spill area saved by that sharing, in spillslot
+    /// units, not counting alignment padding.
+    pub spill_units_saved: usize,
+    /// Spillsets placed in a free register of another class rather
+    /// than a spillslot; see `RegallocOptions::cross_class_spills`.
+    pub cross_class_spills: usize,
+    /// Halves of register pairs kept in a register while the other
+    /// half is spilled; see `RegallocOptions::partial_spills`.
+    pub partial_spills: usize,
     pub blockparam_ins_count: usize,
     pub blockparam_outs_count: usize,
+    /// Blockparam bundles moved to their neighbours' register after
+    /// allocation.
+    pub blockparam_recolors: usize,
+    /// Bundles moved to the register of the value carried into or
+    /// out of them over a loop back edge; see
+    /// `Env::recolor_loop_carried`.
+    pub loop_carried_recolors: usize,
     pub halfmoves_count: usize,
+    /// Parallel-move groups that contained at least one cycle.
+    pub cyclic_move_groups: usize,
+    /// Cycles broken by going through a scratch register or slot.
+    pub scratch_uses: usize,
+    /// Edge moves repeated identically in every predecessor of a
+    /// join block, counting each copy beyond the first; a hint that
+    /// the frontend could tail-duplicate the join.
+    pub duplicated_edge_moves: usize,
+    /// Vreg pairs connected by a program move, a blockparam or a
+    /// reuse constraint that were considered for coalescing.
+    pub coalesce_candidates: usize,
+    /// Candidate pairs that were merged into one bundle.
+    pub coalesced_pairs: usize,
+    /// Candidate pairs left apart by
+    /// `RegallocOptions::conservative_coalescing`.
+    pub conservative_coalesce_refusals: usize,
+    /// Program moves and blockparam moves between coalesced pairs
+    /// whose two vregs still share an allocation across the move
+    /// after splitting, so that no copy is needed.
+    pub coalesced_moves_eliminated: usize,
+    /// Stores of a vreg to a spillslot that already held it on every
+    /// path, e.g. between spilled pieces of the vreg separated by a
+    /// reload, that were elided.
+    pub redundant_stores_elided: usize,
     pub edits_count: usize,
+    /// Edits that store a register to the stack.
+    pub spill_edits: usize,
+    /// Edits that load a register from the stack.
+    pub reload_edits: usize,
+    /// All other edits: register-to-register and stack-to-stack moves.
+    pub move_edits: usize,
+    /// Rematerializations of never-spilled vregs; see
+    /// `Function::never_spill`.
+    pub remat_edits: usize,
+    /// Pure definitions executed again in place of reloads; see
+    /// `Function::inst_is_pure`.
+    pub sink_edits: usize,
+    /// Class-view switches around operands; see
+    /// `Function::operand_class_view`.
+    pub reinterpret_edits: usize,
+    /// Distinct registers that appear in the final allocations and
+    /// edits; see `Output::register_usage`.
+    pub regs_used: usize,
+    /// Time spent computing liveness and building live ranges.
+    #[cfg(feature = "phase-timings")]
+    pub liveness_time: core::time::Duration,
+    /// Time spent merging live ranges into bundles and queueing them.
+    #[cfg(feature = "phase-timings")]
+    pub bundles_time: core::time::Duration,
+    /// Time spent in the main allocation loop, including the second
+    /// chance for spilled bundles.
+    #[cfg(feature = "phase-timings")]
+    pub allocation_time: core::time::Duration,
+    /// Time spent applying allocations and inserting moves.
+    #[cfg(feature = "phase-timings")]
+    pub move_insertion_time: core::time::Duration,
+    /// Time spent resolving the inserted moves into edits.
+    #[cfg(feature = "phase-timings")]
+    pub move_resolution_time: core::time::Duration,
 }
 
 // Helper function for generating sorting keys. The order of arguments is from
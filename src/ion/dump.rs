@@ -5,7 +5,7 @@ use alloc::{format, vec};
 use alloc::{string::String, vec::Vec};
 
 use super::Env;
-use crate::{Block, Function, ProgPoint};
+use crate::{all_clobbers, Block, Function, ProgPoint};
 
 impl<'a, F: Function> Env<'a, F> {
     pub fn dump_state(&self) {
@@ -96,9 +96,7 @@ impl<'a, F: Function> Env<'a, F> {
                     .iter()
                     .map(|op| format!("{}", op))
                     .collect::<Vec<_>>();
-                let clobbers = self
-                    .func
-                    .inst_clobbers(inst)
+                let clobbers = all_clobbers(self.func, self.env, inst)
                     .into_iter()
                     .map(|preg| format!("{}", preg))
                     .collect::<Vec<_>>();
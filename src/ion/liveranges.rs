@@ -18,11 +18,12 @@ use super::{
 };
 use crate::indexset::IndexSet;
 use crate::ion::data_structures::{
-    BlockparamIn, BlockparamOut, FixedRegFixupLevel, MultiFixedRegFixup,
+    BlockparamIn, BlockparamOut, DistinctRegCopy, FixedRegFixupLevel, MultiFixedRegFixup,
 };
 use crate::{
-    Allocation, Block, Function, FxHashMap, FxHashSet, Inst, InstPosition, Operand,
-    OperandConstraint, OperandKind, OperandPos, PReg, ProgPoint, RegAllocError, VReg,
+    all_clobbers, block_edge_defs, Allocation, Block, Function, FxHashMap, FxHashSet, Inst,
+    InstPosition, Operand, OperandConstraint, OperandKind, OperandPos, PReg, PRegSet, ProgPoint,
+    RegAllocError, VReg,
 };
 use alloc::collections::VecDeque;
 use alloc::vec;
@@ -39,6 +40,7 @@ pub fn spill_weight_from_constraint(
     constraint: OperandConstraint,
     loop_depth: usize,
     is_def: bool,
+    is_cold: bool,
 ) -> SpillWeight {
     // A bonus of 1000 for one loop level, 4000 for two loop levels,
     // 16000 for three loop levels, etc. Avoids exponentiation.
@@ -47,10 +49,16 @@ pub fn spill_weight_from_constraint(
     let def_bonus: f32 = if is_def { 2000.0 } else { 0.0 };
     let constraint_bonus: f32 = match constraint {
         OperandConstraint::Any => 1000.0,
-        OperandConstraint::Reg | OperandConstraint::FixedReg(_) => 2000.0,
+        OperandConstraint::Reg
+        | OperandConstraint::FixedReg(_)
+        | OperandConstraint::RegPair
+        | OperandConstraint::RegInGroup(_) => 2000.0,
         _ => 0.0,
     };
-    SpillWeight(hot_bonus + def_bonus + constraint_bonus)
+    let weight = hot_bonus + def_bonus + constraint_bonus;
+    // Cold blocks are scaled down regardless of loop depth, but keep
+    // the relative order of constraints within the block.
+    SpillWeight(if is_cold { weight / 1000.0 } else { weight })
 }
 
 impl SpillWeight {
@@ -128,6 +136,7 @@ impl<'a, F: Function> Env<'a, F> {
                     blockparam: Block::invalid(),
                     // We'll learn the RegClass as we scan the code.
                     class: None,
+                    sink: None,
                 },
             );
         }
@@ -135,9 +144,107 @@ impl<'a, F: Function> Env<'a, F> {
         for inst in 0..self.func.num_insts() {
             let start = self.allocs.len() as u32;
             self.inst_alloc_offsets.push(start);
-            for _ in 0..self.func.inst_operands(Inst::new(inst)).len() {
+            let num_temps = self.func.inst_temps(Inst::new(inst)).len();
+            for _ in 0..self.func.inst_operands(Inst::new(inst)).len() + num_temps {
                 self.allocs.push(Allocation::none());
             }
+            if num_temps > 0 {
+                self.temp_insts.push(Inst::new(inst));
+            }
+            if self.func.inst_soft_clobbers(Inst::new(inst)) != PRegSet::empty() {
+                self.soft_clobber_insts.push(Inst::new(inst));
+            }
+            if all_clobbers(self.func, self.env, Inst::new(inst)) != PRegSet::empty()
+                || self.func.inst_early_kills(Inst::new(inst)) != PRegSet::empty()
+            {
+                self.clobber_insts.push(Inst::new(inst));
+            }
+            self.has_inst_latencies |= self.func.inst_latency(Inst::new(inst)) > 1;
+        }
+        self.find_sinkable_defs();
+    }
+
+    /// Find the vregs whose definition may be sunk to their single use
+    /// instead of being spilled; see `Function::inst_is_pure`. Each is
+    /// the only operand of a pure instruction, which therefore reads
+    /// nothing and can be executed again anywhere.
+    fn find_sinkable_defs(&mut self) {
+        let mut candidates: Vec<(VReg, Inst)> = vec![];
+        for inst in 0..self.func.num_insts() {
+            let inst = Inst::new(inst);
+            if !self.func.inst_is_pure(inst)
+                || self.func.is_branch(inst)
+                || self.func.is_ret(inst)
+                || !self.func.inst_temps(inst).is_empty()
+                || all_clobbers(self.func, self.env, inst) != PRegSet::empty()
+                || self.func.inst_soft_clobbers(inst) != PRegSet::empty()
+                || self.func.inst_early_kills(inst) != PRegSet::empty()
+            {
+                continue;
+            }
+            if let [op] = *self.func.inst_operands(inst) {
+                let on_stack = match op.constraint() {
+                    OperandConstraint::FixedReg(preg) => self.pregs[preg.index()].is_stack,
+                    OperandConstraint::Stack(_) => true,
+                    _ => false,
+                };
+                if op.kind() == OperandKind::Def
+                    && !on_stack
+                    && op.as_fixed_nonallocatable().is_none()
+                    && !self.func.operand_is_immediate(inst, 0)
+                    && !self.func.never_spill(op.vreg())
+                    && !self.func.is_reference_vreg(op.vreg())
+                    && !self.is_pair_vreg(VRegIndex::new(op.vreg().vreg()))
+                {
+                    candidates.push((op.vreg(), inst));
+                }
+            }
+        }
+        if candidates.is_empty() {
+            return;
+        }
+
+        // Count the defs and uses of every vreg. A use that must be on
+        // the stack, or as a branch argument, rules the vreg out.
+        let mut defs = vec![0u32; self.func.num_vregs()];
+        let mut uses = vec![0u32; self.func.num_vregs()];
+        for block in 0..self.func.num_blocks() {
+            let block = Block::new(block);
+            for &param in self.func.block_params(block) {
+                defs[param.vreg()] += 1;
+            }
+            for inst in self.func.block_insns(block).iter() {
+                for (i, op) in self.func.inst_operands(inst).iter().enumerate() {
+                    if op.as_fixed_nonallocatable().is_some()
+                        || self.func.operand_is_immediate(inst, i)
+                    {
+                        continue;
+                    }
+                    let on_stack = match op.constraint() {
+                        OperandConstraint::FixedReg(preg) => self.pregs[preg.index()].is_stack,
+                        OperandConstraint::Stack(_) | OperandConstraint::ReuseStack(_) => true,
+                        _ => false,
+                    };
+                    match op.kind() {
+                        OperandKind::Def => defs[op.vreg().vreg()] += 1,
+                        OperandKind::Use if on_stack => uses[op.vreg().vreg()] += 2,
+                        OperandKind::Use => uses[op.vreg().vreg()] += 1,
+                    }
+                }
+                if self.func.is_branch(inst) {
+                    for succ in 0..self.func.block_succs(block).len() {
+                        for &arg in self.func.branch_blockparams(block, inst, succ) {
+                            uses[arg.vreg()] += 2;
+                        }
+                    }
+                }
+            }
+        }
+        for (vreg, inst) in candidates {
+            if defs[vreg.vreg()] == 1 && uses[vreg.vreg()] == 1 {
+                trace!("vreg {} may be sunk from {:?}", vreg, inst);
+                self.vregs[VRegIndex::new(vreg.vreg())].sink = Some(inst);
+            }
         }
     }
 
@@ -187,7 +294,18 @@ impl<'a, F: Function> Env<'a, F> {
             );
         }
 
+        // In a non-SSA function, the following range may start at a
+        // redefinition of this vreg. That range holds a new value, so
+        // keep it separate rather than extending it backward over the
+        // old one: later stages assume a def only ever starts a range.
+        let follows_redef = !self.func.is_ssa()
+            && self.vregs[vreg]
+                .ranges
+                .last()
+                .is_some_and(|entry| self.ranges[entry.index].has_flag(LiveRangeFlag::StartsAtDef));
+
         if self.vregs[vreg].ranges.is_empty()
+            || follows_redef
             || range.to
                 < self.ranges[self.vregs[vreg].ranges.last().unwrap().index]
                     .range
@@ -220,6 +338,7 @@ impl<'a, F: Function> Env<'a, F> {
             constraint,
             loop_depth,
             operand.kind() != OperandKind::Use,
+            self.cfginfo.block_is_cold[block.index()],
         );
         u.weight = weight.to_bits();
 
@@ -268,6 +387,47 @@ impl<'a, F: Function> Env<'a, F> {
         debug_assert!(res.is_none());
     }
 
+    /// Take the registers of `Function::unavailable_ranges` over their
+    /// ranges. Parts already held by a clobber or another reservation
+    /// are skipped, as overlapping keys cannot coexist in the btree.
+    pub fn reserve_unavailable_ranges(&mut self) {
+        let mut gaps = vec![];
+        for &(preg, from, to) in self.func.unavailable_ranges() {
+            let preg_idx = PRegIndex::new(preg.index());
+            let mut start = from;
+            let first = LiveRangeKey::from_range(&CodeRange {
+                from,
+                to: from.next(),
+            });
+            for (key, _) in self.pregs[preg_idx.index()]
+                .allocations
+                .btree
+                .range(first..)
+            {
+                let held = key.to_range();
+                if held.from >= to {
+                    break;
+                }
+                if start < held.from {
+                    gaps.push((
+                        CodeRange {
+                            from: start,
+                            to: held.from,
+                        },
+                        preg,
+                    ));
+                }
+                start = core::cmp::max(start, held.to);
+            }
+            if start < to {
+                gaps.push((CodeRange { from: start, to }, preg));
+            }
+            for (range, preg) in gaps.drain(..) {
+                self.add_liverange_to_preg(range, preg);
+            }
+        }
+    }
+
     pub fn is_live_in(&mut self, block: Block, vreg: VRegIndex) -> bool {
         self.liveins[block.index()].get(vreg.index())
     }
@@ -288,6 +448,14 @@ impl<'a, F: Function> Env<'a, F> {
             workqueue.push_back(block);
             workqueue_set.insert(block);
         }
+        // Landing pads without predecessors, and the blocks only they
+        // reach, are not in the postorder; add them too.
+        for block in 0..self.func.num_blocks() {
+            let block = Block::new(block);
+            if workqueue_set.insert(block) {
+                workqueue.push_back(block);
+            }
+        }
 
         while let Some(block) = workqueue.pop_front() {
             workqueue_set.remove(&block);
@@ -305,15 +473,17 @@ impl<'a, F: Function> Env<'a, F> {
                 for i in 0..self.func.block_succs(block).len() {
                     for &param in self.func.branch_blockparams(block, insns.last(), i) {
                         live.set(param.vreg(), true);
-                        self.observe_vreg_class(param);
+                        self.observe_vreg_class(param, insns.last())?;
                     }
                 }
             }
 
             for inst in insns.iter().rev() {
                 for pos in &[OperandPos::Late, OperandPos::Early] {
-                    for op in self.func.inst_operands(inst) {
-                        if op.as_fixed_nonallocatable().is_some() {
+                    for (slot, op) in self.func.inst_operands(inst).iter().enumerate() {
+                        if op.as_fixed_nonallocatable().is_some()
+                            || self.func.operand_is_immediate(inst, slot)
+                        {
                             continue;
                         }
                         if op.pos() == *pos {
@@ -327,14 +497,21 @@ impl<'a, F: Function> Env<'a, F> {
                                     live.set(op.vreg().vreg(), false);
                                 }
                             }
-                            self.observe_vreg_class(op.vreg());
+                            self.observe_vreg_class(op.vreg(), inst)?;
                         }
                     }
                 }
             }
             for &blockparam in self.func.block_params(block) {
                 live.set(blockparam.vreg(), false);
-                self.observe_vreg_class(blockparam);
+                self.observe_vreg_class(blockparam, Inst::invalid())?;
+            }
+            // Values defined on the edge into this block are, too.
+            if let Some((_, defs)) = block_edge_defs(self.func, block) {
+                for &(vreg, _) in defs {
+                    live.set(vreg.vreg(), false);
+                    self.observe_vreg_class(vreg, Inst::invalid())?;
+                }
             }
 
             for &pred in self.func.block_preds(block) {
@@ -358,6 +535,16 @@ impl<'a, F: Function> Env<'a, F> {
             );
             return Err(RegAllocError::EntryLivein);
         }
+        // Nor to a landing pad that is only reached by unwinding.
+        for block in 0..self.func.num_blocks() {
+            let block = Block::new(block);
+            if self.func.block_preds(block).is_empty()
+                && !self.func.landing_pad_args(block).is_empty()
+                && !self.liveins[block.index()].is_empty()
+            {
+                return Err(RegAllocError::EntryLivein);
+            }
+        }
 
         Ok(())
     }
@@ -377,6 +564,7 @@ impl<'a, F: Function> Env<'a, F> {
         // only valid if `live.get(vreg)` is true.
         let mut vreg_ranges: Vec<LiveRangeIndex> =
             vec![LiveRangeIndex::invalid(); self.func.num_vregs()];
+        let allocatable = PRegSet::from(self.env);
 
         for i in (0..self.func.num_blocks()).rev() {
             let block = Block::new(i);
@@ -431,11 +619,41 @@ impl<'a, F: Function> Env<'a, F> {
                 self.vregs[param].blockparam = block;
             }
 
+            // Registers the block cannot access are taken for all of
+            // it, so values are moved out of them on the way in.
+            let unavailable = match self.func.block_available_regs(block) {
+                Some(available) => allocatable.difference(available),
+                None => PRegSet::empty(),
+            };
+            for preg in unavailable {
+                let range = CodeRange {
+                    from: self.cfginfo.block_entry[block.index()],
+                    to: self.cfginfo.block_exit[block.index()].next(),
+                };
+                self.add_liverange_to_preg(range, preg);
+            }
+
             // For each instruction, in reverse order, process
             // operands and clobbers.
             for inst in insns.iter().rev() {
-                // Mark clobbers with CodeRanges on PRegs.
-                for clobber in self.func.inst_clobbers(inst) {
+                // Mark clobbers with CodeRanges on PRegs. A register
+                // that is also the fixed register of a late def is
+                // left to the def's own liverange, which holds it
+                // from the same point on.
+                let mut fixed_late_defs = PRegSet::empty();
+                for operand in self.func.inst_operands(inst) {
+                    if let (OperandConstraint::FixedReg(preg), OperandPos::Late, OperandKind::Def) =
+                        (operand.constraint(), operand.pos(), operand.kind())
+                    {
+                        if operand.as_fixed_nonallocatable().is_none() {
+                            fixed_late_defs.add(preg);
+                        }
+                    }
+                }
+                for clobber in all_clobbers(self.func, self.env, inst)
+                    .difference(unavailable)
+                    .difference(fixed_late_defs)
+                {
                     // Clobber range is at After point only: an
                     // instruction can still take an input in a reg
                     // that it later clobbers. (In other words, the
@@ -448,17 +666,61 @@ impl<'a, F: Function> Env<'a, F> {
                     self.add_liverange_to_preg(range, clobber);
                 }
 
+                // Early kills hold their register across the Early
+                // point only, so that neither inputs nor values live
+                // across the instruction can be there but outputs
+                // can.
+                for kill in self.func.inst_early_kills(inst).difference(unavailable) {
+                    let range = CodeRange {
+                        from: ProgPoint::before(inst),
+                        to: ProgPoint::after(inst),
+                    };
+                    self.add_liverange_to_preg(range, kill);
+                }
+
+                // A branch writes the registers of its edge defs
+                // (on one edge each, but we do not know which edge
+                // is taken here), and unwinding to a landing pad
+                // those of the values passed to it, so they are
+                // clobbered likewise.
+                if self.func.is_branch(inst) {
+                    let mut edge_def_regs = PRegSet::empty();
+                    for i in 0..self.func.block_succs(block).len() {
+                        for &(_, preg) in self.func.branch_edge_defs(block, inst, i) {
+                            edge_def_regs.add(preg);
+                        }
+                        let succ = self.func.block_succs(block)[i];
+                        if succ != self.func.entry_block() {
+                            for &(_, preg) in self.func.landing_pad_args(succ) {
+                                edge_def_regs.add(preg);
+                            }
+                        }
+                    }
+                    let clobbers = all_clobbers(self.func, self.env, inst);
+                    for preg in edge_def_regs.difference(clobbers.union(unavailable)) {
+                        let range = CodeRange {
+                            from: ProgPoint::after(inst),
+                            to: ProgPoint::before(inst.next()),
+                        };
+                        self.add_liverange_to_preg(range, preg);
+                    }
+                }
+
                 // Does the instruction have any input-reusing
                 // outputs? This is important below to establish
                 // proper interference wrt other inputs. We note the
                 // *vreg* that is reused, not the index.
                 let mut reused_input = None;
+                let mut reused_slot = None;
                 for op in self.func.inst_operands(inst) {
-                    if let OperandConstraint::Reuse(i) = op.constraint() {
+                    if let OperandConstraint::Reuse(i) | OperandConstraint::ReuseStack(i) =
+                        op.constraint()
+                    {
                         debug_assert!(self.func.inst_operands(inst)[i]
                             .as_fixed_nonallocatable()
                             .is_none());
                         reused_input = Some(self.func.inst_operands(inst)[i].vreg());
+                        reused_slot = Some(i);
                         break;
                     }
                 }
@@ -477,17 +739,18 @@ impl<'a, F: Function> Env<'a, F> {
                 // instruction is with an early-use and a late-def. Anything
                 // else is a user error.
                 let mut operand_rewrites: FxHashMap<usize, Operand> = FxHashMap::default();
-                let mut late_def_fixed: SmallVec<[PReg; 8]> = smallvec![];
+                let mut late_def_fixed = PRegSet::empty();
                 for &operand in self.func.inst_operands(inst) {
                     if let OperandConstraint::FixedReg(preg) = operand.constraint() {
                         match (operand.pos(), operand.kind()) {
                             (OperandPos::Late, OperandKind::Def) => {
-                                late_def_fixed.push(preg);
+                                late_def_fixed.add(preg);
                             }
                             _ => {}
                         }
                     }
                 }
+                let clobbers = all_clobbers(self.func, self.env, inst);
                 for (i, &operand) in self.func.inst_operands(inst).iter().enumerate() {
                     if operand.as_fixed_nonallocatable().is_some() {
                         continue;
@@ -517,9 +780,7 @@ impl<'a, F: Function> Env<'a, F> {
                                 // conflicting constraints for the
                                 // same vreg in a separate pass (see
                                 // `fixup_multi_fixed_vregs` below).
-                                if late_def_fixed.contains(&preg)
-                                    || self.func.inst_clobbers(inst).contains(preg)
-                                {
+                                if late_def_fixed.contains(preg) || clobbers.contains(preg) {
                                     trace!(
                                         concat!(
                                             "-> operand {:?} is fixed to preg {:?}, ",
@@ -563,14 +824,91 @@ impl<'a, F: Function> Env<'a, F> {
                     }
                 }
 
+                // If the instruction needs all its register operands
+                // in distinct registers, a vreg that is used more than
+                // once would still get one register for all its uses.
+                // Keep the first register use (preferring a fixed one,
+                // which pins the whole bundle) and give each further
+                // `Reg` use a temporary vreg of its own, which we copy
+                // the value into just before the instruction.
+                let distinct_regs = self.func.inst_requires_distinct_regs(inst);
+                let mut distinct_copies: SmallVec<[(usize, usize); 2]> = smallvec![];
+                if distinct_regs {
+                    let operands = self.func.inst_operands(inst);
+                    let mut seen: FxHashMap<VReg, usize> = operands
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, op)| {
+                            op.kind() == OperandKind::Use
+                                && op.as_fixed_nonallocatable().is_none()
+                                && matches!(
+                                    op.constraint(),
+                                    OperandConstraint::FixedReg(preg)
+                                        if !self.pregs[preg.index()].is_stack
+                                )
+                        })
+                        .map(|(i, op)| (op.vreg(), i))
+                        // Collected in reverse so that the first fixed
+                        // use of a vreg wins.
+                        .rev()
+                        .collect();
+                    for (i, op) in operands.iter().enumerate() {
+                        if op.kind() != OperandKind::Use
+                            || op.constraint() != OperandConstraint::Reg
+                        {
+                            continue;
+                        }
+                        let first = *seen.entry(op.vreg()).or_insert(i);
+                        if first != i {
+                            distinct_copies.push((i, first));
+                        }
+                    }
+                }
+
                 // Process defs and uses.
                 for &cur_pos in &[InstPosition::After, InstPosition::Before] {
                     for i in 0..self.func.inst_operands(inst).len() {
+                        // An immediate takes no location, so it gets
+                        // no use and its allocation stays `none`.
+                        if self.func.operand_is_immediate(inst, i) {
+                            continue;
+                        }
                         // don't borrow `self`
-                        let operand = operand_rewrites
+                        let mut operand = operand_rewrites
                             .get(&i)
                             .cloned()
                             .unwrap_or(self.func.inst_operands(inst)[i]);
+                        // The instruction reads a register-reused
+                        // input from the output's register, which the
+                        // reuse fixup copies it into from wherever it
+                        // lives; see
+                        // `RegallocOptions::reload_reused_into_output`.
+                        if self.reload_reused_into_output
+                            && Some(i) == reused_slot
+                            && operand.constraint() == OperandConstraint::Reg
+                        {
+                            operand = Operand::new(
+                                operand.vreg(),
+                                OperandConstraint::Any,
+                                operand.kind(),
+                                operand.pos(),
+                            );
+                        }
+                        // A never-spilled or sunk vreg may not be
+                        // accessed in its spillslot.
+                        if operand.constraint() == OperandConstraint::Any
+                            && (self.func.never_spill(operand.vreg())
+                                || self.vregs[VRegIndex::new(operand.vreg().vreg())]
+                                    .sink
+                                    .is_some())
+                        {
+                            operand = Operand::new(
+                                operand.vreg(),
+                                OperandConstraint::Reg,
+                                operand.kind(),
+                                operand.pos(),
+                            );
+                        }
                         let pos = match (operand.kind(), operand.pos()) {
                             (OperandKind::Def, OperandPos::Early) => ProgPoint::before(inst),
                             (OperandKind::Def, OperandPos::Late) => ProgPoint::after(inst),
@@ -588,6 +926,14 @@ impl<'a, F: Function> Env<'a, F> {
                             {
                                 ProgPoint::after(inst)
                             }
+                            // Likewise, if the instruction requires
+                            // distinct registers, extend its uses so
+                            // that they interfere with its defs.
+                            (OperandKind::Use, OperandPos::Early)
+                                if distinct_regs && reused_input.is_none() =>
+                            {
+                                ProgPoint::after(inst)
+                            }
                             (OperandKind::Use, OperandPos::Early) => ProgPoint::before(inst),
                         };
 
@@ -611,6 +957,16 @@ impl<'a, F: Function> Env<'a, F> {
                             continue;
                         }
 
+                        // Extra uses for distinct registers are
+                        // handled below. (`distinct_copies` is in
+                        // operand order.)
+                        if distinct_copies
+                            .binary_search_by_key(&i, |&(slot, _)| slot)
+                            .is_ok()
+                        {
+                            continue;
+                        }
+
                         match operand.kind() {
                             OperandKind::Def => {
                                 trace!("Def of {} at {:?}", operand.vreg(), pos);
@@ -688,6 +1044,73 @@ impl<'a, F: Function> Env<'a, F> {
                         }
                     }
                 }
+
+                // Give each extra use its temporary vreg, live only
+                // across this instruction.
+                for &(slot, first) in &distinct_copies {
+                    let operand = self.func.inst_operands(inst)[slot];
+                    let temp = VReg::new(self.vregs.len(), operand.class());
+                    let temp_idx = self.vregs.add(
+                        temp,
+                        VRegData {
+                            ranges: smallvec![],
+                            blockparam: Block::invalid(),
+                            class: Some(operand.class()),
+                            sink: None,
+                        },
+                    );
+                    let range = CodeRange {
+                        from: ProgPoint::before(inst),
+                        to: ProgPoint::before(inst.next()),
+                    };
+                    let lr = self.add_liverange_to_vreg(temp_idx, range);
+                    let pos = ProgPoint::after(inst);
+                    let temp_operand = Operand::new(
+                        temp,
+                        OperandConstraint::Reg,
+                        OperandKind::Use,
+                        OperandPos::Late,
+                    );
+                    self.insert_use_into_liverange(lr, Use::new(temp_operand, pos, slot as u8));
+                    self.distinct_reg_copies.push(DistinctRegCopy {
+                        pos: ProgPoint::before(inst),
+                        from_slot: first as u8,
+                        to_slot: slot as u8,
+                        vreg: VRegIndex::new(operand.vreg().vreg()),
+                    });
+                }
+
+                // Likewise give each scratch register the instruction
+                // asks for a vreg, defined at its start and held
+                // until the next instruction.
+                let num_operands = self.func.inst_operands(inst).len();
+                for (i, &class) in self.func.inst_temps(inst).iter().enumerate() {
+                    let temp = VReg::new(self.vregs.len(), class);
+                    let temp_idx = self.vregs.add(
+                        temp,
+                        VRegData {
+                            ranges: smallvec![],
+                            blockparam: Block::invalid(),
+                            class: Some(class),
+                            sink: None,
+                        },
+                    );
+                    let pos = ProgPoint::before(inst);
+                    let range = CodeRange {
+                        from: pos,
+                        to: ProgPoint::before(inst.next()),
+                    };
+                    let lr = self.add_liverange_to_vreg(temp_idx, range);
+                    self.ranges[lr].set_flag(LiveRangeFlag::StartsAtDef);
+                    let temp_operand = Operand::new(
+                        temp,
+                        OperandConstraint::Reg,
+                        OperandKind::Def,
+                        OperandPos::Early,
+                    );
+                    let slot = num_operands + i;
+                    self.insert_use_into_liverange(lr, Use::new(temp_operand, pos, slot as u8));
+                }
             }
 
             // Block parameters define vregs at the very beginning of
@@ -717,6 +1140,34 @@ impl<'a, F: Function> Env<'a, F> {
                     });
                 }
             }
+
+            // Values defined on the edge into this block, and the
+            // function's arguments, are handled as block parameters
+            // whose source is the given register (see
+            // `apply_allocations_and_insert_moves`).
+            if let Some((pred, defs)) = block_edge_defs(self.func, block) {
+                for &(vreg, _) in defs {
+                    let vreg_idx = VRegIndex::new(vreg.vreg());
+                    self.vregs[vreg_idx].blockparam = block;
+                    if live.get(vreg.vreg()) {
+                        live.set(vreg.vreg(), false);
+                    } else {
+                        let start = self.cfginfo.block_entry[block.index()];
+                        self.add_liverange_to_vreg(
+                            vreg_idx,
+                            CodeRange {
+                                from: start,
+                                to: start.next(),
+                            },
+                        );
+                    }
+                    self.blockparam_ins.push(BlockparamIn {
+                        to_vreg: vreg_idx,
+                        to_block: block,
+                        from_block: pred,
+                    });
+                }
+            }
         }
 
         // Make ranges in each vreg and uses in each range appear in
@@ -749,6 +1200,10 @@ impl<'a, F: Function> Env<'a, F> {
 
         self.blockparam_ins.sort_unstable_by_key(|x| x.key());
         self.blockparam_outs.sort_unstable_by_key(|x| x.key());
+        let outs = &self.blockparam_outs;
+        let mut by_dest: Vec<u32> = (0..outs.len() as u32).collect();
+        by_dest.sort_unstable_by_key(|&i| outs[i as usize].to_vreg);
+        self.blockparam_outs_by_dest = by_dest;
 
         self.stats.initial_liverange_count = self.ranges.len();
         self.stats.blockparam_ins_count = self.blockparam_ins.len();
@@ -787,11 +1242,16 @@ impl<'a, F: Function> Env<'a, F> {
                     let mut first_stack_slot = None;
                     for u in uses.iter() {
                         match u.operand.constraint() {
-                            OperandConstraint::Any => {
+                            OperandConstraint::Any
+                            | OperandConstraint::ReuseStack(_)
+                            | OperandConstraint::Stack(_) => {
                                 first_reg_slot.get_or_insert(u.slot);
                                 first_stack_slot.get_or_insert(u.slot);
                             }
-                            OperandConstraint::Reg | OperandConstraint::Reuse(_) => {
+                            OperandConstraint::Reg
+                            | OperandConstraint::Reuse(_)
+                            | OperandConstraint::RegPair
+                            | OperandConstraint::RegInGroup(_) => {
                                 first_reg_slot.get_or_insert(u.slot);
                                 requires_reg = true;
                             }
@@ -880,3 +1340,384 @@ impl<'a, F: Function> Env<'a, F> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{int_env, move_of, run_and_check, vreg, TestFunc};
+    use crate::{
+        Allocation, Block, Inst, Operand, PReg, PRegSet, ProgPoint, RegAllocError, RegClass,
+        RegallocOptions,
+    };
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_late_use_not_reused_by_defs() {
+        // With three registers and two outputs, one output can take
+        // the early use's register and the other the free one; the
+        // late use's register must be left alone.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[
+            Operand::reg_def(vreg(2)),
+            Operand::reg_def(vreg(3)),
+            Operand::reg_use_at_end(vreg(0)),
+            Operand::reg_use(vreg(1)),
+        ]);
+        f.ret(&[
+            Operand::reg_use(vreg(0)),
+            Operand::reg_use(vreg(2)),
+            Operand::reg_use(vreg(3)),
+        ]);
+
+        let out = run_and_check(&f, &int_env(3));
+        let allocs = out.inst_allocs(Inst::new(2));
+        assert!(allocs[2].is_reg());
+        assert_ne!(allocs[0], allocs[2]);
+        assert_ne!(allocs[1], allocs[2]);
+    }
+
+    #[test]
+    fn test_distinct_regs() {
+        // v1 is a copy of v0 that coalesces with it, and is then used
+        // twice by an instruction that needs distinct registers for
+        // all its operands, with v3 live across it.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(3))]);
+        f.mov(vreg(1), vreg(0));
+        let inst = f.op(&[
+            Operand::reg_use(vreg(1)),
+            Operand::reg_use(vreg(1)),
+            Operand::reg_def(vreg(2)),
+        ]);
+        f.set_distinct_regs(inst);
+        f.ret(&[Operand::reg_use(vreg(2)), Operand::reg_use(vreg(3))]);
+
+        for n in 3..5 {
+            let out = run_and_check(&f, &int_env(n));
+            let allocs = out.inst_allocs(inst);
+            assert!(allocs.iter().all(|a| a.is_reg()));
+            assert_ne!(allocs[0], allocs[1]);
+            assert_ne!(allocs[0], allocs[2]);
+            assert_ne!(allocs[1], allocs[2]);
+        }
+    }
+
+    #[test]
+    fn test_inst_temps() {
+        // Inst 2 reads v0, defines v2 and needs two int temps while
+        // v1 is live across it, which takes four registers.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        let inst = f.op(&[Operand::reg_use(vreg(0)), Operand::reg_def(vreg(2))]);
+        f.set_temps(inst, &[RegClass::Int, RegClass::Int]);
+        f.ret(&[Operand::reg_use(vreg(1)), Operand::reg_use(vreg(2))]);
+
+        let out = run_and_check(&f, &int_env(4));
+        assert_eq!(out.inst_allocs(inst).len(), 2);
+        let temps: Vec<_> = out.inst_temp_allocs(inst).collect();
+        assert_eq!(temps.len(), 2);
+        assert!(temps.iter().all(|a| a.is_reg()));
+        assert_ne!(temps[0], temps[1]);
+        for alloc in out.inst_allocs(inst) {
+            assert!(!temps.contains(alloc));
+        }
+        assert_eq!(out.inst_temp_allocs(Inst::new(1)).count(), 0);
+        assert!(out.edits.is_empty());
+
+        // With one register fewer, v1 has to be spilled around it.
+        let out = run_and_check(&f, &int_env(3));
+        assert_eq!(out.inst_temp_allocs(inst).count(), 2);
+        assert!(!out.edits.is_empty());
+    }
+
+    #[test]
+    fn test_block_available_regs() {
+        // Block 0 can only use p0 and p1, block 1 only p2 and p3, so
+        // v0 has to move banks on the way from its def to its use.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        let b0 = f.block(&[]);
+        let def = f.op(&[Operand::reg_def(vreg(0))]);
+        f.branch(&[(Block::new(1), &[])]);
+        let b1 = f.block(&[]);
+        let ret = f.ret(&[Operand::reg_use(vreg(0))]);
+        f.set_available_regs(b0, PRegSet::empty().with(p(0)).with(p(1)));
+        f.set_available_regs(b1, PRegSet::empty().with(p(2)).with(p(3)));
+
+        let out = run_and_check(&f, &int_env(4));
+        let from = out.inst_allocs(def)[0];
+        let to = out.inst_allocs(ret)[0];
+        assert!(from == Allocation::reg(p(0)) || from == Allocation::reg(p(1)));
+        assert!(to == Allocation::reg(p(2)) || to == Allocation::reg(p(3)));
+        assert_eq!(out.edits.len(), 1);
+        assert_eq!(move_of(&out.edits[0].1), (from, to));
+
+        // A fixed register must be available where it is needed.
+        let mut g = TestFunc::new();
+        g.block(&[]);
+        let inst = g.op(&[Operand::reg_fixed_def(vreg(0), p(3))]);
+        g.ret(&[Operand::reg_use(vreg(0))]);
+        g.set_available_regs(Block::new(0), PRegSet::empty().with(p(2)));
+        assert!(matches!(
+            crate::run(&g, &int_env(4), &RegallocOptions::default()),
+            Err(RegAllocError::UnavailableReg(i, r)) if i == inst && r == p(3)
+        ));
+    }
+
+    #[test]
+    fn test_unavailable_ranges() {
+        // p0 is taken from inst1 up to inst3, so v0, fixed to p0 on
+        // both sides, has to leave it before the range and come back
+        // after, and nothing may be put in p0 meanwhile.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        let def = f.op(&[Operand::reg_fixed_def(vreg(0), p(0))]);
+        let first = f.op(&[Operand::reg_def(vreg(1))]);
+        let last = f.op(&[Operand::reg_use(vreg(1)), Operand::reg_def(vreg(2))]);
+        let ret = f.ret(&[
+            Operand::reg_fixed_use(vreg(0), p(0)),
+            Operand::reg_use(vreg(2)),
+        ]);
+        let from = ProgPoint::before(first);
+        let to = ProgPoint::before(ret);
+        f.set_unavailable_ranges(&[(p(0), from, to)]);
+
+        let out = run_and_check(&f, &int_env(2));
+        for inst in [first, last] {
+            assert!(!out.inst_allocs(inst).contains(&Allocation::reg(p(0))));
+        }
+        let moves: Vec<_> = out
+            .edits
+            .iter()
+            .map(|(pos, edit)| (*pos, move_of(edit)))
+            .collect();
+        assert!(moves
+            .iter()
+            .all(|&(pos, (_, dst))| pos < from || pos >= to || dst != Allocation::reg(p(0))));
+        assert!(moves
+            .iter()
+            .any(|&(pos, (src, _))| pos <= from && src == Allocation::reg(p(0))));
+        assert!(moves
+            .iter()
+            .any(|&(pos, (_, dst))| pos >= to && dst == Allocation::reg(p(0))));
+        assert_eq!(out.inst_allocs(def)[0], Allocation::reg(p(0)));
+
+        // A fixed register must be available where it is needed.
+        let mut g = TestFunc::new();
+        g.block(&[]);
+        g.op(&[Operand::reg_def(vreg(0))]);
+        let inst = g.op(&[Operand::reg_fixed_use(vreg(0), p(0))]);
+        let ret = g.ret(&[]);
+        g.set_unavailable_ranges(&[(p(0), ProgPoint::after(inst), ProgPoint::before(ret))]);
+        assert!(matches!(
+            crate::run(&g, &int_env(2), &RegallocOptions::default()),
+            Err(RegAllocError::UnavailableReg(i, r)) if i == inst && r == p(0)
+        ));
+    }
+
+    #[test]
+    fn test_call_clobbers() {
+        // v0 and v1 are live across calls that clobber p0 and p1
+        // through the environment's call clobbers, leaving only p2,
+        // so one of them has to be spilled.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0)), Operand::reg_def(vreg(1))]);
+        f.call(&[]);
+        f.call(&[]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+
+        let mut env = int_env(3);
+        let out = run_and_check(&f, &env);
+        assert!(out.edits.is_empty());
+
+        env.call_clobbers = PRegSet::empty().with(p(0)).with(p(1));
+        let out = run_and_check(&f, &env);
+        let spills = out
+            .edits
+            .iter()
+            .filter(|(pos, edit)| {
+                let (from, to) = move_of(edit);
+                pos.inst() == Inst::new(1) && from.is_reg() && to.is_stack()
+            })
+            .count();
+        assert_eq!(spills, 1);
+    }
+
+    #[test]
+    fn test_clobbered_fixed_def() {
+        // The call returns v2 in p1, which it also clobbers, while v1
+        // stays in another register across it.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        let call = f.call(&[
+            Operand::reg_fixed_use(vreg(0), p(1)),
+            Operand::reg_fixed_def(vreg(2), p(1)),
+        ]);
+        f.ret(&[Operand::reg_use(vreg(1)), Operand::reg_use(vreg(2))]);
+
+        let mut env = int_env(3);
+        env.call_clobbers = PRegSet::empty().with(p(1));
+        let out = run_and_check(&f, &env);
+        assert_eq!(out.inst_allocs(call)[1], Allocation::reg(p(1)));
+        assert!(out.edits.iter().all(|(_, edit)| {
+            let (from, to) = move_of(edit);
+            from.is_reg() && to.is_reg()
+        }));
+    }
+
+    #[test]
+    fn test_early_kills() {
+        // Inst 1 kills both registers on entry, so v0 cannot stay in
+        // either across it, but its output v1 may use them.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        let kill = f.op(&[Operand::reg_def(vreg(1))]);
+        f.set_early_kills(kill, PRegSet::empty().with(p(0)).with(p(1)));
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+
+        let out = run_and_check(&f, &int_env(2));
+        assert!(out.inst_allocs(kill)[0].is_reg());
+        assert!(out.edits.iter().any(|(pos, edit)| {
+            let (from, to) = move_of(edit);
+            *pos == ProgPoint::before(kill) && from.is_reg() && to.is_stack()
+        }));
+
+        // An input cannot be fixed to a killed register.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        let kill = f.op(&[Operand::reg_fixed_use(vreg(0), p(0))]);
+        f.set_early_kills(kill, PRegSet::empty().with(p(0)));
+        f.ret(&[]);
+        assert!(matches!(
+            crate::run(&f, &int_env(2), &RegallocOptions::default()),
+            Err(RegAllocError::FixedRegConflict(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_immediate_operand() {
+        // v0 is a constant that inst 2 takes as an immediate. It is
+        // not live across inst 1, so one register is enough for v1
+        // and v2 without spilling anything.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        let add = f.op(&[
+            Operand::reg_use(vreg(1)),
+            Operand::any_use(vreg(0)),
+            Operand::reg_def(vreg(2)),
+        ]);
+        f.set_immediate(add, 1);
+        f.ret(&[Operand::reg_use(vreg(2))]);
+
+        let out = run_and_check(&f, &int_env(1));
+        assert!(out.edits.is_empty());
+        assert_eq!(out.inst_allocs(add)[1], Allocation::none());
+
+        // Only an `Any` use at `Early` can be an immediate.
+        let mut g = f.clone();
+        g.set_immediate(add, 0);
+        assert!(matches!(
+            crate::run(&g, &int_env(1), &RegallocOptions::default()),
+            Err(RegAllocError::Immediate(inst)) if inst == add
+        ));
+    }
+
+    /// Whether some edit of `out` stores a value to the stack.
+    fn spills(out: &crate::Output) -> bool {
+        out.edits.iter().any(|(_, edit)| move_of(edit).1.is_stack())
+    }
+
+    #[test]
+    fn test_redef_straight_line() {
+        // v0 is redefined from its own value and then again from
+        // scratch, while v2 and v3 force it and v1 out of the two
+        // registers in between.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_use(vreg(0)), Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(2)), Operand::reg_def(vreg(3))]);
+        f.op(&[Operand::reg_use(vreg(2)), Operand::reg_use(vreg(3))]);
+        f.op(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+        let options = RegallocOptions {
+            validate: true,
+            ..RegallocOptions::default()
+        };
+        assert!(matches!(
+            crate::run(&f, &int_env(2), &options),
+            Err(RegAllocError::Validation(_))
+        ));
+        f.set_non_ssa();
+
+        let out = run_and_check(&f, &int_env(2));
+        assert!(spills(&out));
+    }
+
+    #[test]
+    fn test_redef_across_branch() {
+        // v0 is redefined in one arm of a diamond only, under
+        // pressure there, so the join sees either def.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.branch(&[(Block::new(1), &[]), (Block::new(2), &[])]);
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(2)), Operand::reg_def(vreg(3))]);
+        f.op(&[Operand::reg_use(vreg(2)), Operand::reg_use(vreg(3))]);
+        f.branch(&[(Block::new(3), &[])]);
+        f.block(&[]);
+        f.branch(&[(Block::new(3), &[])]);
+        f.block(&[]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+        f.set_non_ssa();
+
+        let out = run_and_check(&f, &int_env(2));
+        assert!(spills(&out));
+    }
+
+    #[test]
+    fn test_redef_in_loop() {
+        // v0 is a counter redefined from its own value on every
+        // iteration, and has to be spilled within the loop body.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.branch(&[(Block::new(1), &[])]);
+        f.block(&[]);
+        f.op(&[Operand::reg_use(vreg(0)), Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(2)), Operand::reg_def(vreg(3))]);
+        f.op(&[Operand::reg_use(vreg(2)), Operand::reg_use(vreg(3))]);
+        f.branch(&[(Block::new(2), &[]), (Block::new(3), &[])]);
+        f.block(&[]);
+        f.branch(&[(Block::new(1), &[])]);
+        f.block(&[]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+        f.set_non_ssa();
+
+        let out = run_and_check(&f, &int_env(2));
+        assert!(spills(&out));
+    }
+}
@@ -15,9 +15,10 @@
 use super::{Env, LiveBundleIndex, SpillSet, SpillSlotIndex, VRegIndex};
 use crate::{
     ion::data_structures::{BlockparamOut, CodeRange},
-    Function, Inst, OperandConstraint, OperandKind, PReg, ProgPoint,
+    Function, FxHashSet, Inst, OperandConstraint, OperandKind, PReg, ProgPoint, VReg,
 };
 use alloc::format;
+use alloc::vec;
 use smallvec::smallvec;
 
 impl<'a, F: Function> Env<'a, F> {
@@ -39,6 +40,17 @@ impl<'a, F: Function> Env<'a, F> {
             trace!(" -> mismatching reg classes");
             return false;
         }
+        if self.is_pair_bundle(from) != self.is_pair_bundle(to) {
+            trace!(" -> mismatching register pair and single register");
+            return false;
+        }
+        // A never-spilled or sunk vreg keeps its spillset to itself.
+        if self.spillsets[self.bundles[from].spillset].is_remat()
+            || self.spillsets[self.bundles[to].spillset].is_remat()
+        {
+            trace!(" -> never-spilled or sunk vreg");
+            return false;
+        }
 
         // If either bundle is already assigned (due to a pinned vreg), don't merge.
         if self.bundles[from].allocation.is_some() || self.bundles[to].allocation.is_some() {
@@ -123,6 +135,11 @@ impl<'a, F: Function> Env<'a, F> {
 
         trace!(" -> committing to merge");
 
+        // The ranges of `from` take on the spillset of `to`.
+        let from_align = self.spillsets[self.bundles[from].spillset].align;
+        let to_spillset = &mut self.spillsets[self.bundles[to].spillset];
+        to_spillset.align = core::cmp::max(to_spillset.align, from_align);
+
         // If we reach here, then the bundles do not overlap -- merge
         // them!  We do this with a merge-sort-like scan over both
         // lists, building a new range list and replacing the list on
@@ -237,6 +254,144 @@ impl<'a, F: Function> Env<'a, F> {
         true
     }
 
+    /// Move bundle `from` into the spillset of bundle `to` if the two
+    /// do not overlap, so that both use the same spillslot whenever
+    /// they are spilled. Used when the bundles themselves cannot be
+    /// merged because of conflicting requirements. Both spillsets
+    /// must hold only the one bundle. Returns whether the spillset
+    /// is now shared.
+    fn share_spillset(&mut self, from: LiveBundleIndex, to: LiveBundleIndex) -> bool {
+        let from_spillset = self.bundles[from].spillset;
+        let to_spillset = self.bundles[to].spillset;
+        if from_spillset == to_spillset
+            || self.spillsets[from_spillset].class != self.spillsets[to_spillset].class
+            || self.spillsets[from_spillset].pair != self.spillsets[to_spillset].pair
+            || self.spillsets[from_spillset].is_remat()
+            || self.spillsets[to_spillset].is_remat()
+        {
+            return false;
+        }
+
+        let ranges_from = &self.bundles[from].ranges;
+        let ranges_to = &self.bundles[to].ranges;
+        let (mut idx_from, mut idx_to) = (0, 0);
+        while idx_from < ranges_from.len() && idx_to < ranges_to.len() {
+            if ranges_from[idx_from].range.from >= ranges_to[idx_to].range.to {
+                idx_to += 1;
+            } else if ranges_to[idx_to].range.from >= ranges_from[idx_from].range.to {
+                idx_from += 1;
+            } else {
+                trace!(" -> overlap; not sharing spillset");
+                return false;
+            }
+        }
+
+        trace!(
+            "bundle{} shares spillset {:?} with bundle{}",
+            from.index(),
+            to_spillset,
+            to.index()
+        );
+        let from_range = self.spillsets[from_spillset].range;
+        let from_align = self.spillsets[from_spillset].align;
+        let to_spillset_data = &mut self.spillsets[to_spillset];
+        to_spillset_data.range = to_spillset_data.range.join(from_range);
+        to_spillset_data.align = core::cmp::max(to_spillset_data.align, from_align);
+        self.bundles[from].spillset = to_spillset;
+        true
+    }
+
+    /// `merge_bundles`, counted in the coalescing statistics, and
+    /// only if conservative when `conservative_coalescing` is set.
+    fn coalesce(&mut self, from: LiveBundleIndex, to: LiveBundleIndex) -> bool {
+        self.stats.coalesce_candidates += 1;
+        if self.conservative_coalescing && from != to && !self.is_conservative_merge(from, to) {
+            trace!(" -> not conservative; not merging");
+            self.stats.conservative_coalesce_refusals += 1;
+            return false;
+        }
+        let merged = self.merge_bundles(from, to);
+        if merged {
+            self.stats.coalesced_pairs += 1;
+            if self.conservative_coalescing && from != to {
+                self.merge_interference(from, to);
+            }
+        }
+        merged
+    }
+
+    /// Build the interference graph of the initial, one-per-vreg
+    /// bundles: two bundles of a class interfere if any of their
+    /// ranges overlap.
+    fn build_interference(&mut self) {
+        let mut ranges = vec![];
+        for bundle in 0..self.bundles.len() {
+            let bundle = LiveBundleIndex::new(bundle);
+            for entry in &self.bundles[bundle].ranges {
+                ranges.push((entry.range, bundle));
+            }
+        }
+        ranges.sort_unstable_by_key(|&(range, _)| range.from);
+
+        self.interference = vec![FxHashSet::default(); self.bundles.len()];
+        let mut active: [vec::Vec<(ProgPoint, LiveBundleIndex)>; 3] = Default::default();
+        for (range, bundle) in ranges {
+            let class = self.spillsets[self.bundles[bundle].spillset].class as usize;
+            let active = &mut active[class];
+            active.retain(|&(to, _)| to > range.from);
+            for &(_, other) in active.iter() {
+                if other != bundle {
+                    self.interference[bundle.index()].insert(other);
+                    self.interference[other.index()].insert(bundle);
+                }
+            }
+            active.push((range.to, bundle));
+        }
+    }
+
+    /// Whether merging `from` into `to` passes the Briggs test (the
+    /// merged bundle has fewer than K neighbors of significant degree,
+    /// i.e. K or more) or the George test (every neighbor of `from`
+    /// already interferes with `to` or is of insignificant degree).
+    fn is_conservative_merge(&self, from: LiveBundleIndex, to: LiveBundleIndex) -> bool {
+        let class = self.spillsets[self.bundles[to].spillset].class as usize;
+        let k = self.env.preferred_regs_by_class[class].len()
+            + self.env.non_preferred_regs_by_class[class].len();
+        let (from_adj, to_adj) = (
+            &self.interference[from.index()],
+            &self.interference[to.index()],
+        );
+
+        let george = from_adj
+            .iter()
+            .all(|n| to_adj.contains(n) || self.interference[n.index()].len() < k);
+        if george {
+            return true;
+        }
+
+        // A neighbor of both loses one neighbor in the merge.
+        let significant = |n: &LiveBundleIndex| {
+            let both = from_adj.contains(n) && to_adj.contains(n);
+            self.interference[n.index()].len() - both as usize >= k
+        };
+        let shared = from_adj.intersection(to_adj).filter(|n| significant(n));
+        let count = from_adj.iter().filter(|n| significant(n)).count()
+            + to_adj.iter().filter(|n| significant(n)).count()
+            - shared.count();
+        count < k
+    }
+
+    /// Fold the neighbors of `from` into those of `to` after a merge.
+    fn merge_interference(&mut self, from: LiveBundleIndex, to: LiveBundleIndex) {
+        let from_adj = core::mem::take(&mut self.interference[from.index()]);
+        for &n in &from_adj {
+            let adj = &mut self.interference[n.index()];
+            adj.remove(&from);
+            adj.insert(to);
+        }
+        self.interference[to.index()].extend(from_adj);
+    }
+
     pub fn merge_vreg_bundles(&mut self) {
         // Create a bundle for every vreg, initially.
         trace!("merge_vreg_bundles: creating vreg bundles");
@@ -263,17 +418,24 @@ impl<'a, F: Function> Env<'a, F> {
 
             let mut fixed = false;
             let mut fixed_def = false;
+            let mut stack = false;
+            let mut align = 1;
             for entry in &self.bundles[bundle].ranges {
                 for u in &self.ranges[entry.index].uses {
+                    match u.operand.constraint() {
+                        OperandConstraint::ReuseStack(_) => stack = true,
+                        OperandConstraint::Stack(a) => {
+                            stack = true;
+                            align = core::cmp::max(align, a as u32);
+                        }
+                        _ => {}
+                    }
                     if let OperandConstraint::FixedReg(_) = u.operand.constraint() {
                         fixed = true;
                         if u.operand.kind() == OperandKind::Def {
                             fixed_def = true;
                         }
                     }
-                    if fixed && fixed_def {
-                        break;
-                    }
                 }
             }
             if fixed {
@@ -282,28 +444,56 @@ impl<'a, F: Function> Env<'a, F> {
             if fixed_def {
                 self.bundles[bundle].set_cached_fixed_def();
             }
+            if stack {
+                self.bundles[bundle].set_cached_stack();
+            }
 
             // Create a spillslot for this bundle.
             let reg = self.vreg(vreg);
+            let pair = self.is_pair_vreg(vreg);
             let ssidx = self.spillsets.push(SpillSet {
                 slot: SpillSlotIndex::invalid(),
                 required: false,
                 class: reg.class(),
-                reg_hint: PReg::invalid(),
+                reg_hint: self.allocation_hint(reg),
                 spill_bundle: LiveBundleIndex::invalid(),
                 splits: 0,
+                pair,
+                align,
+                never_spill: self.func.never_spill(reg).then_some(reg),
+                sink: self.vregs[vreg].sink,
                 range,
             });
             self.bundles[bundle].spillset = ssidx;
         }
 
+        if self.conservative_coalescing {
+            self.build_interference();
+        }
+
+        // Merge the pairs the frontend asked for first, so that no
+        // other merge can get in their way.
+        for &(a, b) in self.func.coalesce_hints() {
+            trace!("trying to merge hinted pair: {} and {}", a, b);
+            let (a_ranges, b_ranges) = (&self.vregs[a].ranges, &self.vregs[b].ranges);
+            if a_ranges.is_empty() || b_ranges.is_empty() {
+                continue;
+            }
+            let a_bundle = self.ranges[a_ranges[0].index].bundle;
+            let b_bundle = self.ranges[b_ranges[0].index].bundle;
+            self.coalesce(b_bundle, a_bundle);
+        }
+
+        let mut stack_reuses = vec![];
         for inst in 0..self.func.num_insts() {
             let inst = Inst::new(inst);
 
-            // Attempt to merge Reuse-constraint operand outputs with the
-            // corresponding inputs.
+            // Attempt to merge Reuse- and ReuseStack-constraint operand
+            // outputs with the corresponding inputs.
             for op in self.func.inst_operands(inst) {
-                if let OperandConstraint::Reuse(reuse_idx) = op.constraint() {
+                if let OperandConstraint::Reuse(reuse_idx)
+                | OperandConstraint::ReuseStack(reuse_idx) = op.constraint()
+                {
                     let src_vreg = op.vreg();
                     let dst_vreg = self.func.inst_operands(inst)[reuse_idx].vreg();
 
@@ -316,7 +506,31 @@ impl<'a, F: Function> Env<'a, F> {
                     debug_assert!(src_bundle.is_valid());
                     let dest_bundle = self.ranges[self.vregs[dst_vreg].ranges[0].index].bundle;
                     debug_assert!(dest_bundle.is_valid());
-                    self.merge_bundles(/* from */ dest_bundle, /* to */ src_bundle);
+                    let merged =
+                        self.coalesce(/* from */ dest_bundle, /* to */ src_bundle);
+                    if !merged && matches!(op.constraint(), OperandConstraint::ReuseStack(_)) {
+                        stack_reuses.push((dst_vreg, src_vreg));
+                    }
+                }
+            }
+
+            // Attempt to merge the destination of a program move with
+            // its source, so that the move becomes a no-op.
+            if let Some((src_vreg, dst_vreg)) = self.func.is_move(inst) {
+                trace!("trying to merge move: src {} to dst {}", src_vreg, dst_vreg);
+                let src_ranges = &self.vregs[src_vreg].ranges;
+                let dst_ranges = &self.vregs[dst_vreg].ranges;
+                if !src_ranges.is_empty() && !dst_ranges.is_empty() {
+                    let src_bundle = self.ranges[src_ranges[0].index].bundle;
+                    let dest_bundle = self.ranges[dst_ranges[0].index].bundle;
+                    if self.coalesce(/* from */ dest_bundle, /* to */ src_bundle) {
+                        self.coalesced_moves.push((
+                            VRegIndex::new(src_vreg.vreg()),
+                            ProgPoint::before(inst),
+                            VRegIndex::new(dst_vreg.vreg()),
+                            ProgPoint::after(inst),
+                        ));
+                    }
                 }
             }
         }
@@ -324,7 +538,10 @@ impl<'a, F: Function> Env<'a, F> {
         // Attempt to merge blockparams with their inputs.
         for i in 0..self.blockparam_outs.len() {
             let BlockparamOut {
-                from_vreg, to_vreg, ..
+                from_vreg,
+                from_block,
+                to_vreg,
+                to_block,
             } = self.blockparam_outs[i];
             trace!(
                 "trying to merge blockparam v{} with input v{}",
@@ -340,12 +557,58 @@ impl<'a, F: Function> Env<'a, F> {
                 from_bundle.index(),
                 to_bundle.index()
             );
-            self.merge_bundles(from_bundle, to_bundle);
+            if self.coalesce(from_bundle, to_bundle) {
+                self.coalesced_moves.push((
+                    from_vreg,
+                    self.cfginfo.block_exit[from_block.index()],
+                    to_vreg,
+                    self.cfginfo.block_entry[to_block.index()],
+                ));
+            }
+        }
+
+        // Stack-reuse inputs and outputs that could not be merged
+        // (e.g. because the output is later needed in a register) may
+        // still be able to share a spillslot. This is done once all
+        // bundles are final, and each spillset is shared at most once,
+        // so that only the two bundles need to be checked for overlap.
+        let mut shared = FxHashSet::default();
+        for (input, output) in stack_reuses {
+            let from = self.ranges[self.vregs[input].ranges[0].index].bundle;
+            let to = self.ranges[self.vregs[output].ranges[0].index].bundle;
+            let (from_spillset, to_spillset) =
+                (self.bundles[from].spillset, self.bundles[to].spillset);
+            if shared.contains(&from_spillset) || shared.contains(&to_spillset) {
+                continue;
+            }
+            if self.share_spillset(from, to) {
+                shared.insert(from_spillset);
+                shared.insert(to_spillset);
+            }
         }
 
         trace!("done merging bundles");
     }
 
+    /// The frontend's allocation hint for `vreg` (see
+    /// `Function::allocation_hints`), if it names an allocatable
+    /// register of the vreg's class.
+    fn allocation_hint(&self, vreg: VReg) -> PReg {
+        let hints = self.func.allocation_hints();
+        let i = hints.partition_point(|&(v, _)| v < vreg);
+        let class = vreg.class() as usize;
+        match hints.get(i) {
+            Some(&(v, preg))
+                if v == vreg
+                    && (self.env.preferred_regs_by_class[class].contains(&preg)
+                        || self.env.non_preferred_regs_by_class[class].contains(&preg)) =>
+            {
+                preg
+            }
+            _ => PReg::invalid(),
+        }
+    }
+
     pub fn compute_bundle_prio(&self, bundle: LiveBundleIndex) -> u32 {
         // The priority is simply the total "length" -- the number of
         // instructions covered by all LiveRanges.
@@ -374,3 +637,169 @@ impl<'a, F: Function> Env<'a, F> {
         self.stats.merged_bundle_count = self.allocation_queue.heap.len();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{int_env, run_and_check, run_and_check_with, vreg, TestFunc};
+    use crate::{Block, Operand, PReg, RegClass, RegallocOptions};
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_allocation_hints() {
+        // Six values, three of which end in fixed registers, under
+        // pressure; the second version adds one short-lived value.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let build = |extra: bool| {
+            let mut f = TestFunc::new();
+            f.block(&[]);
+            for v in 0..6 {
+                f.op(&[Operand::reg_def(vreg(v))]);
+            }
+            if extra {
+                f.op(&[Operand::reg_def(vreg(6))]);
+                f.op(&[Operand::reg_use(vreg(6))]);
+            }
+            for v in 0..3 {
+                f.op(&[
+                    Operand::reg_fixed_use(vreg(v), p((v + 1) % 4)),
+                    Operand::reg_use(vreg(v + 3)),
+                ]);
+            }
+            f.ret(&[]);
+            f
+        };
+        let f = build(false);
+        let hints = run_and_check(&f, &int_env(4)).allocation_hints(&f);
+        assert_eq!(hints.len(), 6);
+
+        // Seeding the changed function with the first run's registers
+        // needs fewer probes and evictions.
+        let mut f = build(true);
+        let cold = run_and_check(&f, &int_env(4)).stats;
+        f.set_allocation_hints(&hints);
+        let warm = run_and_check(&f, &int_env(4)).stats;
+        assert!(warm.process_bundle_reg_probes_any < cold.process_bundle_reg_probes_any);
+        assert!(warm.evict_bundle_count <= cold.evict_bundle_count);
+
+        // A hint outside the allocatable registers is ignored.
+        f.set_allocation_hints(&[(vreg(6), p(7))]);
+        run_and_check(&f, &int_env(4));
+    }
+
+    #[test]
+    fn test_move_chain_coalesced() {
+        // v0 -> v1 -> v2 -> v3, with another value live across the
+        // chain.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(4))]);
+        let moves: Vec<_> = (0..3).map(|v| f.mov(vreg(v + 1), vreg(v))).collect();
+        f.ret(&[Operand::reg_use(vreg(3)), Operand::reg_use(vreg(4))]);
+
+        let out = run_and_check(&f, &int_env(3));
+        assert!(out.edits.is_empty());
+        for inst in moves {
+            let allocs = out.inst_allocs(inst);
+            assert_eq!(allocs[0], allocs[1], "{:?}", inst);
+        }
+        assert_eq!(out.stats.coalesce_candidates, 3);
+        assert_eq!(out.stats.coalesced_pairs, 3);
+        assert_eq!(out.stats.coalesced_moves_eliminated, 3);
+    }
+
+    #[test]
+    fn test_coalesce_hints() {
+        // Inst 1 copies v0 into v1 without being a move the allocator
+        // knows of, while v2 is live across it.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0)), Operand::reg_def(vreg(2))]);
+        let copy = f.op(&[Operand::reg_use(vreg(0)), Operand::reg_def(vreg(1))]);
+        f.ret(&[Operand::reg_use(vreg(1)), Operand::reg_use(vreg(2))]);
+        f.set_coalesce_hints(&[(vreg(0), vreg(1))]);
+
+        let out = run_and_check(&f, &int_env(3));
+        let allocs = out.inst_allocs(copy);
+        assert_eq!(allocs[0], allocs[1]);
+        assert!(out.edits.is_empty());
+        assert_eq!(out.stats.coalesced_pairs, 1);
+
+        // If v0 is used again after the copy, the two interfere and
+        // the hint is ignored.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        let copy = f.op(&[Operand::reg_use(vreg(0)), Operand::reg_def(vreg(1))]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+        f.set_coalesce_hints(&[(vreg(0), vreg(1))]);
+
+        let out = run_and_check(&f, &int_env(3));
+        let allocs = out.inst_allocs(copy);
+        assert_ne!(allocs[0], allocs[1]);
+        assert_eq!(out.stats.coalesced_pairs, 0);
+    }
+
+    #[test]
+    fn test_coalescing_stats() {
+        // v1 is a copy of v0 but both are used afterwards, so they
+        // cannot share a bundle. v2 is passed to block 1's param v3,
+        // which can.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.mov(vreg(1), vreg(0));
+        f.op(&[Operand::reg_def(vreg(2))]);
+        f.branch(&[(Block::new(1), &[vreg(2)])]);
+        f.block(&[vreg(3)]);
+        f.ret(&[
+            Operand::reg_use(vreg(0)),
+            Operand::reg_use(vreg(1)),
+            Operand::reg_use(vreg(3)),
+        ]);
+
+        let out = run_and_check(&f, &int_env(3));
+        assert_eq!(out.stats.coalesce_candidates, 2);
+        assert_eq!(out.stats.coalesced_pairs, 1);
+        assert_eq!(out.stats.coalesced_moves_eliminated, 1);
+    }
+
+    #[test]
+    fn test_conservative_coalescing() {
+        // v1 is a copy of v0, which dies at the copy, so the two can
+        // be merged. With two registers, v2 and v3 are live across
+        // both and v4 across v1 alone, all of them with two or more
+        // neighbors: the merged bundle would have too many such
+        // neighbors, and v4 does not interfere with v0.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(2)), Operand::reg_def(vreg(3))]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.mov(vreg(1), vreg(0));
+        f.op(&[Operand::reg_def(vreg(4))]);
+        f.ret(&[
+            Operand::any_use(vreg(1)),
+            Operand::any_use(vreg(2)),
+            Operand::any_use(vreg(3)),
+            Operand::any_use(vreg(4)),
+        ]);
+
+        let out = run_and_check(&f, &int_env(2));
+        assert_eq!(out.stats.coalesced_pairs, 1);
+        assert_eq!(out.stats.conservative_coalesce_refusals, 0);
+
+        let options = RegallocOptions {
+            conservative_coalescing: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(2), &options);
+        assert_eq!(out.stats.coalesce_candidates, 1);
+        assert_eq!(out.stats.coalesced_pairs, 0);
+        assert_eq!(out.stats.conservative_coalesce_refusals, 1);
+
+        // With enough registers every neighbor is of low degree.
+        let out = run_and_check_with(&f, &int_env(8), &options);
+        assert_eq!(out.stats.coalesced_pairs, 1);
+        assert_eq!(out.stats.conservative_coalesce_refusals, 0);
+    }
+}
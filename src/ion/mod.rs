@@ -15,13 +15,18 @@
 
 use crate::cfg::CFGInfo;
 use crate::ssa::validate_ssa;
-use crate::{Function, MachineEnv, Output, PReg, RegAllocError, RegClass};
+use crate::validate::{reuse_input_ok, ValidationError};
+use crate::{
+    block_edge_defs, Allocation, Block, Function, FxHashMap, FxHashSet, Inst, MachineEnv,
+    OperandConstraint, OperandKind, OperandPos, Output, PReg, PRegSet, ProgPoint, RegAllocError,
+    RegClass, RegallocOptions, SpillSlot, VReg,
+};
 use alloc::vec;
 use alloc::vec::Vec;
 
 pub(crate) mod data_structures;
-pub use data_structures::Stats;
 use data_structures::*;
+pub use data_structures::{Ctx, InsertMovePrio, Stats};
 pub(crate) mod reg_traversal;
 use reg_traversal::*;
 pub(crate) mod requirement;
@@ -44,25 +49,28 @@ impl<'a, F: Function> Env<'a, F> {
         env: &'a MachineEnv,
         cfginfo: CFGInfo,
         annotations_enabled: bool,
+        ctx: &mut Ctx,
     ) -> Self {
         let n = func.num_insts();
+        let blocks = func.num_blocks();
         Self {
             func,
             env,
             cfginfo,
 
-            liveins: Vec::with_capacity(func.num_blocks()),
-            liveouts: Vec::with_capacity(func.num_blocks()),
-            blockparam_outs: vec![],
-            blockparam_ins: vec![],
-            bundles: LiveBundles::with_capacity(n),
-            ranges: LiveRanges::with_capacity(4 * n),
-            spillsets: SpillSets::with_capacity(n),
-            vregs: VRegs::with_capacity(n),
-            pregs: vec![],
-            allocation_queue: PrioQueue::new(),
-            spilled_bundles: vec![],
-            spillslots: vec![],
+            liveins: reuse(&mut ctx.liveins, blocks),
+            liveouts: reuse(&mut ctx.liveouts, blocks),
+            blockparam_outs: reuse(&mut ctx.blockparam_outs, 0),
+            blockparam_ins: reuse(&mut ctx.blockparam_ins, 0),
+            blockparam_outs_by_dest: reuse(&mut ctx.blockparam_outs_by_dest, 0),
+            bundles: LiveBundles::reuse(&mut ctx.bundles, n),
+            ranges: LiveRanges::reuse(&mut ctx.ranges, 4 * n),
+            spillsets: SpillSets::reuse(&mut ctx.spillsets, n),
+            vregs: VRegs::reuse(&mut ctx.vregs, n),
+            pregs: reuse(&mut ctx.pregs, 0),
+            allocation_queue: PrioQueue::reuse(&mut ctx.allocation_queue),
+            spilled_bundles: reuse(&mut ctx.spilled_bundles, 0),
+            spillslots: reuse(&mut ctx.spillslots, 0),
             slots_by_class: [
                 SpillSlotList::new(),
                 SpillSlotList::new(),
@@ -73,28 +81,66 @@ impl<'a, F: Function> Env<'a, F> {
             extra_spillslots_by_class: [smallvec![], smallvec![], smallvec![]],
             preferred_victim_by_class: [PReg::invalid(), PReg::invalid(), PReg::invalid()],
 
-            multi_fixed_reg_fixups: vec![],
-            allocs: Vec::with_capacity(4 * n),
-            inst_alloc_offsets: vec![],
+            multi_fixed_reg_fixups: reuse(&mut ctx.multi_fixed_reg_fixups, 0),
+            distinct_reg_copies: reuse(&mut ctx.distinct_reg_copies, 0),
+            soft_clobber_insts: reuse(&mut ctx.soft_clobber_insts, 0),
+            clobber_insts: reuse(&mut ctx.clobber_insts, 0),
+            temp_insts: reuse(&mut ctx.temp_insts, 0),
+            allocs: reuse(&mut ctx.allocs, 4 * n),
+            inst_alloc_offsets: reuse(&mut ctx.inst_alloc_offsets, n),
             num_spillslots: 0,
+            num_secondary_spillslots: 0,
             debug_locations: vec![],
+            reuse_copies: vec![],
 
             stats: Stats::default(),
+            #[cfg(feature = "decision-log")]
+            decision_log: vec![],
 
             debug_annotations: hashbrown::HashMap::new(),
             annotations_enabled,
+            spill_once: false,
+            reload_once_per_block: false,
+            reload_reused_into_output: false,
+            forbid_spilling: false,
+            primary_spillslots: None,
+            minimize_regs: false,
+            has_inst_latencies: false,
+            conservative_coalescing: false,
+            interference: vec![],
+            spill_observer: None,
+            count_edits_only: false,
+            cross_class_spills: false,
+            partial_spills: false,
+            partial_spill_moves: FxHashMap::default(),
+            partial_spill_ranges: FxHashSet::default(),
+            partial_spill_reservations: vec![],
+            used_pregs: PRegSet::empty(),
+            single_block: func.num_blocks() == 1 && func.entry_args().is_empty(),
+            move_prio_ranks: InsertMovePrio::ranks(|a, b| a.cmp(&b)),
+            block_grouped_bundles: reuse_set(&mut ctx.block_grouped_bundles),
+            pair_vregs: vec![],
+            coalesced_moves: reuse(&mut ctx.coalesced_moves, 0),
+            half_moves: core::mem::take(&mut ctx.half_moves),
+            inserted_move_buf: core::mem::take(&mut ctx.inserted_moves),
+            edit_buf: core::mem::take(&mut ctx.edits),
 
-            conflict_set: Default::default(),
+            conflict_set: reuse_set(&mut ctx.conflict_set),
         }
     }
 
     pub(crate) fn init(&mut self) -> Result<(), RegAllocError> {
-        self.create_pregs_and_vregs();
-        self.compute_liveness()?;
-        self.build_liveranges();
-        self.fixup_multi_fixed_vregs();
-        self.merge_vreg_bundles();
-        self.queue_bundles();
+        timed!(self.stats.liveness_time, {
+            self.create_pregs_and_vregs();
+            self.compute_liveness()?;
+            self.build_liveranges();
+            self.fixup_multi_fixed_vregs();
+            self.reserve_unavailable_ranges();
+        });
+        timed!(self.stats.bundles_time, {
+            self.merge_vreg_bundles();
+            self.queue_bundles();
+        });
         if trace_enabled!() {
             self.dump_state();
         }
@@ -102,42 +148,2312 @@ impl<'a, F: Function> Env<'a, F> {
     }
 
     pub(crate) fn run(&mut self) -> Result<Edits, RegAllocError> {
-        self.process_bundles()?;
-        self.try_allocating_regs_for_spilled_bundles();
+        timed!(self.stats.allocation_time, {
+            self.process_bundles()?;
+            self.try_allocating_regs_for_spilled_bundles()?;
+        });
+        self.sort_vreg_ranges();
+        self.recolor_blockparams();
+        self.recolor_loop_carried();
         self.allocate_spillslots();
-        let moves = self.apply_allocations_and_insert_moves();
-        let edits = self.resolve_inserted_moves(moves);
-        Ok(edits)
+        Ok(self.resolve_moves())
     }
 }
 
+/// Upper bound on the number of instructions (and blocks): a
+/// `ProgPoint` packs the instruction index and a position bit into 32
+/// bits and is decoded as a signed value.
+const MAX_INSTS: usize = 1 << 30;
+
+/// Find the vregs that live in register pairs: those mentioned with
+/// `OperandConstraint::RegPair`, and everything connected to them
+/// through blockparams. Indexed by vreg; empty if there are none.
+pub(crate) fn pair_vregs<F: Function>(func: &F) -> Vec<bool> {
+    let mut pairs = vec![];
+    for inst in 0..func.num_insts() {
+        for op in func.inst_operands(Inst::new(inst)) {
+            if op.constraint() == OperandConstraint::RegPair {
+                if pairs.is_empty() {
+                    pairs = vec![false; func.num_vregs()];
+                }
+                pairs[op.vreg().vreg()] = true;
+            }
+        }
+    }
+    if pairs.is_empty() {
+        return pairs;
+    }
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in 0..func.num_blocks() {
+            let block = Block::new(block);
+            let insts = func.block_insns(block);
+            if insts.len() == 0 || !func.is_branch(insts.last()) {
+                continue;
+            }
+            let last = insts.last();
+            for (i, &succ) in func.block_succs(block).iter().enumerate() {
+                let args = func.branch_blockparams(block, last, i);
+                for (&arg, &param) in args.iter().zip(func.block_params(succ)) {
+                    if pairs[arg.vreg()] != pairs[param.vreg()] {
+                        pairs[arg.vreg()] = true;
+                        pairs[param.vreg()] = true;
+                        changed = true;
+                    }
+                }
+            }
+        }
+    }
+    pairs
+}
+
+/// The number of spillslot indices a value of `class` takes; see
+/// `MachineEnv::slot_size_by_class`.
+pub(crate) fn spillslot_size<F: Function>(func: &F, env: &MachineEnv, class: RegClass) -> usize {
+    match (env.slot_size_by_class[class as usize], env.spillslot_unit()) {
+        (0, _) | (_, None) => func.spillslot_size(class),
+        (size, Some(unit)) => size / unit,
+    }
+}
+
+/// Split the allocation of a pair vreg into its low and high halves.
+pub(crate) fn pair_halves<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    alloc: Allocation,
+    class: RegClass,
+) -> (Allocation, Allocation) {
+    if let Some(preg) = alloc.as_reg() {
+        let hi = PReg::new(preg.hw_enc() + 1, class);
+        (alloc, Allocation::reg(hi))
+    } else if let Some(slot) = alloc.as_stack() {
+        let size = spillslot_size(func, env, class);
+        let (lo, hi) = if func.multi_spillslot_named_by_last_slot() {
+            (slot.index() - size, slot.index())
+        } else {
+            (slot.index(), slot.index() + size)
+        };
+        (
+            Allocation::stack(SpillSlot::new(lo)),
+            Allocation::stack(SpillSlot::new(hi)),
+        )
+    } else {
+        (alloc, alloc)
+    }
+}
+
+/// Reject inputs that the allocator cannot represent or whose fixed
+/// constraints contradict each other.
+fn check_input<F: Function>(func: &F, pairs: &[bool]) -> Result<(), RegAllocError> {
+    if func.num_blocks() >= MAX_INSTS {
+        return Err(RegAllocError::TooManyBlocks);
+    }
+    if func.num_insts() >= MAX_INSTS {
+        return Err(RegAllocError::TooManyInsts);
+    }
+    // `VReg::MAX` itself is reserved for `VReg::invalid()`.
+    if func.num_vregs() > VReg::MAX {
+        return Err(RegAllocError::TooManyVRegs);
+    }
+
+    for block in 0..func.num_blocks() {
+        let block = Block::new(block);
+        let last = func.block_insns(block).last();
+        if !func.is_branch(last) {
+            continue;
+        }
+        for (i, &succ) in func.block_succs(block).iter().enumerate() {
+            let defs = func.branch_edge_defs(block, last, i);
+            if !defs.is_empty()
+                && (func.block_preds(succ).len() != 1
+                    || succ == func.entry_block()
+                    || !func.landing_pad_args(succ).is_empty())
+                || defs
+                    .iter()
+                    .any(|&(vreg, preg)| vreg.class() != preg.class())
+            {
+                return Err(RegAllocError::EdgeDef(last));
+            }
+        }
+    }
+
+    for &(vreg, preg) in func.entry_args() {
+        if vreg.class() != preg.class() {
+            return Err(RegAllocError::EntryArg(vreg));
+        }
+    }
+    for block in 0..func.num_blocks() {
+        let block = Block::new(block);
+        if block == func.entry_block() {
+            continue;
+        }
+        for &(vreg, preg) in func.landing_pad_args(block) {
+            if vreg.class() != preg.class() {
+                return Err(RegAllocError::LandingPadArg(vreg, block));
+            }
+        }
+    }
+
+    // The vreg that first claims each register before and after the
+    // current instruction, so that wide instructions (e.g. calls with
+    // many fixed-register arguments) are checked in linear time.
+    let mut fixed_before: FxHashMap<PReg, VReg> = FxHashMap::default();
+    let mut fixed_after: FxHashMap<PReg, VReg> = FxHashMap::default();
+    for inst in 0..func.num_insts() {
+        let inst = Inst::new(inst);
+        fixed_before.clear();
+        fixed_after.clear();
+        let operands = func.inst_operands(inst);
+        for (slot, &op) in operands.iter().enumerate() {
+            let immediate_ok = op.kind() == OperandKind::Use
+                && op.pos() == OperandPos::Early
+                && op.constraint() == OperandConstraint::Any
+                && pairs.get(op.vreg().vreg()) != Some(&true);
+            if !immediate_ok && func.operand_is_immediate(inst, slot) {
+                return Err(RegAllocError::Immediate(inst));
+            }
+            if let OperandConstraint::Reuse(idx) | OperandConstraint::ReuseStack(idx) =
+                op.constraint()
+            {
+                if idx < operands.len() && func.operand_is_immediate(inst, idx) {
+                    return Err(RegAllocError::Immediate(inst));
+                }
+                if let Some(input) = operands.get(idx) {
+                    if !reuse_input_ok(op.constraint(), input.constraint()) {
+                        return Err(RegAllocError::Validation(ValidationError::Reuse(
+                            inst, slot,
+                        )));
+                    }
+                }
+            }
+        }
+        for &op in operands {
+            if let OperandConstraint::Stack(_) | OperandConstraint::ReuseStack(_) = op.constraint()
+            {
+                let reused = match op.constraint() {
+                    OperandConstraint::ReuseStack(idx) => operands.get(idx).map(|o| o.vreg()),
+                    _ => None,
+                };
+                for vreg in core::iter::once(op.vreg()).chain(reused) {
+                    if func.never_spill(vreg) {
+                        return Err(RegAllocError::NeverSpill(vreg, inst));
+                    }
+                }
+            }
+            // A rematerialized value is held nowhere across a
+            // safepoint, so no stackmap could describe a reference.
+            let vreg = op.vreg();
+            if op.as_fixed_nonallocatable().is_none()
+                && func.never_spill(vreg)
+                && func.can_remat(vreg)
+                && func.is_reference_vreg(vreg)
+            {
+                return Err(RegAllocError::NeverSpill(vreg, inst));
+            }
+            if !pairs.is_empty() && op.as_fixed_nonallocatable().is_none() {
+                let reused = match op.constraint() {
+                    OperandConstraint::Reuse(idx) | OperandConstraint::ReuseStack(idx) => {
+                        operands.get(idx).map(|o| o.vreg())
+                    }
+                    _ => None,
+                };
+                if let Some(vreg) = reused.filter(|v| pairs[v.vreg()]) {
+                    return Err(RegAllocError::RegPair(vreg, inst));
+                }
+                let pair_ok = matches!(
+                    op.constraint(),
+                    OperandConstraint::Any | OperandConstraint::RegPair
+                );
+                if pairs[op.vreg().vreg()] && !pair_ok {
+                    return Err(RegAllocError::RegPair(op.vreg(), inst));
+                }
+            }
+            if let OperandConstraint::FixedReg(preg) = op.constraint() {
+                if op.as_fixed_nonallocatable().is_some() {
+                    continue;
+                }
+                if preg.class() != op.class() {
+                    return Err(RegAllocError::OperandClass(op.vreg(), inst));
+                }
+                // A use at `Early` and a def at `Late` hold the
+                // register only before and after the instruction
+                // respectively; everything else holds it on both
+                // sides of the instruction.
+                let before = !(op.kind() == OperandKind::Def && op.pos() == OperandPos::Late);
+                let after = !(op.kind() == OperandKind::Use && op.pos() == OperandPos::Early);
+                if before && func.inst_early_kills(inst).contains(preg) {
+                    return Err(RegAllocError::FixedRegConflict(inst, preg));
+                }
+                for (side, claimed) in [(before, &mut fixed_before), (after, &mut fixed_after)] {
+                    if side && *claimed.entry(preg).or_insert(op.vreg()) != op.vreg() {
+                        return Err(RegAllocError::FixedRegConflict(inst, preg));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that every register class used by a vreg in `func` has at
+/// least one allocatable register in `env`, and an aligned register
+/// pair if any of its vregs need one.
+fn check_env_classes<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    pairs: &[bool],
+) -> Result<(), RegAllocError> {
+    let mut used = [false; 3];
+    let mut used_pair = [false; 3];
+    for block in 0..func.num_blocks() {
+        for &vreg in func.block_params(Block::new(block)) {
+            used[vreg.class() as usize] = true;
+        }
+        if let Some((_, defs)) = block_edge_defs(func, Block::new(block)) {
+            for &(vreg, _) in defs {
+                used[vreg.class() as usize] = true;
+            }
+        }
+    }
+    for inst in 0..func.num_insts() {
+        for &class in func.inst_temps(Inst::new(inst)) {
+            used[class as usize] = true;
+        }
+        for &op in func.inst_operands(Inst::new(inst)) {
+            if op.as_fixed_nonallocatable().is_none() {
+                used[op.class() as usize] = true;
+                if pairs.get(op.vreg().vreg()) == Some(&true) {
+                    used_pair[op.class() as usize] = true;
+                }
+            }
+        }
+    }
+    for class in [RegClass::Int, RegClass::Float, RegClass::Vector] {
+        let i = class as usize;
+        let slot_size = env.slot_size_by_class[i];
+        if slot_size != 0 && !slot_size.is_power_of_two() {
+            return Err(RegAllocError::SlotSize(class));
+        }
+        if used[i]
+            && env.preferred_regs_by_class[i].is_empty()
+            && env.non_preferred_regs_by_class[i].is_empty()
+        {
+            return Err(RegAllocError::NoRegistersForClass(class));
+        }
+        let allocatable = || {
+            env.preferred_regs_by_class[i]
+                .iter()
+                .chain(&env.non_preferred_regs_by_class[i])
+        };
+        let has_pair = allocatable().any(|lo| {
+            lo.hw_enc() & 1 == 0 && allocatable().any(|hi| hi.hw_enc() == lo.hw_enc() + 1)
+        });
+        if used_pair[i] && !has_pair {
+            return Err(RegAllocError::NoRegistersForClass(class));
+        }
+    }
+    Ok(())
+}
+
+/// Reject fixed-register constraints on allocatable vregs that name a
+/// reserved register: satisfying them would mean moving values into
+/// or out of it.
+fn check_reserved<F: Function>(func: &F, env: &MachineEnv) -> Result<(), RegAllocError> {
+    if env.reserved_regs.is_empty() {
+        return Ok(());
+    }
+    for inst in 0..func.num_insts() {
+        let inst = Inst::new(inst);
+        for &op in func.inst_operands(inst) {
+            if let OperandConstraint::FixedReg(preg) = op.constraint() {
+                if op.as_fixed_nonallocatable().is_none() && env.reserved_regs.contains(preg) {
+                    return Err(RegAllocError::ReservedReg(inst, preg));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reject fixed registers that `Function::block_available_regs` takes
+/// away from the block they are needed in, since the allocator
+/// reserves those for the whole block.
+fn check_block_regs<F: Function>(func: &F, env: &MachineEnv) -> Result<(), RegAllocError> {
+    let allocatable = PRegSet::from(env);
+    let unavailable = |block: Block| match func.block_available_regs(block) {
+        Some(available) => allocatable.difference(available),
+        None => PRegSet::empty(),
+    };
+    for block in 0..func.num_blocks() {
+        let block = Block::new(block);
+        let missing = unavailable(block);
+        if missing.is_empty() {
+            continue;
+        }
+        for inst in func.block_insns(block).iter() {
+            for &op in func.inst_operands(inst) {
+                if let OperandConstraint::FixedReg(preg) = op.constraint() {
+                    if op.as_fixed_nonallocatable().is_none() && missing.contains(preg) {
+                        return Err(RegAllocError::UnavailableReg(inst, preg));
+                    }
+                }
+            }
+        }
+        if let Some((pred, defs)) = block_edge_defs(func, block) {
+            for &(vreg, preg) in defs {
+                if !missing.contains(preg) {
+                    continue;
+                }
+                return Err(if pred.is_valid() {
+                    RegAllocError::EdgeDef(func.block_insns(pred).last())
+                } else if block == func.entry_block() {
+                    RegAllocError::EntryArg(vreg)
+                } else {
+                    RegAllocError::LandingPadArg(vreg, block)
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reject fixed registers that `Function::unavailable_ranges` takes
+/// away where they are needed.
+fn check_unavailable_ranges<F: Function>(func: &F) -> Result<(), RegAllocError> {
+    let ranges = func.unavailable_ranges();
+    if ranges.is_empty() {
+        return Ok(());
+    }
+    let unavailable = |preg: PReg, first: ProgPoint, last: ProgPoint| {
+        ranges
+            .iter()
+            .any(|&(p, from, to)| p == preg && from <= last && first < to)
+    };
+    for block in 0..func.num_blocks() {
+        let block = Block::new(block);
+        for inst in func.block_insns(block).iter() {
+            for &op in func.inst_operands(inst) {
+                if let OperandConstraint::FixedReg(preg) = op.constraint() {
+                    if op.as_fixed_nonallocatable().is_none()
+                        && unavailable(preg, ProgPoint::before(inst), ProgPoint::after(inst))
+                    {
+                        return Err(RegAllocError::UnavailableReg(inst, preg));
+                    }
+                }
+            }
+        }
+        if let Some((pred, defs)) = block_edge_defs(func, block) {
+            // The defs are held from the end of the branch, if any, to
+            // the start of the block.
+            let entry = ProgPoint::before(func.block_insns(block).first());
+            let first = if pred.is_valid() {
+                ProgPoint::after(func.block_insns(pred).last())
+            } else {
+                entry
+            };
+            for &(vreg, preg) in defs {
+                if !unavailable(preg, first, first) && !unavailable(preg, entry, entry) {
+                    continue;
+                }
+                return Err(if pred.is_valid() {
+                    RegAllocError::EdgeDef(func.block_insns(pred).last())
+                } else if block == func.entry_block() {
+                    RegAllocError::EntryArg(vreg)
+                } else {
+                    RegAllocError::LandingPadArg(vreg, block)
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Drop every allocatable register that is not in `available` (if
+/// given) or that is reserved, and any reserved scratch register.
+/// Reject `OperandConstraint::RegInGroup` operands whose group is not
+/// declared or has no allocatable register of the operand's class.
+fn check_reg_groups<F: Function>(func: &F, env: &MachineEnv) -> Result<(), RegAllocError> {
+    let allocatable = PRegSet::from(env);
+    for inst in 0..func.num_insts() {
+        let inst = Inst::new(inst);
+        for &op in func.inst_operands(inst) {
+            if let OperandConstraint::RegInGroup(idx) = op.constraint() {
+                let usable = env.reg_groups.get(idx).is_some_and(|group| {
+                    group
+                        .intersection(allocatable)
+                        .iter()
+                        .any(|preg| preg.class() == op.class())
+                });
+                if !usable {
+                    return Err(RegAllocError::RegGroup(inst));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_probe_order(env: &MachineEnv) -> Result<(), RegAllocError> {
+    for class in [RegClass::Int, RegClass::Float, RegClass::Vector] {
+        let i = class as usize;
+        let order = match &env.probe_order_by_class[i] {
+            Some(order) => order,
+            None => continue,
+        };
+        let mut expected = PRegSet::empty();
+        for &preg in env.preferred_regs_by_class[i]
+            .iter()
+            .chain(&env.non_preferred_regs_by_class[i])
+        {
+            expected.add(preg);
+        }
+        let mut seen = PRegSet::empty();
+        for &preg in order {
+            if !expected.contains(preg) || seen.contains(preg) {
+                return Err(RegAllocError::ProbeOrder(class));
+            }
+            seen.add(preg);
+        }
+        if seen != expected {
+            return Err(RegAllocError::ProbeOrder(class));
+        }
+    }
+    Ok(())
+}
+
+fn check_move_prio_order(
+    cmp: fn(InsertMovePrio, InsertMovePrio) -> core::cmp::Ordering,
+) -> Result<[u32; 6], RegAllocError> {
+    let ranks = InsertMovePrio::ranks(cmp);
+    for &(first, then) in InsertMovePrio::REQUIRED_ORDER.iter() {
+        if ranks[first as usize] > ranks[then as usize] {
+            return Err(RegAllocError::MovePrioOrder(first, then));
+        }
+    }
+    Ok(ranks)
+}
+
+fn restrict_machine_env(env: &MachineEnv, available: Option<PRegSet>) -> MachineEnv {
+    let mut env = env.clone();
+    let reserved = env.reserved_regs;
+    let keep = |preg: &PReg| {
+        let available = match available {
+            Some(available) => available.contains(*preg),
+            None => true,
+        };
+        available && !reserved.contains(*preg)
+    };
+    for class in 0..3 {
+        env.preferred_regs_by_class[class].retain(keep);
+        env.non_preferred_regs_by_class[class].retain(keep);
+        if let Some(order) = &mut env.probe_order_by_class[class] {
+            order.retain(keep);
+        }
+        if let Some(scratch) = env.scratch_by_class[class] {
+            if reserved.contains(scratch) {
+                env.scratch_by_class[class] = None;
+            }
+        }
+    }
+    env
+}
+
 pub fn run<F: Function>(
     func: &F,
     mach_env: &MachineEnv,
-    enable_annotations: bool,
-    enable_ssa_checker: bool,
+    options: &RegallocOptions,
+    ctx: &mut Ctx,
 ) -> Result<Output, RegAllocError> {
+    let pairs = pair_vregs(func);
+    check_input(func, &pairs)?;
     let cfginfo = CFGInfo::new(func)?;
 
-    if enable_ssa_checker {
+    check_reserved(func, mach_env)?;
+    check_probe_order(mach_env)?;
+
+    let restricted_env;
+    let mach_env = if options.available_override.is_some() || !mach_env.reserved_regs.is_empty() {
+        restricted_env = restrict_machine_env(mach_env, options.available_override);
+        &restricted_env
+    } else {
+        mach_env
+    };
+
+    check_block_regs(func, mach_env)?;
+    check_unavailable_ranges(func)?;
+    check_reg_groups(func, mach_env)?;
+    check_env_classes(func, mach_env, &pairs)?;
+
+    let move_prio_ranks = match options.move_prio_order {
+        Some(cmp) => check_move_prio_order(cmp)?,
+        None => InsertMovePrio::ranks(|a, b| a.cmp(&b)),
+    };
+
+    if options.validate_ssa && func.is_ssa() {
         validate_ssa(func, &cfginfo)?;
     }
 
-    let mut env = Env::new(func, mach_env, cfginfo, enable_annotations);
+    let mut env = Env::new(func, mach_env, cfginfo, options.verbose_log, ctx);
+    env.spill_once = options.spill_once;
+    env.reload_once_per_block = options.reload_once_per_block;
+    env.reload_reused_into_output = options.reload_reused_into_output;
+    env.forbid_spilling = options.forbid_spilling;
+    env.primary_spillslots = options.primary_spillslots.map(|n| n as u32);
+    env.minimize_regs = options.minimize_regs;
+    env.conservative_coalescing = options.conservative_coalescing;
+    env.spill_observer = options.spill_observer;
+    env.count_edits_only = options.count_edits_only;
+    env.cross_class_spills = options.cross_class_spills;
+    env.partial_spills = options.partial_spills;
+    env.move_prio_ranks = move_prio_ranks;
+    env.pair_vregs = pairs;
     env.init()?;
 
-    let edits = env.run()?;
+    let mut edits = env.run()?;
 
-    if enable_annotations {
+    if options.verbose_log {
         env.dump_results();
     }
 
-    Ok(Output {
-        edits: edits.into_edits().collect(),
+    let hot_spills = match options.hot_spill_threshold {
+        Some(threshold) => env.compute_hot_spills(threshold),
+        None => vec![],
+    };
+
+    let preg_timelines = if options.preg_timelines {
+        env.compute_preg_timelines()
+    } else {
+        vec![]
+    };
+
+    let pressure = if options.pressure {
+        env.compute_pressure()
+    } else {
+        vec![]
+    };
+
+    let free_regs = if options.free_regs {
+        env.compute_free_regs()
+    } else {
+        vec![]
+    };
+
+    let stackmaps = env.compute_stackmaps();
+    let relaxed_constraints = env.compute_relaxed_constraints();
+
+    #[cfg(feature = "debug-bundles")]
+    let vreg_bundles = (0..func.num_vregs())
+        .map(|vreg| env.debug_vreg_bundles(VRegIndex::new(vreg)))
+        .collect();
+
+    let num_spillslots = env.total_spillslots() as usize;
+    let spill_area_size = mach_env.spillslot_unit().map(|unit| num_spillslots * unit);
+    let mut output_edits = core::mem::take(&mut ctx.output_edits);
+    let (move_causes, move_classes, move_vregs) = edits.drain_into(
+        &mut output_edits,
+        options.move_causes,
+        options.move_classes,
+        options.move_vregs,
+    );
+    env.edit_buf = edits.into_buffer();
+    let temp_allocs = env.take_temp_allocs();
+    #[cfg(feature = "decision-log")]
+    let decision_log = core::mem::take(&mut env.decision_log);
+    ctx.reclaim(&mut env);
+    let mut output = Output {
+        edits: output_edits,
+        move_causes,
+        move_classes,
+        move_vregs,
         allocs: env.allocs,
         inst_alloc_offsets: env.inst_alloc_offsets,
-        num_spillslots: env.num_spillslots as usize,
+        num_spillslots,
+        spill_area_size,
+        num_secondary_spillslots: env.num_secondary_spillslots as usize,
         debug_locations: env.debug_locations,
+        reuse_copies: env.reuse_copies,
+        relaxed_constraints,
+        temp_allocs,
+        stackmaps,
         stats: env.stats,
-    })
+        hot_spills,
+        preg_timelines,
+        pressure,
+        free_regs,
+        #[cfg(feature = "debug-bundles")]
+        vreg_bundles,
+        #[cfg(feature = "decision-log")]
+        decision_log,
+    };
+    output.stats.regs_used = output.register_usage(func, mach_env).len();
+    Ok(output)
+}
+
+/// Build the live ranges of `func` as `run` does, but stop before
+/// merging them into bundles, and return the peak of the pressure
+/// curve for each class.
+pub fn max_pressure<F: Function>(
+    func: &F,
+    mach_env: &MachineEnv,
+) -> Result<[usize; 3], RegAllocError> {
+    let pairs = pair_vregs(func);
+    check_input(func, &pairs)?;
+    let cfginfo = CFGInfo::new(func)?;
+    check_block_regs(func, mach_env)?;
+
+    let mut ctx = Ctx::default();
+    let mut env = Env::new(func, mach_env, cfginfo, false, &mut ctx);
+    env.pair_vregs = pairs;
+    env.create_pregs_and_vregs();
+    env.compute_liveness()?;
+    env.build_liveranges();
+
+    let mut peak = [0; 3];
+    for (_, counts) in env.compute_pressure() {
+        for class in 0..3 {
+            peak[class] = peak[class].max(counts[class]);
+        }
+    }
+    Ok(peak)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::checker::{check_output, CheckOutcome, Checker};
+    use crate::testing::{int_env, move_of, run_and_check, run_and_check_with, vreg, TestFunc};
+    use crate::{
+        Allocation, Block, Edit, Function, Inst, MoveCause, Operand, OperandConstraint,
+        OperandKind, OperandPos, PReg, PRegSet, ProgPoint, RegAllocError, RegClass,
+        RegallocOptions, SpillSlot, StreamItem, VReg,
+    };
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_max_pressure() {
+        // v0..v3 are live together, v4 only after they die, and the
+        // float f5 overlaps all of them. The estimate must be the
+        // peak of the allocator's own pressure curve.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(VReg::new(5, RegClass::Float))]);
+        for v in 0..4 {
+            f.op(&[Operand::reg_def(vreg(v))]);
+        }
+        f.op(&[
+            Operand::reg_use(vreg(0)),
+            Operand::reg_use(vreg(1)),
+            Operand::reg_use(vreg(2)),
+            Operand::reg_use(vreg(3)),
+            Operand::reg_def(vreg(4)),
+        ]);
+        f.ret(&[
+            Operand::reg_use(vreg(4)),
+            Operand::reg_use(VReg::new(5, RegClass::Float)),
+        ]);
+
+        let mut env = int_env(4);
+        env.preferred_regs_by_class[RegClass::Float as usize] = vec![PReg::new(0, RegClass::Float)];
+        let peak = crate::max_pressure(&f, &env).unwrap();
+        assert_eq!(peak, [4, 1, 0]);
+
+        let options = RegallocOptions {
+            pressure: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &env, &options);
+        for class in [RegClass::Int, RegClass::Float, RegClass::Vector] {
+            let max = out
+                .pressure
+                .iter()
+                .map(|(_, counts)| counts[class as usize])
+                .max();
+            assert_eq!(max.unwrap_or(0), peak[class as usize]);
+        }
+    }
+
+    #[test]
+    fn test_pressure_of_split_vreg() {
+        // With two registers, v0 is split around every group of three
+        // live values, so its spill bundle overlaps the ranges of its
+        // minimal bundles. It must still count once at each point.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        for i in 0..6 {
+            let (a, b) = (1 + 2 * i, 2 + 2 * i);
+            f.op(&[Operand::reg_def(vreg(a))]);
+            f.op(&[Operand::reg_def(vreg(b))]);
+            f.op(&[Operand::reg_use(vreg(0))]);
+            f.op(&[Operand::reg_use(vreg(a)), Operand::reg_use(vreg(b))]);
+        }
+        f.ret(&[]);
+
+        let env = int_env(2);
+        let peak = crate::max_pressure(&f, &env).unwrap();
+        assert_eq!(peak, [3, 0, 0]);
+
+        let options = RegallocOptions {
+            pressure: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &env, &options);
+        assert!(out.num_spillslots > 0);
+        let max = out.pressure.iter().map(|(_, counts)| counts[0]).max();
+        assert_eq!(max, Some(peak[0]));
+    }
+
+    #[test]
+    fn test_available_override() {
+        // Eight simultaneously live values, but only two of the
+        // eight registers may be used.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        for v in 0..8 {
+            f.op(&[Operand::reg_def(vreg(v))]);
+        }
+        for v in 0..8 {
+            f.op(&[Operand::reg_use(vreg(v))]);
+        }
+        f.ret(&[]);
+
+        let mut available = PRegSet::empty();
+        available.add(PReg::new(3, RegClass::Int));
+        available.add(PReg::new(5, RegClass::Int));
+        let options = RegallocOptions {
+            available_override: Some(available),
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(8), &options);
+        assert!(out.num_spillslots >= 6);
+        let regs = out
+            .allocs
+            .iter()
+            .copied()
+            .chain(out.edits.iter().flat_map(|(_, edit)| {
+                let (from, to) = move_of(edit);
+                [from, to]
+            }));
+        for alloc in regs {
+            if let Some(preg) = alloc.as_reg() {
+                assert!(available.contains(preg), "{} outside budget", preg);
+            }
+        }
+    }
+
+    #[test]
+    fn test_input_errors() {
+        let p0 = PReg::new(0, RegClass::Int);
+        let run = |f: &TestFunc| crate::run(f, &int_env(2), &RegallocOptions::default());
+
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[
+            Operand::reg_fixed_def(vreg(0), p0),
+            Operand::reg_fixed_def(vreg(1), p0),
+        ]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+        assert!(matches!(
+            run(&f),
+            Err(RegAllocError::FixedRegConflict(inst, preg)) if inst == Inst::new(0) && preg == p0
+        ));
+
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.ret(&[Operand::reg_use(VReg::new(0, RegClass::Float))]);
+        let mut env = int_env(2);
+        env.preferred_regs_by_class[RegClass::Float as usize] = vec![PReg::new(0, RegClass::Float)];
+        assert!(matches!(
+            crate::run(&f, &env, &RegallocOptions::default()),
+            Err(RegAllocError::OperandClass(..))
+        ));
+    }
+
+    #[test]
+    fn test_wide_call() {
+        // A call passing 60 values of each class in fixed registers,
+        // one of them in two, and 20 of them again on the stack.
+        let classes = [RegClass::Int, RegClass::Float, RegClass::Vector];
+        let regs = |class| (0..62).map(move |i| PReg::new(i, class)).collect();
+        let mut env = int_env(0);
+        env.preferred_regs_by_class = classes.map(regs);
+
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        let mut args = vec![];
+        for (c, &class) in classes.iter().enumerate() {
+            for i in 0..60 {
+                let v = VReg::new(c * 60 + i, class);
+                f.op(&[Operand::reg_def(v)]);
+                args.push(Operand::reg_fixed_use(v, PReg::new(i, class)));
+            }
+        }
+        args.push(Operand::reg_fixed_use(
+            vreg(0),
+            PReg::new(60, RegClass::Int),
+        ));
+        for v in 0..20 {
+            args.push(Operand::any_use(vreg(v)));
+        }
+        let call = f.call(&args);
+        f.ret(&[]);
+
+        let out = run_and_check(&f, &env);
+        let allocs = out.inst_allocs(call);
+        assert_eq!(allocs.len(), 201);
+        for (op, &alloc) in args.iter().zip(allocs) {
+            if let OperandConstraint::FixedReg(preg) = op.constraint() {
+                assert_eq!(alloc, Allocation::reg(preg));
+            }
+        }
+    }
+
+    #[test]
+    fn test_no_registers_for_class() {
+        let v0 = VReg::new(0, RegClass::Float);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(v0)]);
+        f.ret(&[Operand::reg_use(v0)]);
+
+        let env = int_env(2);
+        assert!(env.preferred_regs_by_class[RegClass::Float as usize].is_empty());
+        assert!(matches!(
+            crate::run(&f, &env, &RegallocOptions::default()),
+            Err(RegAllocError::NoRegistersForClass(RegClass::Float))
+        ));
+
+        // The class is checked before liveness, so a float vreg that
+        // is live into the entry block reports the missing class too.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.ret(&[Operand::reg_use(v0)]);
+        assert!(matches!(
+            crate::run(&f, &env, &RegallocOptions::default()),
+            Err(RegAllocError::NoRegistersForClass(RegClass::Float))
+        ));
+    }
+
+    #[test]
+    fn test_probe_order_mismatch() {
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+
+        let r = |i| PReg::new(i, RegClass::Int);
+        let run = |order: Vec<PReg>| {
+            let mut env = int_env(3);
+            env.probe_order_by_class[RegClass::Int as usize] = Some(order);
+            crate::run(&f, &env, &RegallocOptions::default())
+        };
+        assert!(run(vec![r(2), r(0), r(1)]).is_ok());
+        // A missing, an extra, a repeated and a foreign register.
+        for order in [
+            vec![r(2), r(0)],
+            vec![r(2), r(0), r(1), r(3)],
+            vec![r(2), r(0), r(1), r(0)],
+            vec![r(2), r(0), PReg::new(1, RegClass::Float)],
+        ] {
+            assert!(matches!(
+                run(order),
+                Err(RegAllocError::ProbeOrder(RegClass::Int))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_inst_allocs_match_operands() {
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[]);
+        f.op(&[
+            Operand::reg_def(vreg(1)),
+            Operand::reg_use(vreg(0)),
+            Operand::any_use(vreg(0)),
+        ]);
+        f.ret(&[Operand::reg_use(vreg(1))]);
+
+        let out = run_and_check_with(&f, &int_env(2), &RegallocOptions::default());
+        for inst in 0..f.num_insts() {
+            let inst = Inst::new(inst);
+            let allocs = out.inst_allocs(inst);
+            assert_eq!(allocs.len(), f.inst_operands(inst).len());
+            assert!(allocs.iter().all(|alloc| alloc.is_some()));
+        }
+    }
+
+    #[test]
+    fn test_reg_pairs() {
+        // Eight aligned pairs, but nine pair vregs live at once, so
+        // one has to be spilled; the clobber of p3i evicts whichever
+        // pair sits in p2i/p3i.
+        let env = int_env(16);
+        let pairs: Vec<VReg> = (0..9).map(vreg).collect();
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        for &v in &pairs {
+            f.op(&[Operand::reg_pair_def(v)]);
+        }
+        f.op_with_clobbers(&[], PRegSet::empty().with(PReg::new(3, RegClass::Int)));
+        for chunk in pairs.chunks(3) {
+            let uses: Vec<_> = chunk.iter().map(|&v| Operand::reg_pair_use(v)).collect();
+            f.op(&uses);
+        }
+        f.branch(&[(Block::new(1), &[pairs[0]])]);
+        f.block(&[vreg(9)]);
+        f.ret(&[Operand::reg_pair_use(vreg(9))]);
+
+        let out = run_and_check_with(&f, &env, &RegallocOptions::default());
+        assert!(out.num_spillslots >= 2);
+        for inst in 0..f.num_insts() {
+            let inst = Inst::new(inst);
+            for (op, alloc) in f.inst_operands(inst).iter().zip(out.inst_allocs(inst)) {
+                if op.constraint() == OperandConstraint::RegPair {
+                    let preg = alloc.as_reg().unwrap();
+                    assert_eq!(preg.hw_enc() % 2, 0, "{} is not a pair", preg);
+                }
+            }
+        }
+
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_pair_def(vreg(0))]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+        assert!(matches!(
+            crate::run(&f, &env, &RegallocOptions::default()),
+            Err(RegAllocError::RegPair(v, inst)) if v == vreg(0) && inst == Inst::new(1)
+        ));
+    }
+
+    #[test]
+    fn test_reg_in_group() {
+        // v0 is defined in p0 but must be in p2 or p3 for its last
+        // use, and v1 and v2 compete for the same group.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut env = int_env(4);
+        env.reg_groups = vec![PRegSet::empty().with(p(2)).with(p(3))];
+        let group_use = |v| {
+            Operand::new(
+                v,
+                OperandConstraint::RegInGroup(0),
+                OperandKind::Use,
+                OperandPos::Early,
+            )
+        };
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_fixed_def(vreg(0), p(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_def(vreg(2))]);
+        f.op(&[group_use(vreg(1)), group_use(vreg(2))]);
+        f.op(&[group_use(vreg(0))]);
+        f.ret(&[]);
+
+        let out = run_and_check(&f, &env);
+        for inst in 0..f.num_insts() {
+            let inst = Inst::new(inst);
+            for (op, alloc) in f.inst_operands(inst).iter().zip(out.inst_allocs(inst)) {
+                if let OperandConstraint::RegInGroup(idx) = op.constraint() {
+                    let preg = alloc.as_reg().unwrap();
+                    assert!(env.reg_groups[idx].contains(preg), "{} not in group", preg);
+                }
+            }
+        }
+
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.ret(&[Operand::new(
+            vreg(0),
+            OperandConstraint::RegInGroup(1),
+            OperandKind::Use,
+            OperandPos::Early,
+        )]);
+        assert!(matches!(
+            crate::run(&f, &env, &RegallocOptions::default()),
+            Err(RegAllocError::RegGroup(inst)) if inst == Inst::new(1)
+        ));
+    }
+
+    #[test]
+    fn test_remap_spillslots() {
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        let safepoint = f.op(&[Operand::reg_use(vreg(1)), Operand::any_use(vreg(0))]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+        f.set_reference_vregs(&[vreg(0)]);
+        f.set_safepoint(safepoint);
+
+        let env = int_env(1);
+        let mut out = run_and_check_with(&f, &env, &RegallocOptions::default());
+        assert!(out.num_spillslots > 0);
+        assert!(out.stackmap(safepoint).iter().any(|alloc| alloc.is_stack()));
+        out.remap_spillslots(|slot| SpillSlot::new(slot.index() + 100));
+
+        let moves = out.edits.iter().flat_map(|(_, edit)| {
+            let (from, to) = move_of(edit);
+            [from, to]
+        });
+        let stack: Vec<_> = out
+            .allocs
+            .iter()
+            .copied()
+            .chain(moves)
+            .chain(out.stackmap(safepoint).iter().copied())
+            .filter_map(|alloc| alloc.as_stack())
+            .collect();
+        assert!(!stack.is_empty());
+        assert!(stack.iter().all(|slot| slot.index() >= 100));
+
+        let mut checker = Checker::new(&f, &env);
+        checker.enable_stackmap_checks();
+        checker.prepare(&out);
+        checker.run().unwrap();
+    }
+
+    #[test]
+    fn test_splice_inlined_callee() {
+        // Caller: v0 and v1 are live across a call at inst 2. Callee:
+        // v0 and v1 are live at once. Both have one register, so both
+        // spill.
+        let caller = |body: Option<&dyn Fn(&mut TestFunc)>| {
+            let mut f = TestFunc::new();
+            f.block(&[]);
+            f.op(&[Operand::reg_def(vreg(0))]);
+            f.op(&[Operand::reg_def(vreg(1))]);
+            if let Some(body) = body {
+                body(&mut f);
+            }
+            let call = f.op(&[]);
+            f.op(&[Operand::reg_use(vreg(1))]);
+            f.op(&[Operand::reg_use(vreg(0))]);
+            f.ret(&[]);
+            (f, call)
+        };
+        // The callee's vregs are renumbered by `base`; its return
+        // becomes a plain instruction once inlined.
+        let callee = |f: &mut TestFunc, base: usize| {
+            f.op(&[Operand::reg_def(vreg(base))]);
+            f.op(&[Operand::reg_def(vreg(base + 1))]);
+            f.op(&[Operand::reg_use(vreg(base + 1))]);
+            f.op(&[Operand::reg_use(vreg(base))]);
+        };
+
+        let (f, call) = caller(None);
+        let mut g = TestFunc::new();
+        g.block(&[]);
+        callee(&mut g, 0);
+        g.ret(&[]);
+        // The callee's slots all go to the secondary area.
+        let mut env = int_env(1);
+        env.slot_size_by_class = [8, 0, 0];
+        let options = RegallocOptions {
+            move_vregs: true,
+            ..RegallocOptions::default()
+        };
+        let caller_out = run_and_check_with(&f, &env, &options);
+        let callee_options = RegallocOptions {
+            primary_spillslots: Some(0),
+            ..options
+        };
+        let callee_out = run_and_check_with(&g, &env, &callee_options);
+        assert!(caller_out.num_spillslots > 0 && callee_out.num_spillslots > 0);
+        assert_eq!(caller_out.num_secondary_spillslots, 0);
+        assert_eq!(
+            callee_out.num_secondary_spillslots,
+            callee_out.num_spillslots
+        );
+
+        // Inline the callee before the call, in p1 and in slots of its
+        // own.
+        let (inlined, _) = caller(Some(&|f: &mut TestFunc| {
+            callee(f, 10);
+            f.op(&[]);
+        }));
+        let mut env = int_env(1);
+        env.preferred_regs_by_class[0].push(PReg::new(1, RegClass::Int));
+        let splice = |slot_offset: usize| {
+            let mut out = caller_out.clone();
+            out.splice(
+                call,
+                &callee_out,
+                slot_offset,
+                |preg| PReg::new(preg.hw_enc() + 1, preg.class()),
+                |vreg| VReg::new(vreg.vreg() + 10, vreg.class()),
+            );
+            out
+        };
+        let out = splice(caller_out.num_spillslots);
+        assert_eq!(
+            out.num_spillslots,
+            caller_out.num_spillslots + callee_out.num_spillslots
+        );
+        assert_eq!(out.spill_area_size, Some(out.num_spillslots * 8));
+        for slot in 0..out.num_spillslots {
+            let secondary = slot >= caller_out.num_spillslots;
+            assert_eq!(out.is_secondary_spillslot(SpillSlot::new(slot)), secondary);
+        }
+        // The callee's moves are attributed to its renamed vregs.
+        let callee_insts = call.index()..call.index() + g.num_insts();
+        assert_eq!(out.move_vregs.len(), out.edits.len());
+        assert!(out
+            .move_vregs
+            .iter()
+            .flatten()
+            .any(|vreg| vreg.vreg() >= 10));
+        for ((pos, _), vreg) in out.edits.iter().zip(&out.move_vregs) {
+            if let Some(vreg) = vreg {
+                let in_callee = callee_insts.contains(&pos.inst().index());
+                assert_eq!(vreg.vreg() >= 10, in_callee);
+            }
+        }
+        assert_eq!(out.inst_alloc_offsets.len(), inlined.num_insts());
+        assert!(out.edits.windows(2).all(|w| w[0].0 <= w[1].0));
+        assert_eq!(check_output(&inlined, &env, &out), CheckOutcome::Correct);
+
+        // Sharing the caller's slots clobbers the values it spilled
+        // around the call.
+        let out = splice(0);
+        assert!(!check_output(&inlined, &env, &out).is_correct());
+    }
+
+    #[test]
+    fn test_spillslot_sharing() {
+        // v0 lives in blocks 0 and 2, and the values spilled in
+        // block 1 live in its gap, so they can share one slot even
+        // though v0's extent covers block 1.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.branch(&[(Block::new(1), &[]), (Block::new(2), &[])]);
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_def(vreg(2))]);
+        f.op(&[Operand::reg_use(vreg(2))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.ret(&[]);
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(3))]);
+        f.op(&[Operand::reg_use(vreg(3))]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.ret(&[]);
+
+        let out = run_and_check(&f, &int_env(1));
+        assert_eq!(out.num_spillslots, 1);
+        assert_eq!(out.stats.spillslots_shared, 1);
+        assert_eq!(out.stats.spill_units_saved, 1);
+    }
+
+    #[test]
+    fn test_slot_size_by_class() {
+        // One int and one float register; two values of each class
+        // are live at once, so one of each is spilled.
+        let float = |i| VReg::new(i, RegClass::Float);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(float(1))]);
+        f.op(&[Operand::reg_def(vreg(2))]);
+        f.op(&[Operand::reg_def(float(3))]);
+        for v in [vreg(2), float(3), vreg(0), float(1)] {
+            f.op(&[Operand::reg_use(v)]);
+        }
+        f.ret(&[]);
+
+        let mut env = int_env(1);
+        env.preferred_regs_by_class[1].push(PReg::new(0, RegClass::Float));
+        let out = run_and_check_with(&f, &env, &RegallocOptions::default());
+        assert_eq!(out.num_spillslots, 2);
+        assert_eq!(out.spill_area_size, None);
+
+        // An 8-byte int slot and a 16-byte float slot: the float one
+        // takes two units and is aligned to them.
+        env.slot_size_by_class = [8, 16, 0];
+        let out = run_and_check_with(&f, &env, &RegallocOptions::default());
+        assert_eq!(out.num_spillslots, 4);
+        assert_eq!(out.spill_area_size, Some(32));
+
+        env.slot_size_by_class = [8, 24, 0];
+        assert!(matches!(
+            crate::run(&f, &env, &RegallocOptions::default()),
+            Err(RegAllocError::SlotSize(RegClass::Float))
+        ));
+    }
+
+    #[test]
+    fn test_aligned_stack_operand() {
+        // v0 is spilled into the first 8-byte slot; v1 must be on the
+        // stack in a 16-byte aligned slot, so it skips the next one.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        let inst = f.op(&[Operand::new(
+            vreg(1),
+            OperandConstraint::Stack(2),
+            OperandKind::Use,
+            OperandPos::Early,
+        )]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+
+        let mut env = int_env(1);
+        env.slot_size_by_class = [8, 0, 0];
+        let out = run_and_check_with(&f, &env, &RegallocOptions::default());
+        let slot = out.inst_allocs(inst)[0].as_stack().unwrap();
+        assert_eq!(slot.index() % 2, 0);
+        assert_eq!(out.num_spillslots, 3);
+        assert_eq!(out.spill_area_size, Some(24));
+    }
+
+    #[test]
+    fn test_spill_observer() {
+        use crate::{SpillEvent, SpillEventKind, SpillObserver};
+        use core::cell::RefCell;
+
+        // Counts of pressure spills, pressure reloads and spills
+        // forced by a constraint.
+        let counts = RefCell::new([0; 3]);
+        let observe = |event: SpillEvent| {
+            let which = match (event.kind, event.forced_by_constraint) {
+                (SpillEventKind::Spill, false) => 0,
+                (SpillEventKind::Reload, false) => 1,
+                (SpillEventKind::Spill, true) => 2,
+                (SpillEventKind::Reload, true) => panic!("unexpected {:?}", event),
+            };
+            assert_eq!(event.vreg, vreg(which / 2));
+            counts.borrow_mut()[which] += 1;
+        };
+
+        // v0 is spilled around v1 and reloaded for its use; v1 is also
+        // needed on the stack.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::new(
+            vreg(1),
+            OperandConstraint::Stack(1),
+            OperandKind::Def,
+            OperandPos::Late,
+        )]);
+        f.op(&[Operand::reg_def(vreg(2))]);
+        f.op(&[Operand::reg_use(vreg(2))]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::any_use(vreg(1))]);
+
+        let options = RegallocOptions {
+            spill_observer: Some(SpillObserver(&observe)),
+            ..RegallocOptions::default()
+        };
+        run_and_check_with(&f, &int_env(1), &options);
+        assert_eq!(counts.into_inner(), [1, 1, 1]);
+    }
+
+    #[test]
+    fn test_inst_indices_stable() {
+        // A function needing spills, reloads, a sunk def, temps, a
+        // reused input and edge moves: the output still describes
+        // exactly the original instructions, with every edit placed
+        // between them by original index.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        let pure = f.op(&[Operand::reg_def(vreg(0))]);
+        f.set_pure(pure);
+        f.op(&[Operand::reg_def(vreg(1)), Operand::reg_def(vreg(5))]);
+        let temps = f.op(&[Operand::reg_use(vreg(1)), Operand::reg_def(vreg(2))]);
+        f.set_temps(temps, &[RegClass::Int]);
+        f.op(&[
+            Operand::reg_use(vreg(2)),
+            Operand::reg_reuse_def(vreg(3), 0),
+        ]);
+        f.branch(&[(Block::new(1), &[vreg(3)])]);
+        f.block(&[vreg(4)]);
+        f.op(&[Operand::reg_use(vreg(4))]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(5))]);
+
+        let out = run_and_check_with(&f, &int_env(2), &RegallocOptions::default());
+        assert!(out
+            .edits
+            .iter()
+            .any(|(_, edit)| matches!(edit, Edit::Move { .. })));
+        assert!(out
+            .edits
+            .iter()
+            .any(|(_, edit)| matches!(edit, Edit::Sink { .. })));
+
+        assert_eq!(out.inst_alloc_offsets.len(), f.num_insts());
+        for inst in 0..f.num_insts() {
+            let inst = Inst::new(inst);
+            assert_eq!(out.inst_allocs(inst).len(), f.inst_operands(inst).len());
+        }
+        assert!(out.edits.windows(2).all(|w| w[0].0 <= w[1].0));
+        for &(pos, ref edit) in &out.edits {
+            assert!(pos.inst().index() < f.num_insts());
+            if let Edit::Sink { inst, .. } = *edit {
+                assert_eq!(inst, pure);
+            }
+        }
+        assert!(out.temp_allocs.iter().all(|&(inst, _)| inst == temps));
+
+        // Each block lists its own instructions, in order.
+        for block in 0..f.num_blocks() {
+            let block = Block::new(block);
+            let insts: Vec<_> = out
+                .block_insts_and_edits(&f, block)
+                .filter_map(|item| match item {
+                    crate::InstOrEdit::Inst(inst) => Some(inst),
+                    crate::InstOrEdit::Edit(_) => None,
+                })
+                .collect();
+            assert!(insts.iter().copied().eq(f.block_insns(block).iter()));
+        }
+    }
+
+    #[test]
+    fn test_instruction_stream() {
+        // v0 is spilled around v1 and reloaded for its use.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+
+        let out = run_and_check_with(&f, &int_env(1), &RegallocOptions::default());
+        assert!(!out.edits.is_empty());
+        let stream = out.instruction_stream(&f);
+        assert_eq!(stream.len(), f.num_insts() + out.edits.len());
+
+        // Every edit comes right where its program point says.
+        let mut next_inst = 0;
+        let mut next_edit = 0;
+        for item in &stream {
+            match *item {
+                StreamItem::Inst(inst, allocs) => {
+                    assert_eq!(inst.index(), next_inst);
+                    assert_eq!(allocs, out.inst_allocs(inst));
+                    next_inst += 1;
+                }
+                StreamItem::Edit(edit) => {
+                    let (pos, ref expected) = out.edits[next_edit];
+                    assert!(core::ptr::eq(edit, expected));
+                    let expected_next = match pos.pos() {
+                        crate::InstPosition::Before => pos.inst().index(),
+                        crate::InstPosition::After => pos.inst().index() + 1,
+                    };
+                    assert_eq!(next_inst, expected_next);
+                    next_edit += 1;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_with_ctx() {
+        // A function with spills, then a larger one with edges and a
+        // call, then the first again: allocating them in turn with one
+        // `Ctx` gives exactly what separate runs give.
+        let mut small = TestFunc::new();
+        small.block(&[]);
+        small.op(&[Operand::reg_def(vreg(0))]);
+        small.op(&[Operand::reg_def(vreg(1))]);
+        small.op(&[Operand::reg_use(vreg(1))]);
+        small.ret(&[Operand::reg_use(vreg(0))]);
+
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut large = TestFunc::new();
+        large.block(&[]);
+        for v in 0..4 {
+            large.op(&[Operand::reg_def(vreg(v))]);
+        }
+        large.branch(&[(Block::new(1), &[vreg(0)]), (Block::new(2), &[vreg(1)])]);
+        for v in 4..6 {
+            large.block(&[vreg(v)]);
+            large.op_with_clobbers(&[], PRegSet::empty().with(p(0)).with(p(1)));
+            large.op(&[Operand::reg_use(vreg(v)), Operand::reg_use(vreg(2))]);
+            large.branch(&[(Block::new(3), &[vreg(v)])]);
+        }
+        large.block(&[vreg(6)]);
+        large.ret(&[Operand::reg_use(vreg(6)), Operand::reg_use(vreg(3))]);
+
+        let env = int_env(3);
+        let options = RegallocOptions::default();
+        let mut ctx = crate::Ctx::default();
+        for f in [&small, &large, &small] {
+            let fresh = crate::run(f, &env, &options).unwrap();
+            let reused = crate::run_with_ctx(f, &env, &options, &mut ctx).unwrap();
+            assert_eq!(check_output(f, &env, &reused), CheckOutcome::Correct);
+            assert_eq!(
+                alloc::format!("{:?}", reused.edits),
+                alloc::format!("{:?}", fresh.edits)
+            );
+            assert_eq!(reused.allocs, fresh.allocs);
+            assert_eq!(reused.inst_alloc_offsets, fresh.inst_alloc_offsets);
+            assert_eq!(reused.num_spillslots, fresh.num_spillslots);
+            ctx.recycle(reused);
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        // The same spill and reload as above, as a listing.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+
+        let env = int_env(1);
+        let out = run_and_check_with(&f, &env, &RegallocOptions::default());
+        assert_eq!(
+            out.display(&f, &env),
+            "block0():
+  inst0: [Def: v0i reg] = p0i
+    before inst1: move p0i -> stack0
+  inst1: [Def: v1i reg] = p0i
+  inst2: [Use: v1i reg] = p0i
+    before inst3: move stack0 -> p0i
+  inst3: [Use: v0i reg] = p0i
+"
+        );
+    }
+
+    #[test]
+    fn test_into_blocks() {
+        // A diamond with pressure in every block, so that there are
+        // edits inside blocks and on edges; the last instruction has
+        // a temp.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0)), Operand::reg_def(vreg(1))]);
+        f.branch(&[(Block::new(1), &[]), (Block::new(2), &[])]);
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(2))]);
+        f.op(&[Operand::reg_def(vreg(5))]);
+        f.op(&[
+            Operand::reg_use(vreg(2)),
+            Operand::reg_use(vreg(5)),
+            Operand::reg_use(vreg(0)),
+        ]);
+        f.branch(&[(Block::new(3), &[vreg(1)])]);
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(3))]);
+        f.branch(&[(Block::new(3), &[vreg(3)])]);
+        f.block(&[vreg(4)]);
+        let ret = f.ret(&[Operand::reg_use(vreg(4)), Operand::reg_use(vreg(0))]);
+        f.set_temps(ret, &[RegClass::Int]);
+
+        let out = run_and_check_with(&f, &int_env(3), &RegallocOptions::default());
+        assert!(!out.edits.is_empty());
+        let mut num_edits = 0;
+        let mut blocks = vec![];
+        for block_out in out.clone().into_blocks(&f) {
+            let insts = f.block_insns(block_out.block);
+            assert_eq!(block_out.insts.first(), insts.first());
+            assert_eq!(block_out.insts.len(), insts.len());
+            for inst in insts.iter() {
+                assert_eq!(block_out.inst_allocs(inst), out.inst_allocs(inst));
+            }
+            let next = Inst::new(insts.last().index() + 1);
+            let moves = |edits: &mut dyn Iterator<Item = (ProgPoint, &Edit)>| -> Vec<_> {
+                edits
+                    .map(|(pos, edit)| {
+                        let (from, to) = move_of(edit);
+                        (pos, from, to)
+                    })
+                    .collect()
+            };
+            let edits = moves(
+                &mut out.edits_in_range(ProgPoint::before(insts.first()), ProgPoint::before(next)),
+            );
+            assert_eq!(
+                moves(&mut block_out.edits.iter().map(|(pos, edit)| (*pos, edit))),
+                edits
+            );
+            assert_eq!(
+                block_out.insts_and_edits().count(),
+                insts.len() + edits.len()
+            );
+            num_edits += edits.len();
+            let temps: Vec<_> = block_out.temp_allocs.iter().map(|&(_, a)| a).collect();
+            let expected: Vec<_> = insts.iter().flat_map(|i| out.inst_temp_allocs(i)).collect();
+            assert_eq!(temps, expected);
+            blocks.push(block_out.block.index());
+        }
+        assert_eq!(blocks, [0, 1, 2, 3]);
+        assert_eq!(num_edits, out.edits.len());
+    }
+
+    #[test]
+    fn test_operand_alloc_before_after() {
+        // v1 reuses v0's register, so the instruction overwrites its
+        // input; if v0 is still needed afterwards, it is kept in a
+        // copy elsewhere.
+        for keep_input in [false, true] {
+            let mut f = TestFunc::new();
+            f.block(&[]);
+            f.op(&[Operand::reg_def(vreg(0))]);
+            let inst = f.op(&[
+                Operand::reg_reuse_def(vreg(1), 1),
+                Operand::reg_use(vreg(0)),
+            ]);
+            if keep_input {
+                f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+            } else {
+                f.ret(&[Operand::reg_use(vreg(1))]);
+            }
+
+            let env = int_env(2);
+            let out = run_and_check_with(&f, &env, &RegallocOptions::default());
+            let allocs = out.inst_allocs(inst);
+            assert_eq!(out.operand_alloc_before(&f, inst, 0), Some(allocs[0]));
+            assert_eq!(out.operand_alloc_before(&f, inst, 1), Some(allocs[1]));
+            assert_eq!(out.operand_alloc_after(&f, &env, inst, 0), Some(allocs[0]));
+            assert_eq!(out.operand_alloc_after(&f, &env, inst, 1), None);
+            assert_eq!(allocs[0], allocs[1]);
+            assert_eq!(out.reuse_copies.is_empty(), !keep_input);
+
+            let insts = f.block_insns(Block::new(0));
+            assert_eq!(out.operand_alloc_before(&f, insts.first(), 0), None);
+            assert_eq!(
+                out.operand_alloc_after(&f, &env, insts.last(), 0),
+                Some(out.inst_allocs(insts.last())[0])
+            );
+        }
+
+        // A stack-reusing def holds its input's value on entry too.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        let inst = f.op(&[
+            Operand::stack_reuse_def(vreg(1), 1),
+            Operand::any_use(vreg(0)),
+        ]);
+        f.ret(&[Operand::reg_use(vreg(1))]);
+
+        let env = int_env(2);
+        let out = run_and_check_with(&f, &env, &RegallocOptions::default());
+        let allocs = out.inst_allocs(inst);
+        assert!(allocs[0].is_stack());
+        assert_eq!(allocs[0], allocs[1]);
+        assert_eq!(out.operand_alloc_before(&f, inst, 0), Some(allocs[0]));
+        assert_eq!(out.operand_alloc_before(&f, inst, 1), Some(allocs[1]));
+        assert_eq!(out.operand_alloc_after(&f, &env, inst, 0), Some(allocs[0]));
+        assert_eq!(out.operand_alloc_after(&f, &env, inst, 1), None);
+    }
+
+    #[test]
+    fn test_register_usage() {
+        let p0 = PReg::new(0, RegClass::Int);
+        let p1 = PReg::new(1, RegClass::Int);
+        let mut env = int_env(1);
+        env.non_preferred_regs_by_class[0].push(p1);
+
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_fixed_def(vreg(0), p1)]);
+        f.op(&[Operand::reg_fixed_use(vreg(0), p1)]);
+        f.op(&[Operand::reg_fixed_def(vreg(1), p0)]);
+        f.op(&[Operand::reg_fixed_use(vreg(1), p0)]);
+        f.ret(&[]);
+
+        let out = run_and_check_with(&f, &env, &RegallocOptions::default());
+        assert_eq!(
+            out.register_usage(&f, &env),
+            [
+                (
+                    p0,
+                    ProgPoint::after(Inst::new(2)),
+                    ProgPoint::before(Inst::new(3)),
+                    false
+                ),
+                (
+                    p1,
+                    ProgPoint::after(Inst::new(0)),
+                    ProgPoint::before(Inst::new(1)),
+                    true
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reserved_regs() {
+        let fp = PReg::new(1, RegClass::Int);
+        let mut env = int_env(4);
+        env.reserved_regs = PRegSet::empty().with(fp);
+        env.scratch_by_class[0] = Some(fp);
+
+        // Three values rotate around a loop with every allocatable
+        // register occupied, so the cyclic edge moves need scratch
+        // space. The loop body also reads the reserved register
+        // through a fixed-nonallocatable operand.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        for v in 0..3 {
+            f.op(&[Operand::reg_def(vreg(v))]);
+        }
+        f.branch(&[(Block::new(1), &[vreg(0), vreg(1), vreg(2)])]);
+        f.block(&[vreg(3), vreg(4), vreg(5)]);
+        f.op(&[
+            Operand::reg_use(vreg(3)),
+            Operand::reg_use(vreg(4)),
+            Operand::reg_use(vreg(5)),
+        ]);
+        f.op(&[Operand::fixed_nonallocatable(fp)]);
+        f.branch(&[(Block::new(2), &[]), (Block::new(3), &[])]);
+        f.block(&[]);
+        f.branch(&[(Block::new(1), &[vreg(4), vreg(5), vreg(3)])]);
+        f.block(&[]);
+        f.ret(&[Operand::reg_use(vreg(3))]);
+
+        let out = run_and_check_with(&f, &env, &RegallocOptions::default());
+        let fp_alloc = crate::Allocation::reg(fp);
+        assert!(out.edits.iter().all(|(_, edit)| {
+            let (from, to) = move_of(edit);
+            from != fp_alloc && to != fp_alloc
+        }));
+        for i in 0..f.num_insts() {
+            let inst = Inst::new(i);
+            for (op, &alloc) in f.inst_operands(inst).iter().zip(out.inst_allocs(inst)) {
+                assert!(op.as_fixed_nonallocatable().is_some() || alloc != fp_alloc);
+            }
+        }
+
+        // An allocatable vreg may not be pinned to it.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        let def = f.op(&[Operand::reg_fixed_def(vreg(0), fp)]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+        assert!(matches!(
+            crate::run(&f, &env, &RegallocOptions::default()),
+            Err(RegAllocError::ReservedReg(inst, preg)) if inst == def && preg == fp
+        ));
+    }
+
+    #[test]
+    fn test_forbid_spilling() {
+        // Two values are live across inst 2 but there is only one
+        // register, so one of them has to go to the stack.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.ret(&[]);
+
+        let out = run_and_check_with(&f, &int_env(1), &RegallocOptions::default());
+        assert!(out.num_spillslots > 0);
+
+        let options = RegallocOptions {
+            forbid_spilling: true,
+            ..RegallocOptions::default()
+        };
+        match crate::run(&f, &int_env(1), &options) {
+            Err(RegAllocError::OutOfRegisters { vreg: v, at }) => {
+                assert_eq!(v, vreg(0));
+                assert!(
+                    at > ProgPoint::after(Inst::new(0)) && at <= ProgPoint::before(Inst::new(4))
+                );
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // With a second register everything fits.
+        let out = run_and_check_with(&f, &int_env(2), &options);
+        assert_eq!(out.num_spillslots, 0);
+    }
+
+    #[test]
+    fn test_cross_class_spills() {
+        // As above, with a float register that nothing else uses: the
+        // spilled value can live there instead of in a spillslot.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.ret(&[]);
+        let float = PReg::new(0, RegClass::Float);
+        let mut env = int_env(1);
+        env.preferred_regs_by_class[RegClass::Float as usize] = vec![float];
+
+        let options = RegallocOptions {
+            cross_class_spills: true,
+            move_causes: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &env, &options);
+        assert_eq!(out.num_spillslots, 0);
+        assert_eq!(out.stats.cross_class_spills, 1);
+        let moves: Vec<_> = out.edits.iter().map(|(_, edit)| move_of(edit)).collect();
+        assert!(moves.iter().any(|&(_, to)| to == Allocation::reg(float)));
+        assert!(moves
+            .iter()
+            .any(|&(from, _)| from == Allocation::reg(float)));
+        assert_eq!(out.stats.spill_edits, 1);
+        assert_eq!(out.stats.reload_edits, 1);
+        assert!(out.move_causes.contains(&MoveCause::Spill));
+        assert!(out.move_causes.contains(&MoveCause::Reload));
+
+        // Float spillslots that are smaller than integer ones cannot
+        // hold the value.
+        env.slot_size_by_class = [8, 4, 0];
+        let out = run_and_check_with(&f, &env, &options);
+        assert!(out.num_spillslots > 0);
+        assert_eq!(out.stats.cross_class_spills, 0);
+    }
+
+    #[test]
+    fn test_hot_spills() {
+        // v0 is used in a loop and v1 only outside it, and both have
+        // to be spilled around v2 and v3 in the loop body. Without
+        // `Function::block_frequency`, the body has frequency 4.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.branch(&[(Block::new(1), &[])]);
+        f.block(&[]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(2)), Operand::reg_def(vreg(3))]);
+        f.op(&[Operand::reg_use(vreg(2)), Operand::reg_use(vreg(3))]);
+        f.branch(&[(Block::new(2), &[]), (Block::new(3), &[])]);
+        f.block(&[]);
+        f.branch(&[(Block::new(1), &[])]);
+        f.block(&[]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+
+        let moves = |out: &crate::Output| -> Vec<_> {
+            out.edits
+                .iter()
+                .map(|(pos, edit)| (*pos, move_of(edit)))
+                .collect()
+        };
+        let env = int_env(2);
+        let base = run_and_check(&f, &env);
+        assert!(base.hot_spills.is_empty());
+        for (threshold, hot) in [
+            (1.0, vec![(vreg(0), 4.0), (vreg(1), 1.0)]),
+            (4.0, vec![(vreg(0), 4.0)]),
+            (5.0, vec![]),
+        ] {
+            let options = RegallocOptions {
+                hot_spill_threshold: Some(threshold),
+                ..RegallocOptions::default()
+            };
+            let out = run_and_check_with(&f, &env, &options);
+            assert_eq!(out.hot_spills, hot);
+            assert_eq!(out.allocs, base.allocs);
+            assert_eq!(moves(&out), moves(&base));
+        }
+    }
+
+    #[test]
+    fn test_partial_spills() {
+        // v0 is a vector pair in p0v/p1v, but v1 needs p1v while v0 is
+        // live: only v0's high half has to go to the stack.
+        let p = |i| PReg::new(i, RegClass::Vector);
+        let v = |i| VReg::new(i, RegClass::Vector);
+        let mut env = int_env(0);
+        env.preferred_regs_by_class[RegClass::Vector as usize] = vec![p(0), p(1)];
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_pair_def(v(0))]);
+        f.op(&[Operand::reg_fixed_def(v(1), p(1))]);
+        f.op(&[Operand::reg_fixed_use(v(1), p(1))]);
+        f.op(&[Operand::reg_pair_use(v(0))]);
+        f.ret(&[]);
+
+        let options = RegallocOptions {
+            partial_spills: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &env, &options);
+        assert_eq!(out.stats.partial_spills, 1);
+        let moves: Vec<_> = out.edits.iter().map(|(_, edit)| move_of(edit)).collect();
+        let slot = match moves[..] {
+            [(from, slot), (slot2, to)] => {
+                assert_eq!(from, Allocation::reg(p(1)));
+                assert_eq!(to, Allocation::reg(p(1)));
+                assert_eq!(slot, slot2);
+                slot
+            }
+            _ => panic!("expected one store and one reload: {:?}", moves),
+        };
+        assert!(slot.is_stack());
+        assert_eq!(out.inst_allocs(Inst::new(3)), [Allocation::reg(p(0))]);
+
+        // Without the option, both halves are stored.
+        let out = run_and_check_with(&f, &env, &RegallocOptions::default());
+        assert_eq!(out.stats.partial_spills, 0);
+        let stores = out
+            .edits
+            .iter()
+            .filter(|(_, edit)| move_of(edit).1.is_stack())
+            .count();
+        assert_eq!(stores, 2);
+    }
+
+    #[test]
+    fn test_never_spill() {
+        // As above, but v0 may never live on the stack: it is
+        // rematerialized into its register for the final use.
+        let build = |can_remat| {
+            let mut f = TestFunc::new();
+            f.block(&[]);
+            f.op(&[Operand::reg_def(vreg(0))]);
+            f.op(&[Operand::reg_def(vreg(1))]);
+            f.op(&[]);
+            f.op(&[Operand::reg_use(vreg(1))]);
+            f.op(&[Operand::reg_use(vreg(0))]);
+            f.ret(&[]);
+            f.set_never_spill(vreg(0), can_remat);
+            f
+        };
+
+        let f = build(true);
+        let out = run_and_check(&f, &int_env(1));
+        assert!(out.stats.remat_edits > 0);
+        assert!(out.edits.iter().any(
+            |(_, edit)| matches!(edit, Edit::Remat { vreg: v, to } if *v == vreg(0) && to.is_reg())
+        ));
+        for (_, edit) in &out.edits {
+            if let Edit::Move { from, to } = edit {
+                assert!(from.is_reg() || to.is_reg());
+            }
+        }
+
+        // Without rematerialization v1 cannot be spilled either, so
+        // there is nowhere for one of them to go.
+        let mut f = build(false);
+        f.set_never_spill(vreg(1), false);
+        assert!(matches!(
+            crate::run(&f, &int_env(1), &RegallocOptions::default()),
+            Err(RegAllocError::OutOfRegisters { .. })
+        ));
+
+        // A stack operand contradicts `never_spill`.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::new(
+            vreg(0),
+            OperandConstraint::Stack(1),
+            OperandKind::Def,
+            OperandPos::Late,
+        )]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+        f.set_never_spill(vreg(0), true);
+        assert!(matches!(
+            crate::run(&f, &int_env(1), &RegallocOptions::default()),
+            Err(RegAllocError::NeverSpill(v, inst)) if v == vreg(0) && inst == Inst::new(0)
+        ));
+
+        // So does rematerializing a reference, which would leave it
+        // nowhere across the safepoint.
+        let mut f = build(true);
+        f.set_reference_vregs(&[vreg(0)]);
+        f.set_safepoint(Inst::new(2));
+        assert!(matches!(
+            crate::run(&f, &int_env(1), &RegallocOptions::default()),
+            Err(RegAllocError::NeverSpill(v, inst)) if v == vreg(0) && inst == Inst::new(0)
+        ));
+
+        // Without rematerialization, the reference stays in its
+        // register, and the stackmap lists it there.
+        let mut f = build(false);
+        f.set_reference_vregs(&[vreg(0)]);
+        f.set_safepoint(Inst::new(2));
+        let env = int_env(2);
+        let out = run_and_check(&f, &env);
+        let mut checker = Checker::new(&f, &env);
+        checker.enable_stackmap_checks();
+        checker.prepare(&out);
+        assert!(checker.run().is_ok());
+        assert_eq!(
+            out.stackmap(Inst::new(2)),
+            &out.inst_allocs(Inst::new(0))[..]
+        );
+    }
+
+    #[test]
+    fn test_sink_pure_def() {
+        // As above, but v0 is defined by a pure instruction: instead
+        // of being spilled, its definition is executed again for its
+        // single use.
+        let build = |uses: usize| {
+            let mut f = TestFunc::new();
+            f.block(&[]);
+            let def = f.op(&[Operand::reg_def(vreg(0))]);
+            f.op(&[Operand::reg_def(vreg(1))]);
+            f.op(&[]);
+            f.op(&[Operand::reg_use(vreg(1))]);
+            for _ in 0..uses {
+                f.op(&[Operand::reg_use(vreg(0))]);
+            }
+            f.ret(&[]);
+            f.set_pure(def);
+            (f, def)
+        };
+        let stores = |out: &crate::Output| {
+            out.edits
+                .iter()
+                .filter(|(_, edit)| matches!(edit, Edit::Move { to, .. } if to.is_stack()))
+                .count()
+        };
+
+        let (f, def) = build(1);
+        let out = run_and_check(&f, &int_env(1));
+        assert_eq!(out.stats.sink_edits, 1);
+        assert_eq!(stores(&out), 0);
+        assert_eq!(out.num_spillslots, 0);
+        let sinks: Vec<_> = out
+            .edits
+            .iter()
+            .filter_map(|(pos, edit)| match *edit {
+                Edit::Sink { inst, vreg, to } => Some((*pos, inst, vreg, to)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            sinks,
+            [(
+                ProgPoint::before(Inst::new(4)),
+                def,
+                vreg(0),
+                Allocation::reg(PReg::new(0, RegClass::Int))
+            )]
+        );
+
+        // With a second use, the value is spilled as usual.
+        let (f, _) = build(2);
+        let out = run_and_check(&f, &int_env(1));
+        assert_eq!(out.stats.sink_edits, 0);
+        assert_eq!(stores(&out), 1);
+    }
+
+    #[test]
+    fn test_secondary_spillslots() {
+        // Four values live at once with one register, so all of them
+        // are spilled, but the primary area only holds one slot.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        for v in 0..4 {
+            f.op(&[Operand::reg_def(vreg(v))]);
+        }
+        for v in 0..4 {
+            f.op(&[Operand::reg_use(vreg(v))]);
+        }
+        f.ret(&[]);
+
+        let options = RegallocOptions {
+            primary_spillslots: Some(1),
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(1), &options);
+        assert_eq!(out.num_secondary_spillslots, 3);
+        assert_eq!(out.num_spillslots, 4);
+        let mut slots: Vec<_> = out
+            .edits
+            .iter()
+            .flat_map(|(_, edit)| {
+                let (from, to) = move_of(edit);
+                [from, to]
+            })
+            .filter_map(|alloc| alloc.as_stack())
+            .collect();
+        slots.sort_unstable();
+        slots.dedup();
+        let secondary: Vec<_> = slots
+            .iter()
+            .map(|&slot| out.is_secondary_spillslot(slot))
+            .collect();
+        assert_eq!(secondary, [false, true, true, true]);
+
+        // A value only spilled within a cold block goes straight to
+        // the secondary area.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.branch(&[(Block::new(1), &[])]);
+        let cold = f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+        f.set_cold(cold);
+
+        let out = run_and_check_with(&f, &int_env(1), &options);
+        assert_eq!(out.num_secondary_spillslots, 1);
+        assert_eq!(out.num_spillslots, 2);
+    }
+
+    #[test]
+    fn test_edge_defs() {
+        // A call that also branches: it leaves v1 in p0 on the edge to
+        // block 1 and v2 in p0 on the edge to block 2. v0 is live
+        // across it and so must not stay in p0; in block 1, v1 has to
+        // move out of p0 for a fixed use of v3.
+        let r = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.branch(&[(Block::new(1), &[]), (Block::new(2), &[])]);
+        f.set_edge_defs(Block::new(0), 0, &[(vreg(1), r(0))]);
+        f.set_edge_defs(Block::new(0), 1, &[(vreg(2), r(0))]);
+        f.block(&[]);
+        f.op(&[Operand::reg_fixed_def(vreg(3), r(0))]);
+        f.ret(&[
+            Operand::reg_use(vreg(0)),
+            Operand::reg_use(vreg(1)),
+            Operand::reg_use(vreg(3)),
+        ]);
+        f.block(&[]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(2))]);
+
+        let options = RegallocOptions::default();
+        let out = run_and_check_with(&f, &int_env(3), &options);
+        assert_ne!(out.inst_allocs(Inst::new(0))[0], Allocation::reg(r(0)));
+        assert_eq!(out.inst_allocs(Inst::new(4))[1], Allocation::reg(r(0)));
+
+        // Edge defs need an edge of their own.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.branch(&[(Block::new(1), &[])]);
+        f.set_edge_defs(Block::new(0), 0, &[(vreg(0), r(0))]);
+        f.block(&[]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+        assert!(crate::run(&f, &int_env(1), &options).is_ok());
+        f.block(&[]);
+        f.branch(&[(Block::new(1), &[])]);
+        assert!(matches!(
+            crate::run(&f, &int_env(1), &options),
+            Err(RegAllocError::EdgeDef(_))
+        ));
+    }
+
+    #[test]
+    fn test_entry_args() {
+        // Three arguments in registers, two on the stack. The first
+        // instruction wants v0 and v2 swapped, and everything else is
+        // needed in a register or anywhere at the return.
+        let r = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[
+            Operand::reg_fixed_use(vreg(2), r(0)),
+            Operand::reg_fixed_use(vreg(0), r(2)),
+        ]);
+        f.ret(&[
+            Operand::reg_use(vreg(1)),
+            Operand::reg_use(vreg(3)),
+            Operand::any_use(vreg(4)),
+        ]);
+        f.set_entry_args(&[
+            (vreg(0), r(0)),
+            (vreg(1), r(1)),
+            (vreg(2), r(2)),
+            (vreg(3), r(10)),
+            (vreg(4), r(11)),
+        ]);
+        let mut env = int_env(3);
+        env.fixed_stack_slots = vec![r(10), r(11)];
+
+        let out = run_and_check(&f, &env);
+        let sources: Vec<_> = out
+            .edits
+            .iter()
+            .filter(|(pos, _)| *pos == ProgPoint::before(Inst::new(0)))
+            .map(|(_, edit)| move_of(edit).0)
+            .collect();
+        for i in [0, 2, 10, 11] {
+            assert!(sources.contains(&Allocation::reg(r(i))));
+        }
+
+        // An argument must be passed in a register of its class.
+        f.set_entry_args(&[(vreg(0), PReg::new(0, RegClass::Float))]);
+        assert!(matches!(
+            crate::run(&f, &env, &RegallocOptions::default()),
+            Err(RegAllocError::EntryArg(_))
+        ));
+    }
+
+    #[test]
+    fn test_landing_pad_args() {
+        // Block 0 ends in an invoke-like branch to block 1 and to the
+        // landing pad block 2, which receives v1 in p0 and v2 in p1
+        // and needs them swapped. v0 lives across the branch, so it
+        // cannot be in either register there. Block 3 is a landing pad
+        // that nothing branches to.
+        let r = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.branch(&[(Block::new(1), &[]), (Block::new(2), &[])]);
+        f.block(&[]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+        f.block(&[]);
+        f.ret(&[
+            Operand::reg_use(vreg(0)),
+            Operand::reg_fixed_use(vreg(2), r(0)),
+            Operand::reg_fixed_use(vreg(1), r(1)),
+        ]);
+        f.block(&[]);
+        f.ret(&[Operand::reg_fixed_use(vreg(3), r(1))]);
+        f.set_landing_pad(Block::new(2), &[(vreg(1), r(0)), (vreg(2), r(1))]);
+        f.set_landing_pad(Block::new(3), &[(vreg(3), r(0))]);
+
+        let out = run_and_check(&f, &int_env(3));
+        assert_eq!(out.inst_allocs(Inst::new(0))[0], Allocation::reg(r(2)));
+        let pad_moves = |inst| {
+            out.edits
+                .iter()
+                .filter(|(pos, _)| *pos == ProgPoint::before(Inst::new(inst)))
+                .map(|(_, edit)| move_of(edit))
+                .collect::<Vec<_>>()
+        };
+        let sources: Vec<_> = pad_moves(3).iter().map(|&(from, _)| from).collect();
+        assert!(sources.contains(&Allocation::reg(r(0))));
+        assert!(sources.contains(&Allocation::reg(r(1))));
+        assert_eq!(
+            pad_moves(4),
+            vec![(Allocation::reg(r(0)), Allocation::reg(r(1)))]
+        );
+
+        // A landing pad cannot also receive edge defs.
+        f.set_edge_defs(Block::new(0), 1, &[(vreg(4), r(2))]);
+        assert!(matches!(
+            crate::run(&f, &int_env(3), &RegallocOptions::default()),
+            Err(RegAllocError::EdgeDef(_))
+        ));
+    }
+
+    #[cfg(feature = "phase-timings")]
+    #[test]
+    fn test_phase_timings() {
+        // v0 is spilled around v1, so that every phase has work.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+
+        let out = run_and_check_with(&f, &int_env(1), &RegallocOptions::default());
+        let stats = out.stats;
+        let total = stats.liveness_time
+            + stats.bundles_time
+            + stats.allocation_time
+            + stats.move_insertion_time
+            + stats.move_resolution_time;
+        assert!(total > core::time::Duration::ZERO);
+    }
+
+    #[cfg(feature = "debug-bundles")]
+    #[test]
+    fn test_vreg_bundles() {
+        // v0 is spilled around v1 with only one register; v2 is
+        // merged with v1 through a blockparam.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.branch(&[(Block::new(1), &[vreg(1)])]);
+        f.block(&[vreg(2)]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::any_use(vreg(2))]);
+
+        let out = run_and_check_with(&f, &int_env(1), &RegallocOptions::default());
+        let v0 = out.vreg_bundles(vreg(0));
+        assert!(v0.windows(2).all(|w| w[0].to <= w[1].from));
+        assert!(v0
+            .iter()
+            .any(|b| b.alloc.is_stack() && b.spillset.is_some()));
+        assert!(v0.iter().any(|b| b.alloc.is_reg() && b.spillset.is_none()));
+        let v1_last = out.vreg_bundles(vreg(1)).last().unwrap();
+        let v2_first = out.vreg_bundles(vreg(2)).first().unwrap();
+        assert_eq!(v1_last.bundle, v2_first.bundle);
+        assert_eq!(v1_last.alloc, v2_first.alloc);
+    }
+
+    #[cfg(feature = "debug-bundles")]
+    #[test]
+    fn test_vreg_bundles_numbering_gap() {
+        // v1 and v2 are never mentioned, so their class is never
+        // observed; they just have no bundles.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(3))]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(3))]);
+        assert_eq!(f.num_vregs(), 4);
+
+        let out = run_and_check(&f, &int_env(2));
+        assert_eq!(out.vreg_bundles.len(), 4);
+        assert!(out.vreg_bundles(vreg(1)).is_empty());
+        assert!(out.vreg_bundles(vreg(2)).is_empty());
+        assert!(!out.vreg_bundles(vreg(0)).is_empty());
+        assert!(!out.vreg_bundles(vreg(3)).is_empty());
+    }
+
+    #[cfg(feature = "decision-log")]
+    #[test]
+    fn test_decision_log() {
+        // With one register, v0 is split around v1.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.ret(&[]);
+
+        // v0 first gets the register, is evicted for v1 and split;
+        // its def and its use each get the register back, and the
+        // part in between stays in the spillslot.
+        use crate::Decision::*;
+        let p0 = PReg::new(0, RegClass::Int);
+        let out = run_and_check(&f, &int_env(1));
+        assert_eq!(
+            out.decision_log,
+            [
+                Assign {
+                    bundle: 0,
+                    preg: p0
+                },
+                Evict { bundle: 0 },
+                Assign {
+                    bundle: 1,
+                    preg: p0
+                },
+                Split {
+                    bundle: 0,
+                    at: ProgPoint::before(Inst::new(2))
+                },
+                Assign {
+                    bundle: 0,
+                    preg: p0
+                },
+                Assign {
+                    bundle: 2,
+                    preg: p0
+                },
+                Spill { bundle: 3 },
+            ]
+        );
+        assert_eq!(
+            run_and_check(&f, &int_env(1)).decision_log,
+            out.decision_log
+        );
+    }
 }
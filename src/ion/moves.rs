@@ -14,23 +14,79 @@
 
 use super::{
     Env, InsertMovePrio, InsertedMove, InsertedMoves, LiveRangeFlag, LiveRangeIndex,
-    RedundantMoveEliminator, VRegIndex,
+    RedundantMoveEliminator, RedundantStoreEliminator, VRegIndex,
 };
 use crate::ion::data_structures::{
     u64_key, BlockparamIn, BlockparamOut, CodeRange, Edits, FixedRegFixupLevel, LiveRangeKey,
-    LiveRangeListEntry,
+    LiveRangeListEntry, PosWithPrio, SpillTier,
 };
 use crate::ion::reg_traversal::RegTraversalIter;
+use crate::ion::{pair_halves, spillslot_size};
 use crate::moves::{MoveAndScratchResolver, ParallelMoves};
 use crate::{
-    Allocation, Block, Edit, Function, FxHashMap, Inst, InstPosition, OperandConstraint,
-    OperandKind, OperandPos, PReg, ProgPoint, RegClass, SpillSlot,
+    all_clobbers, block_edge_defs, Allocation, Block, Edit, Function, FxHashMap, FxHashSet, Inst,
+    InstPosition, MoveCause, OperandConstraint, OperandKind, OperandPos, PReg, PRegSet, ProgPoint,
+    RegClass, RelaxationKind, SpillEvent, SpillEventKind, SpillSlot, VReg,
 };
 use alloc::vec::Vec;
 use alloc::{format, vec};
 use hashbrown::hash_map::Entry;
 use smallvec::{smallvec, SmallVec};
 
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct InterBlockDest {
+    to: Block,
+    from: Block,
+    alloc: Allocation,
+}
+
+impl InterBlockDest {
+    fn key(&self) -> u64 {
+        u64_key(self.from.raw_u32(), self.to.raw_u32())
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub(crate) struct BlockparamSourceKey {
+    bits: u64,
+}
+
+impl BlockparamSourceKey {
+    fn new(from_block: Block, to_vreg: VRegIndex) -> Self {
+        BlockparamSourceKey {
+            bits: u64_key(from_block.raw_u32(), to_vreg.raw_u32()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct BlockparamDest {
+    from_block: Block,
+    to_block: Block,
+    to_vreg: VRegIndex,
+    alloc: Allocation,
+}
+
+impl BlockparamDest {
+    fn key(&self) -> u64 {
+        u64_key(self.to_block.raw_u32(), self.from_block.raw_u32())
+    }
+
+    fn source(&self) -> BlockparamSourceKey {
+        BlockparamSourceKey::new(self.from_block, self.to_vreg)
+    }
+}
+
+/// The half-moves that `apply_allocations_and_insert_moves` gathers
+/// before pairing them up, in buffers kept in a `Ctx` between runs.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HalfMoves {
+    inter_block_dests: Vec<InterBlockDest>,
+    block_param_sources: FxHashMap<BlockparamSourceKey, Allocation>,
+    block_param_dests: Vec<BlockparamDest>,
+    reuse_input_insts: Vec<Inst>,
+}
+
 impl<'a, F: Function> Env<'a, F> {
     pub fn is_start_of_block(&self, pos: ProgPoint) -> bool {
         let block = self.cfginfo.insn_block[pos.inst().index()];
@@ -51,6 +107,28 @@ impl<'a, F: Function> Env<'a, F> {
         inst_allocs[slot] = alloc;
     }
 
+    /// Move the allocations of instruction temps out of `allocs`,
+    /// leaving exactly one slot per operand there.
+    pub fn take_temp_allocs(&mut self) -> Vec<(Inst, Allocation)> {
+        if self.temp_insts.is_empty() {
+            return vec![];
+        }
+        let mut temp_allocs = vec![];
+        let mut allocs = Vec::with_capacity(self.allocs.len());
+        for inst in 0..self.func.num_insts() {
+            let inst = Inst::new(inst);
+            let start = self.inst_alloc_offsets[inst.index()] as usize;
+            let num_operands = self.func.inst_operands(inst).len();
+            let num_temps = self.func.inst_temps(inst).len();
+            self.inst_alloc_offsets[inst.index()] = allocs.len() as u32;
+            allocs.extend_from_slice(&self.allocs[start..start + num_operands]);
+            let temps = &self.allocs[start + num_operands..start + num_operands + num_temps];
+            temp_allocs.extend(temps.iter().map(|&alloc| (inst, alloc)));
+        }
+        self.allocs = allocs;
+        temp_allocs
+    }
+
     pub fn get_alloc_for_range(&self, range: LiveRangeIndex) -> Allocation {
         trace!("get_alloc_for_range: {:?}", range);
         let bundle = self.ranges[range].bundle;
@@ -69,21 +147,78 @@ impl<'a, F: Function> Env<'a, F> {
         }
     }
 
-    pub fn apply_allocations_and_insert_moves(&mut self) -> InsertedMoves {
-        trace!("apply_allocations_and_insert_moves");
-        trace!("blockparam_ins: {:?}", self.blockparam_ins);
-        trace!("blockparam_outs: {:?}", self.blockparam_outs);
-
-        let mut inserted_moves = InsertedMoves::default();
+    /// Describe each live range of `vreg` together with the bundle,
+    /// spillset and allocation that `get_alloc_for_range` resolves it
+    /// through, in program order.
+    #[cfg(feature = "debug-bundles")]
+    pub fn debug_vreg_bundles(&self, vreg: VRegIndex) -> Vec<crate::VRegBundle> {
+        self.vregs[vreg]
+            .ranges
+            .iter()
+            .map(|entry| {
+                let bundle = self.ranges[entry.index].bundle;
+                let spillset = if self.bundles[bundle].allocation.is_none() {
+                    Some(self.bundles[bundle].spillset.index())
+                } else {
+                    None
+                };
+                crate::VRegBundle {
+                    live_range: entry.index.index(),
+                    from: entry.range.from,
+                    to: entry.range.to,
+                    bundle: bundle.index(),
+                    spillset,
+                    alloc: self.get_alloc_for_range(entry.index),
+                }
+            })
+            .collect()
+    }
 
-        // Now that all splits are done, we can pay the cost once to
-        // sort VReg range lists and update with the final ranges.
+    /// Now that all splits are done, we can pay the cost once to sort
+    /// VReg range lists and update them with the final ranges.
+    pub fn sort_vreg_ranges(&mut self) {
         for vreg in &mut self.vregs {
             for entry in &mut vreg.ranges {
                 entry.range = self.ranges[entry.index].range;
             }
             vreg.ranges.sort_unstable_by_key(|entry| entry.range.from);
         }
+    }
+
+    /// Apply the bundle and spillslot allocations to the operands
+    /// and insert the moves between them. This may be re-run after
+    /// editing those allocations by hand to regenerate the
+    /// instruction allocations and edits from scratch;
+    /// `RegallocOptions::spill_observer` then sees the reloads again.
+    pub(crate) fn resolve_moves(&mut self) -> Edits {
+        let moves = timed!(self.stats.move_insertion_time, {
+            self.plan_partial_spills();
+            self.apply_allocations_and_insert_moves()
+        });
+        timed!(
+            self.stats.move_resolution_time,
+            self.resolve_inserted_moves(moves)
+        )
+    }
+
+    pub fn apply_allocations_and_insert_moves(&mut self) -> InsertedMoves {
+        trace!("apply_allocations_and_insert_moves");
+        trace!("blockparam_ins: {:?}", self.blockparam_ins);
+        trace!("blockparam_outs: {:?}", self.blockparam_outs);
+
+        let mut inserted_moves = InsertedMoves {
+            moves: core::mem::take(&mut self.inserted_move_buf),
+            prio_ranks: self.move_prio_ranks,
+            cross_class_spills: self.cross_class_spills,
+        };
+
+        // Resolution may be re-run (see `resolve_moves`), so drop
+        // the results of any previous run.
+        self.debug_locations.clear();
+        self.reuse_copies.clear();
+        self.stats.halfmoves_count = 0;
+        self.stats.cyclic_move_groups = 0;
+        self.stats.scratch_uses = 0;
 
         /// Buffered information about the previous liverange that was processed.
         struct PrevBuffer {
@@ -171,6 +306,13 @@ impl<'a, F: Function> Env<'a, F> {
             from: Block,
             to: Block,
         ) -> (ProgPoint, InsertMovePrio) {
+            // Function arguments come from no block at all.
+            if !from.is_valid() {
+                return (
+                    ProgPoint::before(env.func.block_insns(to).first()),
+                    InsertMovePrio::InEdgeMoves,
+                );
+            }
             let from_last_insn = env.func.block_insns(from).last();
             let to_first_insn = env.func.block_insns(to).first();
             let from_is_ret = env.func.is_ret(from_last_insn);
@@ -207,69 +349,44 @@ impl<'a, F: Function> Env<'a, F> {
                     InsertMovePrio::InEdgeMoves,
                 )
             } else {
-                panic!(
+                // `CFGInfo::new` rejects critical edges up front.
+                unreachable!(
                     "Critical edge: can't insert moves between blocks {:?} and {:?}",
                     from, to
                 );
             }
         }
 
-        #[derive(PartialEq)]
-        struct InterBlockDest {
-            to: Block,
-            from: Block,
-            alloc: Allocation,
-        }
-
-        impl InterBlockDest {
-            fn key(&self) -> u64 {
-                u64_key(self.from.raw_u32(), self.to.raw_u32())
-            }
-        }
+        let mut half_moves = core::mem::take(&mut self.half_moves);
+        let HalfMoves {
+            inter_block_dests,
+            block_param_sources,
+            block_param_dests,
+            reuse_input_insts,
+        } = &mut half_moves;
 
         let mut inter_block_sources: FxHashMap<Block, Allocation> = FxHashMap::default();
-        let mut inter_block_dests = Vec::with_capacity(self.func.num_blocks());
-
-        #[derive(Hash, Eq, PartialEq)]
-        struct BlockparamSourceKey {
-            bits: u64,
-        }
-
-        impl BlockparamSourceKey {
-            fn new(from_block: Block, to_vreg: VRegIndex) -> Self {
-                BlockparamSourceKey {
-                    bits: u64_key(from_block.raw_u32(), to_vreg.raw_u32()),
-                }
-            }
-        }
-
-        struct BlockparamDest {
-            from_block: Block,
-            to_block: Block,
-            to_vreg: VRegIndex,
-            alloc: Allocation,
-        }
-
-        impl BlockparamDest {
-            fn key(&self) -> u64 {
-                u64_key(self.to_block.raw_u32(), self.from_block.raw_u32())
-            }
-
-            fn source(&self) -> BlockparamSourceKey {
-                BlockparamSourceKey::new(self.from_block, self.to_vreg)
-            }
-        }
-
-        let mut block_param_sources =
-            FxHashMap::<BlockparamSourceKey, Allocation>::with_capacity_and_hasher(
-                3 * self.func.num_insts(),
-                Default::default(),
-            );
-        let mut block_param_dests = Vec::with_capacity(3 * self.func.num_insts());
+        inter_block_dests.clear();
+        inter_block_dests.reserve(self.func.num_blocks());
+
+        // A single block without arguments has no edges and no
+        // blockparams, so the scans for half-moves below find nothing
+        // and are skipped altogether.
+        let single_block = self.single_block;
+        let halfmove_capacity = if single_block {
+            0
+        } else {
+            3 * self.func.num_insts()
+        };
+        block_param_sources.clear();
+        block_param_sources.reserve(halfmove_capacity);
+        block_param_dests.clear();
+        block_param_dests.reserve(halfmove_capacity);
 
         let debug_labels = self.func.debug_value_labels();
 
-        let mut reuse_input_insts = Vec::with_capacity(self.func.num_insts() / 2);
+        reuse_input_insts.clear();
+        reuse_input_insts.reserve(self.func.num_insts() / 2);
 
         let mut blockparam_in_idx = 0;
         let mut blockparam_out_idx = 0;
@@ -376,7 +493,11 @@ impl<'a, F: Function> Env<'a, F> {
                 // already in this range (hence guaranteed to have the
                 // same allocation) and if the vreg is live, add a
                 // Source half-move.
-                let mut block = self.cfginfo.insn_block[range.from.inst().index()];
+                let mut block = if single_block {
+                    Block::invalid()
+                } else {
+                    self.cfginfo.insn_block[range.from.inst().index()]
+                };
                 while block.is_valid() && block.index() < self.func.num_blocks() {
                     if range.to < self.cfginfo.block_exit[block.index()].next() {
                         break;
@@ -464,7 +585,9 @@ impl<'a, F: Function> Env<'a, F> {
                 // start of the block. For each, for each predecessor,
                 // add a Dest half-move.
                 let mut block = self.cfginfo.insn_block[range.from.inst().index()];
-                if self.cfginfo.block_entry[block.index()] < range.from {
+                if single_block {
+                    block = Block::invalid();
+                } else if self.cfginfo.block_entry[block.index()] < range.from {
                     block = block.next();
                 }
                 while block.is_valid() && block.index() < self.func.num_blocks() {
@@ -555,7 +678,9 @@ impl<'a, F: Function> Env<'a, F> {
                     block = block.next();
                 }
 
-                // Scan over def/uses and apply allocations.
+                // Scan over def/uses and apply allocations. Immediate
+                // operands (see `Function::operand_is_immediate`) have
+                // no use here, so they keep `Allocation::none()`.
                 for use_idx in 0..self.ranges[entry.index].uses.len() {
                     let usedata = self.ranges[entry.index].uses[use_idx];
                     trace!("applying to use: {:?}", usedata);
@@ -564,15 +689,24 @@ impl<'a, F: Function> Env<'a, F> {
                     let slot = usedata.slot;
                     let operand = usedata.operand;
                     self.set_alloc(inst, slot as usize, alloc);
-                    if let OperandConstraint::Reuse(_) = operand.constraint() {
+                    if let OperandConstraint::Reuse(_) | OperandConstraint::ReuseStack(_) =
+                        operand.constraint()
+                    {
                         reuse_input_insts.push(inst);
                     }
                 }
 
                 // Scan debug-labels on this vreg that overlap with
                 // this range, producing a debug-info output record
-                // giving the allocation location for each label.
-                if !debug_labels.is_empty() {
+                // giving the allocation location for each label. A
+                // never-spilled or sunk vreg has no location while it
+                // is only rematerializable.
+                let remat_only = alloc.is_stack()
+                    && self.spillsets[self.bundles[self.ranges[entry.index].bundle].spillset]
+                        .is_remat();
+                // Nor does a pair while it is only partly spilled.
+                let partial = self.partial_spill_ranges.contains(&entry.index);
+                if !debug_labels.is_empty() && !remat_only && !partial {
                     // Do a binary search to find the start of any
                     // labels for this vreg. Recall that we require
                     // debug-label requests to be sorted by vreg as a
@@ -641,12 +775,23 @@ impl<'a, F: Function> Env<'a, F> {
             blockparam_in_idx = prev.blockparam_ins_idx();
         }
 
+        // The source of an edge def is the register the branch leaves
+        // it in; that of a function argument is its ABI register.
+        for block in 0..self.func.num_blocks() {
+            if let Some((pred, defs)) = block_edge_defs(self.func, Block::new(block)) {
+                for &(vreg, preg) in defs {
+                    let key = BlockparamSourceKey::new(pred, VRegIndex::new(vreg.vreg()));
+                    block_param_sources.insert(key, Allocation::reg(preg));
+                }
+            }
+        }
+
         if !block_param_dests.is_empty() {
             self.stats.halfmoves_count += block_param_sources.len();
             self.stats.halfmoves_count += block_param_dests.len();
 
             trace!("processing block-param moves");
-            for dest in block_param_dests {
+            for dest in block_param_dests.drain(..) {
                 let src = dest.source();
                 let src_alloc = block_param_sources.get(&src).unwrap();
                 let (pos, prio) = choose_move_location(self, dest.from_block, dest.to_block);
@@ -655,7 +800,8 @@ impl<'a, F: Function> Env<'a, F> {
         }
 
         // Handle multi-fixed-reg constraints by copying.
-        for fixup in core::mem::replace(&mut self.multi_fixed_reg_fixups, vec![]) {
+        for i in 0..self.multi_fixed_reg_fixups.len() {
+            let fixup = self.multi_fixed_reg_fixups[i].clone();
             let from_alloc = self.get_alloc(fixup.pos.inst(), fixup.from_slot as usize);
             let to_alloc = Allocation::reg(PReg::from_index(fixup.to_preg.index()));
             trace!(
@@ -677,6 +823,28 @@ impl<'a, F: Function> Env<'a, F> {
             );
         }
 
+        // Copy repeated inputs into the temporaries' registers for
+        // instructions that require distinct registers.
+        for i in 0..self.distinct_reg_copies.len() {
+            let copy = self.distinct_reg_copies[i].clone();
+            let from_alloc = self.get_alloc(copy.pos.inst(), copy.from_slot as usize);
+            let to_alloc = self.get_alloc(copy.pos.inst(), copy.to_slot as usize);
+            trace!(
+                "distinct-reg copy at {:?} from {} to {} for v{}",
+                copy.pos,
+                from_alloc,
+                to_alloc,
+                copy.vreg.index(),
+            );
+            inserted_moves.push(
+                copy.pos,
+                InsertMovePrio::MultiFixedRegSecondary,
+                from_alloc,
+                to_alloc,
+                self.vreg(copy.vreg),
+            );
+        }
+
         // Handle outputs that reuse inputs: copy beforehand, then set
         // input's alloc to output's.
         //
@@ -721,11 +889,13 @@ impl<'a, F: Function> Env<'a, F> {
         // move instruction.
         //
         // [0] https://searchfox.org/mozilla-central/rev/3a798ef9252896fb389679f06dd3203169565af0/js/src/jit/shared/Lowering-shared-inl.h#108-110
-        for inst in reuse_input_insts {
+        for inst in reuse_input_insts.drain(..) {
             let mut input_reused: SmallVec<[usize; 4]> = smallvec![];
             for output_idx in 0..self.func.inst_operands(inst).len() {
                 let operand = self.func.inst_operands(inst)[output_idx];
-                if let OperandConstraint::Reuse(input_idx) = operand.constraint() {
+                if let OperandConstraint::Reuse(input_idx)
+                | OperandConstraint::ReuseStack(input_idx) = operand.constraint()
+                {
                     debug_assert!(!input_reused.contains(&input_idx));
                     debug_assert_eq!(operand.pos(), OperandPos::Late);
                     input_reused.push(input_idx);
@@ -756,16 +926,504 @@ impl<'a, F: Function> Env<'a, F> {
                             input_operand.vreg(),
                         );
                         self.set_alloc(inst, input_idx, output_alloc);
+                        self.reuse_copies.push((inst, input_idx, output_idx));
                     }
                 }
             }
         }
+        self.half_moves = half_moves;
 
         // Sort the debug-locations vector; we provide this
         // invariant to the client.
         self.debug_locations.sort_unstable();
+        self.reuse_copies.sort_unstable();
+
+        if self.spill_once && self.func.is_ssa() {
+            self.store_spills_once(&mut inserted_moves);
+        }
+
+        inserted_moves
+    }
+
+    /// Rewrite the spill moves of every spilled instruction-defined
+    /// vreg so that the value is stored to its spillslot exactly once,
+    /// right after the def, and never again. This is sound because
+    /// the def dominates all uses, and no other vreg can occupy the
+    /// slot while this one is live: vregs sharing a spillset never
+    /// overlap, and spillsets sharing a slot have disjoint ranges.
+    /// References are left alone: a safepoint may move the value in
+    /// their register, leaving the stored copy stale.
+    fn store_spills_once(&self, inserted_moves: &mut InsertedMoves) {
+        let mut slots = FxHashMap::default();
+        let mut stores = Vec::new();
+        for vreg in 0..self.vregs.len() {
+            let vreg = VRegIndex::new(vreg);
+            let ranges = &self.vregs[vreg].ranges;
+            if !self.is_vreg_used(vreg)
+                || ranges.is_empty()
+                || self.vregs[vreg].blockparam.is_valid()
+                || self.func.is_reference_vreg(self.vreg(vreg))
+            {
+                continue;
+            }
+            let spillset = self.bundles[self.ranges[ranges[0].index].bundle].spillset;
+            let slot = self.spillsets[spillset].slot;
+            if !slot.is_valid() {
+                continue;
+            }
+            let slot = self.spillslots[slot.index()].alloc;
+            if !ranges
+                .iter()
+                .any(|entry| self.get_alloc_for_range(entry.index) == slot)
+            {
+                continue;
+            }
+            let def = self.ranges[ranges[0].index]
+                .uses
+                .iter()
+                .find(|u| u.operand.kind() == OperandKind::Def)
+                .map(|u| u.pos.inst());
+            let def = match def {
+                Some(def) if !self.func.is_branch(def) => def,
+                _ => continue,
+            };
+            trace!("spill once: v{} to {} after {:?}", vreg.index(), slot, def);
+            slots.insert(self.vreg(vreg), slot);
+            let def_alloc = self.get_alloc_for_range(ranges[0].index);
+            if def_alloc != slot {
+                stores.push((ProgPoint::after(def), def_alloc, slot, self.vreg(vreg)));
+            }
+        }
 
         inserted_moves
+            .moves
+            .retain(|m| slots.get(&m.to_vreg) != Some(&m.to_alloc));
+        for (pos, from, to, vreg) in stores {
+            inserted_moves.push(pos, InsertMovePrio::Regular, from, to, vreg);
+        }
+    }
+
+    /// Add the class-view switches around operands that access their
+    /// location as another class (see `Function::operand_class_view`)
+    /// to `edits`, which must not hold any moves yet: the switch into
+    /// a use's view comes after all moves before the instruction, and
+    /// the switch back from a def's view before all moves after it.
+    fn add_reinterprets(&self, edits: &mut Edits) {
+        for inst in 0..self.func.num_insts() {
+            let inst = Inst::new(inst);
+            for (slot, op) in self.func.inst_operands(inst).iter().enumerate() {
+                let view = match self.func.operand_class_view(inst, slot) {
+                    Some(view) if view != op.class() => view,
+                    _ => continue,
+                };
+                if self.func.operand_is_immediate(inst, slot) {
+                    continue;
+                }
+                let alloc = self.get_alloc(inst, slot);
+                let (pos_prio, from_class, to_class) = match op.kind() {
+                    OperandKind::Use => (
+                        PosWithPrio {
+                            pos: ProgPoint::before(inst),
+                            prio: u32::MAX,
+                        },
+                        op.class(),
+                        view,
+                    ),
+                    OperandKind::Def => (
+                        PosWithPrio {
+                            pos: ProgPoint::after(inst),
+                            prio: 0,
+                        },
+                        view,
+                        op.class(),
+                    ),
+                };
+                edits.add_reinterpret(pos_prio, alloc, from_class, to_class);
+            }
+        }
+    }
+
+    /// Invert the final range-to-allocation mapping: for every
+    /// register, the half-open program-point ranges during which it
+    /// holds each vreg. Abutting ranges of the same vreg in the same
+    /// register are merged. Sorted by register, then program point.
+    pub fn compute_preg_timelines(&self) -> Vec<(PReg, ProgPoint, ProgPoint, VReg)> {
+        let mut timelines = Vec::new();
+        for vreg in 0..self.vregs.len() {
+            let vreg = VRegIndex::new(vreg);
+            for entry in &self.vregs[vreg].ranges {
+                if let Some(preg) = self.get_alloc_for_range(entry.index).as_reg() {
+                    timelines.push((preg, entry.range.from, entry.range.to, self.vreg(vreg)));
+                }
+            }
+        }
+        timelines.sort_unstable_by_key(|&(preg, from, _, _)| (preg, from));
+        timelines.dedup_by(|next, prev| {
+            if prev.0 == next.0 && prev.3 == next.3 && prev.2 == next.1 {
+                prev.2 = next.2;
+                true
+            } else {
+                false
+            }
+        });
+        timelines
+    }
+
+    /// Compute the register pressure curve from the final live
+    /// ranges: the number of live vregs of each class (a register
+    /// pair counting twice), as a step function of `(point, counts)`
+    /// entries sorted by program point, each giving the counts from
+    /// its point up to the next entry's.
+    pub fn compute_pressure(&self) -> Vec<(ProgPoint, [usize; 3])> {
+        let mut events = Vec::new();
+        let mut ranges = Vec::new();
+        for vreg in 0..self.vregs.len() {
+            let vreg = VRegIndex::new(vreg);
+            let class = self.vreg(vreg).class() as usize;
+            let weight = if self.is_pair_vreg(vreg) { 2 } else { 1 };
+
+            // Once a vreg is split, its spill bundle's ranges overlap
+            // the ranges of its minimal bundles; merge them so the
+            // vreg counts once at every point.
+            ranges.clear();
+            ranges.extend(self.vregs[vreg].ranges.iter().map(|entry| entry.range));
+            ranges.sort_unstable_by_key(|range| range.from);
+            let mut iter = ranges.iter();
+            let first = match iter.next() {
+                Some(&range) => range,
+                None => continue,
+            };
+            let (mut from, mut to) = (first.from, first.to);
+            for range in iter {
+                if range.from > to {
+                    events.push((from, class, weight as isize));
+                    events.push((to, class, -(weight as isize)));
+                    from = range.from;
+                }
+                to = to.max(range.to);
+            }
+            events.push((from, class, weight as isize));
+            events.push((to, class, -(weight as isize)));
+        }
+        events.sort_unstable_by_key(|&(pos, _, _)| pos);
+
+        let mut pressure: Vec<(ProgPoint, [usize; 3])> = Vec::new();
+        let mut counts = [0isize; 3];
+        for (i, &(pos, class, delta)) in events.iter().enumerate() {
+            counts[class] += delta;
+            if events.get(i + 1).map(|&(next, _, _)| next) == Some(pos) {
+                continue;
+            }
+            let step = counts.map(|count| count as usize);
+            match pressure.last() {
+                Some(&(_, last)) if last == step => {}
+                _ => pressure.push((pos, step)),
+            }
+        }
+        pressure
+    }
+
+    /// Compute the free allocatable registers from the final
+    /// occupancy of every register, which covers both allocated live
+    /// ranges and the reservations for clobbers and fixed-register
+    /// operands, as a step function of `(point, free)` entries sorted
+    /// by program point.
+    pub fn compute_free_regs(&self) -> Vec<(ProgPoint, PRegSet)> {
+        let mut allocatable = PRegSet::empty();
+        for class in 0..3 {
+            let regs = self.env.preferred_regs_by_class[class]
+                .iter()
+                .chain(&self.env.non_preferred_regs_by_class[class]);
+            for &preg in regs {
+                allocatable.add(preg);
+            }
+            if let Some(scratch) = self.env.scratch_by_class[class] {
+                allocatable.remove(scratch);
+            }
+        }
+
+        // Ranges in one register never overlap, so each event either
+        // frees or occupies it; frees sort first at a given point.
+        let mut events = Vec::new();
+        for preg in allocatable {
+            for key in self.pregs[preg.index()].allocations.btree.keys() {
+                events.push((key.from, true, preg));
+                events.push((key.to, false, preg));
+            }
+        }
+        events.sort_unstable_by_key(|&(pos, occupy, _)| (pos, occupy));
+
+        let mut free_regs = vec![(ProgPoint::from_index(0), allocatable)];
+        let mut free = allocatable;
+        for (i, &(pos, occupy, preg)) in events.iter().enumerate() {
+            if occupy {
+                free.remove(preg);
+            } else {
+                free.add(preg);
+            }
+            if events.get(i + 1).map(|&(next, _, _)| next) == Some(pos) {
+                continue;
+            }
+            let pos = ProgPoint::from_index(pos);
+            match free_regs.last_mut() {
+                Some((_, last)) if *last == free => {}
+                Some((point, last)) if *point == pos => *last = free,
+                _ => free_regs.push((pos, free)),
+            }
+        }
+        free_regs
+    }
+
+    /// Find the operands whose soft constraints the final allocation
+    /// does not meet: an `Any` operand on the stack, or else one that
+    /// is not in its preferred register. A preference for a register
+    /// that is not allocatable was ignored, so it is not reported.
+    pub fn compute_relaxed_constraints(&self) -> Vec<(Inst, usize, RelaxationKind)> {
+        let mut relaxed = Vec::new();
+        for inst in 0..self.func.num_insts() {
+            let inst = Inst::new(inst);
+            for (slot, op) in self.func.inst_operands(inst).iter().enumerate() {
+                let alloc = self.get_alloc(inst, slot);
+                let class = op.class() as usize;
+                let kind = if op.constraint() == OperandConstraint::Any && alloc.is_stack() {
+                    RelaxationKind::Spilled
+                } else if let Some(preg) = self.func.operand_preferred_reg(inst, slot) {
+                    let allocatable = self.env.preferred_regs_by_class[class].contains(&preg)
+                        || self.env.non_preferred_regs_by_class[class].contains(&preg);
+                    if !allocatable || alloc == Allocation::reg(preg) {
+                        continue;
+                    }
+                    RelaxationKind::PreferredReg
+                } else {
+                    continue;
+                };
+                relaxed.push((inst, slot, kind));
+            }
+        }
+        relaxed
+    }
+
+    /// Compute the stackmap of each safepoint from the final
+    /// allocation of the reference vregs live across it.
+    pub fn compute_stackmaps(&self) -> Vec<(Inst, Vec<Allocation>)> {
+        let refs: Vec<VRegIndex> = (0..self.vregs.len())
+            .map(VRegIndex::new)
+            .filter(|&vreg| {
+                !self.vregs[vreg].ranges.is_empty() && self.func.is_reference_vreg(self.vreg(vreg))
+            })
+            .collect();
+        let mut stackmaps = vec![];
+        for inst in 0..self.func.num_insts() {
+            let inst = Inst::new(inst);
+            if self.func.is_branch(inst) || !self.func.requires_stackmap(inst) {
+                continue;
+            }
+            let block = self.cfginfo.insn_block[inst.index()];
+            let is_last = self.func.block_insns(block).last() == inst;
+            let mut defs: SmallVec<[usize; 4]> = self
+                .func
+                .inst_operands(inst)
+                .iter()
+                .filter(|op| op.kind() == OperandKind::Def)
+                .map(|op| op.vreg().vreg())
+                .collect();
+            defs.sort_unstable();
+            let mut allocs = vec![];
+            for &vreg in &refs {
+                if defs.binary_search(&vreg.index()).is_ok() {
+                    continue;
+                }
+                // A reload may overlap the spilled range it comes
+                // from, leaving the value in both places.
+                let ranges = &self.vregs[vreg].ranges;
+                let live_at = |pos: ProgPoint| {
+                    ranges
+                        .iter()
+                        .filter(move |entry| entry.range.contains_point(pos))
+                        .map(|entry| entry.index)
+                };
+                let live_after = if is_last {
+                    self.liveouts[block.index()].get(vreg.index())
+                } else {
+                    live_at(ProgPoint::before(inst.next())).next().is_some()
+                };
+                if !live_after {
+                    continue;
+                }
+                for range in live_at(ProgPoint::before(inst)) {
+                    let alloc = self.get_alloc_for_range(range);
+                    allocs.push(alloc);
+                    if self.is_pair_vreg(vreg) {
+                        let class = self.vreg(vreg).class();
+                        allocs.push(pair_halves(self.func, self.env, alloc, class).1);
+                    }
+                }
+            }
+            allocs.sort_unstable();
+            allocs.dedup();
+            stackmaps.push((inst, allocs));
+        }
+        stackmaps
+    }
+
+    /// The allocation of `vreg` at `pos`, if it is live there.
+    fn vreg_alloc_at(&self, vreg: VRegIndex, pos: ProgPoint) -> Option<Allocation> {
+        self.vregs[vreg]
+            .ranges
+            .iter()
+            .find(|entry| entry.range.contains_point(pos))
+            .map(|entry| self.get_alloc_for_range(entry.index))
+    }
+
+    /// Count the coalesced moves whose source and destination ended
+    /// up in the same allocation, so that no copy is needed.
+    fn count_coalesced_moves_eliminated(&self) -> usize {
+        self.coalesced_moves
+            .iter()
+            .filter(|&&(src, read, dst, written)| {
+                let from = self.vreg_alloc_at(src, read);
+                from.is_some() && from == self.vreg_alloc_at(dst, written)
+            })
+            .count()
+    }
+
+    /// Count the edge moves that are repeated identically at the end
+    /// of every predecessor of a join block, once per predecessor
+    /// beyond the first. They are still placed in each predecessor;
+    /// the count tells a frontend whether tail-duplicating the join
+    /// would pay off.
+    fn count_duplicated_edge_moves(&self, inserted_moves: &InsertedMoves) -> usize {
+        let mut out_moves: FxHashMap<Block, Vec<(Allocation, Allocation)>> = FxHashMap::default();
+        for m in &inserted_moves.moves {
+            if m.cause == MoveCause::EdgeOut {
+                let block = self.cfginfo.insn_block[m.pos_prio.pos.inst().index()];
+                out_moves
+                    .entry(block)
+                    .or_default()
+                    .push((m.from_alloc, m.to_alloc));
+            }
+        }
+        for moves in out_moves.values_mut() {
+            moves.sort_unstable();
+        }
+
+        let mut duplicated = 0;
+        for block in 0..self.func.num_blocks() {
+            let block = Block::new(block);
+            let preds = self.func.block_preds(block);
+            if preds.len() < 2 {
+                continue;
+            }
+            let first = match out_moves.get(&preds[0]) {
+                Some(moves) => moves,
+                None => continue,
+            };
+            if preds[1..]
+                .iter()
+                .all(|pred| out_moves.get(pred) == Some(first))
+            {
+                trace!(
+                    "identical edge moves {:?} into {:?} from all of {:?}",
+                    first,
+                    block,
+                    preds
+                );
+                duplicated += first.len() * (preds.len() - 1);
+            }
+        }
+        duplicated
+    }
+
+    /// The spillslots that some vreg other than a reference is moved
+    /// into more than once; see `RedundantStoreEliminator`.
+    fn repeated_store_slots(&self, inserted_moves: &InsertedMoves) -> FxHashSet<Allocation> {
+        let mut stores = FxHashSet::default();
+        let mut repeated = FxHashSet::default();
+        for m in &inserted_moves.moves {
+            if m.to_alloc.is_stack()
+                && !self.func.is_reference_vreg(m.to_vreg)
+                && !stores.insert((m.to_alloc, m.to_vreg))
+            {
+                repeated.insert(m.to_alloc);
+            }
+        }
+        repeated
+    }
+
+    /// Find a resolved move that clobbers a fixed-register input: once
+    /// the moves at the point before an instruction have run, each
+    /// register an input of it is fixed to must either be untouched or
+    /// hold that input's vreg. `moves` are the resolved moves in the
+    /// order they are emitted, each with the vreg it moves, or `None`
+    /// for the resolver's saves and restores of a borrowed register.
+    /// Returns the point and source and destination of the last move
+    /// into such a register, with the input's vreg.
+    #[cfg(debug_assertions)]
+    fn clobbered_fixed_input(
+        &self,
+        moves: &[(ProgPoint, Allocation, Allocation, Option<VReg>)],
+    ) -> Option<(ProgPoint, Allocation, Allocation, VReg)> {
+        // What a location holds after the moves so far: its own value
+        // from before the point, or a vreg.
+        #[derive(Clone, Copy)]
+        enum Content {
+            Initial(Allocation),
+            Vreg(VReg),
+        }
+        let mut contents: FxHashMap<Allocation, (Content, usize)> = FxHashMap::default();
+        let mut i = 0;
+        while i < moves.len() {
+            let start = i;
+            let pos = moves[i].0;
+            while i < moves.len() && moves[i].0 == pos {
+                i += 1;
+            }
+            if pos.pos() != InstPosition::Before {
+                continue;
+            }
+
+            contents.clear();
+            for (j, &(_, from, to, vreg)) in moves.iter().enumerate().take(i).skip(start) {
+                let content = match vreg {
+                    Some(vreg) => Content::Vreg(vreg),
+                    None => contents
+                        .get(&from)
+                        .map_or(Content::Initial(from), |&(content, _)| content),
+                };
+                contents.insert(to, (content, j));
+            }
+
+            let inst = pos.inst();
+            for (slot, op) in self.func.inst_operands(inst).iter().enumerate() {
+                let preg = match op.constraint() {
+                    OperandConstraint::FixedReg(preg) if op.kind() == OperandKind::Use => preg,
+                    _ => continue,
+                };
+                if op.as_fixed_nonallocatable().is_some()
+                    || self.func.operand_is_immediate(inst, slot)
+                {
+                    continue;
+                }
+                let lo = Allocation::reg(preg);
+                let mut regs: SmallVec<[Allocation; 2]> = smallvec![lo];
+                if self.is_pair_vreg(VRegIndex::new(op.vreg().vreg())) {
+                    regs.push(pair_halves(self.func, self.env, lo, op.class()).1);
+                }
+                for reg in regs {
+                    if let Some(&(content, j)) = contents.get(&reg) {
+                        let ok = match content {
+                            Content::Vreg(vreg) => vreg == op.vreg(),
+                            Content::Initial(from) => from == reg,
+                        };
+                        if !ok {
+                            let (_, from, to, _) = moves[j];
+                            return Some((pos, from, to, op.vreg()));
+                        }
+                    }
+                }
+            }
+        }
+        None
     }
 
     pub fn resolve_inserted_moves(&mut self, mut inserted_moves: InsertedMoves) -> Edits {
@@ -776,7 +1434,12 @@ impl<'a, F: Function> Env<'a, F> {
             .moves
             .sort_unstable_by_key(|m| m.pos_prio.key());
 
-        // Redundant-move elimination state tracker.
+        // Redundant-move elimination state tracker. Its state carries
+        // over from one priority group to the next at the same
+        // program point, as no instruction runs between them: a move
+        // in a later group that repeats a copy made by an earlier one
+        // (say, a `Regular` move after an `InEdgeMoves` one) is
+        // elided, whatever order `move_prio_order` puts them in.
         let mut redundant_moves = RedundantMoveEliminator::default();
 
         fn redundant_move_process_side_effects<'a, F: Function>(
@@ -785,14 +1448,27 @@ impl<'a, F: Function> Env<'a, F> {
             from: ProgPoint,
             to: ProgPoint,
         ) {
-            // If we cross a block boundary, clear and return.
-            if this.cfginfo.insn_block[from.inst().index()]
-                != this.cfginfo.insn_block[to.inst().index()]
-            {
+            // If we cross a block boundary, clear and return, unless
+            // every block we enter falls through from the one before
+            // it and has no other predecessor: then the copies known
+            // at the end of that block still hold at its successor's
+            // start.
+            let from_block = this.cfginfo.insn_block[from.inst().index()];
+            let to_block = this.cfginfo.insn_block[to.inst().index()];
+            let falls_through = (from_block.index()..to_block.index())
+                .all(|b| this.func.block_preds(Block::new(b + 1)) == [Block::new(b)]);
+            if !falls_through {
                 redundant_moves.clear();
                 return;
             }
 
+            // A register taken over a range holds nothing after it.
+            for &(preg, range_from, range_to) in this.func.unavailable_ranges() {
+                if range_from <= to && from < range_to {
+                    redundant_moves.clear_alloc(Allocation::reg(preg));
+                }
+            }
+
             let start_inst = if from.pos() == InstPosition::Before {
                 from.inst()
             } else {
@@ -810,11 +1486,25 @@ impl<'a, F: Function> Env<'a, F> {
                         OperandKind::Def => {
                             let alloc = this.get_alloc(inst, i);
                             redundant_moves.clear_alloc(alloc);
+                            if this.is_pair_vreg(VRegIndex::new(op.vreg().vreg())) {
+                                let (_, hi) = pair_halves(this.func, this.env, alloc, op.class());
+                                redundant_moves.clear_alloc(hi);
+                            }
                         }
                         _ => {}
                     }
                 }
-                for reg in this.func.inst_clobbers(inst) {
+                let num_operands = this.func.inst_operands(inst).len();
+                for i in 0..this.func.inst_temps(inst).len() {
+                    redundant_moves.clear_alloc(this.get_alloc(inst, num_operands + i));
+                }
+                // The collector may move any reference at a
+                // safepoint, leaving only its stackmap entry current.
+                if this.func.requires_stackmap(inst) {
+                    redundant_moves.clear();
+                }
+                let killed = this.func.inst_early_kills(inst);
+                for reg in all_clobbers(this.func, this.env, inst).union(killed) {
                     redundant_moves.clear_alloc(Allocation::reg(reg));
                 }
                 // The dedicated scratch registers may be clobbered by any
@@ -827,8 +1517,80 @@ impl<'a, F: Function> Env<'a, F> {
             }
         }
 
+        self.stats.duplicated_edge_moves = self.count_duplicated_edge_moves(&inserted_moves);
+        self.stats.coalesced_moves_eliminated = self.count_coalesced_moves_eliminated();
+
+        // Stores of a vreg to a spillslot that already holds it on
+        // every path are elided too. Only slots that the same vreg is
+        // moved into more than once can see such a store.
+        let mut redundant_stores = RedundantStoreEliminator::new(
+            self.repeated_store_slots(&inserted_moves),
+            self.func.num_blocks(),
+        );
+
+        fn redundant_store_advance<'a, F: Function>(
+            this: &Env<'a, F>,
+            redundant_stores: &mut RedundantStoreEliminator,
+            to: ProgPoint,
+        ) {
+            let enter = |redundant_stores: &mut RedundantStoreEliminator, inst: Inst| {
+                let block = this.cfginfo.insn_block[inst.index()];
+                while redundant_stores.block < block {
+                    let next = Block::new(redundant_stores.block.index() + 1);
+                    redundant_stores.enter_block(
+                        next,
+                        this.func.block_preds(next),
+                        this.func.block_params(next),
+                    );
+                }
+            };
+            let end = if to.pos() == InstPosition::Before {
+                to.inst()
+            } else {
+                to.inst().next()
+            };
+            while redundant_stores.next_inst < end {
+                let inst = redundant_stores.next_inst;
+                enter(redundant_stores, inst);
+                // The collector may move any reference at a
+                // safepoint, leaving a copy outside its stackmap
+                // stale.
+                if this.func.requires_stackmap(inst) {
+                    redundant_stores.clear();
+                }
+                for (i, op) in this.func.inst_operands(inst).iter().enumerate() {
+                    if op.kind() == OperandKind::Def {
+                        redundant_stores.redefine(op.vreg());
+                        redundant_stores.write(this.get_alloc(inst, i), Some(op.vreg()));
+                    }
+                }
+                redundant_stores.next_inst = inst.next();
+            }
+            enter(redundant_stores, to.inst());
+        }
+
         let mut last_pos = ProgPoint::before(Inst::new(0));
-        let mut edits = Edits::with_capacity(self.func.num_insts());
+        // The resolved moves with their vregs, for
+        // `clobbered_fixed_input`.
+        #[cfg(debug_assertions)]
+        let mut resolved_moves = vec![];
+        let mut edits = if self.count_edits_only {
+            Edits::counting()
+        } else {
+            Edits::with_buffer(&mut self.edit_buf, self.func.num_insts())
+        };
+        for spillset in self.spillsets.iter() {
+            if !spillset.is_remat() || !spillset.slot.is_valid() {
+                continue;
+            }
+            let slot = self.spillslots[spillset.slot.index()].alloc;
+            let vreg = match spillset.sink {
+                Some(inst) => self.func.inst_operands(inst)[0].vreg(),
+                None => spillset.never_spill.unwrap(),
+            };
+            edits.remat_slots.insert(slot, (vreg, spillset.sink));
+        }
+        self.add_reinterprets(&mut edits);
 
         while i < inserted_moves.moves.len() {
             let start = i;
@@ -840,6 +1602,9 @@ impl<'a, F: Function> Env<'a, F> {
 
             redundant_move_process_side_effects(self, &mut redundant_moves, last_pos, pos_prio.pos);
             last_pos = pos_prio.pos;
+            if redundant_stores.is_enabled() {
+                redundant_store_advance(self, &mut redundant_stores, pos_prio.pos);
+            }
 
             // Gather all the moves in each RegClass separately.
             // These cannot interact, so it is safe to have separate
@@ -852,16 +1617,42 @@ impl<'a, F: Function> Env<'a, F> {
             let mut vec_moves: SmallVec<[InsertedMove; 8]> = smallvec![];
 
             for m in moves {
-                match m.to_vreg.class() {
-                    RegClass::Int => {
-                        int_moves.push(m.clone());
-                    }
-                    RegClass::Float => {
-                        float_moves.push(m.clone());
-                    }
-                    RegClass::Vector => {
-                        vec_moves.push(m.clone());
+                let class_moves = match m.to_vreg.class() {
+                    RegClass::Int => &mut int_moves,
+                    RegClass::Float => &mut float_moves,
+                    RegClass::Vector => &mut vec_moves,
+                };
+                // A register pair is moved one half at a time; the
+                // halves are independent moves as far as the
+                // parallel-move resolver is concerned.
+                if self.is_pair_vreg(VRegIndex::new(m.to_vreg.vreg())) {
+                    let class = m.to_vreg.class();
+                    let (from_lo, from_hi) = pair_halves(self.func, self.env, m.from_alloc, class);
+                    let (to_lo, to_hi) = pair_halves(self.func, self.env, m.to_alloc, class);
+                    // A half kept in a register by a partial spill is
+                    // neither stored nor reloaded from its slot.
+                    let kept = match self.partial_spill_moves.get(&(m.to_vreg, m.pos_prio.pos)) {
+                        Some(p) if p.from == m.from_alloc && p.to == m.to_alloc => p.kept,
+                        _ => [None; 2],
+                    };
+                    for (&(from, to), &kept) in
+                        [(from_lo, to_lo), (from_hi, to_hi)].iter().zip(&kept)
+                    {
+                        let from = match kept {
+                            Some(_) if to.is_stack() => continue,
+                            Some(reg) => reg,
+                            None => from,
+                        };
+                        if from != to {
+                            class_moves.push(InsertedMove {
+                                from_alloc: from,
+                                to_alloc: to,
+                                ..m.clone()
+                            });
+                        }
                     }
+                } else {
+                    class_moves.push(m.clone());
                 }
             }
 
@@ -881,10 +1672,14 @@ impl<'a, F: Function> Env<'a, F> {
                 );
                 for m in moves {
                     trace!(" {} -> {}", m.from_alloc, m.to_alloc);
-                    parallel_moves.add(m.from_alloc, m.to_alloc, Some(m.to_vreg));
+                    parallel_moves.add(m.from_alloc, m.to_alloc, Some((m.to_vreg, m.cause)));
                 }
 
                 let resolved = parallel_moves.resolve();
+                if resolved.needs_scratch() {
+                    self.stats.cyclic_move_groups += 1;
+                    self.stats.scratch_uses += resolved.num_cycles();
+                }
                 let mut scratch_iter = RegTraversalIter::new(
                     self.env,
                     regclass,
@@ -952,6 +1747,7 @@ impl<'a, F: Function> Env<'a, F> {
                     get_stackslot,
                     is_stack_alloc,
                     borrowed_scratch_reg: preferred_victim,
+                    stack_to_stack_moves: self.env.stack_to_stack_moves,
                 };
 
                 let resolved = scratch_resolver.compute(resolved);
@@ -959,8 +1755,8 @@ impl<'a, F: Function> Env<'a, F> {
                 let mut rewrites = FxHashMap::default();
                 for i in 0..stackslot_idx {
                     if i >= self.extra_spillslots_by_class[regclass as usize].len() {
-                        let slot =
-                            self.allocate_spillslot(self.func.spillslot_size(regclass) as u32);
+                        let size = spillslot_size(self.func, self.env, regclass) as u32;
+                        let slot = self.allocate_spillslot(size, 1, SpillTier::Primary);
                         self.extra_spillslots_by_class[regclass as usize].push(slot);
                     }
                     rewrites.insert(
@@ -969,34 +1765,106 @@ impl<'a, F: Function> Env<'a, F> {
                     );
                 }
 
-                for (src, dst, to_vreg) in resolved {
+                for (src, dst, data) in resolved {
                     let src = rewrites.get(&src).cloned().unwrap_or(src);
                     let dst = rewrites.get(&dst).cloned().unwrap_or(dst);
-                    trace!("  resolved: {} -> {} ({:?})", src, dst, to_vreg);
+                    trace!("  resolved: {} -> {} ({:?})", src, dst, data);
+                    let to_vreg = data.map(|(vreg, _)| vreg);
+                    // Moves without a vreg are the resolver's own saves
+                    // and restores of a borrowed scratch register.
+                    let cause = data.map_or(MoveCause::StackStackLowering, |(_, cause)| cause);
+                    // An edge move into a blockparam carries its next
+                    // value, so no slot holds that one yet.
+                    if let (Some(vreg), MoveCause::EdgeIn | MoveCause::EdgeOut) = (to_vreg, cause) {
+                        if self.vregs[VRegIndex::new(vreg.vreg())]
+                            .blockparam
+                            .is_valid()
+                        {
+                            redundant_stores.redefine(vreg);
+                        }
+                    }
                     let action = redundant_moves.process_move(src, dst, to_vreg);
-                    if !action.elide {
-                        edits.add(pos_prio, src, dst);
-                    } else {
+                    if action.elide {
                         trace!("    -> redundant move elided");
+                        redundant_stores.write(dst, to_vreg);
+                    } else if redundant_stores.process_move(dst, to_vreg) {
+                        trace!("    -> redundant store elided");
+                        self.stats.redundant_stores_elided += 1;
+                    } else {
+                        if let (Some(observer), Some(vreg), MoveCause::Reload) =
+                            (self.spill_observer, to_vreg, cause)
+                        {
+                            (observer.0)(SpillEvent {
+                                kind: SpillEventKind::Reload,
+                                vreg,
+                                pos: pos_prio.pos,
+                                forced_by_constraint: self
+                                    .vreg_has_stack_constraint(VRegIndex::new(vreg.vreg())),
+                            });
+                        }
+                        edits.add(pos_prio, src, dst, cause, regclass, to_vreg);
+                        #[cfg(debug_assertions)]
+                        resolved_moves.push((pos_prio.pos, src, dst, to_vreg));
                     }
                 }
             }
         }
 
+        #[cfg(debug_assertions)]
+        if let Some((pos, from, to, vreg)) = self.clobbered_fixed_input(&resolved_moves) {
+            panic!(
+                "move {} -> {} at {:?} clobbers the fixed register of input {}",
+                from, to, pos, vreg
+            );
+        }
+
         // Ensure edits are in sorted ProgPoint order. N.B.: this must
         // be a stable sort! We have to keep the order produced by the
         // parallel-move resolver for all moves within a single sort
-        // key.
+        // key. (`Edits::sort` checks this in debug builds.)
         edits.sort();
-        self.stats.edits_count = edits.len();
+        self.stats.edits_count = edits.spills
+            + edits.reloads
+            + edits.moves
+            + edits.remats
+            + edits.sinks
+            + edits.reinterprets;
+        self.stats.spill_edits = edits.spills;
+        self.stats.reload_edits = edits.reloads;
+        self.stats.move_edits = edits.moves;
+        self.stats.remat_edits = edits.remats;
+        self.stats.sink_edits = edits.sinks;
+        self.stats.reinterpret_edits = edits.reinterprets;
+
+        inserted_moves.moves.clear();
+        self.inserted_move_buf = inserted_moves.moves;
 
         // Add debug annotations.
         if self.annotations_enabled {
-            for &(pos_prio, ref edit) in edits.iter() {
-                match edit {
-                    &Edit::Move { from, to } => {
+            for &(pos_prio, ref edit, ..) in edits.iter() {
+                match *edit {
+                    Edit::Move { from, to } => {
                         self.annotate(pos_prio.pos, format!("move {} -> {}", from, to));
                     }
+                    Edit::Remat { vreg, to } => {
+                        self.annotate(pos_prio.pos, format!("remat {} -> {}", vreg, to));
+                    }
+                    Edit::Sink { inst, vreg, to } => {
+                        self.annotate(
+                            pos_prio.pos,
+                            format!("sink inst{} ({}) -> {}", inst.index(), vreg, to),
+                        );
+                    }
+                    Edit::Reinterpret {
+                        alloc,
+                        from_class,
+                        to_class,
+                    } => {
+                        self.annotate(
+                            pos_prio.pos,
+                            format!("reinterpret {} {:?} -> {:?}", alloc, from_class, to_class),
+                        );
+                    }
                 }
             }
         }
@@ -1004,3 +1872,1238 @@ impl<'a, F: Function> Env<'a, F> {
         edits
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::CFGInfo;
+    use crate::checker::Checker;
+    use crate::ion::data_structures::{Ctx, InsertMovePrio, InsertedMoves, LiveBundleIndex};
+    use crate::ion::Env;
+    use crate::testing::{int_env, move_of, run_and_check, run_and_check_with, vreg, TestFunc};
+    use crate::{
+        Allocation, Block, Edit, Inst, MoveCause, Operand, PReg, PRegSet, ProgPoint, RegClass,
+        RegallocOptions, RelaxationKind, SpillSlot, VReg,
+    };
+    use alloc::vec::Vec;
+    use alloc::{format, vec};
+
+    #[test]
+    fn test_direct_stack_to_stack_move() {
+        let r = |i| PReg::new(i, RegClass::Int);
+        let mut env = int_env(1);
+        env.fixed_stack_slots = vec![r(5), r(6)];
+        env.stack_to_stack_moves = true;
+
+        // v0 moves between two fixed stack slots while v1 occupies
+        // the only register, so without direct stack-to-stack moves
+        // this would need to borrow the register and save it to an
+        // extra spillslot.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_fixed_def(vreg(0), r(5))]);
+        f.op(&[Operand::reg_fixed_use(vreg(0), r(6))]);
+        f.ret(&[Operand::reg_use(vreg(1))]);
+
+        let out = run_and_check(&f, &env);
+        assert_eq!(out.num_spillslots, 0);
+        assert!(out.edits.iter().any(|(_, edit)| matches!(
+            edit,
+            Edit::Move { from, to }
+                if *from == Allocation::reg(r(5)) && *to == Allocation::reg(r(6))
+        )));
+    }
+
+    #[test]
+    fn test_preg_timeline() {
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1)), Operand::reg_use(vreg(0))]);
+        f.ret(&[Operand::reg_use(vreg(1))]);
+
+        let options = RegallocOptions {
+            preg_timelines: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(1), &options);
+        let timeline = out.preg_timeline(PReg::new(0, RegClass::Int));
+        let vregs: Vec<_> = timeline.iter().map(|&(_, _, v)| v).collect();
+        assert_eq!(vregs, vec![vreg(0), vreg(1)]);
+        assert!(timeline[0].1 <= timeline[1].0);
+        assert!(out.preg_timeline(PReg::new(1, RegClass::Int)).is_empty());
+    }
+
+    #[test]
+    fn test_pressure() {
+        // Three values are live across inst 3 with only
+        // two registers, so one of them is spilled; pressure counts
+        // it regardless.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        for v in 0..3 {
+            f.op(&[Operand::reg_def(vreg(v))]);
+        }
+        f.op(&[]);
+        f.op(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+        f.ret(&[Operand::reg_use(vreg(2))]);
+
+        let options = RegallocOptions {
+            pressure: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(2), &options);
+        let at = |pos| out.pressure_at(pos, RegClass::Int);
+        assert_eq!(at(ProgPoint::before(Inst::new(0))), 0);
+        assert_eq!(at(ProgPoint::after(Inst::new(0))), 1);
+        assert_eq!(at(ProgPoint::after(Inst::new(2))), 3);
+        assert_eq!(at(ProgPoint::before(Inst::new(4))), 3);
+        assert_eq!(at(ProgPoint::after(Inst::new(4))), 1);
+        assert_eq!(
+            out.pressure_at(ProgPoint::after(Inst::new(2)), RegClass::Float),
+            0
+        );
+
+        let out = run_and_check(&f, &int_env(2));
+        assert_eq!(
+            out.pressure_at(ProgPoint::after(Inst::new(2)), RegClass::Int),
+            0
+        );
+    }
+
+    #[test]
+    fn test_free_regs() {
+        // v0 lives across a clobber of p2, so it sits in p0 or p1
+        // and only the other one is free after the clobbering
+        // instruction.
+        let p2 = PReg::new(2, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op_with_clobbers(&[], PRegSet::empty().with(p2));
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.ret(&[]);
+
+        let options = RegallocOptions {
+            free_regs: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(3), &options);
+        let v0 = out.inst_allocs(Inst::new(0))[0].as_reg().unwrap();
+        let at = |pos| out.free_regs_at(pos, RegClass::Int);
+        assert_eq!(at(ProgPoint::before(Inst::new(0))).len(), 3);
+        let before = at(ProgPoint::before(Inst::new(1)));
+        assert_eq!(before.len(), 2);
+        assert!(!before.contains(v0) && before.contains(p2));
+        let after = at(ProgPoint::after(Inst::new(1)));
+        assert_eq!(after.len(), 1);
+        assert!(!after.contains(v0) && !after.contains(p2));
+        assert!(out
+            .free_regs_at(ProgPoint::after(Inst::new(1)), RegClass::Float)
+            .is_empty());
+
+        let out = run_and_check(&f, &int_env(3));
+        assert!(out
+            .free_regs_at(ProgPoint::before(Inst::new(0)), RegClass::Int)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_stackmap_spilled_refs() {
+        // The call clobbers every register, so the reference v0 and
+        // the plain value v1 both cross it on the stack; only v0 is
+        // in the stackmap.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0)), Operand::reg_def(vreg(1))]);
+        let call = f.call(&[]);
+        f.op(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+        f.ret(&[]);
+        f.set_reference_vregs(&[vreg(0)]);
+        f.set_safepoint(call);
+        let mut env = int_env(2);
+        env.call_clobbers = PRegSet::empty()
+            .with(PReg::new(0, RegClass::Int))
+            .with(PReg::new(1, RegClass::Int));
+        let out = run_and_check(&f, &env);
+
+        let stackmap = out.stackmap(call);
+        assert_eq!(stackmap.len(), 1);
+        let slot = stackmap[0];
+        assert!(slot.is_stack());
+        let v0 = out.inst_allocs(Inst::new(0))[0];
+        assert!(out
+            .edits
+            .iter()
+            .any(|(_, edit)| move_of(edit) == (v0, slot)));
+        assert!(out.stackmap(Inst::new(0)).is_empty());
+
+        let mut checker = Checker::new(&f, &env);
+        checker.enable_stackmap_checks();
+        checker.prepare(&out);
+        assert!(checker.run().is_ok());
+    }
+
+    #[test]
+    fn test_returns_with_different_abi_regs() {
+        let r = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.branch(&[(Block::new(1), &[]), (Block::new(2), &[])]);
+        f.block(&[]);
+        let ret1 = f.ret(&[
+            Operand::reg_fixed_use(vreg(0), r(0)),
+            Operand::reg_fixed_use(vreg(1), r(1)),
+        ]);
+        f.block(&[]);
+        let ret2 = f.ret(&[
+            Operand::reg_fixed_use(vreg(0), r(1)),
+            Operand::reg_fixed_use(vreg(1), r(0)),
+        ]);
+
+        let out = run_and_check(&f, &int_env(4));
+        assert_eq!(
+            out.inst_allocs(ret1),
+            [Allocation::reg(r(0)), Allocation::reg(r(1))]
+        );
+        assert_eq!(
+            out.inst_allocs(ret2),
+            [Allocation::reg(r(1)), Allocation::reg(r(0))]
+        );
+    }
+
+    #[test]
+    fn test_spill_once() {
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.branch(&[(Block::new(1), &[]), (Block::new(3), &[])]);
+        f.block(&[]);
+        f.branch(&[(Block::new(2), &[])]);
+        // Block 2 has two predecessors, so the store in block 0 is
+        // not known to reach it.
+        f.block(&[]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(2))]);
+        f.op(&[Operand::reg_use(vreg(2))]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+        f.block(&[]);
+        f.branch(&[(Block::new(2), &[])]);
+
+        let stores = |out: &crate::Output| {
+            out.edits
+                .iter()
+                .filter(|(_, edit)| move_of(edit).1.is_stack())
+                .count()
+        };
+        let out = run_and_check(&f, &int_env(1));
+        assert_eq!(stores(&out), 2);
+        let options = RegallocOptions {
+            spill_once: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(1), &options);
+        assert_eq!(stores(&out), 1);
+    }
+
+    #[test]
+    fn test_relaxed_constraints() {
+        // v1 prefers p1, where v0 is defined, and gets it: v0 makes
+        // way for v1 and v2, so its `Any` use reads it from the stack.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_fixed_def(vreg(0), p(1))]);
+        let def1 = f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_def(vreg(2))]);
+        let use0 = f.op(&[
+            Operand::any_use(vreg(0)),
+            Operand::reg_use(vreg(1)),
+            Operand::reg_use(vreg(2)),
+        ]);
+        f.ret(&[Operand::reg_use(vreg(1)), Operand::reg_use(vreg(2))]);
+        f.set_preferred_reg(def1, 0, p(1));
+        let out = run_and_check(&f, &int_env(2));
+        assert_eq!(
+            out.relaxed_constraints,
+            vec![(use0, 0, RelaxationKind::Spilled)]
+        );
+
+        // v0 is fixed to p1 throughout, so v1 goes elsewhere.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_fixed_def(vreg(0), p(1))]);
+        let def1 = f.op(&[Operand::reg_def(vreg(1))]);
+        f.ret(&[
+            Operand::reg_fixed_use(vreg(0), p(1)),
+            Operand::reg_use(vreg(1)),
+        ]);
+        f.set_preferred_reg(def1, 0, p(1));
+        let out = run_and_check(&f, &int_env(2));
+        assert_eq!(
+            out.relaxed_constraints,
+            vec![(def1, 0, RelaxationKind::PreferredReg)]
+        );
+    }
+
+    #[test]
+    fn test_reuse_copies() {
+        // `v0` is still live after the first two-address instruction,
+        // so its reuse needs a copy; the second is its last use and
+        // can be reused in place.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        let first = f.op(&[
+            Operand::reg_use(vreg(0)),
+            Operand::reg_reuse_def(vreg(1), 0),
+        ]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.op(&[
+            Operand::reg_use(vreg(0)),
+            Operand::reg_reuse_def(vreg(2), 0),
+        ]);
+        f.ret(&[Operand::reg_use(vreg(2))]);
+
+        let out = run_and_check(&f, &int_env(2));
+        assert_eq!(out.reuse_copies, vec![(first, 0, 1)]);
+    }
+
+    #[test]
+    fn test_spilled_reused_input() {
+        // With two registers, v0 is spilled around v1 and v4 before it
+        // is the reused input of a two-address instruction, and its
+        // output v2 is spilled around v3 and v5 before its use. Each
+        // is stored once and reloaded once. If v0 stays live in its
+        // slot afterwards, it is reloaded into a register of its own
+        // and copied into the output's, unless
+        // `reload_reused_into_output` reloads it there directly.
+        for live_after in [false, true] {
+            for direct in [false, true] {
+                let mut f = TestFunc::new();
+                f.block(&[]);
+                f.op(&[Operand::reg_def(vreg(0))]);
+                f.op(&[Operand::reg_def(vreg(1)), Operand::reg_def(vreg(4))]);
+                f.op(&[Operand::reg_use(vreg(1)), Operand::reg_use(vreg(4))]);
+                let inst = f.op(&[
+                    Operand::reg_use(vreg(0)),
+                    Operand::reg_reuse_def(vreg(2), 0),
+                ]);
+                f.op(&[Operand::reg_def(vreg(3)), Operand::reg_def(vreg(5))]);
+                f.op(&[Operand::reg_use(vreg(3)), Operand::reg_use(vreg(5))]);
+                let ret = if live_after {
+                    f.ret(&[Operand::reg_use(vreg(2)), Operand::any_use(vreg(0))])
+                } else {
+                    f.ret(&[Operand::reg_use(vreg(2))])
+                };
+
+                let options = RegallocOptions {
+                    reload_reused_into_output: direct,
+                    ..RegallocOptions::default()
+                };
+                let out = run_and_check_with(&f, &int_env(2), &options);
+                let reg = out.inst_allocs(inst)[1];
+                assert!(reg.is_reg());
+                assert_eq!(out.inst_allocs(inst)[0], reg);
+                let moves: Vec<_> = out
+                    .edits
+                    .iter()
+                    .map(|(pos, edit)| (*pos, move_of(edit)))
+                    .collect();
+                let slot0 = moves[0].1 .1;
+                let slot2 = moves[moves.len() - 2].1 .1;
+                assert!(slot0.is_stack() && slot2.is_stack());
+                let mut expected = vec![(
+                    ProgPoint::before(Inst::new(1)),
+                    (out.inst_allocs(Inst::new(0))[0], slot0),
+                )];
+                if live_after && !direct {
+                    let own = moves[1].1 .1;
+                    assert!(own.is_reg() && own != reg);
+                    expected.push((ProgPoint::before(inst), (slot0, own)));
+                    expected.push((ProgPoint::before(inst), (own, reg)));
+                } else {
+                    expected.push((ProgPoint::before(inst), (slot0, reg)));
+                }
+                expected.push((ProgPoint::before(inst.next()), (reg, slot2)));
+                expected.push((ProgPoint::before(ret), (slot2, out.inst_allocs(ret)[0])));
+                assert_eq!(moves, expected);
+                let copies = if live_after {
+                    vec![(inst, 0, 1)]
+                } else {
+                    vec![]
+                };
+                assert_eq!(out.reuse_copies, copies);
+                if live_after {
+                    assert_eq!(out.inst_allocs(ret)[1], slot0);
+                }
+            }
+        }
+    }
+
+    /// Models x86 `idiv`, which reads the dividend from RDX:RAX and
+    /// writes the quotient and remainder back to RAX and RDX. There is
+    /// no `Mod` operand kind: each read-modify-write fixed register is
+    /// a fixed use of the old vreg plus a fixed def of a new one.
+    fn idiv(hi: usize, dividend_live_after: bool) -> (TestFunc, Inst) {
+        let rax = PReg::new(0, RegClass::Int);
+        let rdx = PReg::new(2, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_def(vreg(2))]);
+        let idiv = f.op(&[
+            Operand::reg_fixed_use(vreg(0), rax),
+            Operand::reg_fixed_use(vreg(hi), rdx),
+            Operand::reg_use(vreg(2)),
+            Operand::reg_fixed_def(vreg(3), rax),
+            Operand::reg_fixed_def(vreg(4), rdx),
+        ]);
+        f.op(&[Operand::reg_use(vreg(3)), Operand::reg_use(vreg(4))]);
+        if dividend_live_after {
+            f.ret(&[Operand::reg_use(vreg(0))]);
+        } else {
+            f.ret(&[]);
+        }
+        (f, idiv)
+    }
+
+    #[test]
+    fn test_stack_reuse() {
+        // v1 updates v0's stack slot in place; v0 is optionally still
+        // live afterwards.
+        let build = |input_live_after: bool| {
+            let mut f = TestFunc::new();
+            f.block(&[]);
+            f.op(&[Operand::any_def(vreg(0))]);
+            let inst = f.op(&[
+                Operand::any_use(vreg(0)),
+                Operand::stack_reuse_def(vreg(1), 0),
+            ]);
+            let mut uses = vec![Operand::any_use(vreg(1))];
+            if input_live_after {
+                uses.push(Operand::reg_use(vreg(0)));
+            }
+            f.ret(&uses);
+            (f, inst)
+        };
+
+        let (f, inst) = build(false);
+        let out = run_and_check(&f, &int_env(2));
+        let allocs = out.inst_allocs(inst);
+        assert!(allocs[1].is_stack());
+        assert_eq!(allocs[0], allocs[1]);
+        assert!(out.reuse_copies.is_empty());
+        assert!(!out
+            .edits
+            .iter()
+            .any(|&(pos, _)| pos == ProgPoint::before(inst)));
+
+        let (f, inst) = build(true);
+        let out = run_and_check(&f, &int_env(2));
+        let allocs = out.inst_allocs(inst);
+        assert!(allocs[1].is_stack());
+        assert_eq!(allocs[0], allocs[1]);
+        assert_eq!(out.reuse_copies, vec![(inst, 0, 1)]);
+    }
+
+    #[test]
+    fn test_fixed_reg_read_modify_write() {
+        let rax = Allocation::reg(PReg::new(0, RegClass::Int));
+
+        // The dividend dies at the `idiv`: it is defined straight into
+        // its fixed registers and no moves are needed.
+        let (f, _) = idiv(1, false);
+        let out = run_and_check(&f, &int_env(4));
+        assert!(out.edits.is_empty());
+
+        // The low half outlives the `idiv`, so exactly one copy into
+        // RAX is needed before it.
+        let (f, inst) = idiv(1, true);
+        let out = run_and_check(&f, &int_env(4));
+        assert_eq!(out.edits.len(), 1);
+        let (pos, (_, to)) = (out.edits[0].0, move_of(&out.edits[0].1));
+        assert_eq!(pos, ProgPoint::before(inst));
+        assert_eq!(to, rax);
+
+        // The same vreg in both fixed inputs goes through the
+        // multi-fixed-register fixup path.
+        let (f, _) = idiv(0, false);
+        run_and_check(&f, &int_env(4));
+    }
+
+    #[test]
+    fn test_move_prio_order() {
+        use crate::{InsertMovePrio, RegAllocError};
+
+        assert_eq!(InsertMovePrio::ranks(|a, b| b.cmp(&a)), [5, 4, 3, 2, 1, 0]);
+        // Groups that compare equal keep their default order.
+        assert_eq!(
+            InsertMovePrio::ranks(|_, _| core::cmp::Ordering::Equal),
+            [0, 1, 2, 3, 4, 5]
+        );
+
+        // Before inst 3, a value is spilled by a regular move and the
+        // reuse constraint needs a copy at the same point.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_def(vreg(2))]);
+        f.op(&[
+            Operand::reg_reuse_def(vreg(3), 1),
+            Operand::reg_use(vreg(0)),
+            Operand::reg_use(vreg(1)),
+        ]);
+        f.op(&[
+            Operand::reg_use(vreg(0)),
+            Operand::reg_use(vreg(2)),
+            Operand::reg_use(vreg(3)),
+        ]);
+        f.ret(&[]);
+        let env = int_env(3);
+        let moves = |out: &crate::Output| -> Vec<_> {
+            out.edits
+                .iter()
+                .map(|(pos, edit)| {
+                    let (from, to) = move_of(edit);
+                    (*pos, from, to)
+                })
+                .collect()
+        };
+        let default = moves(&run_and_check(&f, &env));
+
+        let options = RegallocOptions {
+            move_prio_order: Some(|a, b| a.cmp(&b)),
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &env, &options);
+        assert_eq!(moves(&out), default);
+
+        // The reused input's copy does not depend on the copies into
+        // fixed registers, so it may go first; ties keep the default
+        // order, so it still follows the regular moves.
+        let options = RegallocOptions {
+            move_prio_order: Some(|a, b| {
+                let key = |prio| match prio {
+                    InsertMovePrio::ReusedInput => InsertMovePrio::Regular,
+                    prio => prio,
+                };
+                key(a).cmp(&key(b))
+            }),
+            ..RegallocOptions::default()
+        };
+        run_and_check_with(&f, &env, &options);
+
+        // But copying it before the spill would clobber the spilled
+        // value, so that order is rejected, as is reversing them all.
+        let options = RegallocOptions {
+            move_prio_order: Some(|a, b| {
+                let key = |prio| match prio {
+                    InsertMovePrio::ReusedInput => InsertMovePrio::InEdgeMoves,
+                    prio => prio,
+                };
+                key(a).cmp(&key(b))
+            }),
+            ..RegallocOptions::default()
+        };
+        assert!(matches!(
+            crate::run(&f, &env, &options),
+            Err(RegAllocError::MovePrioOrder(
+                InsertMovePrio::Regular,
+                InsertMovePrio::ReusedInput
+            ))
+        ));
+        let options = RegallocOptions {
+            move_prio_order: Some(|a, b| b.cmp(&a)),
+            ..RegallocOptions::default()
+        };
+        assert!(matches!(
+            crate::run(&f, &env, &options),
+            Err(RegAllocError::MovePrioOrder(
+                InsertMovePrio::InEdgeMoves,
+                InsertMovePrio::Regular
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_redundant_spill_across_fallthrough() {
+        // v0 is reloaded at inst 3 and stays live into block 1 with
+        // its register untouched, but the lone register is needed for
+        // v2, so v0 is stored again before inst 6. The slot already
+        // holds v0 from the store at inst 1, so that second store is
+        // redundant even though it is in a different block.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.branch(&[(Block::new(1), &[])]);
+        f.block(&[]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(2))]);
+        f.op(&[Operand::reg_use(vreg(2))]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.ret(&[]);
+
+        let out = run_and_check(&f, &int_env(1));
+        assert_eq!(out.edits.len(), 3);
+        assert!(out.edits.iter().all(|(pos, _)| pos.inst() != Inst::new(6)));
+    }
+
+    #[test]
+    fn test_move_causes() {
+        // v0 is spilled around v1 and reloaded for its use.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+
+        let out = run_and_check(&f, &int_env(1));
+        assert!(out.move_causes.is_empty());
+
+        let options = RegallocOptions {
+            move_causes: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(1), &options);
+        assert_eq!(out.move_causes.len(), out.edits.len());
+        for ((_, edit), &cause) in out.edits.iter().zip(&out.move_causes) {
+            let (from, to) = move_of(edit);
+            match cause {
+                MoveCause::Spill => assert!(to.is_stack()),
+                MoveCause::Reload => assert!(from.is_stack()),
+                _ => panic!("unexpected cause {:?}", cause),
+            }
+        }
+        assert!(out.move_causes.contains(&MoveCause::Spill));
+        assert!(out.move_causes.contains(&MoveCause::Reload));
+
+        // v1's def reuses v0, which is still needed afterwards, so
+        // the input is copied first.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[
+            Operand::reg_reuse_def(vreg(1), 1),
+            Operand::reg_use(vreg(0)),
+        ]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+
+        let out = run_and_check_with(&f, &int_env(2), &options);
+        assert_eq!(out.move_causes, vec![MoveCause::ReuseInput]);
+    }
+
+    #[test]
+    fn test_move_classes() {
+        // An int and a float value are each spilled around another
+        // value of their class.
+        let mut env = int_env(1);
+        env.preferred_regs_by_class[RegClass::Float as usize] = vec![PReg::new(0, RegClass::Float)];
+        let fvreg = |i| VReg::new(i, RegClass::Float);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0)), Operand::reg_def(fvreg(1))]);
+        f.op(&[Operand::reg_def(vreg(2)), Operand::reg_def(fvreg(3))]);
+        f.op(&[Operand::reg_use(vreg(2)), Operand::reg_use(fvreg(3))]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(fvreg(1))]);
+
+        let out = run_and_check(&f, &env);
+        assert!(out.move_classes.is_empty());
+
+        let options = RegallocOptions {
+            move_classes: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &env, &options);
+        assert_eq!(out.move_classes.len(), out.edits.len());
+        for ((_, edit), &class) in out.edits.iter().zip(&out.move_classes) {
+            let (from, to) = move_of(edit);
+            let reg = from.as_reg().or_else(|| to.as_reg()).unwrap();
+            assert_eq!(reg.class(), class);
+        }
+        assert!(out.move_classes.contains(&RegClass::Int));
+        assert!(out.move_classes.contains(&RegClass::Float));
+    }
+
+    #[test]
+    fn test_moves_for_vreg() {
+        // With one register, v0 is spilled around v1 and reloaded for
+        // its use; v1 never moves.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+
+        let out = run_and_check(&f, &int_env(1));
+        assert!(out.move_vregs.is_empty());
+        assert_eq!(out.moves_for_vreg(vreg(0)).count(), 0);
+
+        let options = RegallocOptions {
+            move_vregs: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(1), &options);
+        assert_eq!(out.move_vregs.len(), out.edits.len());
+        let moves: Vec<_> = out
+            .moves_for_vreg(vreg(0))
+            .map(|(_, edit)| move_of(edit))
+            .collect();
+        assert_eq!(moves.len(), 2);
+        assert!(moves[0].0.is_reg() && moves[0].1.is_stack());
+        assert_eq!((moves[1].0, moves[1].1), (moves[0].1, moves[0].0));
+        assert_eq!(out.moves_for_vreg(vreg(1)).count(), 0);
+    }
+
+    #[test]
+    fn test_redundant_store_elided() {
+        // With two registers, v0 is spilled around v1 and v2, reloaded
+        // in both arms of a diamond and spilled again around v3 and
+        // v4 after the join. Its slot still holds it there on both
+        // paths, so only the first of its three spilled pieces needs
+        // a store.
+        let (b1, b2, b3) = (Block::new(1), Block::new(2), Block::new(3));
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1)), Operand::reg_def(vreg(2))]);
+        f.op(&[Operand::reg_use(vreg(1)), Operand::reg_use(vreg(2))]);
+        f.branch(&[(b1, &[]), (b2, &[])]);
+        f.block(&[]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.branch(&[(b3, &[])]);
+        f.block(&[]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.branch(&[(b3, &[])]);
+        f.block(&[]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(3)), Operand::reg_def(vreg(4))]);
+        f.op(&[Operand::reg_use(vreg(3)), Operand::reg_use(vreg(4))]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+
+        let options = RegallocOptions {
+            move_vregs: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(2), &options);
+        let stores = out
+            .moves_for_vreg(vreg(0))
+            .map(|(_, edit)| move_of(edit))
+            .filter(|(from, to)| from.is_reg() && to.is_stack())
+            .count();
+        assert_eq!(stores, 1);
+        assert_eq!(out.stats.redundant_stores_elided, 1);
+    }
+
+    #[test]
+    fn test_redundant_store_across_safepoint() {
+        // With two registers, v5 and then the reference v0 are each
+        // spilled twice, around a pair of short-lived values, and
+        // share a slot that is therefore tracked. v0 is reloaded for
+        // a safepoint in between, where only its register is in the
+        // stackmap: the collector may move it there, leaving the
+        // slot's copy stale, so the second store of v0 must stay.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(5))]);
+        f.op(&[Operand::reg_def(vreg(1)), Operand::reg_def(vreg(2))]);
+        f.op(&[Operand::reg_use(vreg(1)), Operand::reg_use(vreg(2))]);
+        f.op(&[Operand::reg_use(vreg(5))]);
+        f.op(&[Operand::reg_def(vreg(3)), Operand::reg_def(vreg(4))]);
+        f.op(&[Operand::reg_use(vreg(3)), Operand::reg_use(vreg(4))]);
+        f.op(&[Operand::reg_use(vreg(5))]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(6)), Operand::reg_def(vreg(7))]);
+        f.op(&[Operand::reg_use(vreg(6)), Operand::reg_use(vreg(7))]);
+        let safepoint = f.op(&[Operand::reg_use(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(8)), Operand::reg_def(vreg(9))]);
+        f.op(&[Operand::reg_use(vreg(8)), Operand::reg_use(vreg(9))]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+        f.set_reference_vregs(&[vreg(0)]);
+        f.set_safepoint(safepoint);
+
+        let env = int_env(2);
+        let out = run_and_check(&f, &env);
+        assert!(out.stackmap(safepoint)[0].is_reg());
+        assert!(out.edits.iter().any(|(pos, edit)| {
+            let (from, to) = move_of(edit);
+            pos.inst() > safepoint && from.is_reg() && to.is_stack()
+        }));
+
+        let mut checker = Checker::new(&f, &env);
+        checker.enable_stackmap_checks();
+        checker.prepare(&out);
+        assert!(checker.run().is_ok());
+    }
+
+    #[test]
+    fn test_redundant_store_loop_blockparam() {
+        // With two registers, the loop parameter v1 lives in its slot
+        // at the header and is reloaded twice there. The back edge
+        // stores v6 into that slot as the next v1: the slot last held
+        // v1, but the old value, so that store must stay.
+        let (b1, b2, b3) = (Block::new(1), Block::new(2), Block::new(3));
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.branch(&[(b1, &[vreg(0)])]);
+        f.block(&[vreg(1)]);
+        f.op(&[Operand::reg_def(vreg(2)), Operand::reg_def(vreg(3))]);
+        f.op(&[Operand::reg_use(vreg(2)), Operand::reg_use(vreg(3))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.op(&[Operand::reg_def(vreg(4)), Operand::reg_def(vreg(5))]);
+        f.op(&[Operand::reg_use(vreg(4)), Operand::reg_use(vreg(5))]);
+        let def = f.op(&[Operand::reg_use(vreg(1)), Operand::reg_def(vreg(6))]);
+        f.branch(&[(b2, &[]), (b3, &[])]);
+        f.block(&[]);
+        f.branch(&[(b1, &[vreg(6)])]);
+        f.block(&[]);
+        f.ret(&[Operand::reg_use(vreg(1))]);
+
+        let out = run_and_check(&f, &int_env(2));
+        let v6 = out.inst_allocs(def)[1];
+        assert!(out
+            .edits
+            .iter()
+            .any(|(_, edit)| matches!(move_of(edit), (from, to) if from == v6 && to.is_stack())));
+        assert_eq!(out.stats.redundant_stores_elided, 0);
+    }
+
+    #[test]
+    fn test_duplicated_edge_moves() {
+        // Both arms of a diamond pass v0 (fixed to p0) to a join
+        // parameter needed in p1, so each ends in the same move,
+        // unless the second arm passes v2 (fixed to p2) instead.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let (b1, b2, b3) = (Block::new(1), Block::new(2), Block::new(3));
+        for same in [true, false] {
+            let arg = if same { vreg(0) } else { vreg(2) };
+            let mut f = TestFunc::new();
+            f.block(&[]);
+            f.op(&[
+                Operand::reg_fixed_def(vreg(0), p(0)),
+                Operand::reg_fixed_def(vreg(2), p(2)),
+            ]);
+            f.branch(&[(b1, &[]), (b2, &[])]);
+            f.block(&[]);
+            f.branch(&[(b3, &[vreg(0)])]);
+            f.block(&[]);
+            f.branch(&[(b3, &[arg])]);
+            f.block(&[vreg(1)]);
+            f.ret(&[Operand::reg_fixed_use(vreg(1), p(1))]);
+
+            let out = run_and_check(&f, &int_env(3));
+            assert_eq!(out.edits.len(), 2);
+            assert_eq!(out.stats.duplicated_edge_moves, same as usize);
+        }
+    }
+
+    #[test]
+    fn test_edits_by_cause() {
+        // v0 is spilled around v2..v4 and reloaded for its fixed use,
+        // then both arms of a diamond pass it to a join parameter
+        // needed in p1.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let (b1, b2, b3) = (Block::new(1), Block::new(2), Block::new(3));
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_fixed_def(vreg(0), p(0))]);
+        f.op(&[
+            Operand::reg_def(vreg(2)),
+            Operand::reg_def(vreg(3)),
+            Operand::reg_def(vreg(4)),
+        ]);
+        f.op(&[
+            Operand::reg_use(vreg(2)),
+            Operand::reg_use(vreg(3)),
+            Operand::reg_use(vreg(4)),
+        ]);
+        f.op(&[Operand::reg_fixed_use(vreg(0), p(0))]);
+        f.branch(&[(b1, &[]), (b2, &[])]);
+        f.block(&[]);
+        f.branch(&[(b3, &[vreg(0)])]);
+        f.block(&[]);
+        f.branch(&[(b3, &[vreg(0)])]);
+        f.block(&[vreg(1)]);
+        f.ret(&[Operand::reg_fixed_use(vreg(1), p(1))]);
+
+        let out = run_and_check(&f, &int_env(3));
+        assert_eq!(out.edits.len(), 4);
+        assert_eq!(out.spill_edits().count(), 0);
+
+        let options = RegallocOptions {
+            move_causes: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(3), &options);
+        let collect = |edits: &mut dyn Iterator<Item = (ProgPoint, &Edit)>| {
+            edits
+                .map(|(pos, edit)| (pos, move_of(edit)))
+                .collect::<Vec<_>>()
+        };
+        let slot = move_of(&out.edits[0].1).1;
+        assert!(slot.is_stack());
+        let (r0, r1) = (Allocation::reg(p(0)), Allocation::reg(p(1)));
+        assert_eq!(
+            collect(&mut out.spill_edits()),
+            vec![(ProgPoint::before(Inst::new(1)), (r0, slot))]
+        );
+        assert_eq!(
+            collect(&mut out.reload_edits()),
+            vec![(ProgPoint::before(Inst::new(3)), (slot, r0))]
+        );
+        assert_eq!(
+            collect(&mut out.edge_move_edits()),
+            vec![
+                (ProgPoint::before(Inst::new(5)), (r0, r1)),
+                (ProgPoint::before(Inst::new(6)), (r0, r1)),
+            ]
+        );
+        assert_eq!(out.edits_with_cause(MoveCause::Split).count(), 0);
+    }
+
+    #[test]
+    fn test_no_self_moves() {
+        // v0 is passed in p0 to a parameter needed in p0: the
+        // hand-over needs no edit, and the checker follows it through
+        // the branch arguments alone.
+        let p0 = PReg::new(0, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_fixed_def(vreg(0), p0)]);
+        f.branch(&[(Block::new(1), &[vreg(0)])]);
+        f.block(&[vreg(1)]);
+        f.ret(&[Operand::reg_fixed_use(vreg(1), p0)]);
+
+        let out = run_and_check(&f, &int_env(2));
+        assert!(out.edits.is_empty());
+        assert_eq!(out.stats.edits_count, 0);
+    }
+
+    #[test]
+    fn test_class_views() {
+        // v0 is defined and later used through a vector view, and
+        // spilled in between: the switches sit right next to the
+        // instructions, the reload coming before the switch into the
+        // use's view.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        let def = f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        let used = f.op(&[Operand::reg_use(vreg(0))]);
+        f.ret(&[]);
+        f.set_class_view(def, 0, RegClass::Vector);
+        f.set_class_view(used, 0, RegClass::Vector);
+
+        let options = RegallocOptions {
+            move_causes: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(1), &options);
+        assert_eq!(out.stats.reinterpret_edits, 2);
+        let p0 = Allocation::reg(PReg::new(0, RegClass::Int));
+        let slot = move_of(&out.edits[1].1).1;
+        let expected = [
+            (
+                ProgPoint::after(def),
+                Edit::Reinterpret {
+                    alloc: p0,
+                    from_class: RegClass::Vector,
+                    to_class: RegClass::Int,
+                },
+            ),
+            (
+                ProgPoint::before(Inst::new(1)),
+                Edit::Move { from: p0, to: slot },
+            ),
+            (ProgPoint::before(used), Edit::Move { from: slot, to: p0 }),
+            (
+                ProgPoint::before(used),
+                Edit::Reinterpret {
+                    alloc: p0,
+                    from_class: RegClass::Int,
+                    to_class: RegClass::Vector,
+                },
+            ),
+        ];
+        assert_eq!(format!("{:?}", out.edits), format!("{:?}", expected));
+        assert_eq!(
+            out.move_causes,
+            [
+                MoveCause::Reinterpret,
+                MoveCause::Spill,
+                MoveCause::Reload,
+                MoveCause::Reinterpret
+            ]
+        );
+    }
+
+    #[test]
+    fn test_edge_moves() {
+        // Block 0 passes v0 and v1, which it defines in p0 and p1, to
+        // block 1 swapped; block 1 returns its params in p0 and p1.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[
+            Operand::reg_fixed_def(vreg(0), p(0)),
+            Operand::reg_fixed_def(vreg(1), p(1)),
+        ]);
+        f.branch(&[(Block::new(1), &[vreg(1), vreg(0)])]);
+        f.block(&[vreg(2), vreg(3)]);
+        f.ret(&[
+            Operand::reg_fixed_use(vreg(2), p(0)),
+            Operand::reg_fixed_use(vreg(3), p(1)),
+        ]);
+
+        let options = RegallocOptions {
+            move_causes: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(3), &options);
+        let moves = out.edge_moves(&f, Block::new(0), Block::new(1)).unwrap();
+        // The swap is a cycle: save one side to the scratch, move the
+        // other across, then restore from the scratch.
+        assert_eq!(moves.moves.len(), 3);
+        let (save_from, scratch) = moves.moves[0];
+        assert_eq!(moves.moves[1], (Allocation::reg(p(1)), save_from));
+        assert_eq!(moves.moves[2], (scratch, Allocation::reg(p(1))));
+        assert_eq!(moves.deps, vec![vec![], vec![0], vec![0, 1]]);
+        assert_eq!(moves.critical_path(), 3);
+        assert!(out.edge_moves(&f, Block::new(1), Block::new(0)).is_none());
+    }
+
+    #[test]
+    fn test_count_edits_only() {
+        // Four values live across a loop with three registers, so
+        // that there are spills, reloads and edge moves.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        for v in 0..4 {
+            f.op(&[Operand::reg_def(vreg(v))]);
+        }
+        f.branch(&[(Block::new(1), &[])]);
+        f.block(&[]);
+        f.op(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(2)), Operand::reg_use(vreg(3))]);
+        f.branch(&[(Block::new(1), &[])]);
+
+        let out = run_and_check(&f, &int_env(3));
+        let count = |pred: fn(Allocation, Allocation) -> bool| {
+            out.edits
+                .iter()
+                .filter(|(_, edit)| {
+                    let (from, to) = move_of(edit);
+                    pred(from, to)
+                })
+                .count()
+        };
+        assert_eq!(out.stats.edits_count, out.edits.len());
+        assert_eq!(
+            out.stats.spill_edits,
+            count(|f, t| f.is_reg() && t.is_stack())
+        );
+        assert_eq!(
+            out.stats.reload_edits,
+            count(|f, t| f.is_stack() && t.is_reg())
+        );
+        assert_eq!(out.stats.move_edits, count(|f, t| f.is_reg() == t.is_reg()));
+        assert!(out.stats.spill_edits > 0 && out.stats.reload_edits > 0);
+
+        let options = RegallocOptions {
+            count_edits_only: true,
+            ..RegallocOptions::default()
+        };
+        let counted = crate::run(&f, &int_env(3), &options).unwrap();
+        assert!(counted.edits.is_empty());
+        assert_eq!(counted.stats.edits_count, out.stats.edits_count);
+        assert_eq!(counted.stats.spill_edits, out.stats.spill_edits);
+        assert_eq!(counted.stats.reload_edits, out.stats.reload_edits);
+        assert_eq!(counted.stats.move_edits, out.stats.move_edits);
+        assert_eq!(counted.allocs, out.allocs);
+    }
+
+    #[test]
+    fn test_single_block_resolution() {
+        // Enough pressure for splits and spills, plus a reuse and a
+        // program move, in one block.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        for v in 0..4 {
+            f.op(&[Operand::reg_def(vreg(v))]);
+        }
+        f.op(&[
+            Operand::reg_use(vreg(0)),
+            Operand::reg_reuse_def(vreg(4), 0),
+        ]);
+        f.mov(vreg(5), vreg(4));
+        f.op(&[Operand::reg_fixed_use(vreg(1), p(0))]);
+        f.ret(&[
+            Operand::reg_use(vreg(1)),
+            Operand::reg_use(vreg(2)),
+            Operand::any_use(vreg(3)),
+            Operand::reg_use(vreg(5)),
+        ]);
+        let machine_env = int_env(3);
+        let cfginfo = CFGInfo::new(&f).unwrap();
+        let mut env = Env::new(&f, &machine_env, cfginfo, false, &mut Ctx::default());
+        assert!(env.single_block);
+        env.init().unwrap();
+        let edits = env.run().unwrap();
+        let allocs = env.allocs.clone();
+        assert!(edits.len() > 0);
+
+        // The general path gives the same result.
+        env.single_block = false;
+        let general = env.resolve_moves();
+        assert_eq!(format!("{:?}", general), format!("{:?}", edits));
+        assert_eq!(env.allocs, allocs);
+    }
+
+    #[test]
+    fn test_redundant_moves_across_prios() {
+        let p = |i| Allocation::reg(PReg::new(i, RegClass::Int));
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+        let machine_env = int_env(3);
+        let cfginfo = CFGInfo::new(&f).unwrap();
+        let mut env = Env::new(&f, &machine_env, cfginfo, false, &mut Ctx::default());
+        env.init().unwrap();
+        env.run().unwrap();
+
+        // An edge move followed by a regular move of the same value
+        // at the same point: the second copy is redundant, in either
+        // group order.
+        let cases: [(InsertMovePrio, fn(InsertMovePrio, InsertMovePrio) -> _); 2] = [
+            (InsertMovePrio::InEdgeMoves, |a, b| a.cmp(&b)),
+            (InsertMovePrio::OutEdgeMoves, |a, b| b.cmp(&a)),
+        ];
+        for &(edge, cmp) in cases.iter() {
+            let mut moves = InsertedMoves {
+                moves: vec![],
+                prio_ranks: InsertMovePrio::ranks(cmp),
+                cross_class_spills: false,
+            };
+            let pos = ProgPoint::before(Inst::new(1));
+            moves.push(pos, edge, p(0), p(1), vreg(0));
+            moves.push(pos, InsertMovePrio::Regular, p(0), p(1), vreg(0));
+            let edits = env.resolve_inserted_moves(moves);
+            let edits: Vec<_> = edits.iter().map(|(_, edit, ..)| move_of(edit)).collect();
+            assert_eq!(edits, vec![(p(0), p(1))]);
+        }
+
+        // A move that changes the destination's value is kept.
+        let mut moves = InsertedMoves {
+            moves: vec![],
+            prio_ranks: InsertMovePrio::ranks(|a, b| a.cmp(&b)),
+            cross_class_spills: false,
+        };
+        let pos = ProgPoint::before(Inst::new(1));
+        moves.push(pos, InsertMovePrio::InEdgeMoves, p(0), p(1), vreg(0));
+        moves.push(pos, InsertMovePrio::Regular, p(2), p(1), vreg(1));
+        assert_eq!(env.resolve_inserted_moves(moves).len(), 2);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_clobbered_fixed_input() {
+        let p = |i| Allocation::reg(PReg::new(i, RegClass::Int));
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0)), Operand::reg_def(vreg(1))]);
+        f.op(&[
+            Operand::reg_fixed_use(vreg(0), PReg::new(0, RegClass::Int)),
+            Operand::reg_use(vreg(1)),
+        ]);
+        f.ret(&[]);
+        let machine_env = int_env(3);
+        let cfginfo = CFGInfo::new(&f).unwrap();
+        let env = Env::new(&f, &machine_env, cfginfo, false, &mut Ctx::default());
+        let pos = ProgPoint::before(Inst::new(1));
+        let slot = Allocation::stack(SpillSlot::new(0));
+
+        // Moving v0 into its register, or borrowing the register for
+        // v1 and restoring it, is fine.
+        assert_eq!(
+            env.clobbered_fixed_input(&[(pos, p(1), p(0), Some(vreg(0)))]),
+            None
+        );
+        assert_eq!(
+            env.clobbered_fixed_input(&[
+                (pos, p(0), slot, None),
+                (pos, p(2), p(0), Some(vreg(1))),
+                (pos, p(0), p(1), Some(vreg(1))),
+                (pos, slot, p(0), None),
+            ]),
+            None
+        );
+
+        // Overwriting it with v1 afterwards is not.
+        assert_eq!(
+            env.clobbered_fixed_input(&[
+                (pos, p(1), p(0), Some(vreg(0))),
+                (pos, p(2), p(0), Some(vreg(1))),
+            ]),
+            Some((pos, p(2), p(0), vreg(0)))
+        );
+    }
+
+    #[test]
+    fn test_resolve_moves_again() {
+        // v0 is needed in both p0 and p1 at inst 1, which takes a
+        // multi-fixed-reg copy.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[
+            Operand::reg_fixed_use(vreg(0), p(0)),
+            Operand::reg_fixed_use(vreg(0), p(1)),
+        ]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+        let machine_env = int_env(3);
+        let cfginfo = CFGInfo::new(&f).unwrap();
+        let mut env = Env::new(&f, &machine_env, cfginfo, false, &mut Ctx::default());
+        env.init().unwrap();
+        let edits = env.run().unwrap();
+
+        // Resolving again without changes gives the same result.
+        assert!(!env.multi_fixed_reg_fixups.is_empty());
+        let allocs = env.allocs.clone();
+        let again = env.resolve_moves();
+        assert_eq!(format!("{:?}", again), format!("{:?}", edits));
+        assert_eq!(env.allocs, allocs);
+
+        // Moving every bundle in v0's register to p2 by hand moves
+        // v0's operands there too.
+        let old = env.get_alloc(Inst::new(0), 0);
+        assert_ne!(old, Allocation::reg(p(2)));
+        for bundle in 0..env.bundles.len() {
+            let bundle = LiveBundleIndex::new(bundle);
+            if env.bundles[bundle].allocation == old {
+                env.bundles[bundle].allocation = Allocation::reg(p(2));
+            }
+        }
+        env.resolve_moves();
+        assert_eq!(env.get_alloc(Inst::new(0), 0), Allocation::reg(p(2)));
+        assert_eq!(env.get_alloc(Inst::new(2), 0), Allocation::reg(p(2)));
+    }
+}
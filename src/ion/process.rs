@@ -15,15 +15,15 @@
 use super::{
     spill_weight_from_constraint, Env, LiveBundleIndex, LiveBundleVec, LiveRangeFlag,
     LiveRangeIndex, LiveRangeKey, LiveRangeList, LiveRangeListEntry, PRegIndex, RegTraversalIter,
-    Requirement, SpillWeight, UseList, VRegIndex,
+    Requirement, SpillWeight, Use, UseList, VRegIndex,
 };
 use crate::{
     ion::data_structures::{
         CodeRange, BUNDLE_MAX_NORMAL_SPILL_WEIGHT, MAX_SPLITS_PER_SPILLSET,
         MINIMAL_BUNDLE_SPILL_WEIGHT, MINIMAL_FIXED_BUNDLE_SPILL_WEIGHT,
     },
-    Allocation, Function, FxHashSet, Inst, InstPosition, OperandConstraint, OperandKind, PReg,
-    ProgPoint, RegAllocError,
+    Allocation, Block, Function, FxHashSet, Inst, InstPosition, OperandConstraint, OperandKind,
+    PReg, PRegSet, ProgPoint, RegAllocError, RegClass,
 };
 use core::fmt::Debug;
 use smallvec::{smallvec, SmallVec};
@@ -75,114 +75,126 @@ impl<'a, F: Function> Env<'a, F> {
         // *overlap*, so we are checking whether the BTree contains
         // any preg range that *overlaps* with range `range`, not
         // literally the range `range`.
-        let bundle_ranges = &self.bundles[bundle].ranges;
-        let from_key = LiveRangeKey::from_range(&CodeRange {
-            from: bundle_ranges.first().unwrap().range.from,
-            to: bundle_ranges.first().unwrap().range.from,
-        });
-        let mut preg_range_iter = self.pregs[reg.index()]
-            .allocations
-            .btree
-            .range(from_key..)
-            .peekable();
-        trace!(
-            "alloc map for {:?} in range {:?}..: {:?}",
-            reg,
-            from_key,
-            self.pregs[reg.index()].allocations.btree
-        );
+        // A register pair also needs its high half: scan both
+        // registers' allocation maps for conflicts.
+        let preg = PReg::from_index(reg.index());
+        let mut scan_regs: SmallVec<[PRegIndex; 2]> = smallvec![reg];
+        if self.is_pair_bundle(bundle) {
+            let hi = self.pair_high(preg);
+            debug_assert!(hi.is_some());
+            scan_regs.extend(hi.map(|hi| PRegIndex::new(hi.index())));
+        }
         let mut first_conflict: Option<ProgPoint> = None;
+        for &scan_reg in &scan_regs {
+            let bundle_ranges = &self.bundles[bundle].ranges;
+            let from_key = LiveRangeKey::from_range(&CodeRange {
+                from: bundle_ranges.first().unwrap().range.from,
+                to: bundle_ranges.first().unwrap().range.from,
+            });
+            let mut preg_range_iter = self.pregs[scan_reg.index()]
+                .allocations
+                .btree
+                .range(from_key..)
+                .peekable();
+            trace!(
+                "alloc map for {:?} in range {:?}..: {:?}",
+                scan_reg,
+                from_key,
+                self.pregs[scan_reg.index()].allocations.btree
+            );
 
-        'ranges: for entry in bundle_ranges {
-            trace!(" -> range LR {:?}: {:?}", entry.index, entry.range);
-            let key = LiveRangeKey::from_range(&entry.range);
+            'ranges: for entry in bundle_ranges {
+                trace!(" -> range LR {:?}: {:?}", entry.index, entry.range);
+                let key = LiveRangeKey::from_range(&entry.range);
 
-            let mut skips = 0;
-            'alloc: loop {
-                trace!("  -> PReg range {:?}", preg_range_iter.peek());
+                let mut skips = 0;
+                'alloc: loop {
+                    trace!("  -> PReg range {:?}", preg_range_iter.peek());
 
-                // Advance our BTree traversal until it is >= this bundle
-                // range (i.e., skip PReg allocations in the BTree that
-                // are completely before this bundle range).
+                    // Advance our BTree traversal until it is >= this bundle
+                    // range (i.e., skip PReg allocations in the BTree that
+                    // are completely before this bundle range).
 
-                if preg_range_iter.peek().is_some() && *preg_range_iter.peek().unwrap().0 < key {
-                    trace!(
-                        "Skipping PReg range {:?}",
-                        preg_range_iter.peek().unwrap().0
-                    );
-                    preg_range_iter.next();
-                    skips += 1;
-                    if skips >= 16 {
-                        let from_pos = entry.range.from;
-                        let from_key = LiveRangeKey::from_range(&CodeRange {
-                            from: from_pos,
-                            to: from_pos,
-                        });
-                        preg_range_iter = self.pregs[reg.index()]
-                            .allocations
-                            .btree
-                            .range(from_key..)
-                            .peekable();
-                        skips = 0;
+                    if preg_range_iter.peek().is_some() && *preg_range_iter.peek().unwrap().0 < key
+                    {
+                        trace!(
+                            "Skipping PReg range {:?}",
+                            preg_range_iter.peek().unwrap().0
+                        );
+                        preg_range_iter.next();
+                        skips += 1;
+                        if skips >= 16 {
+                            let from_pos = entry.range.from;
+                            let from_key = LiveRangeKey::from_range(&CodeRange {
+                                from: from_pos,
+                                to: from_pos,
+                            });
+                            preg_range_iter = self.pregs[scan_reg.index()]
+                                .allocations
+                                .btree
+                                .range(from_key..)
+                                .peekable();
+                            skips = 0;
+                        }
+                        continue 'alloc;
                     }
-                    continue 'alloc;
-                }
-                skips = 0;
-
-                // If there are no more PReg allocations, we're done!
-                if preg_range_iter.peek().is_none() {
-                    trace!(" -> no more PReg allocations; so no conflict possible!");
-                    break 'ranges;
-                }
+                    skips = 0;
 
-                // If the current PReg range is beyond this range, there is no conflict; continue.
-                if *preg_range_iter.peek().unwrap().0 > key {
-                    trace!(
-                        " -> next PReg allocation is at {:?}; moving to next VReg range",
-                        preg_range_iter.peek().unwrap().0
-                    );
-                    break 'alloc;
-                }
+                    // If there are no more PReg allocations, we're done!
+                    if preg_range_iter.peek().is_none() {
+                        trace!(" -> no more PReg allocations; so no conflict possible!");
+                        break 'ranges;
+                    }
 
-                // Otherwise, there is a conflict.
-                let preg_key = *preg_range_iter.peek().unwrap().0;
-                debug_assert_eq!(preg_key, key); // Assert that this range overlaps.
-                let preg_range = preg_range_iter.next().unwrap().1;
-
-                trace!(" -> btree contains range {:?} that overlaps", preg_range);
-                if preg_range.is_valid() {
-                    trace!("   -> from vreg {:?}", self.ranges[*preg_range].vreg);
-                    // range from an allocated bundle: find the bundle and add to
-                    // conflicts list.
-                    let conflict_bundle = self.ranges[*preg_range].bundle;
-                    trace!("   -> conflict bundle {:?}", conflict_bundle);
-                    if self.conflict_set.insert(conflict_bundle) {
-                        conflicts.push(conflict_bundle);
-                        max_conflict_weight = core::cmp::max(
-                            max_conflict_weight,
-                            self.bundles[conflict_bundle].cached_spill_weight(),
+                    // If the current PReg range is beyond this range, there is no conflict; continue.
+                    if *preg_range_iter.peek().unwrap().0 > key {
+                        trace!(
+                            " -> next PReg allocation is at {:?}; moving to next VReg range",
+                            preg_range_iter.peek().unwrap().0
                         );
-                        if max_allowable_cost.is_some()
-                            && max_conflict_weight > max_allowable_cost.unwrap()
-                        {
-                            trace!("   -> reached high cost, retrying early");
-                            return AllocRegResult::ConflictHighCost;
-                        }
+                        break 'alloc;
                     }
 
-                    if first_conflict.is_none() {
-                        first_conflict = Some(ProgPoint::from_index(core::cmp::max(
-                            preg_key.from,
-                            key.from,
-                        )));
+                    // Otherwise, there is a conflict.
+                    let preg_key = *preg_range_iter.peek().unwrap().0;
+                    debug_assert_eq!(preg_key, key); // Assert that this range overlaps.
+                    let preg_range = preg_range_iter.next().unwrap().1;
+
+                    trace!(" -> btree contains range {:?} that overlaps", preg_range);
+                    if preg_range.is_valid() {
+                        trace!("   -> from vreg {:?}", self.ranges[*preg_range].vreg);
+                        // range from an allocated bundle: find the bundle and add to
+                        // conflicts list.
+                        let conflict_bundle = self.ranges[*preg_range].bundle;
+                        trace!("   -> conflict bundle {:?}", conflict_bundle);
+                        if self.conflict_set.insert(conflict_bundle) {
+                            conflicts.push(conflict_bundle);
+                            max_conflict_weight = core::cmp::max(
+                                max_conflict_weight,
+                                self.bundles[conflict_bundle].cached_spill_weight(),
+                            );
+                            if max_allowable_cost.is_some()
+                                && max_conflict_weight > max_allowable_cost.unwrap()
+                            {
+                                trace!("   -> reached high cost, retrying early");
+                                return AllocRegResult::ConflictHighCost;
+                            }
+                        }
+
+                        let conflict_point =
+                            ProgPoint::from_index(core::cmp::max(preg_key.from, key.from));
+                        first_conflict = Some(match first_conflict {
+                            Some(p) => core::cmp::min(p, conflict_point),
+                            None => conflict_point,
+                        });
+                    } else {
+                        trace!("   -> conflict with fixed reservation");
+                        // range from a direct use of the PReg (due to clobber).
+                        return AllocRegResult::ConflictWithFixed(
+                            max_conflict_weight,
+                            ProgPoint::from_index(preg_key.from),
+                        );
                     }
-                } else {
-                    trace!("   -> conflict with fixed reservation");
-                    // range from a direct use of the PReg (due to clobber).
-                    return AllocRegResult::ConflictWithFixed(
-                        max_conflict_weight,
-                        ProgPoint::from_index(preg_key.from),
-                    );
                 }
             }
         }
@@ -191,19 +203,27 @@ impl<'a, F: Function> Env<'a, F> {
             return AllocRegResult::Conflict(conflicts, first_conflict.unwrap());
         }
 
-        // We can allocate! Add our ranges to the preg's BTree.
-        let preg = PReg::from_index(reg.index());
+        // We can allocate! Add our ranges to the preg's BTree (and to
+        // the high half's, for a register pair).
         trace!("  -> bundle {:?} assigned to preg {:?}", bundle, preg);
         self.bundles[bundle].allocation = Allocation::reg(preg);
-        for entry in &self.bundles[bundle].ranges {
-            let key = LiveRangeKey::from_range(&entry.range);
-            let res = self.pregs[reg.index()]
-                .allocations
-                .btree
-                .insert(key, entry.index);
-
-            // We disallow LR overlap within bundles, so this should never be possible.
-            debug_assert!(res.is_none());
+        #[cfg(feature = "decision-log")]
+        self.decision_log.push(crate::Decision::Assign {
+            bundle: bundle.index(),
+            preg,
+        });
+        for &scan_reg in &scan_regs {
+            self.used_pregs.add(PReg::from_index(scan_reg.index()));
+            for entry in &self.bundles[bundle].ranges {
+                let key = LiveRangeKey::from_range(&entry.range);
+                let res = self.pregs[scan_reg.index()]
+                    .allocations
+                    .btree
+                    .insert(key, entry.index);
+
+                // We disallow LR overlap within bundles, so this should never be possible.
+                debug_assert!(res.is_none());
+            }
         }
 
         AllocRegResult::Allocated(Allocation::reg(preg))
@@ -225,14 +245,23 @@ impl<'a, F: Function> Env<'a, F> {
                 return;
             }
         };
-        let preg_idx = PRegIndex::new(preg.index());
+        #[cfg(feature = "decision-log")]
+        self.decision_log.push(crate::Decision::Evict {
+            bundle: bundle.index(),
+        });
+        let mut pregs: SmallVec<[PReg; 2]> = smallvec![preg];
+        if self.is_pair_bundle(bundle) {
+            pregs.extend(self.pair_high(preg));
+        }
         self.bundles[bundle].allocation = Allocation::none();
-        for entry in &self.bundles[bundle].ranges {
-            trace!(" -> removing LR {:?} from reg {:?}", entry.index, preg_idx);
-            self.pregs[preg_idx.index()]
-                .allocations
-                .btree
-                .remove(&LiveRangeKey::from_range(&entry.range));
+        for preg in pregs {
+            for entry in &self.bundles[bundle].ranges {
+                trace!(" -> removing LR {:?} from reg {:?}", entry.index, preg);
+                self.pregs[preg.index()]
+                    .allocations
+                    .btree
+                    .remove(&LiveRangeKey::from_range(&entry.range));
+            }
         }
         let prio = self.bundles[bundle].prio;
         trace!(" -> prio {}; back into queue", prio);
@@ -518,6 +547,12 @@ impl<'a, F: Function> Env<'a, F> {
             }
         }
 
+        #[cfg(feature = "decision-log")]
+        self.decision_log.push(crate::Decision::Split {
+            bundle: bundle.index(),
+            at: split_at,
+        });
+
         trace!(
             " -> last LR in old bundle: LR {:?}",
             self.bundles[bundle].ranges[last_lr_in_old_bundle_idx]
@@ -637,6 +672,7 @@ impl<'a, F: Function> Env<'a, F> {
                         to: end,
                     };
                     let empty_lr = self.ranges.add(range);
+                    self.ranges[empty_lr].vreg = vreg;
                     self.bundles[spill].ranges.push(LiveRangeListEntry {
                         range,
                         index: empty_lr,
@@ -702,6 +738,7 @@ impl<'a, F: Function> Env<'a, F> {
                         to: split,
                     };
                     let empty_lr = self.ranges.add(range);
+                    self.ranges[empty_lr].vreg = vreg;
                     self.bundles[spill].ranges.push(LiveRangeListEntry {
                         range,
                         index: empty_lr,
@@ -772,6 +809,10 @@ impl<'a, F: Function> Env<'a, F> {
     /// registers just at uses/defs and moves the "spilled" value
     /// into/out of them immediately.
     pub fn split_into_minimal_bundles(&mut self, bundle: LiveBundleIndex, reg_hint: PReg) {
+        #[cfg(feature = "decision-log")]
+        self.decision_log.push(crate::Decision::SplitMinimal {
+            bundle: bundle.index(),
+        });
         let mut removed_lrs: FxHashSet<LiveRangeIndex> = FxHashSet::default();
         let mut removed_lrs_vregs: FxHashSet<VRegIndex> = FxHashSet::default();
         let mut new_lrs: SmallVec<[(VRegIndex, LiveRangeIndex); 16]> = smallvec![];
@@ -782,6 +823,20 @@ impl<'a, F: Function> Env<'a, F> {
             .get_or_create_spill_bundle(bundle, /* create_if_absent = */ true)
             .unwrap();
 
+        // With `reload_once_per_block`, the first minimal split of a
+        // spillset keeps each run of register uses within a block in
+        // one bundle. If such a bundle has to be split again, the
+        // split counter is past the limit and we fall through to
+        // truly minimal bundles, so this always terminates.
+        let group_by_block = self.reload_once_per_block
+            && self.spillsets[spillset].splits == MAX_SPLITS_PER_SPILLSET;
+        if group_by_block {
+            self.spillsets[spillset].splits += 1;
+        }
+        // Splitting a grouped bundle again: the spill bundle already
+        // covers its range from the first split.
+        let regrouped = self.block_grouped_bundles.remove(&bundle);
+
         trace!(
             "Splitting bundle {:?} into minimal bundles with reg hint {}",
             bundle,
@@ -837,8 +892,13 @@ impl<'a, F: Function> Env<'a, F> {
                 }
 
                 // If we just created a LR for this inst at the last
-                // pos, add this use to the same LR.
-                if Some(u.pos.inst()) == last_inst && Some(vreg) == last_vreg {
+                // pos, add this use to the same LR. In a non-SSA
+                // function, a redef of the vreg in this inst belongs
+                // to a separate LR that starts after the last one.
+                if Some(u.pos.inst()) == last_inst
+                    && Some(vreg) == last_vreg
+                    && self.ranges[last_lr.unwrap()].range.contains_point(u.pos)
+                {
                     self.ranges[last_lr.unwrap()].uses.push(u);
                     trace!("    -> appended to last LR {:?}", last_lr.unwrap());
                     continue;
@@ -850,10 +910,48 @@ impl<'a, F: Function> Env<'a, F> {
                 // the After) of this inst.
                 let to = core::cmp::min(ProgPoint::before(u.pos.inst().next()), lr_to);
 
+                // When grouping by block, extend the previous use's LR
+                // from the same original LR over this use rather than
+                // reloading again, as long as we stay in one block.
+                let is_reg = |u: &Use| u.operand.constraint() == OperandConstraint::Reg;
+                let extendable = match (last_lr, last_inst) {
+                    (Some(lr), Some(inst)) => {
+                        Some(vreg) == last_vreg
+                            && is_reg(&u)
+                            && self.ranges[lr].uses.iter().all(is_reg)
+                            && self.ranges[lr].range.from >= lr_from
+                            && self.bundles[self.ranges[lr].bundle]
+                                .ranges
+                                .last()
+                                .map(|e| e.index)
+                                == Some(lr)
+                            && self.cfginfo.insn_block[u.pos.inst().index()]
+                                == self.cfginfo.insn_block[inst.index()]
+                    }
+                    _ => false,
+                };
+                if group_by_block && !is_def && extendable {
+                    let lr = last_lr.unwrap();
+                    self.ranges[lr].range.to = to;
+                    self.ranges[lr].uses.push(u);
+                    let last_bundle = last_bundle.unwrap();
+                    self.bundles[last_bundle]
+                        .ranges
+                        .last_mut()
+                        .unwrap()
+                        .range
+                        .to = to;
+                    self.block_grouped_bundles.insert(last_bundle);
+                    trace!("    -> extended last LR {:?} over this use", lr);
+                    last_live_pos = ProgPoint::before(u.pos.inst().next());
+                    last_inst = Some(u.pos.inst());
+                    continue;
+                }
+
                 // If the last bundle was at the same inst, add a new
                 // LR to the same bundle; otherwise, create a LR and a
                 // new bundle.
-                if Some(u.pos.inst()) == last_inst {
+                if Some(u.pos.inst()) == last_inst && Some(vreg) != last_vreg {
                     let cr = CodeRange { from: u.pos, to };
                     let lr = self.ranges.add(cr);
                     new_lrs.push((vreg, lr));
@@ -924,7 +1022,9 @@ impl<'a, F: Function> Env<'a, F> {
                 last_vreg = Some(vreg);
             }
 
-            if !spill_range.is_empty() {
+            if regrouped {
+                debug_assert!(spill_uses.is_empty());
+            } else if !spill_range.is_empty() {
                 // Make one entry in the spill bundle that covers the whole range.
                 // TODO: it might be worth tracking enough state to only create this LR when there is
                 // open space in the original LR.
@@ -980,6 +1080,146 @@ impl<'a, F: Function> Env<'a, F> {
         }
     }
 
+    /// The union of the soft clobbers of all instructions that the
+    /// bundle is live across.
+    fn bundle_soft_clobbers(&self, bundle: LiveBundleIndex) -> PRegSet {
+        let mut soft_clobbers = PRegSet::empty();
+        if self.soft_clobber_insts.is_empty() {
+            return soft_clobbers;
+        }
+        for entry in &self.bundles[bundle].ranges {
+            let start = self
+                .soft_clobber_insts
+                .partition_point(|&inst| inst < entry.range.from.inst());
+            for &inst in &self.soft_clobber_insts[start..] {
+                if ProgPoint::after(inst) >= entry.range.to {
+                    break;
+                }
+                if entry.range.from <= ProgPoint::before(inst) {
+                    soft_clobbers.union_from(self.func.inst_soft_clobbers(inst));
+                }
+            }
+        }
+        soft_clobbers
+    }
+
+    /// The registers of `class` that are still busy at `pos` with a
+    /// long-latency producer (see `Function::inst_latency`): the
+    /// instruction that wrote their last value before `pos` has not
+    /// finished by then, or, if the one at `pos` is itself slow, they
+    /// are written again before it finishes.
+    fn latency_busy_regs(&self, class: RegClass, pos: ProgPoint) -> PRegSet {
+        let mut busy = PRegSet::empty();
+        let key = LiveRangeKey::from_range(&CodeRange {
+            from: pos,
+            to: pos.next(),
+        });
+        let own_latency = self.func.inst_latency(pos.inst()) as usize;
+        let regs = self.env.preferred_regs_by_class[class as usize]
+            .iter()
+            .chain(&self.env.non_preferred_regs_by_class[class as usize]);
+        for &preg in regs {
+            let btree = &self.pregs[preg.index()].allocations.btree;
+            if let Some((prev, _)) = btree.range(..key).next_back() {
+                let producer = prev.to_range().from.inst();
+                let latency = self.func.inst_latency(producer) as usize;
+                if latency > 1 && pos.inst().index() < producer.index() + latency {
+                    busy.add(preg);
+                }
+            }
+            if own_latency > 1 {
+                let next = btree
+                    .range(key..)
+                    .take(2)
+                    .map(|(next, _)| next.to_range().from)
+                    .find(|&from| from > pos);
+                if let Some(from) = next {
+                    if from.inst().index() < pos.inst().index() + own_latency {
+                        busy.add(preg);
+                    }
+                }
+            }
+        }
+        busy
+    }
+
+    /// The first allocatable register among the preferences of the
+    /// bundle's operands (see `Function::operand_preferred_reg`), or
+    /// `PReg::invalid()`.
+    fn bundle_preferred_reg(&self, bundle: LiveBundleIndex, class: RegClass) -> PReg {
+        let class_regs = &self.env.preferred_regs_by_class[class as usize];
+        let other_regs = &self.env.non_preferred_regs_by_class[class as usize];
+        for entry in &self.bundles[bundle].ranges {
+            for u in &self.ranges[entry.index].uses {
+                // Instruction temps have no operand slot.
+                if u.slot as usize >= self.func.inst_operands(u.pos.inst()).len() {
+                    continue;
+                }
+                let preg = match self
+                    .func
+                    .operand_preferred_reg(u.pos.inst(), u.slot as usize)
+                {
+                    Some(preg) => preg,
+                    None => continue,
+                };
+                if class_regs.contains(&preg) || other_regs.contains(&preg) {
+                    return preg;
+                }
+            }
+        }
+        PReg::invalid()
+    }
+
+    /// For a bundle that lives in a register pair, the registers that
+    /// can hold the low half of a pair; `None` for other bundles.
+    pub fn pair_bases(&self, bundle: LiveBundleIndex, class: RegClass) -> Option<PRegSet> {
+        if !self.is_pair_bundle(bundle) {
+            return None;
+        }
+        let mut bases = PRegSet::empty();
+        for &preg in self.env.preferred_regs_by_class[class as usize]
+            .iter()
+            .chain(&self.env.non_preferred_regs_by_class[class as usize])
+        {
+            if self.pair_high(preg).is_some() {
+                bases.add(preg);
+            }
+        }
+        Some(bases)
+    }
+
+    /// Find the latest split hint (see `Function::split_hints`) for one
+    /// of the bundle's vregs that lies inside the bundle, after its
+    /// start, and no later than `limit`.
+    pub fn hinted_split_point(
+        &self,
+        bundle: LiveBundleIndex,
+        limit: ProgPoint,
+    ) -> Option<ProgPoint> {
+        let hints = self.func.split_hints();
+        if hints.is_empty() {
+            return None;
+        }
+        let start = self.bundles[bundle].ranges[0].range.from;
+        let mut best = None;
+        for entry in &self.bundles[bundle].ranges {
+            if entry.range.from > limit {
+                break;
+            }
+            let vreg = self.vreg(self.ranges[entry.index].vreg);
+            let first = hints.partition_point(|&hint| hint < (vreg, entry.range.from));
+            for &(hint_vreg, point) in &hints[first..] {
+                if hint_vreg != vreg || point >= entry.range.to || point > limit {
+                    break;
+                }
+                if point > start {
+                    best = core::cmp::max(best, Some(point));
+                }
+            }
+        }
+        best
+    }
+
     pub fn process_bundle(
         &mut self,
         bundle: LiveBundleIndex,
@@ -1039,6 +1279,21 @@ impl<'a, F: Function> Env<'a, F> {
             _ => {}
         }
 
+        let soft_clobbers = self.bundle_soft_clobbers(bundle);
+        let pair_bases = self.pair_bases(bundle, class);
+        // An operand's own preference is tried before the hint.
+        let preferred_reg = self.bundle_preferred_reg(bundle, class);
+        let (hint_reg, hint2_reg) = if preferred_reg != PReg::invalid() {
+            let hint2_reg = if hint_reg == preferred_reg {
+                PReg::invalid()
+            } else {
+                hint_reg
+            };
+            (preferred_reg, hint2_reg)
+        } else {
+            (hint_reg, PReg::invalid())
+        };
+
         // Try to allocate!
         let mut attempts = 0;
         loop {
@@ -1048,7 +1303,18 @@ impl<'a, F: Function> Env<'a, F> {
 
             let fixed_preg = match req {
                 Requirement::FixedReg(preg) | Requirement::FixedStack(preg) => Some(preg),
-                Requirement::Register => None,
+                Requirement::Register | Requirement::Group(_) => None,
+                Requirement::Stack => {
+                    // The bundle has to live in its spillslot; there
+                    // is no point in probing registers for it.
+                    self.spillsets[self.bundles[bundle].spillset].required = true;
+                    self.observe_spilled_bundle(bundle, /* forced_by_constraint = */ true);
+                    #[cfg(feature = "decision-log")]
+                    self.decision_log.push(crate::Decision::Spill {
+                        bundle: bundle.index(),
+                    });
+                    return Ok(());
+                }
 
                 Requirement::Any => {
                     self.spilled_bundles.push(bundle);
@@ -1076,15 +1342,41 @@ impl<'a, F: Function> Env<'a, F> {
                 .index()
                 + bundle.index();
 
+            // Registers soft-clobbered by instructions the bundle is
+            // live across are probed last, so they are only taken if
+            // nothing else is free. Under `minimize_regs`, registers
+            // already in use are probed before fresh ones within each
+            // of these two groups, and within each of those, registers
+            // still busy with a long-latency producer come last.
+            let (minimize_regs, used_pregs) = (self.minimize_regs, self.used_pregs);
+            let busy = if self.has_inst_latencies {
+                let start = self.ranges[self.bundles[bundle].ranges[0].index].range.from;
+                self.latency_busy_regs(class, start)
+            } else {
+                PRegSet::empty()
+            };
+            let rank = move |preg: PReg| {
+                let soft = soft_clobbers.contains(preg) as u8;
+                let fresh = (minimize_regs && !used_pregs.contains(preg)) as u8;
+                let busy = busy.contains(preg) as u8;
+                4 * soft + 2 * fresh + busy
+            };
+            let env = self.env;
+            let probe =
+                || RegTraversalIter::new(env, class, hint_reg, hint2_reg, scan_offset, fixed_preg);
+            let probe_order = (0..8)
+                .flat_map(|r| probe().filter(move |&preg| rank(preg) == r))
+                .filter(|&preg| match pair_bases {
+                    Some(bases) => bases.contains(preg),
+                    None => true,
+                })
+                .filter(move |&preg| match req {
+                    Requirement::Group(group) => group.contains(preg),
+                    _ => true,
+                });
+
             self.stats.process_bundle_reg_probe_start_any += 1;
-            for preg in RegTraversalIter::new(
-                self.env,
-                class,
-                hint_reg,
-                PReg::invalid(),
-                scan_offset,
-                fixed_preg,
-            ) {
+            for preg in probe_order {
                 self.stats.process_bundle_reg_probes_any += 1;
                 let preg_idx = PRegIndex::new(preg.index());
                 trace!("trying preg {:?}", preg_idx);
@@ -1120,12 +1412,13 @@ impl<'a, F: Function> Env<'a, F> {
                             lowest_cost_evict_conflict_set = Some(bundles);
                         }
 
-                        let loop_depth = self.cfginfo.approx_loop_depth
-                            [self.cfginfo.insn_block[first_conflict_point.inst().index()].index()];
+                        let block = self.cfginfo.insn_block[first_conflict_point.inst().index()];
+                        let loop_depth = self.cfginfo.approx_loop_depth[block.index()];
                         let move_cost = spill_weight_from_constraint(
                             OperandConstraint::Reg,
                             loop_depth as usize,
                             /* is_def = */ true,
+                            self.cfginfo.block_is_cold[block.index()],
                         )
                         .to_int();
                         if lowest_cost_split_conflict_cost.is_none()
@@ -1140,12 +1433,13 @@ impl<'a, F: Function> Env<'a, F> {
                     AllocRegResult::ConflictWithFixed(max_cost, point) => {
                         trace!(" -> conflict with fixed alloc; cost of other bundles up to point is {}, conflict at {:?}", max_cost, point);
 
-                        let loop_depth = self.cfginfo.approx_loop_depth
-                            [self.cfginfo.insn_block[point.inst().index()].index()];
+                        let block = self.cfginfo.insn_block[point.inst().index()];
+                        let loop_depth = self.cfginfo.approx_loop_depth[block.index()];
                         let move_cost = spill_weight_from_constraint(
                             OperandConstraint::Reg,
                             loop_depth as usize,
                             /* is_def = */ true,
+                            self.cfginfo.block_is_cold[block.index()],
                         )
                         .to_int();
 
@@ -1203,19 +1497,32 @@ impl<'a, F: Function> Env<'a, F> {
             {
                 if let Requirement::Register = req {
                     // Check if this is a too-many-live-registers situation.
-                    let range = self.bundles[bundle].ranges[0].range;
+                    // A minimal bundle spans at most one instruction,
+                    // but may consist of several ranges there.
+                    let ranges = &self.bundles[bundle].ranges;
+                    let range = CodeRange {
+                        from: ranges[0].range.from,
+                        to: ranges.last().unwrap().range.to,
+                    };
                     trace!("checking for too many live regs");
                     let mut min_bundles_assigned = 0;
                     let mut fixed_assigned = 0;
                     let mut total_regs = 0;
+                    let mut blocked = PRegSet::empty();
                     for preg in self.env.preferred_regs_by_class[class as u8 as usize]
                         .iter()
                         .chain(self.env.non_preferred_regs_by_class[class as u8 as usize].iter())
                     {
                         trace!(" -> PR {:?}", preg);
+                        // Nothing comes before the first point.
+                        let before = if range.from.to_index() == 0 {
+                            range.from
+                        } else {
+                            range.from.prev()
+                        };
                         let start = LiveRangeKey::from_range(&CodeRange {
-                            from: range.from.prev(),
-                            to: range.from.prev(),
+                            from: before,
+                            to: before,
                         });
                         for (key, lr) in self.pregs[preg.index()].allocations.btree.range(start..) {
                             let preg_range = key.to_range();
@@ -1229,12 +1536,14 @@ impl<'a, F: Function> Env<'a, F> {
                                 if self.minimal_bundle(self.ranges[*lr].bundle) {
                                     trace!("  -> min bundle {:?}", lr);
                                     min_bundles_assigned += 1;
+                                    blocked.add(*preg);
                                 } else {
                                     trace!("  -> non-min bundle {:?}", lr);
                                 }
                             } else {
                                 trace!("  -> fixed bundle");
                                 fixed_assigned += 1;
+                                blocked.add(*preg);
                             }
                         }
                         total_regs += 1;
@@ -1245,12 +1554,30 @@ impl<'a, F: Function> Env<'a, F> {
                         fixed_assigned,
                         min_bundles_assigned
                     );
-                    if min_bundles_assigned + fixed_assigned >= total_regs {
+                    // A register pair also needs both halves of one
+                    // aligned pair to be free.
+                    let no_free_pair = self.is_pair_bundle(bundle)
+                        && !self.env.preferred_regs_by_class[class as u8 as usize]
+                            .iter()
+                            .chain(
+                                self.env.non_preferred_regs_by_class[class as u8 as usize].iter(),
+                            )
+                            .filter_map(|&lo| Some((lo, self.pair_high(lo)?)))
+                            .any(|(lo, hi)| !blocked.contains(lo) && !blocked.contains(hi));
+                    if min_bundles_assigned + fixed_assigned >= total_regs || no_free_pair {
                         return Err(RegAllocError::TooManyLiveRegs);
                     }
                 }
 
-                panic!("Could not allocate minimal bundle, but the allocation problem should be possible to solve");
+                // The check above should have found the pressure
+                // unsatisfiable; if it did not, this is a bug, but
+                // release builds still report the bundle as one that
+                // cannot be placed.
+                debug_assert!(
+                    false,
+                    "Could not allocate minimal bundle, but the allocation problem should be possible to solve"
+                );
+                return Err(RegAllocError::TooManyLiveRegs);
             }
 
             // If our bundle's weight is less than or equal to(*) the
@@ -1298,6 +1625,12 @@ impl<'a, F: Function> Env<'a, F> {
                     }
                 }
 
+                // A split hint from the frontend takes precedence.
+                if let Some(hint) = self.hinted_split_point(bundle, split_at_point) {
+                    trace!(" -> using split hint {:?}", hint);
+                    split_at_point = hint;
+                }
+
                 self.split_and_requeue_bundle(
                     bundle,
                     split_at_point,
@@ -1316,4 +1649,616 @@ impl<'a, F: Function> Env<'a, F> {
             }
         }
     }
+
+    /// The bundle holding `vreg` at `pos`, if the vreg is live there.
+    /// Requires sorted vreg range lists (see `sort_vreg_ranges`).
+    fn vreg_bundle_at(&self, vreg: VRegIndex, pos: ProgPoint) -> Option<LiveBundleIndex> {
+        let ranges = &self.vregs[vreg].ranges;
+        let i = ranges.partition_point(|entry| entry.range.to <= pos);
+        ranges
+            .get(i)
+            .filter(|entry| entry.range.contains_point(pos))
+            .map(|entry| self.ranges[entry.index].bundle)
+    }
+
+    /// The registers of the values that flow into or out of the
+    /// bundle's ranges, other than through the bundle itself: the
+    /// adjacent ranges of the same vreg, the same vreg across CFG
+    /// edges, and the blockparam args and params on either side of an
+    /// edge. Each entry is a move that is elided if the bundle lives
+    /// in that register.
+    fn bundle_neighbor_regs(&self, bundle: LiveBundleIndex) -> SmallVec<[PReg; 8]> {
+        let mut regs = smallvec![];
+        let mut push = |neighbor: Option<LiveBundleIndex>| {
+            if let Some(neighbor) = neighbor.filter(|&b| b != bundle) {
+                regs.extend(self.bundles[neighbor].allocation.as_reg());
+            }
+        };
+        for entry in &self.bundles[bundle].ranges {
+            let vreg = self.ranges[entry.index].vreg;
+
+            let from = entry.range.from;
+            let block = self.cfginfo.insn_block[from.inst().index()];
+            if from != self.cfginfo.block_entry[block.index()] {
+                push(self.vreg_bundle_at(vreg, from.prev()));
+            } else if self.liveins[block.index()].get(vreg.index()) {
+                for &pred in self.func.block_preds(block) {
+                    push(self.vreg_bundle_at(vreg, self.cfginfo.block_exit[pred.index()]));
+                }
+            } else {
+                let outs = &self.blockparam_outs_by_dest;
+                let first =
+                    outs.partition_point(|&i| self.blockparam_outs[i as usize].to_vreg < vreg);
+                for &i in &outs[first..] {
+                    let out = &self.blockparam_outs[i as usize];
+                    if out.to_vreg != vreg {
+                        break;
+                    }
+                    let exit = self.cfginfo.block_exit[out.from_block.index()];
+                    push(self.vreg_bundle_at(out.from_vreg, exit));
+                }
+            }
+
+            let last = entry.range.to.prev();
+            let block = self.cfginfo.insn_block[last.inst().index()];
+            if last != self.cfginfo.block_exit[block.index()] {
+                push(self.vreg_bundle_at(vreg, entry.range.to));
+                continue;
+            }
+            for &succ in self.func.block_succs(block) {
+                if self.liveins[succ.index()].get(vreg.index()) {
+                    push(self.vreg_bundle_at(vreg, self.cfginfo.block_entry[succ.index()]));
+                }
+            }
+            let first = self
+                .blockparam_outs
+                .partition_point(|out| (out.from_vreg, out.from_block) < (vreg, block));
+            for out in &self.blockparam_outs[first..] {
+                if (out.from_vreg, out.from_block) != (vreg, block) {
+                    break;
+                }
+                let entry = self.cfginfo.block_entry[out.to_block.index()];
+                push(self.vreg_bundle_at(out.to_vreg, entry));
+            }
+        }
+        regs
+    }
+
+    /// Whether `bundle` could live in `preg` instead of its current
+    /// register without conflicting with anything allocated so far.
+    fn can_recolor(&self, bundle: LiveBundleIndex, preg: PReg) -> bool {
+        let class = preg.class() as usize;
+        let allocatable = self.env.preferred_regs_by_class[class]
+            .iter()
+            .chain(&self.env.non_preferred_regs_by_class[class])
+            .any(|&p| p == preg);
+        if !allocatable || self.bundle_soft_clobbers(bundle).contains(preg) {
+            return false;
+        }
+        let btree = &self.pregs[preg.index()].allocations.btree;
+        self.bundles[bundle]
+            .ranges
+            .iter()
+            .all(|entry| !btree.contains_key(&LiveRangeKey::from_range(&entry.range)))
+    }
+
+    /// Once every bundle has its register, move blockparam bundles to
+    /// the register that most of the values flowing into and out of
+    /// them already occupy, if it is free over the whole bundle. At
+    /// the join of a diamond whose arms both produce the value in the
+    /// same register, this elides the move on each incoming edge.
+    pub fn recolor_blockparams(&mut self) {
+        let mut last = None;
+        for i in 0..self.blockparam_outs_by_dest.len() {
+            let out = self.blockparam_outs[self.blockparam_outs_by_dest[i] as usize];
+            if last == Some(out.to_vreg) {
+                continue;
+            }
+            last = Some(out.to_vreg);
+            let entry = self.cfginfo.block_entry[out.to_block.index()];
+            if let Some(bundle) = self.vreg_bundle_at(out.to_vreg, entry) {
+                self.recolor_bundle(bundle);
+            }
+        }
+    }
+
+    /// The register of `bundle` if it may be moved to another one.
+    fn recolorable_reg(&self, bundle: LiveBundleIndex) -> Option<PReg> {
+        let cur = self.bundles[bundle].allocation.as_reg()?;
+        if self.is_pair_bundle(bundle) {
+            return None;
+        }
+        match self.compute_requirement(bundle) {
+            Ok(Requirement::Register) | Ok(Requirement::Any) => Some(cur),
+            _ => None,
+        }
+    }
+
+    fn recolor_bundle(&mut self, bundle: LiveBundleIndex) {
+        let cur = match self.recolorable_reg(bundle) {
+            Some(preg) => preg,
+            None => return,
+        };
+
+        let neighbors = self.bundle_neighbor_regs(bundle);
+        let count = |preg: PReg| neighbors.iter().filter(|&&p| p == preg).count();
+        let mut best = (cur, count(cur));
+        for &preg in &neighbors {
+            let n = count(preg);
+            if n > best.1 && self.can_recolor(bundle, preg) {
+                best = (preg, n);
+            }
+        }
+        let preg = best.0;
+        if preg == cur {
+            return;
+        }
+        self.stats.blockparam_recolors += 1;
+        self.move_bundle_reg(bundle, cur, preg);
+    }
+
+    fn move_bundle_reg(&mut self, bundle: LiveBundleIndex, cur: PReg, preg: PReg) {
+        trace!(
+            "recoloring bundle{} from {} to {}",
+            bundle.index(),
+            cur,
+            preg
+        );
+        #[cfg(feature = "decision-log")]
+        self.decision_log.push(crate::Decision::Recolor {
+            bundle: bundle.index(),
+            from: cur,
+            to: preg,
+        });
+        for entry in &self.bundles[bundle].ranges {
+            let key = LiveRangeKey::from_range(&entry.range);
+            self.pregs[cur.index()].allocations.btree.remove(&key);
+            let res = self.pregs[preg.index()]
+                .allocations
+                .btree
+                .insert(key, entry.index);
+            debug_assert!(res.is_none());
+        }
+        self.bundles[bundle].allocation = Allocation::reg(preg);
+    }
+
+    /// Once every bundle has its register, line up the values that
+    /// loops carry around: on each back edge, where the value leaving
+    /// the latch and the one entering the header (a blockparam and its
+    /// arg, or a vreg live through the loop) are in different
+    /// registers, move the header's bundle into the latch's register,
+    /// or else the other way round. This is done if the register is
+    /// free over the whole bundle and at least as many of the bundle's
+    /// other neighbors are in it as in its current one: unlike
+    /// `recolor_blockparams`, ties go to the back edge, whose move
+    /// would run on every iteration.
+    pub fn recolor_loop_carried(&mut self) {
+        let mut carried: SmallVec<[(VRegIndex, Block, VRegIndex, Block); 8]> = smallvec![];
+        for out in &self.blockparam_outs {
+            if self.cfginfo.dominates(out.to_block, out.from_block) {
+                carried.push((out.from_vreg, out.from_block, out.to_vreg, out.to_block));
+            }
+        }
+        for latch in 0..self.func.num_blocks() {
+            let latch = Block::new(latch);
+            for &header in self.func.block_succs(latch) {
+                if self.cfginfo.dominates(header, latch) {
+                    for vreg in self.liveins[header.index()].iter() {
+                        let vreg = VRegIndex::new(vreg);
+                        carried.push((vreg, latch, vreg, header));
+                    }
+                }
+            }
+        }
+
+        for (from_vreg, latch, to_vreg, header) in carried {
+            let exit = self.cfginfo.block_exit[latch.index()];
+            let entry = self.cfginfo.block_entry[header.index()];
+            let (from, to) = match (
+                self.vreg_bundle_at(from_vreg, exit),
+                self.vreg_bundle_at(to_vreg, entry),
+            ) {
+                (Some(from), Some(to)) if from != to => (from, to),
+                _ => continue,
+            };
+            let (from_reg, to_reg) = match (self.recolorable_reg(from), self.recolorable_reg(to)) {
+                (Some(from_reg), Some(to_reg)) if from_reg != to_reg => (from_reg, to_reg),
+                _ => continue,
+            };
+            for (bundle, cur, preg) in [(to, to_reg, from_reg), (from, from_reg, to_reg)] {
+                let neighbors = self.bundle_neighbor_regs(bundle);
+                let count = |preg: PReg| neighbors.iter().filter(|&&p| p == preg).count();
+                if count(preg) >= count(cur) && self.can_recolor(bundle, preg) {
+                    self.stats.loop_carried_recolors += 1;
+                    self.move_bundle_reg(bundle, cur, preg);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{int_env, move_of, run_and_check, run_and_check_with, vreg, TestFunc};
+    use crate::{
+        Allocation, Block, Function, Inst, Operand, OperandConstraint, OperandKind, OperandPos,
+        PReg, PRegSet, ProgPoint, RegAllocError, RegClass, RegallocOptions,
+    };
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    /// Two values are live out of the entry block but only one
+    /// register is available; each is used in only one of the two
+    /// successors. The spill and reload should land in whichever
+    /// successor is cold.
+    fn spill_into_cold(cold: usize) {
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::any_def(vreg(0))]);
+        f.op(&[Operand::any_def(vreg(1))]);
+        f.branch(&[(Block::new(1), &[]), (Block::new(2), &[])]);
+        for v in 0..2 {
+            let block = f.block(&[]);
+            f.op(&[Operand::reg_use(vreg(v))]);
+            f.ret(&[]);
+            if block.index() == cold {
+                f.set_cold(block);
+            }
+        }
+
+        let out = run_and_check(&f, &int_env(1));
+        let hot = f.block_insns(Block::new(3 - cold));
+        assert!(out
+            .edits
+            .iter()
+            .all(|(pos, _)| pos.inst() < hot.first() || pos.inst() > hot.last()));
+    }
+
+    #[test]
+    fn test_spill_into_cold_block() {
+        spill_into_cold(1);
+        spill_into_cold(2);
+    }
+
+    fn soft_clobber_func() -> TestFunc {
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        let call = f.op(&[]);
+        f.set_soft_clobbers(call, PRegSet::empty().with(PReg::new(0, RegClass::Int)));
+        f.ret(&[Operand::reg_use(vreg(0))]);
+        f
+    }
+
+    #[test]
+    fn test_soft_clobber_avoided() {
+        let f = soft_clobber_func();
+        let out = run_and_check(&f, &int_env(2));
+        assert_eq!(
+            out.inst_allocs(Inst::new(0)),
+            [Allocation::reg(PReg::new(1, RegClass::Int))]
+        );
+    }
+
+    #[test]
+    fn test_soft_clobber_kept_without_pressure() {
+        // The only register is soft-clobbered, but nothing else wants
+        // it, so the value stays there instead of being spilled.
+        let f = soft_clobber_func();
+        let out = run_and_check(&f, &int_env(1));
+        assert_eq!(out.num_spillslots, 0);
+        assert!(out.edits.is_empty());
+    }
+
+    #[test]
+    fn test_spilled_value_kept_in_reg_between_calls() {
+        // `v0` lives across two calls that clobber every register and
+        // is used only through `Any` operands. The segments between
+        // the calls should still get a register, leaving only the
+        // call-crossing parts in the spillslot.
+        let all = PRegSet::empty()
+            .with(PReg::new(0, RegClass::Int))
+            .with(PReg::new(1, RegClass::Int));
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_def(vreg(1))]);
+        for _ in 0..3 {
+            f.op(&[Operand::any_use(vreg(0))]);
+        }
+        f.op_with_clobbers(&[], all);
+        for _ in 0..3 {
+            f.op(&[Operand::any_use(vreg(0))]);
+        }
+        f.op_with_clobbers(&[], all);
+        for _ in 0..3 {
+            f.op(&[Operand::any_use(vreg(0))]);
+        }
+        f.ret(&[]);
+
+        let out = run_and_check(&f, &int_env(2));
+        for inst in (6..9).chain(10..13) {
+            assert!(out.inst_allocs(Inst::new(inst))[0].is_reg());
+        }
+        let stores = out
+            .edits
+            .iter()
+            .filter(|(_, edit)| move_of(edit).1.is_stack())
+            .count();
+        assert_eq!(stores, 1);
+    }
+
+    #[test]
+    fn test_reload_once_per_block() {
+        // `v0` is forced onto the stack by conflicting fixed uses and
+        // a call, then read five times in one block between short-lived
+        // temporaries that compete for the same two registers.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_fixed_def(vreg(0), p(0))]);
+        f.op(&[Operand::reg_fixed_use(vreg(0), p(1))]);
+        f.op(&[Operand::reg_fixed_use(vreg(0), p(0))]);
+        f.op(&[Operand::reg_fixed_use(vreg(0), p(1))]);
+        f.op_with_clobbers(&[], PRegSet::empty().with(p(0)).with(p(1)));
+        for i in 1..6 {
+            f.op(&[Operand::reg_use(vreg(0))]);
+            f.op(&[Operand::reg_def(vreg(i))]);
+            f.op(&[]);
+            f.op(&[Operand::reg_use(vreg(i))]);
+        }
+        f.ret(&[]);
+
+        let reloads = |once| {
+            let options = RegallocOptions {
+                reload_once_per_block: once,
+                ..RegallocOptions::default()
+            };
+            let out = run_and_check_with(&f, &int_env(2), &options);
+            out.edits
+                .iter()
+                .filter(|(_, edit)| {
+                    let (from, to) = move_of(edit);
+                    from.is_stack() && to.is_reg()
+                })
+                .count()
+        };
+        assert!(reloads(false) > 1);
+        assert_eq!(reloads(true), 1);
+    }
+
+    #[test]
+    fn test_preferred_regs() {
+        // v0 and v1 each prefer a register that is free, and get it.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        let def0 = f.op(&[Operand::reg_def(vreg(0))]);
+        let def1 = f.op(&[Operand::reg_def(vreg(1))]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+        f.set_preferred_reg(def0, 0, p(3));
+        f.set_preferred_reg(def1, 0, p(2));
+        let out = run_and_check(&f, &int_env(4));
+        assert_eq!(out.inst_allocs(def0)[0], Allocation::reg(p(3)));
+        assert_eq!(out.inst_allocs(def1)[0], Allocation::reg(p(2)));
+
+        // v1 prefers p3, which v0 is fixed to; it goes elsewhere
+        // rather than being moved.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_fixed_def(vreg(0), p(3))]);
+        let def1 = f.op(&[Operand::reg_def(vreg(1))]);
+        f.ret(&[Operand::reg_use(vreg(0)), Operand::reg_use(vreg(1))]);
+        f.set_preferred_reg(def1, 0, p(3));
+        let out = run_and_check(&f, &int_env(4));
+        assert_ne!(out.inst_allocs(def1)[0], Allocation::reg(p(3)));
+        assert!(out.edits.is_empty());
+    }
+
+    #[test]
+    fn test_split_hints() {
+        // `v0` is defined into the only register and must give it up
+        // to `v1` at inst 5. By default it stays in the register up to
+        // the conflict; a hint before its `Any` uses moves the spill
+        // there instead.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        for _ in 0..3 {
+            f.op(&[Operand::any_use(vreg(0))]);
+        }
+        f.op(&[Operand::reg_def(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(1))]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        f.ret(&[]);
+
+        let spill_at = |f: &TestFunc| {
+            let out = run_and_check(f, &int_env(1));
+            let stores: Vec<_> = out
+                .edits
+                .iter()
+                .filter(|(_, edit)| {
+                    let (from, to) = move_of(edit);
+                    from.is_reg() && to.is_stack()
+                })
+                .map(|&(pos, _)| pos)
+                .collect();
+            assert_eq!(stores.len(), 1);
+            (stores[0], out.inst_allocs(Inst::new(2))[0])
+        };
+        let (default_spill, alloc) = spill_at(&f);
+        assert_eq!(default_spill, ProgPoint::before(Inst::new(5)));
+        assert!(alloc.is_reg());
+
+        // Hints outside the range being split are ignored.
+        f.set_split_hints(vec![
+            (vreg(0), ProgPoint::before(Inst::new(0))),
+            (vreg(0), ProgPoint::before(Inst::new(7))),
+            (vreg(1), ProgPoint::before(Inst::new(2))),
+        ]);
+        assert_eq!(spill_at(&f).0, default_spill);
+
+        f.set_split_hints(vec![(vreg(0), ProgPoint::before(Inst::new(2)))]);
+        let (spill, alloc) = spill_at(&f);
+        assert_eq!(spill, ProgPoint::before(Inst::new(2)));
+        assert!(alloc.is_stack());
+    }
+
+    #[test]
+    fn test_blockparam_recolored_at_join() {
+        // Both arms of a diamond produce their value in p1, but the
+        // join param is later needed in p2, which is busy until then.
+        // The param should stay in p1 so the edges need no moves.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_fixed_def(vreg(3), p(2))]);
+        f.branch(&[(Block::new(1), &[]), (Block::new(2), &[])]);
+        for v in 1..3 {
+            f.block(&[]);
+            f.op(&[Operand::reg_def(vreg(v))]);
+            f.op(&[Operand::reg_fixed_use(vreg(v), p(1))]);
+            f.branch(&[(Block::new(3), &[vreg(v)])]);
+        }
+        f.block(&[vreg(5)]);
+        let join = f.op(&[Operand::reg_use(vreg(5))]);
+        f.op(&[Operand::reg_fixed_use(vreg(3), p(2))]);
+        f.op(&[Operand::reg_fixed_use(vreg(5), p(2))]);
+        f.ret(&[]);
+
+        let out = run_and_check(&f, &int_env(3));
+        assert_eq!(out.inst_allocs(join), &[Allocation::reg(p(1))]);
+        assert_eq!(out.edits.len(), 1);
+        assert_eq!(out.stats.blockparam_recolors, 1);
+    }
+
+    #[test]
+    fn test_loop_carried_recolored() {
+        // A counted-loop shape: the header's param is dead on entry
+        // and the latch passes back a value computed in the body,
+        // with `v9` live around the loop. Without lining up the
+        // carried value the back edge needs a move every iteration.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0)), Operand::reg_def(vreg(9))]);
+        f.branch(&[(Block::new(1), &[vreg(0)])]);
+        f.block(&[vreg(1)]);
+        f.op(&[Operand::reg_use(vreg(9)), Operand::reg_def(vreg(10))]);
+        f.op(&[Operand::reg_use(vreg(10)), Operand::reg_def(vreg(11))]);
+        f.op(&[Operand::reg_use(vreg(10)), Operand::reg_def(vreg(12))]);
+        f.op(&[
+            Operand::reg_use(vreg(9)),
+            Operand::reg_use(vreg(10)),
+            Operand::reg_use(vreg(11)),
+        ]);
+        f.branch(&[(Block::new(2), &[]), (Block::new(3), &[])]);
+        f.block(&[]);
+        let latch = f.branch(&[(Block::new(1), &[vreg(12)])]);
+        f.block(&[]);
+        f.ret(&[Operand::reg_use(vreg(9))]);
+
+        let out = run_and_check(&f, &int_env(3));
+        assert_eq!(out.stats.loop_carried_recolors, 1);
+        let header = f.block_insns(Block::new(1)).first();
+        assert!(out
+            .edits
+            .iter()
+            .all(|(pos, _)| pos.inst() != latch && pos.inst() != header));
+    }
+
+    #[test]
+    fn test_minimize_regs() {
+        // A chain of short-lived values, each used by the
+        // instruction defining the next, so that a single register
+        // can hold all of them.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        for v in 1..12 {
+            f.op(&[Operand::reg_use(vreg(v - 1)), Operand::reg_def(vreg(v))]);
+        }
+        f.ret(&[Operand::reg_use(vreg(11))]);
+
+        let out = run_and_check(&f, &int_env(8));
+        assert!(out.stats.regs_used > 1);
+
+        let options = RegallocOptions {
+            minimize_regs: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_and_check_with(&f, &int_env(8), &options);
+        assert_eq!(out.stats.regs_used, 1);
+        assert!(out.edits.is_empty());
+    }
+
+    #[test]
+    fn test_inst_latency() {
+        // v0 comes from a slow instruction and dies right away; v1 is
+        // defined next. Both are hinted into p0, but with the latency
+        // declared, p0 is still busy with v0's producer when v1 is
+        // written, so they only share it if nothing else is free.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        let slow = f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_use(vreg(0))]);
+        let def = f.op(&[Operand::reg_def(vreg(1))]);
+        f.ret(&[Operand::reg_use(vreg(1))]);
+        f.set_allocation_hints(&[(vreg(0), p(0)), (vreg(1), p(0))]);
+
+        let out = run_and_check(&f, &int_env(4));
+        assert_eq!(out.inst_allocs(slow), out.inst_allocs(def));
+
+        f.set_latency(slow, 4);
+        let out = run_and_check(&f, &int_env(4));
+        assert_ne!(out.inst_allocs(slow), out.inst_allocs(def));
+        let out = run_and_check(&f, &int_env(1));
+        assert_eq!(out.inst_allocs(slow), out.inst_allocs(def));
+    }
+
+    #[test]
+    fn test_too_many_live_regs_for_pair() {
+        // Only one aligned pair exists, and v0 occupies half of it
+        // where the pair v1 is defined: the pair cannot be placed
+        // although a register is free, which is an error rather than
+        // an internal failure.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.op(&[Operand::reg_pair_def(vreg(1)), Operand::reg_use(vreg(0))]);
+        f.op(&[Operand::reg_pair_use(vreg(1)), Operand::reg_use(vreg(0))]);
+        f.ret(&[]);
+        assert!(matches!(
+            crate::run(&f, &int_env(2), &RegallocOptions::default()),
+            Err(RegAllocError::TooManyLiveRegs)
+        ));
+    }
+
+    #[test]
+    fn test_too_many_live_regs_at_entry() {
+        // The first instruction defines three values early with only
+        // two registers, so the check starts at the very first point.
+        let early_def = |v| {
+            Operand::new(
+                vreg(v),
+                OperandConstraint::Reg,
+                OperandKind::Def,
+                OperandPos::Early,
+            )
+        };
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[early_def(0), early_def(1), early_def(2)]);
+        f.ret(&[
+            Operand::reg_use(vreg(0)),
+            Operand::reg_use(vreg(1)),
+            Operand::reg_use(vreg(2)),
+        ]);
+        assert!(matches!(
+            crate::run(&f, &int_env(2), &RegallocOptions::default()),
+            Err(RegAllocError::TooManyLiveRegs)
+        ));
+    }
 }
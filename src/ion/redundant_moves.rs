@@ -1,6 +1,8 @@
 //! Redundant-move elimination.
 
-use crate::{Allocation, FxHashMap, VReg};
+use crate::{Allocation, Block, FxHashMap, FxHashSet, Inst, VReg};
+use alloc::vec;
+use alloc::vec::Vec;
 use smallvec::{smallvec, SmallVec};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -127,3 +129,99 @@ impl RedundantMoveEliminator {
         self.allocs.remove(&alloc);
     }
 }
+
+/// Tracks which vreg each of a set of spillslots holds, across
+/// blocks, so that a store of a vreg to a slot that already holds it
+/// on every path (e.g. when several spilled pieces of the vreg are
+/// separated by a reload) can be elided. A block's entry state is
+/// what its predecessors agree on at their exits; predecessors not
+/// yet visited, i.e. back edges, agree on nothing.
+#[derive(Clone, Debug)]
+pub struct RedundantStoreEliminator {
+    /// The slots tracked; writes to others are ignored.
+    slots: FxHashSet<Allocation>,
+    /// The vreg each tracked slot holds at the current point.
+    contents: FxHashMap<Allocation, VReg>,
+    /// `contents` at the exit of each block visited so far.
+    block_exits: Vec<Option<FxHashMap<Allocation, VReg>>>,
+    /// The block of the current point.
+    pub block: Block,
+    /// The first instruction whose effects are not applied yet.
+    pub next_inst: Inst,
+}
+
+impl RedundantStoreEliminator {
+    pub fn new(slots: FxHashSet<Allocation>, num_blocks: usize) -> Self {
+        Self {
+            slots,
+            contents: FxHashMap::default(),
+            block_exits: vec![None; num_blocks],
+            block: Block::new(0),
+            next_inst: Inst::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.slots.is_empty()
+    }
+
+    /// Move on to `block`, which follows the current block in layout
+    /// order, with predecessors `preds` and blockparams `params`.
+    pub fn enter_block(&mut self, block: Block, preds: &[Block], params: &[VReg]) {
+        let exit = core::mem::take(&mut self.contents);
+        self.block_exits[self.block.index()] = Some(exit);
+        self.block = block;
+        let mut entry: Option<FxHashMap<Allocation, VReg>> = None;
+        for (i, pred) in preds.iter().enumerate() {
+            match self.block_exits[pred.index()].as_ref() {
+                None => {
+                    entry = None;
+                    break;
+                }
+                Some(exit) if i == 0 => entry = Some(exit.clone()),
+                Some(exit) => {
+                    if let Some(entry) = entry.as_mut() {
+                        entry.retain(|slot, vreg| exit.get(slot) == Some(vreg));
+                    }
+                }
+            }
+        }
+        self.contents = entry.unwrap_or_default();
+        for &param in params {
+            self.redefine(param);
+        }
+    }
+
+    /// Record that `slot` now holds `vreg`, or something unknown.
+    pub fn write(&mut self, slot: Allocation, vreg: Option<VReg>) {
+        if !self.slots.contains(&slot) {
+            return;
+        }
+        match vreg {
+            Some(vreg) => self.contents.insert(slot, vreg),
+            None => self.contents.remove(&slot),
+        };
+    }
+
+    /// Forget what every slot holds.
+    pub fn clear(&mut self) {
+        self.contents.clear();
+    }
+
+    /// Record that `vreg` has a new value, which no slot holds yet.
+    pub fn redefine(&mut self, vreg: VReg) {
+        if !self.contents.is_empty() {
+            self.contents.retain(|_, v| *v != vreg);
+        }
+    }
+
+    /// Process a move of `vreg` into `to`, and return whether it is
+    /// a store that can be elided because `to` already holds `vreg`.
+    pub fn process_move(&mut self, to: Allocation, vreg: Option<VReg>) -> bool {
+        if vreg.is_some() && self.contents.get(&to) == vreg.as_ref() {
+            return true;
+        }
+        self.write(to, vreg);
+        false
+    }
+}
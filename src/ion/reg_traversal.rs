@@ -13,6 +13,10 @@ use crate::{MachineEnv, PReg, RegClass};
 ///   prferred registers; then, non-preferred registers. (In normal
 ///   usage, these consist of caller-save and callee-save registers
 ///   respectively, to minimize clobber-saves; but they need not.)
+///
+/// If the environment gives an explicit probe order for the class,
+/// that order replaces the offset-based scan entirely (hints are
+/// still tried first).
 
 pub struct RegTraversalIter<'a> {
     env: &'a MachineEnv,
@@ -23,6 +27,8 @@ pub struct RegTraversalIter<'a> {
     non_pref_idx: usize,
     offset_pref: usize,
     offset_non_pref: usize,
+    ordered: Option<&'a [PReg]>,
+    ordered_idx: usize,
     is_fixed: bool,
     fixed: Option<PReg>,
 }
@@ -72,6 +78,8 @@ impl<'a> RegTraversalIter<'a> {
             non_pref_idx: 0,
             offset_pref,
             offset_non_pref,
+            ordered: env.probe_order_by_class[class].as_deref(),
+            ordered_idx: 0,
             is_fixed: fixed.is_some(),
             fixed,
         }
@@ -100,6 +108,17 @@ impl<'a> core::iter::Iterator for RegTraversalIter<'a> {
             self.hint_idx += 1;
             return h;
         }
+        if let Some(arr) = self.ordered {
+            while self.ordered_idx < arr.len() {
+                let r = arr[self.ordered_idx];
+                self.ordered_idx += 1;
+                if Some(r) == self.hints[0] || Some(r) == self.hints[1] {
+                    continue;
+                }
+                return Some(r);
+            }
+            return None;
+        }
         while self.pref_idx < self.env.preferred_regs_by_class[self.class].len() {
             let arr = &self.env.preferred_regs_by_class[self.class][..];
             let r = arr[wrap(self.pref_idx + self.offset_pref, arr.len())];
@@ -121,3 +140,51 @@ impl<'a> core::iter::Iterator for RegTraversalIter<'a> {
         None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::PRegSet;
+
+    fn env(probe_order: Option<Vec<PReg>>) -> MachineEnv {
+        let r = |i| PReg::new(i, RegClass::Int);
+        MachineEnv {
+            preferred_regs_by_class: [vec![r(0), r(1), r(2), r(3)], vec![], vec![]],
+            non_preferred_regs_by_class: [vec![r(4), r(5)], vec![], vec![]],
+            probe_order_by_class: [probe_order, None, None],
+            scratch_by_class: [None, None, None],
+            fixed_stack_slots: vec![],
+            stack_to_stack_moves: false,
+            reserved_regs: PRegSet::empty(),
+            slot_size_by_class: [0; 3],
+            call_clobbers: PRegSet::empty(),
+            reg_groups: vec![],
+        }
+    }
+
+    fn probe(env: &MachineEnv, hint: PReg, offset: usize) -> Vec<usize> {
+        RegTraversalIter::new(env, RegClass::Int, hint, PReg::invalid(), offset, None)
+            .map(|preg| preg.hw_enc())
+            .collect()
+    }
+
+    #[test]
+    fn test_rotated_probe_order() {
+        let env = env(None);
+        assert_eq!(probe(&env, PReg::invalid(), 0), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(probe(&env, PReg::invalid(), 1), vec![1, 2, 3, 0, 5, 4]);
+    }
+
+    #[test]
+    fn test_explicit_probe_order() {
+        let r = |i| PReg::new(i, RegClass::Int);
+        let env = env(Some(vec![r(3), r(5), r(0), r(1), r(4), r(2)]));
+        for offset in 0..4 {
+            assert_eq!(probe(&env, PReg::invalid(), offset), vec![3, 5, 0, 1, 4, 2]);
+        }
+        assert_eq!(probe(&env, r(1), 2), vec![1, 3, 5, 0, 4, 2]);
+    }
+}
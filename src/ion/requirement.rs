@@ -13,7 +13,7 @@
 //! Requirements computation.
 
 use super::{Env, LiveBundleIndex};
-use crate::{Function, Inst, Operand, OperandConstraint, PReg, ProgPoint};
+use crate::{Function, Inst, Operand, OperandConstraint, PReg, PRegSet, ProgPoint};
 
 pub struct RequirementConflict;
 
@@ -60,7 +60,10 @@ impl RequirementConflictAt {
 pub enum Requirement {
     FixedReg(PReg),
     FixedStack(PReg),
+    Stack,
     Register,
+    /// Any register in the set; see `OperandConstraint::RegInGroup`.
+    Group(PRegSet),
     Any,
 }
 impl Requirement {
@@ -73,8 +76,20 @@ impl Requirement {
             | (Requirement::FixedReg(preg), Requirement::Register) => {
                 Ok(Requirement::FixedReg(preg))
             }
+            (Requirement::Register, Requirement::Group(set))
+            | (Requirement::Group(set), Requirement::Register) => Ok(Requirement::Group(set)),
+            (Requirement::Group(a), Requirement::Group(b)) if !a.intersection(b).is_empty() => {
+                Ok(Requirement::Group(a.intersection(b)))
+            }
+            (Requirement::Group(set), Requirement::FixedReg(preg))
+            | (Requirement::FixedReg(preg), Requirement::Group(set))
+                if set.contains(preg) =>
+            {
+                Ok(Requirement::FixedReg(preg))
+            }
             (Requirement::FixedReg(a), Requirement::FixedReg(b)) if a == b => Ok(self),
             (Requirement::FixedStack(a), Requirement::FixedStack(b)) if a == b => Ok(self),
+            (Requirement::Stack, Requirement::Stack) => Ok(self),
             _ => Err(RequirementConflict),
         }
     }
@@ -82,8 +97,8 @@ impl Requirement {
     #[inline(always)]
     pub fn is_stack(self) -> bool {
         match self {
-            Requirement::FixedStack(..) => true,
-            Requirement::Register | Requirement::FixedReg(..) => false,
+            Requirement::FixedStack(..) | Requirement::Stack => true,
+            Requirement::Register | Requirement::FixedReg(..) | Requirement::Group(..) => false,
             Requirement::Any => false,
         }
     }
@@ -91,8 +106,8 @@ impl Requirement {
     #[inline(always)]
     pub fn is_reg(self) -> bool {
         match self {
-            Requirement::Register | Requirement::FixedReg(..) => true,
-            Requirement::FixedStack(..) => false,
+            Requirement::Register | Requirement::FixedReg(..) | Requirement::Group(..) => true,
+            Requirement::FixedStack(..) | Requirement::Stack => false,
             Requirement::Any => false,
         }
     }
@@ -109,7 +124,11 @@ impl<'a, F: Function> Env<'a, F> {
                     Requirement::FixedReg(preg)
                 }
             }
-            OperandConstraint::Reg | OperandConstraint::Reuse(_) => Requirement::Register,
+            OperandConstraint::Reg | OperandConstraint::Reuse(_) | OperandConstraint::RegPair => {
+                Requirement::Register
+            }
+            OperandConstraint::RegInGroup(idx) => Requirement::Group(self.env.reg_groups[idx]),
+            OperandConstraint::ReuseStack(_) | OperandConstraint::Stack(_) => Requirement::Stack,
             OperandConstraint::Any => Requirement::Any,
         }
     }
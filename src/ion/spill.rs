@@ -13,13 +13,20 @@
 //! Spillslot allocation.
 
 use super::{
-    AllocRegResult, Env, LiveRangeKey, PReg, PRegIndex, RegTraversalIter, SpillSetIndex,
-    SpillSlotData, SpillSlotIndex,
+    AllocRegResult, Env, LiveBundleIndex, LiveRangeKey, LiveRangeList, LiveRangeListEntry, PReg,
+    PRegIndex, RegTraversalIter, SpillSetIndex, SpillSlotData, SpillSlotIndex, UseList, VRegIndex,
 };
-use crate::{ion::data_structures::SpillSetRanges, Allocation, Function, SpillSlot};
+use crate::{
+    ion::data_structures::{CodeRange, PartialSpillMove, SpillSetRanges, SpillTier},
+    Allocation, Block, Function, OperandConstraint, ProgPoint, RegAllocError, RegClass, SpillEvent,
+    SpillEventKind, SpillSlot, VReg,
+};
+use alloc::vec;
+use alloc::vec::Vec;
+use smallvec::{smallvec, SmallVec};
 
 impl<'a, F: Function> Env<'a, F> {
-    pub fn try_allocating_regs_for_spilled_bundles(&mut self) {
+    pub fn try_allocating_regs_for_spilled_bundles(&mut self) -> Result<(), RegAllocError> {
         trace!("allocating regs for spilled bundles");
         for i in 0..self.spilled_bundles.len() {
             let bundle = self.spilled_bundles[i]; // don't borrow self
@@ -28,77 +35,389 @@ impl<'a, F: Function> Env<'a, F> {
                 continue;
             }
 
-            let class = self.spillsets[self.bundles[bundle].spillset].class;
-            let hint = self.spillsets[self.bundles[bundle].spillset].reg_hint;
-
             // This may be an empty-range bundle whose ranges are not
             // sorted; sort all range-lists again here.
             self.bundles[bundle]
                 .ranges
                 .sort_unstable_by_key(|entry| entry.range.from);
 
-            let mut success = false;
-            self.stats.spill_bundle_reg_probes += 1;
-            for preg in
-                RegTraversalIter::new(self.env, class, hint, PReg::invalid(), bundle.index(), None)
-            {
-                trace!("trying bundle {:?} to preg {:?}", bundle, preg);
-                let preg_idx = PRegIndex::new(preg.index());
-                if let AllocRegResult::Allocated(_) =
-                    self.try_to_allocate_bundle_to_reg(bundle, preg_idx, None)
-                {
-                    self.stats.spill_bundle_reg_success += 1;
-                    success = true;
-                    break;
+            if self.try_spilled_bundle_to_reg(bundle) {
+                continue;
+            }
+
+            // A bundle that lives across a call will almost never
+            // find a register free for its whole range. Rather than
+            // leaving it on the stack everywhere, split it around the
+            // clobbering instructions and give each piece a second
+            // chance on its own. A piece with a single use is better
+            // served by the stack slot directly than by a reload, so
+            // only pieces with several uses are tried (unless spilling
+            // is forbidden outright).
+            let mut failed = Some(bundle);
+            let pieces = self.split_spilled_bundle_at_clobbers(bundle);
+            if !pieces.is_empty() {
+                failed = None;
+                for piece in pieces {
+                    let uses: usize = self.bundles[piece]
+                        .ranges
+                        .iter()
+                        .map(|entry| self.ranges[entry.index].uses.len())
+                        .sum();
+                    if (uses < 2 && self.may_spill(piece)) || !self.try_spilled_bundle_to_reg(piece)
+                    {
+                        failed = failed.or(Some(piece));
+                    }
                 }
             }
-            if !success {
+            if let Some(failed) = failed {
+                if !self.may_spill(failed) {
+                    let first = self.bundles[failed].ranges[0];
+                    return Err(RegAllocError::OutOfRegisters {
+                        vreg: self.vreg(self.ranges[first.index].vreg),
+                        at: first.range.from,
+                    });
+                }
                 trace!(
                     "spilling bundle {:?}: marking spillset {:?} as required",
                     bundle,
                     self.bundles[bundle].spillset
                 );
                 self.spillsets[self.bundles[bundle].spillset].required = true;
+                self.observe_spilled_bundle(failed, /* forced_by_constraint = */ false);
+                #[cfg(feature = "decision-log")]
+                self.decision_log.push(crate::Decision::Spill {
+                    bundle: failed.index(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `bundle` may be left in its spillslot: spilling is not
+    /// forbidden, and if its vreg may never be spilled, it can be
+    /// rematerialized instead (see `Function::never_spill`).
+    fn may_spill(&self, bundle: LiveBundleIndex) -> bool {
+        if self.forbid_spilling {
+            return false;
+        }
+        let spillset = &self.spillsets[self.bundles[bundle].spillset];
+        match spillset.never_spill {
+            Some(vreg) => {
+                self.func.can_remat(vreg)
+                    && !spillset.pair
+                    && !self.vregs[VRegIndex::new(vreg.vreg())]
+                        .blockparam
+                        .is_valid()
+            }
+            None => true,
+        }
+    }
+
+    /// Report every vreg with ranges in `bundle`, which now lives in
+    /// its spillslot, to the spill observer, if any.
+    pub fn observe_spilled_bundle(&self, bundle: LiveBundleIndex, forced_by_constraint: bool) {
+        let observer = match self.spill_observer {
+            Some(observer) => observer.0,
+            None => return,
+        };
+        let mut seen: SmallVec<[VRegIndex; 4]> = smallvec![];
+        for entry in &self.bundles[bundle].ranges {
+            let vreg = self.ranges[entry.index].vreg;
+            if seen.contains(&vreg) {
+                continue;
+            }
+            seen.push(vreg);
+            observer(SpillEvent {
+                kind: SpillEventKind::Spill,
+                vreg: self.vreg(vreg),
+                pos: entry.range.from,
+                forced_by_constraint,
+            });
+        }
+    }
+
+    /// Whether some operand of `vreg` requires it to be on the stack.
+    pub fn vreg_has_stack_constraint(&self, vreg: VRegIndex) -> bool {
+        self.vregs[vreg].ranges.iter().any(|entry| {
+            self.ranges[entry.index].uses.iter().any(|u| {
+                matches!(
+                    u.operand.constraint(),
+                    OperandConstraint::Stack(_) | OperandConstraint::ReuseStack(_)
+                )
+            })
+        })
+    }
+
+    /// Probe all registers of the bundle's class for one that is free
+    /// across the whole bundle, and allocate it if found.
+    fn try_spilled_bundle_to_reg(&mut self, bundle: LiveBundleIndex) -> bool {
+        let class = self.spillsets[self.bundles[bundle].spillset].class;
+        let hint = self.spillsets[self.bundles[bundle].spillset].reg_hint;
+        self.stats.spill_bundle_reg_probes += 1;
+        let pair_bases = self.pair_bases(bundle, class);
+        // See `RegallocOptions::minimize_regs`.
+        let (minimize_regs, used_pregs) = (self.minimize_regs, self.used_pregs);
+        let probe =
+            || RegTraversalIter::new(self.env, class, hint, PReg::invalid(), bundle.index(), None);
+        for preg in probe()
+            .filter(|&preg| !minimize_regs || used_pregs.contains(preg))
+            .chain(probe().filter(|&preg| minimize_regs && !used_pregs.contains(preg)))
+            .filter(|&preg| match pair_bases {
+                Some(bases) => bases.contains(preg),
+                None => true,
+            })
+        {
+            trace!("trying bundle {:?} to preg {:?}", bundle, preg);
+            let preg_idx = PRegIndex::new(preg.index());
+            if let AllocRegResult::Allocated(_) =
+                self.try_to_allocate_bundle_to_reg(bundle, preg_idx, None)
+            {
+                self.stats.spill_bundle_reg_success += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Split a (sorted) spilled bundle just before and just after
+    /// every instruction with clobbers that falls strictly inside it.
+    /// The first piece stays in `bundle`; the others are new bundles
+    /// in the same spillset. Returns all pieces, or an empty list if
+    /// the bundle does not span any clobbering instruction.
+    fn split_spilled_bundle_at_clobbers(
+        &mut self,
+        bundle: LiveBundleIndex,
+    ) -> SmallVec<[LiveBundleIndex; 4]> {
+        let start = self.bundles[bundle].ranges.first().unwrap().range.from;
+        let end = self.bundles[bundle].ranges.last().unwrap().range.to;
+
+        let first = self
+            .clobber_insts
+            .partition_point(|&inst| inst < start.inst());
+        let mut cuts: SmallVec<[ProgPoint; 8]> = smallvec![];
+        for &inst in &self.clobber_insts[first..] {
+            if inst > end.inst() {
+                break;
+            }
+            for point in [ProgPoint::before(inst), ProgPoint::before(inst.next())] {
+                if point > start && point < end && cuts.last() != Some(&point) {
+                    cuts.push(point);
+                }
+            }
+        }
+        if cuts.is_empty() {
+            return smallvec![];
+        }
+        trace!("splitting spilled bundle {:?} at {:?}", bundle, cuts);
+        self.stats.splits += 1;
+        #[cfg(feature = "decision-log")]
+        for &at in &cuts {
+            self.decision_log.push(crate::Decision::Split {
+                bundle: bundle.index(),
+                at,
+            });
+        }
+
+        let mut lists: SmallVec<[LiveRangeList; 4]> = smallvec![];
+        lists.resize(cuts.len() + 1, LiveRangeList::new());
+        let entries = core::mem::take(&mut self.bundles[bundle].ranges);
+        for entry in entries {
+            let mut lr = entry.index;
+            loop {
+                let range = self.ranges[lr].range;
+                let piece = cuts.partition_point(|&cut| cut <= range.from);
+                let cut = match cuts.get(piece) {
+                    Some(&cut) if cut < range.to => cut,
+                    _ => {
+                        lists[piece].push(LiveRangeListEntry { range, index: lr });
+                        break;
+                    }
+                };
+
+                // Chop the LR at the cut and carry on with the tail.
+                let vreg = self.ranges[lr].vreg;
+                let tail = self.ranges.add(CodeRange {
+                    from: cut,
+                    to: range.to,
+                });
+                self.ranges[tail].vreg = vreg;
+                let first_use = self.ranges[lr]
+                    .uses
+                    .iter()
+                    .position(|u| u.pos >= cut)
+                    .unwrap_or(self.ranges[lr].uses.len());
+                let rest: UseList = self.ranges[lr].uses.drain(first_use..).collect();
+                self.ranges[tail].uses = rest;
+                self.ranges[lr].range.to = cut;
+                self.recompute_range_properties(lr);
+                self.recompute_range_properties(tail);
+                lists[piece].push(LiveRangeListEntry {
+                    range: self.ranges[lr].range,
+                    index: lr,
+                });
+                // Lazy split in the VReg data, as in
+                // `split_and_requeue_bundle`.
+                self.vregs[vreg].ranges.push(LiveRangeListEntry {
+                    range: self.ranges[tail].range,
+                    index: tail,
+                });
+                lr = tail;
             }
         }
+
+        let spillset = self.bundles[bundle].spillset;
+        let mut pieces = smallvec![];
+        for list in lists {
+            if list.is_empty() {
+                continue;
+            }
+            let piece = if pieces.is_empty() {
+                bundle
+            } else {
+                let new_bundle = self.bundles.add();
+                self.bundles[new_bundle].spillset = spillset;
+                new_bundle
+            };
+            for entry in &list {
+                self.ranges[entry.index].bundle = piece;
+            }
+            self.bundles[piece].ranges = list;
+            pieces.push(piece);
+        }
+        pieces
     }
 
     pub fn spillslot_can_fit_spillset(
         &mut self,
         spillslot: SpillSlotIndex,
-        spillset: SpillSetIndex,
+        ranges: &[CodeRange],
     ) -> bool {
-        !self.spillslots[spillslot.index()]
-            .ranges
-            .btree
-            .contains_key(&LiveRangeKey::from_range(&self.spillsets[spillset].range))
+        let btree = &self.spillslots[spillslot.index()].ranges.btree;
+        ranges
+            .iter()
+            .all(|range| !btree.contains_key(&LiveRangeKey::from_range(range)))
     }
 
     pub fn allocate_spillset_to_spillslot(
         &mut self,
         spillset: SpillSetIndex,
         spillslot: SpillSlotIndex,
+        ranges: &[CodeRange],
     ) {
         self.spillsets[spillset].slot = spillslot;
 
-        let res = self.spillslots[spillslot.index()].ranges.btree.insert(
-            LiveRangeKey::from_range(&self.spillsets[spillset].range),
-            spillset,
-        );
+        for range in ranges {
+            let res = self.spillslots[spillslot.index()]
+                .ranges
+                .btree
+                .insert(LiveRangeKey::from_range(range), spillset);
+            debug_assert!(res.is_none());
+        }
+    }
 
-        debug_assert!(res.is_none());
+    /// The live ranges of the vregs in each required spillset, sorted
+    /// by spillset and then program point, with overlapping and
+    /// abutting ranges joined. A spillset only needs its slot where
+    /// one of its vregs is live, so two spillsets can share a slot as
+    /// long as these ranges are disjoint, even if their extents (see
+    /// `SpillSet::range`) overlap.
+    fn required_spillset_ranges(&self) -> Vec<(SpillSetIndex, CodeRange)> {
+        let mut ranges = Vec::new();
+        for vreg in self.vregs.iter() {
+            for entry in &vreg.ranges {
+                let bundle = self.ranges[entry.index].bundle;
+                if entry.range.from >= entry.range.to || !bundle.is_valid() {
+                    continue;
+                }
+                let spillset = self.bundles[bundle].spillset;
+                if self.spillsets[spillset].required {
+                    ranges.push((spillset, entry.range));
+                }
+            }
+        }
+        ranges.sort_unstable_by_key(|&(spillset, range)| (spillset, range.from));
+        ranges.dedup_by(|next, prev| {
+            if prev.0 == next.0 && next.1.from <= prev.1.to {
+                prev.1.to = core::cmp::max(prev.1.to, next.1.to);
+                true
+            } else {
+                false
+            }
+        });
+        ranges
     }
 
     pub fn allocate_spillslots(&mut self) {
         const MAX_ATTEMPTS: usize = 10;
 
+        // With a limited primary area, spillslots only used within
+        // cold blocks prefer the secondary area. `hot_blocks[i]` counts
+        // the blocks before block `i` that are not cold.
+        let mut hot_blocks = Vec::new();
+        if self.primary_spillslots.is_some() {
+            hot_blocks.push(0);
+            for block in 0..self.func.num_blocks() {
+                let hot = !self.func.block_is_cold(Block::new(block));
+                hot_blocks.push(hot_blocks[block] + hot as usize);
+            }
+        }
+
+        let spillset_ranges = self.required_spillset_ranges();
+        let spilled_uses = if self.cross_class_spills {
+            self.spillsets_with_spilled_uses()
+        } else {
+            Vec::new()
+        };
+        let mut next_ranges = 0;
+        let mut remat_slots = 0;
         for spillset in 0..self.spillsets.len() {
             trace!("allocate spillslot: {}", spillset);
             let spillset = SpillSetIndex::new(spillset);
             if !self.spillsets[spillset].required {
                 continue;
             }
+            let first = next_ranges;
+            while spillset_ranges
+                .get(next_ranges)
+                .is_some_and(|&(s, _)| s == spillset)
+            {
+                next_ranges += 1;
+            }
+            let ranges: SmallVec<[CodeRange; 8]> = spillset_ranges[first..next_ranges]
+                .iter()
+                .map(|&(_, range)| range)
+                .collect();
+
+            // A never-spilled or sunk vreg's slot is never written, so
+            // it takes no space in the frame; it only needs an index
+            // that `Edits` can recognize, counted down from the top.
+            if self.spillsets[spillset].is_remat() {
+                let spillslot = SpillSlotIndex::new(self.spillslots.len());
+                self.spillslots.push(SpillSlotData {
+                    ranges: SpillSetRanges::new(),
+                    alloc: Allocation::stack(SpillSlot::new(SpillSlot::MAX - remat_slots)),
+                    slots: 1,
+                    align: 1,
+                    tier: SpillTier::Secondary,
+                });
+                self.spillsets[spillset].slot = spillslot;
+                remat_slots += 1;
+                continue;
+            }
             let class = self.spillsets[spillset].class as usize;
+            // A register pair spills to a slot of twice the size, and
+            // slots are only shared between spillsets of equal size.
+            let regclass = self.spillsets[spillset].class;
+            let mut size = super::spillslot_size(self.func, self.env, regclass) as u32;
+            if self.spillsets[spillset].pair {
+                size *= 2;
+            }
+            if self.cross_class_spills
+                && !spilled_uses[spillset.index()]
+                && self.try_cross_class_spill(spillset, &ranges)
+            {
+                continue;
+            }
             // Try a few existing spillslots.
             let mut i = self.slots_by_class[class].probe_start;
             let mut success = false;
@@ -115,8 +434,14 @@ impl<'a, F: Function> Env<'a, F> {
                 // after this loop).
                 let spillslot = self.slots_by_class[class].slots[i];
 
-                if self.spillslot_can_fit_spillset(spillslot, spillset) {
-                    self.allocate_spillset_to_spillslot(spillset, spillslot);
+                if self.spillslots[spillslot.index()].slots == size
+                    && self.spillslot_can_fit_spillset(spillslot, &ranges)
+                {
+                    let slot_align = &mut self.spillslots[spillslot.index()].align;
+                    *slot_align = core::cmp::max(*slot_align, self.spillsets[spillset].align);
+                    self.allocate_spillset_to_spillslot(spillset, spillslot, &ranges);
+                    self.stats.spillslots_shared += 1;
+                    self.stats.spill_units_saved += size as usize;
                     success = true;
                     self.slots_by_class[class].probe_start = i;
                     break;
@@ -131,35 +456,375 @@ impl<'a, F: Function> Env<'a, F> {
                 self.spillslots.push(SpillSlotData {
                     ranges: SpillSetRanges::new(),
                     alloc: Allocation::none(),
-                    slots: self.func.spillslot_size(self.spillsets[spillset].class) as u32,
+                    slots: size,
+                    align: self.spillsets[spillset].align,
+                    tier: SpillTier::Secondary,
                 });
                 self.slots_by_class[class].slots.push(spillslot);
                 self.slots_by_class[class].probe_start = self.slots_by_class[class].slots.len() - 1;
 
-                self.allocate_spillset_to_spillslot(spillset, spillslot);
+                self.allocate_spillset_to_spillslot(spillset, spillslot, &ranges);
+            }
+
+            if hot_blocks.is_empty() || !self.spillset_is_cold(spillset, &hot_blocks) {
+                let slot = self.spillsets[spillset].slot;
+                self.spillslots[slot.index()].tier = SpillTier::Primary;
             }
         }
 
-        // Assign actual slot indices to spillslots.
-        for i in 0..self.spillslots.len() {
-            self.spillslots[i].alloc = self.allocate_spillslot(self.spillslots[i].slots);
+        // Assign actual slot indices to spillslots: those that prefer
+        // the primary area first, so that they get it if it is too
+        // small for all of them.
+        for tier in [SpillTier::Primary, SpillTier::Secondary] {
+            for i in 0..self.spillslots.len() {
+                // Remat slots already have their index.
+                if self.spillslots[i].tier == tier && self.spillslots[i].alloc.is_none() {
+                    let (size, align) = (self.spillslots[i].slots, self.spillslots[i].align);
+                    self.spillslots[i].alloc = self.allocate_spillslot(size, align, tier);
+                }
+            }
         }
 
         trace!("spillslot allocator done");
     }
 
-    pub fn allocate_spillslot(&mut self, size: u32) -> Allocation {
-        let mut offset = self.num_spillslots;
-        // Align up to `size`.
-        debug_assert!(size.is_power_of_two());
-        offset = (offset + size - 1) & !(size - 1);
+    /// For each spillset, whether an operand is allocated to its slot,
+    /// i.e. whether one of its spilled bundles has a use.
+    fn spillsets_with_spilled_uses(&self) -> Vec<bool> {
+        let mut spilled_uses = vec![false; self.spillsets.len()];
+        for range in self.ranges.iter() {
+            if range.bundle.is_valid()
+                && !range.uses.is_empty()
+                && self.bundles[range.bundle].allocation.is_none()
+            {
+                spilled_uses[self.bundles[range.bundle].spillset.index()] = true;
+            }
+        }
+        spilled_uses
+    }
+
+    /// Try to give the spillset a register of another class in place
+    /// of a spillslot (see `RegallocOptions::cross_class_spills`): one
+    /// that is free over all of `ranges` and reserved for them here.
+    ///
+    /// Moves of different classes at one point are not resolved as one
+    /// parallel move, so one into or out of the register there could
+    /// happen in the wrong order. The register must therefore also be
+    /// free at the points around each range, and at both ends of every
+    /// CFG edge that the ranges touch, since edge moves read and write
+    /// allocations that are only live on the other side of the edge.
+    fn try_cross_class_spill(&mut self, spillset: SpillSetIndex, ranges: &[CodeRange]) -> bool {
+        let set = &self.spillsets[spillset];
+        if set.is_remat() || set.pair || set.align > 1 {
+            return false;
+        }
+        let size = super::spillslot_size(self.func, self.env, set.class);
+        let mut reserved: SmallVec<[CodeRange; 8]> = SmallVec::new();
+        for range in ranges {
+            let from = if range.from.to_index() > 0 {
+                range.from.prev()
+            } else {
+                range.from
+            };
+            let padded = CodeRange {
+                from,
+                to: range.to.next(),
+            };
+            reserved.push(padded);
+            let first = self.cfginfo.insn_block[padded.from.inst().index()];
+            let last = self.cfginfo.insn_block[range.to.prev().inst().index()];
+            for block in first.index()..=last.index() {
+                let block = Block::new(block);
+                if padded.contains_point(self.cfginfo.block_entry[block.index()]) {
+                    for &pred in self.func.block_preds(block) {
+                        let exit = self.cfginfo.block_exit[pred.index()];
+                        reserved.push(CodeRange {
+                            from: exit.prev(),
+                            to: exit.next(),
+                        });
+                    }
+                }
+                if padded.contains_point(self.cfginfo.block_exit[block.index()]) {
+                    for &succ in self.func.block_succs(block) {
+                        let entry = self.cfginfo.block_entry[succ.index()];
+                        reserved.push(CodeRange {
+                            from: entry,
+                            to: entry.next(),
+                        });
+                    }
+                }
+            }
+        }
+        reserved.sort_unstable_by_key(|range| range.from);
+        let mut merged: SmallVec<[CodeRange; 8]> = SmallVec::new();
+        for range in reserved {
+            match merged.last_mut() {
+                Some(last) if range.from <= last.to => {
+                    last.to = core::cmp::max(last.to, range.to);
+                }
+                _ => merged.push(range),
+            }
+        }
+        for class in [RegClass::Int, RegClass::Float, RegClass::Vector] {
+            if class == set.class || super::spillslot_size(self.func, self.env, class) < size {
+                continue;
+            }
+            let scratch = self.env.scratch_by_class[class as usize];
+            let candidates = self.env.preferred_regs_by_class[class as usize]
+                .iter()
+                .chain(self.env.non_preferred_regs_by_class[class as usize].iter());
+            for &preg in candidates {
+                let btree = &self.pregs[preg.index()].allocations.btree;
+                if Some(preg) == scratch
+                    || self.pregs[preg.index()].is_stack
+                    || merged
+                        .iter()
+                        .any(|range| btree.contains_key(&LiveRangeKey::from_range(range)))
+                {
+                    continue;
+                }
+                for range in &merged {
+                    self.add_liverange_to_preg(*range, preg);
+                }
+                let spillslot = SpillSlotIndex::new(self.spillslots.len());
+                self.spillslots.push(SpillSlotData {
+                    ranges: SpillSetRanges::new(),
+                    alloc: Allocation::reg(preg),
+                    slots: size as u32,
+                    align: 1,
+                    tier: SpillTier::Secondary,
+                });
+                self.spillsets[spillset].slot = spillslot;
+                self.stats.cross_class_spills += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether every block that the spillset's range touches is cold.
+    fn spillset_is_cold(&self, spillset: SpillSetIndex, hot_blocks: &[usize]) -> bool {
+        let range = self.spillsets[spillset].range;
+        let first = self.cfginfo.insn_block[range.from.inst().index()].index();
+        let last = self.cfginfo.insn_block[range.to.prev().inst().index()].index();
+        hot_blocks[last + 1] == hot_blocks[first]
+    }
+
+    /// Allocate a new slot of `size` in the area given by `tier`,
+    /// aligned to `size` or to `align` if larger. With a limited
+    /// primary area (`RegallocOptions::primary_spillslots`), a slot
+    /// that does not fit there goes to the secondary area, which
+    /// starts right after it; otherwise there is only one area.
+    pub fn allocate_spillslot(&mut self, size: u32, align: u32, tier: SpillTier) -> Allocation {
+        debug_assert!(size.is_power_of_two() && align.is_power_of_two());
+        let align_to = core::cmp::max(size, align);
+        let align = |offset: u32| (offset + align_to - 1) & !(align_to - 1);
+        let offset = match self.primary_spillslots {
+            Some(limit)
+                if tier == SpillTier::Secondary || align(self.num_spillslots) + size > limit =>
+            {
+                let offset = align(limit + self.num_secondary_spillslots);
+                self.num_secondary_spillslots = offset + size - limit;
+                offset
+            }
+            _ => {
+                let offset = align(self.num_spillslots);
+                self.num_spillslots = offset + size;
+                offset
+            }
+        };
         let slot = if self.func.multi_spillslot_named_by_last_slot() {
             offset + size - 1
         } else {
             offset
         };
-        offset += size;
-        self.num_spillslots = offset;
         Allocation::stack(SpillSlot::new(slot as usize))
     }
+
+    /// The number of slots spanned by both areas.
+    pub fn total_spillslots(&self) -> u32 {
+        match self.primary_spillslots {
+            Some(limit) if self.num_secondary_spillslots > 0 => {
+                limit + self.num_secondary_spillslots
+            }
+            _ => self.num_spillslots,
+        }
+    }
+
+    /// Find the spills of `RegClass::Vector` register pairs that
+    /// could keep one half in its register (see
+    /// `RegallocOptions::partial_spills`), reserve that register for
+    /// the spill and record the partial store and reload for move
+    /// resolution. A half is kept if its register is free from the
+    /// store to the reload, which must be in one block with no uses
+    /// of the pair in between. Runs after allocation, so the
+    /// registers' `btree`s are final.
+    pub fn plan_partial_spills(&mut self) {
+        for (preg, range) in self.partial_spill_reservations.drain(..) {
+            self.pregs[preg.index()]
+                .allocations
+                .btree
+                .remove(&LiveRangeKey::from_range(&range));
+        }
+        self.partial_spill_moves.clear();
+        self.partial_spill_ranges.clear();
+        self.stats.partial_spills = 0;
+        if !self.partial_spills || self.spill_once || self.reload_once_per_block {
+            return;
+        }
+
+        // The number of ranges in each spillset's slot. A store can
+        // only be skipped if nothing but its reload reads the slot
+        // afterwards, so the spill must be the only time the
+        // spillset is in its slot.
+        let mut spilled_ranges = vec![0; self.spillsets.len()];
+        for vreg in self.vregs.iter() {
+            for entry in &vreg.ranges {
+                let bundle = self.ranges[entry.index].bundle;
+                if bundle.is_valid() && self.get_alloc_for_range(entry.index).is_stack() {
+                    spilled_ranges[self.bundles[bundle].spillset.index()] += 1;
+                }
+            }
+        }
+
+        for vreg in 0..self.vregs.len() {
+            let vreg = VRegIndex::new(vreg);
+            if !self.is_pair_vreg(vreg)
+                || self.vregs[vreg].class != Some(RegClass::Vector)
+                || self.func.is_reference_vreg(self.vreg(vreg))
+            {
+                continue;
+            }
+            let ranges = &self.vregs[vreg].ranges;
+            let mut i = 0;
+            while i + 2 < ranges.len() {
+                let prev = ranges[i];
+                let spill = ranges[i + 1];
+                i += 1;
+                let from = self.get_alloc_for_range(prev.index);
+                let spill_alloc = self.get_alloc_for_range(spill.index);
+                if !from.is_reg() || !spill_alloc.is_stack() || prev.range.to != spill.range.from {
+                    continue;
+                }
+                // The spill may be split into several ranges in the
+                // same slot; find the end of the run.
+                let is_spilled = |entry: LiveRangeListEntry| {
+                    self.get_alloc_for_range(entry.index) == spill_alloc
+                        && self.ranges[entry.index].uses.is_empty()
+                        && !self.ranges[entry.index].has_flag(super::LiveRangeFlag::StartsAtDef)
+                };
+                let mut j = i;
+                while j < ranges.len()
+                    && is_spilled(ranges[j])
+                    && ranges[j - 1].range.to == ranges[j].range.from
+                {
+                    j += 1;
+                }
+                if j == i || j == ranges.len() {
+                    continue;
+                }
+                let next = ranges[j];
+                let to = self.get_alloc_for_range(next.index);
+                let span = CodeRange {
+                    from: spill.range.from,
+                    to: next.range.from,
+                };
+                let block = self.cfginfo.insn_block[span.from.inst().index()];
+                let spillset = self.bundles[self.ranges[spill.index].bundle].spillset;
+                if !to.is_reg()
+                    || spilled_ranges[spillset.index()] != j - i
+                    || ranges[j - 1].range.to != next.range.from
+                    || self.ranges[next.index].has_flag(super::LiveRangeFlag::StartsAtDef)
+                    || self.is_start_of_block(span.from)
+                    || self.is_start_of_block(span.to)
+                    || self.cfginfo.insn_block[span.to.inst().index()] != block
+                    || self.spillsets[spillset].is_remat()
+                {
+                    continue;
+                }
+
+                let class = RegClass::Vector;
+                let (from_lo, from_hi) = super::pair_halves(self.func, self.env, from, class);
+                let (to_lo, to_hi) = super::pair_halves(self.func, self.env, to, class);
+                let mut kept = [None; 2];
+                for (half, &(from, to)) in [(from_lo, to_lo), (from_hi, to_hi)].iter().enumerate() {
+                    // The reload reads the kept register at the start
+                    // of the next range, unless it stays there.
+                    let range = CodeRange {
+                        from: span.from,
+                        to: if from == to { span.to } else { span.to.next() },
+                    };
+                    let preg = PRegIndex::new(from.as_reg().unwrap().index());
+                    let btree = &mut self.pregs[preg.index()].allocations.btree;
+                    let key = LiveRangeKey::from_range(&range);
+                    if btree.contains_key(&key) {
+                        continue;
+                    }
+                    trace!(
+                        "partial spill: keep {} of {:?} over {:?}",
+                        from,
+                        vreg,
+                        range
+                    );
+                    btree.insert(key, super::LiveRangeIndex::invalid());
+                    self.partial_spill_reservations.push((preg, range));
+                    self.stats.partial_spills += 1;
+                    kept[half] = Some(from);
+                }
+                if kept != [None; 2] {
+                    let vreg = self.vreg(vreg);
+                    self.partial_spill_moves.insert(
+                        (vreg, span.from),
+                        PartialSpillMove {
+                            from,
+                            to: spill_alloc,
+                            kept,
+                        },
+                    );
+                    self.partial_spill_moves.insert(
+                        (vreg, span.to),
+                        PartialSpillMove {
+                            from: spill_alloc,
+                            to,
+                            kept,
+                        },
+                    );
+                    self.partial_spill_ranges
+                        .extend(ranges[i..j].iter().map(|entry| entry.index));
+                }
+                i = j;
+            }
+        }
+    }
+
+    fn block_frequency(&self, block: Block) -> f32 {
+        self.func.block_frequency(block).unwrap_or_else(|| {
+            let depth = core::cmp::min(10, self.cfginfo.approx_loop_depth[block.index()]);
+            (0..depth).fold(1.0, |f, _| f * 4.0)
+        })
+    }
+
+    /// Find vregs that live in a spillslot for at least part of their
+    /// lifetime and that have a use in a block of frequency at least
+    /// `threshold`. This only inspects the final allocation.
+    pub fn compute_hot_spills(&self, threshold: f32) -> Vec<(VReg, f32)> {
+        let mut hot_spills = Vec::new();
+        for vreg in 0..self.vregs.len() {
+            let vreg = VRegIndex::new(vreg);
+            let mut spilled = false;
+            let mut max_freq: f32 = 0.0;
+            for entry in &self.vregs[vreg].ranges {
+                spilled |= self.get_alloc_for_range(entry.index).is_stack();
+                for u in &self.ranges[entry.index].uses {
+                    let block = self.cfginfo.insn_block[u.pos.inst().index()];
+                    max_freq = max_freq.max(self.block_frequency(block));
+                }
+            }
+            if spilled && max_freq >= threshold {
+                trace!("hot spill: {:?} with frequency {}", vreg, max_freq);
+                hot_spills.push((self.vreg(vreg), max_freq));
+            }
+        }
+        hot_spills
+    }
 }
@@ -34,11 +34,28 @@ macro_rules! trace_enabled {
     };
 }
 
+// Evaluates `$e`, adding the wall-clock time it takes to the
+// `Duration` at `$time` when phase timings are enabled.
+macro_rules! timed {
+    ($time:expr, $e:expr) => {{
+        #[cfg(feature = "phase-timings")]
+        let start = ::std::time::Instant::now();
+        let result = $e;
+        #[cfg(feature = "phase-timings")]
+        {
+            $time += start.elapsed();
+        }
+        result
+    }};
+}
+
 use core::hash::BuildHasherDefault;
 use rustc_hash::FxHasher;
 type FxHashMap<K, V> = hashbrown::HashMap<K, V, BuildHasherDefault<FxHasher>>;
 type FxHashSet<V> = hashbrown::HashSet<V, BuildHasherDefault<FxHasher>>;
 
+pub mod batch;
+pub mod builder;
 pub(crate) mod cfg;
 pub(crate) mod domtree;
 pub mod indexset;
@@ -46,15 +63,26 @@ pub(crate) mod ion;
 pub(crate) mod moves;
 pub(crate) mod postorder;
 pub mod ssa;
+pub mod validate;
 
 #[macro_use]
 mod index;
 
+use alloc::format;
+use alloc::string::String;
 use alloc::vec::Vec;
+pub use batch::{run_batch, BatchCall, BatchFunction, BatchOutput};
+pub use builder::{BuiltFunction, FunctionBuilder};
+pub use cfg::CFGInfo;
 pub use index::{Block, Inst, InstRange};
+pub use ion::{Ctx, InsertMovePrio};
+pub use validate::{validate_function, ValidationError};
 
 pub mod checker;
 
+#[cfg(test)]
+pub(crate) mod testing;
+
 #[cfg(feature = "fuzzing")]
 pub mod fuzzing;
 
@@ -186,11 +214,13 @@ impl core::fmt::Display for PReg {
 /// A type for internal bit arrays.
 type Bits = u64;
 
-/// A physical register set. Used to represent clobbers
-/// efficiently.
+/// A physical register set. Used to represent clobbers, register
+/// pools and other masks efficiently.
 ///
 /// The set is `Copy` and is guaranteed to have constant, and small,
-/// size, as it is based on a bitset internally.
+/// size, as it is based on a bitset internally. It is keyed by
+/// `PReg::index()`, so it covers the registers of all classes at
+/// once; iteration yields registers in index order.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct PRegSet {
@@ -250,6 +280,58 @@ impl PRegSet {
             self.bits[i] |= other.bits[i];
         }
     }
+
+    /// Returns the registers in either set.
+    pub const fn union(self, other: PRegSet) -> Self {
+        let mut out = self;
+        let mut i = 0;
+        while i < Self::LEN {
+            out.bits[i] |= other.bits[i];
+            i += 1;
+        }
+        out
+    }
+
+    /// Returns the registers in both sets.
+    pub const fn intersection(self, other: PRegSet) -> Self {
+        let mut out = self;
+        let mut i = 0;
+        while i < Self::LEN {
+            out.bits[i] &= other.bits[i];
+            i += 1;
+        }
+        out
+    }
+
+    /// Returns the registers in this set but not in `other`.
+    pub const fn difference(self, other: PRegSet) -> Self {
+        let mut out = self;
+        let mut i = 0;
+        while i < Self::LEN {
+            out.bits[i] &= !other.bits[i];
+            i += 1;
+        }
+        out
+    }
+
+    /// Returns whether the set contains no registers.
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&bits| bits == 0)
+    }
+
+    /// Returns the number of registers in the set.
+    pub fn len(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|bits| bits.count_ones() as usize)
+            .sum()
+    }
+
+    /// Returns an iterator over the registers in the set, in index
+    /// order.
+    pub fn iter(&self) -> PRegSetIter {
+        self.into_iter()
+    }
 }
 
 impl IntoIterator for PRegSet {
@@ -263,6 +345,7 @@ impl IntoIterator for PRegSet {
     }
 }
 
+/// An iterator over the registers in a `PRegSet`, in index order.
 pub struct PRegSetIter {
     bits: [Bits; PRegSet::LEN],
     cur: usize,
@@ -451,6 +534,43 @@ pub enum OperandConstraint {
     FixedReg(PReg),
     /// On defs only: reuse a use's register.
     Reuse(usize),
+    /// On defs only: the def is placed in a stack slot, and the use
+    /// with the given index is placed in the same slot, for memory
+    /// forms that update an operand in place. Like `Reuse`, the
+    /// input is copied into the output's slot before the
+    /// instruction if the two could not be given the same slot. There
+    /// is no copy if the input dies at the instruction and none of its
+    /// other operands require a register. The input must have an
+    /// `Any` or `Stack` constraint, and the index must be at most 15.
+    ReuseStack(usize),
+    /// Operand must be in a spillslot whose index is a multiple of
+    /// the given alignment, in spillslot units (see
+    /// `MachineEnv::spillslot_unit`), e.g. for an aligned vector load
+    /// from a spilled value. The alignment must be a power of two of
+    /// at most 128; 1 asks for no alignment beyond the slot's size.
+    /// The vreg must not also need a register at the same program
+    /// point, and may not be a register pair.
+    Stack(usize),
+    /// Operand must be in an aligned register pair: an even register
+    /// and the register following it. The reported `Allocation` is
+    /// the even (low) register.
+    ///
+    /// A vreg mentioned with this constraint is a pair vreg for its
+    /// whole lifetime, as are the blockparams and branch args it flows
+    /// through: whenever it is in registers it occupies both halves,
+    /// and its spillslot is twice the size given by
+    /// `Function::spillslot_size`. Moves of a pair vreg are emitted as
+    /// one `Edit::Move` per half; the high half of a spillslot `s` is
+    /// the slot `spillslot_size` units above the low half (see
+    /// `Function::multi_spillslot_named_by_last_slot`). Other operands
+    /// of a pair vreg may only use `Any` or `RegPair`.
+    RegPair,
+    /// Operand must be in one of the registers of the group with the
+    /// given index in `MachineEnv::reg_groups`, e.g. for the target of
+    /// an indirect call that the encoding restricts to a subset of
+    /// registers. The register chosen is reported as the operand's
+    /// allocation as usual. The index must be at most 3.
+    RegInGroup(usize),
 }
 
 impl core::fmt::Display for OperandConstraint {
@@ -460,12 +580,25 @@ impl core::fmt::Display for OperandConstraint {
             Self::Reg => write!(f, "reg"),
             Self::FixedReg(preg) => write!(f, "fixed({})", preg),
             Self::Reuse(idx) => write!(f, "reuse({})", idx),
+            Self::ReuseStack(idx) => write!(f, "reuse_stack({})", idx),
+            Self::Stack(align) => write!(f, "stack({})", align),
+            Self::RegPair => write!(f, "regpair"),
+            Self::RegInGroup(idx) => write!(f, "group({})", idx),
         }
     }
 }
 
 /// The "kind" of the operand: whether it reads a vreg (Use) or writes
 /// a vreg (Def).
+///
+/// There is no read-modify-write kind. An instruction that updates a
+/// register in place takes the old value as a use and defines the new
+/// value as a separate vreg: with `OperandConstraint::Reuse` for a
+/// two-address register, or, for a fixed register such as the RDX:RAX
+/// pair of x86 `idiv`, a `reg_fixed_use` of the old vreg together with
+/// a `reg_fixed_def` of the new one in the same register. The
+/// allocator then only inserts a copy if the old value outlives the
+/// instruction.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub enum OperandKind {
@@ -491,6 +624,14 @@ pub enum OperandKind {
 /// for longer than usual and that a register is not reused between
 /// the use (normally complete at "Early") and the def (normally
 /// starting at "Late"). See `Operand` for more.
+///
+/// In particular, a use at `Late` keeps its vreg live in the same
+/// allocation across both the "before" and "after" points of the
+/// instruction: no def of the instruction, early or late, may be
+/// assigned the same register. This is the way to express an input
+/// that must survive the whole instruction (e.g. a buffer pointer
+/// that is re-read after the outputs are written) without tying it
+/// to an output with a `Reuse` constraint.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub enum OperandPos {
@@ -534,9 +675,12 @@ pub struct Operand {
     /// The constraints are encoded as follows:
     /// - 1xxxxxx => FixedReg(preg)
     /// - 01xxxxx => Reuse(index)
+    /// - 001xxxx => ReuseStack(index)
+    /// - 0001xxx => Stack(1 << xxx)
     /// - 0000000 => Any
     /// - 0000001 => Reg
-    /// - 0000010 => Stack
+    /// - 0000011 => RegPair
+    /// - 00001xx => RegInGroup(index)
     /// - _ => Unused for now
     bits: u32,
 }
@@ -561,6 +705,19 @@ impl Operand {
                 debug_assert!(which <= 31);
                 0b0100000 | which as u32
             }
+            OperandConstraint::ReuseStack(which) => {
+                debug_assert!(which <= 15);
+                0b0010000 | which as u32
+            }
+            OperandConstraint::Stack(align) => {
+                debug_assert!(align.is_power_of_two() && align <= 128);
+                0b0001000 | align.trailing_zeros()
+            }
+            OperandConstraint::RegPair => 3,
+            OperandConstraint::RegInGroup(which) => {
+                debug_assert!(which <= 3);
+                0b0000100 | which as u32
+            }
         };
         let class_field = vreg.class() as u8 as u32;
         let pos_field = pos as u8 as u32;
@@ -589,7 +746,8 @@ impl Operand {
 
     /// Create an `Operand` that designates a use of a VReg that must
     /// be in a register, and that is used up until the "after" point,
-    /// i.e., must not conflict with any results.
+    /// i.e., must not conflict with any results. The register holds
+    /// the value for the whole instruction.
     #[inline(always)]
     pub fn reg_use_at_end(vreg: VReg) -> Self {
         Operand::new(
@@ -660,6 +818,32 @@ impl Operand {
         )
     }
 
+    /// Create an `Operand` that designates a use of a vreg that must
+    /// be in an aligned register pair, used at the "before" point.
+    /// See `OperandConstraint::RegPair`.
+    #[inline(always)]
+    pub fn reg_pair_use(vreg: VReg) -> Self {
+        Operand::new(
+            vreg,
+            OperandConstraint::RegPair,
+            OperandKind::Use,
+            OperandPos::Early,
+        )
+    }
+
+    /// Create an `Operand` that designates a def of a vreg that must
+    /// be in an aligned register pair, at the "after" point. See
+    /// `OperandConstraint::RegPair`.
+    #[inline(always)]
+    pub fn reg_pair_def(vreg: VReg) -> Self {
+        Operand::new(
+            vreg,
+            OperandConstraint::RegPair,
+            OperandKind::Def,
+            OperandPos::Late,
+        )
+    }
+
     /// Create an `Operand` that designates a def of a vreg that must
     /// reuse the register assigned to an input to the
     /// instruction. The input is identified by `idx` (is the `idx`th
@@ -675,6 +859,21 @@ impl Operand {
         )
     }
 
+    /// Create an `Operand` that designates a def of a vreg that is
+    /// placed in the same stack slot as an input to the instruction
+    /// (see `OperandConstraint::ReuseStack`). The input is identified
+    /// by `idx` (is the `idx`th `Operand` for the instruction) and
+    /// would usually be the result of `Operand::any_use(vreg)`.
+    #[inline(always)]
+    pub fn stack_reuse_def(vreg: VReg, idx: usize) -> Self {
+        Operand::new(
+            vreg,
+            OperandConstraint::ReuseStack(idx),
+            OperandKind::Def,
+            OperandPos::Late,
+        )
+    }
+
     /// Create an `Operand` that designates a use of a vreg and
     /// ensures that it is placed in the given, fixed PReg at the
     /// use. It is guaranteed that the `Allocation` resulting for this
@@ -821,10 +1020,16 @@ impl Operand {
             OperandConstraint::FixedReg(PReg::new(constraint_field & 0b0111111, self.class()))
         } else if constraint_field & 0b0100000 != 0 {
             OperandConstraint::Reuse(constraint_field & 0b0011111)
+        } else if constraint_field & 0b0010000 != 0 {
+            OperandConstraint::ReuseStack(constraint_field & 0b0001111)
+        } else if constraint_field & 0b0001000 != 0 {
+            OperandConstraint::Stack(1 << (constraint_field & 0b0000111))
         } else {
             match constraint_field {
                 0 => OperandConstraint::Any,
                 1 => OperandConstraint::Reg,
+                3 => OperandConstraint::RegPair,
+                4..=7 => OperandConstraint::RegInGroup(constraint_field & 0b0000011),
                 _ => unreachable!(),
             }
         }
@@ -1066,6 +1271,11 @@ pub trait Function {
     fn block_params(&self, block: Block) -> &[VReg];
 
     /// Determine whether an instruction is a return instruction.
+    ///
+    /// A function may have any number of returns. Each one declares
+    /// its own live-out ABI registers through `FixedReg` uses among
+    /// its operands, so different returns (e.g. a normal return and a
+    /// tail call) may place results in different registers.
     fn is_ret(&self, insn: Inst) -> bool;
 
     /// Determine whether an instruction is the end-of-block
@@ -1078,6 +1288,86 @@ pub trait Function {
     /// for each respective successor block.
     fn branch_blockparams(&self, block: Block, insn: Inst, succ_idx: usize) -> &[VReg];
 
+    /// If `insn` is a branch at the end of `block`, returns the values
+    /// it defines only on its edge to the given successor, as
+    /// `(vreg, preg)`: on that edge the branch leaves `vreg` in `preg`
+    /// (e.g. a call that also branches, whose result only exists on
+    /// its normal-return edge). Each vreg is defined at the start of
+    /// the successor, like a block parameter, and moved out of `preg`
+    /// there as needed. The successor must have no other
+    /// predecessor. `preg` is treated as clobbered by the branch on
+    /// all of its edges.
+    fn branch_edge_defs(&self, _block: Block, _insn: Inst, _succ_idx: usize) -> &[(VReg, PReg)] {
+        &[]
+    }
+
+    /// The function's arguments, as `(vreg, preg)`: on entry, `vreg`
+    /// is in `preg` as the calling convention dictates. A `preg` may
+    /// be one of `MachineEnv::fixed_stack_slots` for an argument
+    /// passed on the stack. Each vreg is defined at the start of the
+    /// entry block, like a block parameter, and moved out of `preg`
+    /// into wherever it is allocated there.
+    fn entry_args(&self) -> &[(VReg, PReg)] {
+        &[]
+    }
+
+    /// If `block` is an exception landing pad, the values the runtime
+    /// passes to it, as `(vreg, preg)`: control arrives there by
+    /// unwinding with `vreg` in `preg` (e.g. the exception pointer
+    /// and selector). Each vreg is defined at the start of the block,
+    /// like a block parameter, and moved out of `preg` there as
+    /// needed; the block need not have any predecessors, and it must
+    /// not also receive edge defs (see `branch_edge_defs`). Any
+    /// branch that does lead to the block treats `preg` as clobbered,
+    /// like an edge def. Ignored for the entry block.
+    fn landing_pad_args(&self, _block: Block) -> &[(VReg, PReg)] {
+        &[]
+    }
+
+    /// Get the relative execution frequency of a block, if profile or
+    /// static-estimate data is available. This is only used for
+    /// diagnostics (see `RegallocOptions::hot_spill_threshold`); when
+    /// it returns `None`, a frequency of `4^d` is assumed for a block
+    /// at approximate loop depth `d`.
+    fn block_frequency(&self, _block: Block) -> Option<f32> {
+        None
+    }
+
+    /// Is the block known to be rarely executed (e.g. a panic or
+    /// slow path)? Uses in cold blocks carry almost no spill weight,
+    /// so bundles used only there are evicted first and splits with
+    /// their spill and reload moves are preferably placed there.
+    fn block_is_cold(&self, _block: Block) -> bool {
+        false
+    }
+
+    /// The registers that code in the block can access, for targets
+    /// whose register banks are only reachable from some regions of
+    /// code; `None`, the default, means all of them. A value is only
+    /// given a register in the blocks where that register is
+    /// available, and is moved or spilled where it crosses into a
+    /// block without it. Operands of the block's instructions, edge
+    /// defs into the block and its entry or landing-pad args must not
+    /// be fixed to an allocatable register that is missing here. Moves
+    /// on an edge between blocks with different registers read from
+    /// one side's registers and write the other's. Scratch registers
+    /// (`MachineEnv::scratch_by_class`) must be available everywhere.
+    fn block_available_regs(&self, _block: Block) -> Option<PRegSet> {
+        None
+    }
+
+    /// Registers that may not hold any value over a range of program
+    /// points, as `(preg, from, to)` with `to` exclusive, e.g. one
+    /// that inline asm spanning several instructions uses for itself.
+    /// Unlike a clobber, which takes a register at one instruction,
+    /// this takes it for the whole range: values live across it are
+    /// kept elsewhere. No operand of an instruction with either of
+    /// its points in the range may be fixed to the register, nor may
+    /// an edge def or entry or landing-pad arg that is live there.
+    fn unavailable_ranges(&self) -> &[(PReg, ProgPoint, ProgPoint)] {
+        &[]
+    }
+
     // --------------------------
     // Instruction register slots
     // --------------------------
@@ -1115,8 +1405,104 @@ pub trait Function {
     /// might have a constant clobber set determined by the ABI, but
     /// some of those clobbered registers are sometimes return
     /// value(s).
+    ///
+    /// Calls also clobber `MachineEnv::call_clobbers` (see
+    /// `Function::is_call`), which need not be repeated here.
     fn inst_clobbers(&self, insn: Inst) -> PRegSet;
 
+    /// Whether the instruction is a call, which additionally clobbers
+    /// the environment's `MachineEnv::call_clobbers`. This saves
+    /// building the same ABI clobber set for every call.
+    fn is_call(&self, _insn: Inst) -> bool {
+        false
+    }
+
+    /// Get the "soft clobbers" for an instruction: registers that the
+    /// instruction may use only if the caller saved them, e.g. under
+    /// some call conventions. Unlike `inst_clobbers`, these do not
+    /// interfere with values live across the instruction; the
+    /// allocator merely prefers other registers for such values and
+    /// still uses a soft-clobbered register rather than spilling.
+    fn inst_soft_clobbers(&self, _insn: Inst) -> PRegSet {
+        PRegSet::empty()
+    }
+
+    /// Get the registers killed at the start of an instruction: like
+    /// clobbers, but at the `Early` point rather than the `Late`
+    /// one, e.g. for inline asm that scribbles over a register before
+    /// reading its inputs. No value is kept in these registers across
+    /// the instruction, and no `Use` or `Early` `Def` operand is
+    /// placed in them; only `Late` defs may be.
+    fn inst_early_kills(&self, _insn: Inst) -> PRegSet {
+        PRegSet::empty()
+    }
+
+    /// A register that the operand in `slot` of `insn` would like to
+    /// be allocated to, e.g. for a shorter encoding. Unlike a
+    /// `FixedReg` constraint this is only a preference: the allocator
+    /// tries the register first for the bundle holding the operand
+    /// when it is free, and otherwise picks any other register
+    /// without inserting moves for it. A preference for a register
+    /// that is not allocatable in the operand's class is ignored.
+    /// (`Operand` has no spare bits to carry it.)
+    fn operand_preferred_reg(&self, _insn: Inst, _slot: usize) -> Option<PReg> {
+        None
+    }
+
+    /// The class through which the operand in `slot` of `insn`
+    /// accesses its vreg's location, if not the vreg's own, e.g. an
+    /// integer register read as the low lane of a vector register on
+    /// an ISA with a unified register file. The operand is allocated
+    /// like any other of the vreg's class; the allocator then emits
+    /// an `Edit::Reinterpret` into the view just before the
+    /// instruction for a use, and one back out of it just after the
+    /// instruction for a def. (`Operand` has no spare bits to carry
+    /// it.)
+    fn operand_class_view(&self, _insn: Inst, _slot: usize) -> Option<RegClass> {
+        None
+    }
+
+    /// Whether the use in `slot` of `insn` is of a constant that the
+    /// instruction encodes as an immediate, so the operand needs no
+    /// location. The allocator leaves it unallocated: its allocation
+    /// is `Allocation::none()`, and it neither keeps its vreg live
+    /// nor needs a reload or `Edit::Remat` of it, so a constant used
+    /// only as an immediate holds no register past its def. Only an
+    /// `Any` use at `Early` of a vreg that is not a register pair may
+    /// be an immediate, and not as the input of a `Reuse` def.
+    /// (`Operand` has no spare bits to carry it.)
+    fn operand_is_immediate(&self, _insn: Inst, _slot: usize) -> bool {
+        false
+    }
+
+    /// Whether all register operands of the instruction must be in
+    /// distinct physical registers, e.g. for some vector shuffles.
+    /// Uses then interfere with the instruction's defs, and a vreg
+    /// used more than once with a `Reg` constraint is copied into a
+    /// separate register for each extra use.
+    fn inst_requires_distinct_regs(&self, _insn: Inst) -> bool {
+        false
+    }
+
+    /// The classes of the scratch registers the instruction needs for
+    /// its own lowering, one entry per register. Each is held across
+    /// the whole instruction, so it is distinct from all operands and
+    /// clobbers and from every value live across the instruction; its
+    /// contents are undefined on entry and discarded afterwards. The
+    /// registers are reported by `Output::inst_temp_allocs`.
+    fn inst_temps(&self, _insn: Inst) -> &[RegClass] {
+        &[]
+    }
+
+    /// If the instruction is a plain register-to-register copy,
+    /// return its `(src, dst)` vregs. Both must also appear among the
+    /// instruction's operands, as a use and a def respectively. The
+    /// allocator then tries to give both the same allocation, in
+    /// which case the client can drop the instruction entirely.
+    fn is_move(&self, _insn: Inst) -> Option<(VReg, VReg)> {
+        None
+    }
+
     /// Get the number of `VReg` in use in this function.
     fn num_vregs(&self) -> usize;
 
@@ -1141,6 +1527,77 @@ pub trait Function {
         &[]
     }
 
+    // -----------
+    // Split hints
+    // -----------
+
+    /// Preferred split points, e.g. derived from profiling, as
+    /// `(vreg, point)` pairs. When the allocator has to split a range
+    /// of `vreg` ahead of a conflict, it splits at the latest hinted
+    /// point of the range's vregs that comes before the conflict,
+    /// instead of choosing a point itself. Hints that fall outside
+    /// the range being split are ignored, and a hint in the middle of
+    /// an instruction is moved to just before the next one.
+    ///
+    /// Precondition: we require this slice to be sorted by vreg and
+    /// then program point.
+    fn split_hints(&self) -> &[(VReg, ProgPoint)] {
+        &[]
+    }
+
+    // --------------
+    // Coalesce hints
+    // --------------
+
+    /// Pairs of vregs that the frontend knows to hold the same
+    /// logical value, e.g. across a copy that `Function::is_move`
+    /// cannot describe, and would like to be given the same
+    /// allocation. The allocator merges each pair into one bundle
+    /// before any other coalescing, unless the two interfere (or
+    /// differ in class), in which case the hint is ignored.
+    fn coalesce_hints(&self) -> &[(VReg, VReg)] {
+        &[]
+    }
+
+    // ----------------
+    // Allocation hints
+    // ----------------
+
+    /// Registers that vregs held in an earlier allocation of this
+    /// function, or of a slightly different version of it, as
+    /// `(vreg, preg)` pairs; see `Output::allocation_hints`. Each
+    /// bundle tries its vreg's hinted register before any other, so
+    /// the unchanged parts of the function settle on their previous
+    /// assignment with fewer probes and evictions. Hints only change
+    /// the search order, never the result's validity: a hint that is
+    /// not an allocatable register of the vreg's class is ignored,
+    /// and a hinted register that is taken is treated like any other.
+    ///
+    /// Precondition: we require this slice to be sorted by vreg.
+    fn allocation_hints(&self) -> &[(VReg, PReg)] {
+        &[]
+    }
+
+    // -------------------------------
+    // Reference types and safepoints
+    // -------------------------------
+
+    /// Whether `vreg` holds a reference that a garbage collector must
+    /// be able to find (and may move) at every safepoint it is live
+    /// across; see `Output::stackmaps`.
+    fn is_reference_vreg(&self, _vreg: VReg) -> bool {
+        false
+    }
+
+    /// Whether `insn` is a safepoint, e.g. a call that may collect
+    /// garbage, which needs a stackmap in `Output::stackmaps`. After
+    /// a safepoint, the allocator only reads a live reference from
+    /// the allocations in its stackmap, as the collector may have
+    /// moved the object it points to. Branches are never safepoints.
+    fn requires_stackmap(&self, _insn: Inst) -> bool {
+        false
+    }
+
     // --------------
     // Spills/reloads
     // --------------
@@ -1154,6 +1611,48 @@ pub trait Function {
     /// regalloc.rs' trait of the same name.)
     fn spillslot_size(&self, regclass: RegClass) -> usize;
 
+    /// Whether `vreg` must never be stored to the stack, e.g. because
+    /// spilling it would be a bug. Where the allocator would otherwise
+    /// spill it, it emits an `Edit::Remat` at each point it would
+    /// reload it instead, if `Function::can_remat` holds; otherwise
+    /// allocation fails with `RegAllocError::OutOfRegisters`. Such a
+    /// vreg may not have operands constrained to the stack, nor be a
+    /// rematerializable reference vreg; its `Any` operands are given
+    /// a register.
+    fn never_spill(&self, _vreg: VReg) -> bool {
+        false
+    }
+
+    /// Whether the frontend can recompute the value of `vreg` at any
+    /// point where it is live; see `Function::never_spill`. Block
+    /// parameters and register-pair vregs are never rematerialized.
+    fn can_remat(&self, _vreg: VReg) -> bool {
+        false
+    }
+
+    /// Whether `insn` is pure: it has no effect besides writing its
+    /// defs, so it may be executed again elsewhere. If such an
+    /// instruction has a single operand, a def of a vreg with a
+    /// single use, and no temps or clobbers, the allocator sinks the
+    /// def to the use where it would otherwise spill the value: it
+    /// emits an `Edit::Sink` in place of each reload, and stores
+    /// nothing. The instruction itself keeps its place and its
+    /// allocation.
+    fn inst_is_pure(&self, _insn: Inst) -> bool {
+        false
+    }
+
+    /// The latency of `insn` in cycles, as a hint for pipelined
+    /// targets: a register written by an instruction with a latency
+    /// above 1 is still busy for that many instructions, and writing
+    /// it again meanwhile adds a false dependency on the producer.
+    /// Among otherwise equal candidates, the allocator probes such
+    /// registers last. The default of 1 leaves the probe order as it
+    /// is.
+    fn inst_latency(&self, _insn: Inst) -> u32 {
+        1
+    }
+
     /// When providing a spillslot number for a multi-slot spillslot,
     /// do we provide the first or the last? This is usually related
     /// to which direction the stack grows and different clients may
@@ -1179,6 +1678,20 @@ pub trait Function {
     fn allow_multiple_vreg_defs(&self) -> bool {
         false
     }
+
+    /// Is the function in SSA form? If not, a vreg may be defined by
+    /// more than one instruction, and each def simply overwrites the
+    /// value seen by the uses that follow it. Liveness is computed
+    /// per def, so no renaming is needed on the embedder's side; all
+    /// defs of a vreg do share one spillslot. Uses must still be
+    /// reached by some def along every path from the entry block.
+    ///
+    /// Non-SSA functions are skipped by the SSA validator (the
+    /// `validate_ssa` option) and by the SSA checks in
+    /// [`validate_function`].
+    fn is_ssa(&self) -> bool {
+        true
+    }
 }
 
 /// A position before or after an instruction at which we can make an
@@ -1296,12 +1809,154 @@ impl ProgPoint {
 pub enum Edit {
     /// Move one allocation to another. Each allocation may be a
     /// register or a stack slot (spillslot). However, stack-to-stack
-    /// moves will never be generated.
+    /// moves will never be generated unless the `MachineEnv` sets
+    /// `stack_to_stack_moves`. Both registers have the same class
+    /// unless `RegallocOptions::cross_class_spills` is set, in which
+    /// case one may be a register of another class holding a spilled
+    /// value.
     ///
-    /// `Move` edits will be generated even if src and dst allocation
-    /// are the same if the vreg changes; this allows proper metadata
-    /// tracking even when moves are elided.
+    /// A move whose source and destination are the same allocation is
+    /// never emitted, even if it hands the value over to another vreg
+    /// (e.g. a block parameter); the checker tracks such hand-overs
+    /// itself, so no metadata-only edits appear in the output.
     Move { from: Allocation, to: Allocation },
+
+    /// Recompute the value of `vreg` into `to`, in place of a reload of
+    /// a vreg that may never be spilled; see `Function::never_spill`.
+    Remat { vreg: VReg, to: Allocation },
+
+    /// Execute the pure instruction `inst` again, writing the value
+    /// of `vreg`, its def, into `to` in place of a reload of it; see
+    /// `Function::inst_is_pure`.
+    Sink {
+        inst: Inst,
+        vreg: VReg,
+        to: Allocation,
+    },
+
+    /// Switch the view of the value in `alloc` from `from_class` to
+    /// `to_class`, around an operand that accesses its location as
+    /// another class; see `Function::operand_class_view`. The value
+    /// does not move, so this needs no code unless the emitter has to
+    /// know which encoding the location is in.
+    Reinterpret {
+        alloc: Allocation,
+        from_class: RegClass,
+        to_class: RegClass,
+    },
+}
+
+/// Why the allocator inserted a move; see `Output::move_causes`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum MoveCause {
+    /// Moves a value into a block parameter or live-in at the start
+    /// of a block.
+    EdgeIn,
+    /// Moves a value into a block parameter or live-in at the end of
+    /// a block.
+    EdgeOut,
+    /// Stores a value to its spillslot within a block.
+    Spill,
+    /// Loads a value from its spillslot within a block.
+    Reload,
+    /// Moves a value between two registers within a block, where its
+    /// live range was split.
+    Split,
+    /// Copies a value into another register it is needed in at one
+    /// instruction, for a fixed-register constraint or
+    /// `Function::inst_requires_distinct_regs`.
+    FixedReg,
+    /// Copies an input into its output's allocation for a `Reuse`
+    /// constraint.
+    ReuseInput,
+    /// Saves or restores a register that was borrowed as the
+    /// temporary for stack-to-stack moves, or saves a value to the
+    /// scratch location to break a cycle of moves. The loads and
+    /// stores of the stack-to-stack moves themselves keep the cause
+    /// of the move they implement.
+    StackStackLowering,
+    /// Switches the class view of an operand's location; see
+    /// `Edit::Reinterpret`.
+    Reinterpret,
+}
+
+/// How the allocator fell short of an operand's soft constraint; see
+/// `Output::relaxed_constraints`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum RelaxationKind {
+    /// The operand did not get the register it prefers (see
+    /// `Function::operand_preferred_reg`).
+    PreferredReg,
+    /// An `OperandConstraint::Any` operand, which would rather be in
+    /// a register, was given a stack slot.
+    Spilled,
+}
+
+/// A spill or reload decision, as reported to
+/// `RegallocOptions::spill_observer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpillEvent {
+    /// Whether a spilled value is brought back into a register, as
+    /// opposed to (part of) a vreg being given its spillslot.
+    pub kind: SpillEventKind,
+    /// The vreg spilled or reloaded.
+    pub vreg: VReg,
+    /// For a spill, where the part of the vreg that lives in the
+    /// spillslot starts; for a reload, where the move is inserted.
+    pub pos: ProgPoint,
+    /// Whether the vreg is on the stack because an operand requires
+    /// it (`OperandConstraint::Stack` or `ReuseStack`), rather than
+    /// because no register was free.
+    pub forced_by_constraint: bool,
+}
+
+/// See `SpillEvent::kind`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SpillEventKind {
+    Spill,
+    Reload,
+}
+
+/// A callback for `RegallocOptions::spill_observer`. It is only
+/// borrowed, so it may capture state (e.g. a `RefCell` of counters),
+/// while the options stay `Copy`.
+#[derive(Clone, Copy)]
+pub struct SpillObserver<'a>(pub &'a dyn Fn(SpillEvent));
+
+impl core::fmt::Debug for SpillObserver<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str("SpillObserver(..)")
+    }
+}
+
+/// The moves on one CFG edge; see `Output::edge_moves`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EdgeMoves {
+    /// The moves, as `(from, to)`, in the order they are emitted.
+    /// `from` is `Allocation::none()` for an `Edit::Remat` or an
+    /// `Edit::Sink`; no `Edit::Reinterpret` is ever part of an edge's
+    /// moves.
+    pub moves: Vec<(Allocation, Allocation)>,
+    /// For each move, the earlier moves that must precede it: those
+    /// that write what it reads or overwrites, and those that read
+    /// what it overwrites.
+    pub deps: Vec<Vec<usize>>,
+}
+
+impl EdgeMoves {
+    /// The number of moves in the longest chain of dependent moves.
+    /// This is the lower bound on the length of the sequence even if
+    /// independent moves could be emitted in parallel.
+    pub fn critical_path(&self) -> usize {
+        let mut depth: Vec<usize> = Vec::with_capacity(self.moves.len());
+        for deps in &self.deps {
+            let d = deps.iter().map(|&i| depth[i]).max().unwrap_or(0) + 1;
+            depth.push(d);
+        }
+        depth.into_iter().max().unwrap_or(0)
+    }
 }
 
 /// Wrapper around either an original instruction or an inserted edit.
@@ -1311,6 +1966,16 @@ pub enum InstOrEdit<'a> {
     Edit(&'a Edit),
 }
 
+/// An item of `Output::instruction_stream`.
+#[derive(Clone, Debug)]
+pub enum StreamItem<'a> {
+    /// An original instruction, with its operand allocations (see
+    /// `Output::inst_allocs`).
+    Inst(Inst, &'a [Allocation]),
+    /// A move inserted by the allocator.
+    Edit(&'a Edit),
+}
+
 /// Iterator over the instructions and edits in a block.
 pub struct OutputIter<'a> {
     /// List of edits starting at the first for the current block.
@@ -1344,6 +2009,145 @@ impl<'a> Iterator for OutputIter<'a> {
     }
 }
 
+/// The allocation results for one block, as yielded by
+/// `Output::into_blocks`.
+#[derive(Clone, Debug)]
+pub struct BlockOutput {
+    /// The block.
+    pub block: Block,
+    /// The block's instructions.
+    pub insts: InstRange,
+    /// The edits within the block, sorted by program point.
+    pub edits: Vec<(ProgPoint, Edit)>,
+    /// The registers assigned to the block's instruction temps; see
+    /// `Output::temp_allocs`.
+    pub temp_allocs: Vec<(Inst, Allocation)>,
+    /// The stackmaps of the block's safepoints; see
+    /// `Output::stackmaps`.
+    pub stackmaps: Vec<(Inst, Vec<Allocation>)>,
+    /// Allocations for each operand of the block's instructions.
+    allocs: Vec<Allocation>,
+    /// Allocation offset in `allocs` for each instruction.
+    inst_alloc_offsets: Vec<u32>,
+}
+
+impl BlockOutput {
+    /// Get the allocations assigned to a given instruction of the
+    /// block, like `Output::inst_allocs`.
+    pub fn inst_allocs(&self, inst: Inst) -> &[Allocation] {
+        let i = inst.index() - self.insts.first().index();
+        let start = self.inst_alloc_offsets[i] as usize;
+        let end = self
+            .inst_alloc_offsets
+            .get(i + 1)
+            .map_or(self.allocs.len(), |&offset| offset as usize);
+        &self.allocs[start..end]
+    }
+
+    /// Returns an iterator over the instructions and edits in the
+    /// block, in order, like `Output::block_insts_and_edits`.
+    pub fn insts_and_edits(&self) -> OutputIter<'_> {
+        OutputIter {
+            edits: &self.edits,
+            inst_range: self.insts,
+        }
+    }
+}
+
+/// Iterator over the blocks of an `Output`; see `Output::into_blocks`.
+pub struct BlockOutputs<'a, F: Function> {
+    func: &'a F,
+    /// Blocks still to be yielded, the next one last.
+    blocks: Vec<Block>,
+    /// The allocations not yet yielded, in reverse order so that
+    /// those of the next block can be split off the end.
+    allocs: Vec<Allocation>,
+    num_allocs: usize,
+    inst_alloc_offsets: Vec<u32>,
+    /// Likewise for the edits, temp allocations and stackmaps.
+    edits: Vec<(ProgPoint, Edit)>,
+    temp_allocs: Vec<(Inst, Allocation)>,
+    stackmaps: Vec<(Inst, Vec<Allocation>)>,
+}
+
+impl<'a, F: Function> BlockOutputs<'a, F> {
+    /// Take the entries of `list` (in reverse order) that come before
+    /// `end`, after dropping those before `start`.
+    fn take<T, K: PartialOrd>(
+        list: &mut Vec<T>,
+        key: impl Fn(&T) -> K,
+        start: K,
+        end: K,
+    ) -> Vec<T> {
+        while list.last().is_some_and(|t| key(t) < start) {
+            list.pop();
+        }
+        let split = list.partition_point(|t| key(t) >= end);
+        let mut taken = list.split_off(split);
+        taken.reverse();
+        Self::release(list);
+        taken
+    }
+
+    /// Give back the memory of entries taken from `list` once it is
+    /// mostly unused.
+    fn release<T>(list: &mut Vec<T>) {
+        if list.capacity() > 2 * list.len() + 64 {
+            list.shrink_to_fit();
+        }
+    }
+}
+
+impl<'a, F: Function> Iterator for BlockOutputs<'a, F> {
+    type Item = BlockOutput;
+
+    fn next(&mut self) -> Option<BlockOutput> {
+        let block = self.blocks.pop()?;
+        let insts = self.func.block_insns(block);
+        let next = Inst::new(insts.last().index() + 1);
+        let num_allocs = self.num_allocs;
+        let offsets = &self.inst_alloc_offsets;
+        let offset = |inst: Inst| {
+            offsets
+                .get(inst.index())
+                .map_or(num_allocs, |&offset| offset as usize)
+        };
+        let (start, end) = (offset(insts.first()), offset(next));
+        let inst_alloc_offsets = insts
+            .iter()
+            .map(|inst| (offset(inst) - start) as u32)
+            .collect();
+        // The allocations left are those from index
+        // `num_allocs - self.allocs.len()` on.
+        self.allocs.truncate(num_allocs - start);
+        let mut allocs = self.allocs.split_off(num_allocs - end);
+        allocs.reverse();
+        Self::release(&mut self.allocs);
+        let edits = Self::take(
+            &mut self.edits,
+            |&(pos, _)| pos,
+            ProgPoint::before(insts.first()),
+            ProgPoint::before(next),
+        );
+        let temp_allocs = Self::take(
+            &mut self.temp_allocs,
+            |&(inst, _)| inst,
+            insts.first(),
+            next,
+        );
+        let stackmaps = Self::take(&mut self.stackmaps, |&(inst, _)| inst, insts.first(), next);
+        Some(BlockOutput {
+            block,
+            insts,
+            edits,
+            temp_allocs,
+            stackmaps,
+            allocs,
+            inst_alloc_offsets,
+        })
+    }
+}
+
 /// A machine environment tells the register allocator which registers
 /// are available to allocate and what register may be used as a
 /// scratch register for each class, and some other miscellaneous info
@@ -1367,6 +2171,18 @@ pub struct MachineEnv {
     /// it must not appear in this list.
     pub non_preferred_regs_by_class: [Vec<PReg>; 3],
 
+    /// Optional explicit probe order for each class. By default the
+    /// allocator rotates its starting point within the preferred and
+    /// non-preferred lists per bundle to spread register pressure. If
+    /// an order is given here, free registers of that class are
+    /// instead always tried exactly in this order (after any hinted
+    /// register), e.g. to favor registers with shorter encodings.
+    ///
+    /// The list must contain exactly the registers of the class's
+    /// preferred and non-preferred lists, in any order; allocation
+    /// fails with `RegAllocError::ProbeOrder` otherwise.
+    pub probe_order_by_class: [Option<Vec<PReg>>; 3],
+
     /// Optional dedicated scratch register per class. This is needed to perform
     /// moves between registers when cyclic move patterns occur. The
     /// register should not be placed in either the preferred or
@@ -1391,21 +2207,105 @@ pub struct MachineEnv {
     /// `PReg`s in this list cannot be used as an allocatable or scratch
     /// register.
     pub fixed_stack_slots: Vec<PReg>,
+
+    /// Whether the target can move a value directly from one stack
+    /// location to another. If set, the allocator emits stack-to-stack
+    /// `Edit::Move`s as-is rather than expanding them through a
+    /// scratch register, and so never needs an extra spillslot to save
+    /// a borrowed scratch register around them.
+    pub stack_to_stack_moves: bool,
+
+    /// Registers the allocator must never write, e.g. a frame or base
+    /// pointer. They are dropped from the allocatable lists if
+    /// present, are never used as scratch registers (a reserved
+    /// `scratch_by_class` entry is ignored), and may not appear in a
+    /// `FixedReg` constraint on an allocatable vreg; a
+    /// fixed-nonallocatable operand can still name them to read them.
+    /// No `Edit` produced by the allocator reads or writes them.
+    pub reserved_regs: PRegSet,
+
+    /// The size in bytes of a spill of each register class, or 0 if
+    /// not declared. Declared sizes must be powers of two. If any are
+    /// declared, spillslot indices count units of the smallest
+    /// declared size (see `MachineEnv::spillslot_unit`), and a value
+    /// of a declared class takes `size / unit` of them in place of
+    /// `Function::spillslot_size`.
+    pub slot_size_by_class: [usize; 3],
+
+    /// Registers clobbered by every instruction for which
+    /// `Function::is_call` holds, on top of its own
+    /// `Function::inst_clobbers`; typically the ABI's caller-saved
+    /// registers.
+    pub call_clobbers: PRegSet,
+
+    /// Register groups for `OperandConstraint::RegInGroup`, by index.
+    /// At most four groups can be named by an operand.
+    pub reg_groups: Vec<PRegSet>,
+}
+
+impl MachineEnv {
+    /// The size in bytes of one spillslot index, i.e. the smallest
+    /// size in `slot_size_by_class`; `None` if none is declared.
+    pub fn spillslot_unit(&self) -> Option<usize> {
+        self.slot_size_by_class
+            .iter()
+            .copied()
+            .filter(|&size| size != 0)
+            .min()
+    }
 }
 
 /// The output of the register allocator.
+///
+/// Instructions are never renumbered: every `Inst` and `ProgPoint`
+/// in the output, in `edits` as well as in the per-instruction
+/// tables, refers to the function's own instruction indices. The
+/// moves the allocator needs are reported as edits between those
+/// instructions, not inserted among them, so metadata keyed by
+/// instruction index stays valid. Only `Output::splice`, which merges
+/// in the output of another function, renumbers instructions.
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct Output {
     /// How many spillslots are needed in the frame?
     pub num_spillslots: usize,
 
+    /// The size in bytes of the spill area, i.e. `num_spillslots`
+    /// times `MachineEnv::spillslot_unit`, with spillslot `i` at byte
+    /// offset `i * unit`; `None` unless
+    /// `MachineEnv::slot_size_by_class` declares sizes.
+    pub spill_area_size: Option<usize>,
+
+    /// How many of those are in the secondary spill area, i.e. the
+    /// last this many; zero unless
+    /// `RegallocOptions::primary_spillslots` is set and exceeded.
+    pub num_secondary_spillslots: usize,
+
     /// Edits (insertions or removals). Guaranteed to be sorted by
     /// program point.
     pub edits: Vec<(ProgPoint, Edit)>,
 
+    /// Diagnostic: the cause of each edit, in step with `edits`.
+    /// Empty unless `RegallocOptions::move_causes` is set.
+    pub move_causes: Vec<MoveCause>,
+
+    /// The register class of the value each edit moves, in step with
+    /// `edits`; unlike the `Allocation`s, this also tells the class
+    /// of a stack-to-stack move or of a cross-class spill or reload
+    /// (see `RegallocOptions::cross_class_spills`). Empty unless
+    /// `RegallocOptions::move_classes` is set.
+    pub move_classes: Vec<RegClass>,
+
+    /// The vreg whose value each edit moves, in step with `edits`;
+    /// `None` for a move of the resolver's own, e.g. the save or
+    /// restore of a borrowed scratch register, and for an
+    /// `Edit::Reinterpret`. Empty unless `RegallocOptions::move_vregs`
+    /// is set; see `Output::moves_for_vreg`.
+    pub move_vregs: Vec<Option<VReg>>,
+
     /// Allocations for each operand. Mapping from instruction to
-    /// allocations provided by `inst_alloc_offsets` below.
+    /// allocations provided by `inst_alloc_offsets` below; prefer
+    /// `Output::inst_allocs` to index this.
     pub allocs: Vec<Allocation>,
 
     /// Allocation offset in `allocs` for each instruction.
@@ -1419,12 +2319,133 @@ pub struct Output {
     /// be disjoint.
     pub debug_locations: Vec<(u32, ProgPoint, ProgPoint, Allocation)>,
 
+    /// Diagnostic: each `OperandConstraint::Reuse` that could not be
+    /// satisfied in place and instead required a copy from the input's
+    /// allocation into the output's before the instruction, as
+    /// `(inst, input_idx, output_idx)`. Sorted by instruction. A
+    /// two-address frontend can use this to spot instructions whose
+    /// selection forced an extra move.
+    pub reuse_copies: Vec<(Inst, usize, usize)>,
+
+    /// Diagnostic: each operand whose soft constraint was not ideally
+    /// met, as `(inst, operand_idx, kind)`. Sorted by instruction and
+    /// operand. A frontend can use this to find where register
+    /// pressure cost it the code it would have liked.
+    pub relaxed_constraints: Vec<(Inst, usize, RelaxationKind)>,
+
+    /// The registers assigned to the scratch registers of each
+    /// instruction (see `Function::inst_temps`), as `(inst, alloc)`.
+    /// Sorted by instruction, and in the order of `inst_temps` within
+    /// one; prefer `Output::inst_temp_allocs` to index this.
+    pub temp_allocs: Vec<(Inst, Allocation)>,
+
+    /// The stackmap of each safepoint (see
+    /// `Function::requires_stackmap`): the allocations holding the
+    /// reference vregs (see `Function::is_reference_vreg`) that are
+    /// live across it, i.e. live both before and after it and not
+    /// defined by it, as `(inst, allocs)`. A value may be listed in
+    /// several allocations, e.g. both the register it was reloaded
+    /// into and its spillslot, and a register pair lists both
+    /// halves. Sorted by instruction, with the allocations of each
+    /// sorted too; prefer `Output::stackmap` to index this.
+    pub stackmaps: Vec<(Inst, Vec<Allocation>)>,
+
     /// Internal stats from the allocator.
     pub stats: ion::Stats,
+
+    /// Diagnostic: vregs that were (at least partly) spilled although
+    /// some of their uses are in blocks whose frequency is at or
+    /// above `RegallocOptions::hot_spill_threshold`, together with
+    /// the highest such frequency. Sorted by vreg. Empty unless the
+    /// threshold is set.
+    pub hot_spills: Vec<(VReg, f32)>,
+
+    /// Diagnostic: for each register, the program-point ranges
+    /// `[from, to)` during which it holds each vreg, as
+    /// `(preg, from, to, vreg)`. Sorted by register and then program
+    /// point. Empty unless `RegallocOptions::preg_timelines` is set;
+    /// see `Output::preg_timeline`.
+    pub preg_timelines: Vec<(PReg, ProgPoint, ProgPoint, VReg)>,
+
+    /// Diagnostic: register pressure as a step function of
+    /// `(point, live)` entries sorted by program point, where
+    /// `live[class]` is the number of vregs of each class live from
+    /// `point` up to the next entry (a register pair counts twice).
+    /// Empty unless `RegallocOptions::pressure` is set; see
+    /// `Output::pressure_at`.
+    pub pressure: Vec<(ProgPoint, [usize; 3])>,
+
+    /// Diagnostic: the allocatable registers not occupied by any
+    /// value, clobber or fixed-register reservation, as a step
+    /// function of `(point, free)` entries sorted by program point,
+    /// where `free` holds from `point` up to the next entry. Empty
+    /// unless `RegallocOptions::free_regs` is set; see
+    /// `Output::free_regs_at`.
+    pub free_regs: Vec<(ProgPoint, PRegSet)>,
+
+    /// Debug info: for every vreg, how its live ranges were grouped
+    /// into bundles and spillsets; see `Output::vreg_bundles`.
+    #[cfg(feature = "debug-bundles")]
+    pub vreg_bundles: Vec<Vec<VRegBundle>>,
+
+    /// Debug info: the allocator's decisions, in the order it made
+    /// them. The log depends only on the function, environment and
+    /// options, so tests can compare it across allocator changes.
+    #[cfg(feature = "decision-log")]
+    pub decision_log: Vec<Decision>,
+}
+
+/// One live range of a vreg at the end of allocation, and the chain
+/// through which its allocation was resolved. The indices are the
+/// allocator's internal ones; they are only meaningful relative to
+/// each other within one `Output`.
+#[cfg(feature = "debug-bundles")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct VRegBundle {
+    /// Index of the live range.
+    pub live_range: usize,
+    /// First program point covered by the live range (inclusive).
+    pub from: ProgPoint,
+    /// Last program point covered by the live range (exclusive).
+    pub to: ProgPoint,
+    /// Index of the bundle the live range ended up in. Ranges of
+    /// different vregs in the same bundle were merged (e.g. through
+    /// blockparams or reuse constraints) and share an allocation.
+    pub bundle: usize,
+    /// Index of the bundle's spillset, if the bundle got no register
+    /// and so lives in the spillset's spillslot.
+    pub spillset: Option<usize>,
+    /// The allocation of the live range.
+    pub alloc: Allocation,
+}
+
+/// One decision of the allocator; see `Output::decision_log`. Bundle
+/// indices are the allocator's internal ones, as in `VRegBundle`.
+#[cfg(feature = "decision-log")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum Decision {
+    /// The bundle was assigned to the register.
+    Assign { bundle: usize, preg: PReg },
+    /// The bundle was taken out of its register to make room for
+    /// another, and queued again.
+    Evict { bundle: usize },
+    /// The bundle was split at the point, and the parts queued.
+    Split { bundle: usize, at: ProgPoint },
+    /// The bundle was split into minimal bundles around its uses, the
+    /// rest of it going to its spill bundle.
+    SplitMinimal { bundle: usize },
+    /// The bundle was left in its spillslot.
+    Spill { bundle: usize },
+    /// The bundle was moved to another register after allocation.
+    Recolor { bundle: usize, from: PReg, to: PReg },
 }
 
 impl Output {
-    /// Get the allocations assigned to a given instruction.
+    /// Get the allocations assigned to a given instruction: exactly one
+    /// per operand, in the same order as `Function::inst_operands`, so
+    /// the two can be zipped together when emitting code.
     pub fn inst_allocs(&self, inst: Inst) -> &[Allocation] {
         let start = self.inst_alloc_offsets[inst.index()] as usize;
         let end = if inst.index() + 1 == self.inst_alloc_offsets.len() {
@@ -1435,6 +2456,635 @@ impl Output {
         &self.allocs[start..end]
     }
 
+    /// Get the registers assigned to the scratch registers `inst`
+    /// asked for, in the same order as `Function::inst_temps`.
+    pub fn inst_temp_allocs(&self, inst: Inst) -> impl Iterator<Item = Allocation> + '_ {
+        let start = self.temp_allocs.partition_point(|&(i, _)| i < inst);
+        self.temp_allocs[start..]
+            .iter()
+            .take_while(move |&&(i, _)| i == inst)
+            .map(|&(_, alloc)| alloc)
+    }
+
+    /// Get the stackmap of safepoint `inst`: where the references
+    /// live across it are (see `Output::stackmaps`). Empty if `inst`
+    /// is not a safepoint.
+    pub fn stackmap(&self, inst: Inst) -> &[Allocation] {
+        match self.stackmaps.binary_search_by_key(&inst, |&(i, _)| i) {
+            Ok(i) => &self.stackmaps[i].1,
+            Err(_) => &[],
+        }
+    }
+
+    /// Get where the value of operand `slot` of `inst` is just before
+    /// the instruction executes, i.e. after the moves at its `Before`
+    /// point. This is the operand's allocation for uses, and for a
+    /// def that reuses an input (`Reuse` or `ReuseStack`), whose
+    /// register or slot holds that input's value on entry (possibly
+    /// copied there, see `reuse_copies`). Returns `None` for other
+    /// defs, which have no value yet.
+    /// `func` must be the function the output was computed for.
+    pub fn operand_alloc_before<F: Function>(
+        &self,
+        func: &F,
+        inst: Inst,
+        slot: usize,
+    ) -> Option<Allocation> {
+        let op = func.inst_operands(inst)[slot];
+        match (op.kind(), op.constraint()) {
+            (OperandKind::Use, _)
+            | (OperandKind::Def, OperandConstraint::Reuse(_) | OperandConstraint::ReuseStack(_)) => {
+                Some(self.inst_allocs(inst)[slot])
+            }
+            (OperandKind::Def, _) => None,
+        }
+    }
+
+    /// Get where the value of operand `slot` of `inst` is just after
+    /// the instruction executes, i.e. before the moves at its `After`
+    /// point. This is the operand's allocation for defs and late
+    /// uses. An early use keeps its allocation only if the
+    /// instruction writes neither a def nor a clobber there, e.g. the
+    /// input of a reused-input def is overwritten; `None` otherwise.
+    /// `func` and `env` must be the ones the output was computed for.
+    pub fn operand_alloc_after<F: Function>(
+        &self,
+        func: &F,
+        env: &MachineEnv,
+        inst: Inst,
+        slot: usize,
+    ) -> Option<Allocation> {
+        let ops = func.inst_operands(inst);
+        let allocs = self.inst_allocs(inst);
+        let (op, alloc) = (ops[slot], allocs[slot]);
+        if op.kind() == OperandKind::Def || op.pos() == OperandPos::Late {
+            return Some(alloc);
+        }
+        let clobbered = match alloc.as_reg() {
+            Some(preg) => all_clobbers(func, env, inst).contains(preg),
+            None => false,
+        };
+        let overwritten = ops
+            .iter()
+            .zip(allocs)
+            .any(|(other, &other_alloc)| other.kind() == OperandKind::Def && other_alloc == alloc);
+        if clobbered || overwritten {
+            None
+        } else {
+            Some(alloc)
+        }
+    }
+
+    /// Whether `slot` lies in the secondary spill area; see
+    /// `RegallocOptions::primary_spillslots`.
+    pub fn is_secondary_spillslot(&self, slot: SpillSlot) -> bool {
+        slot.index() >= self.num_spillslots - self.num_secondary_spillslots
+    }
+
+    /// The register each vreg was first given, in instruction order,
+    /// as `(vreg, preg)` pairs sorted by vreg, to warm-start a later
+    /// allocation through `Function::allocation_hints`. Vregs whose
+    /// operands were all given stack slots are left out.
+    pub fn allocation_hints(&self, func: &impl Function) -> Vec<(VReg, PReg)> {
+        let mut regs: Vec<Option<(VReg, PReg)>> = alloc::vec![None; func.num_vregs()];
+        for inst in 0..func.num_insts() {
+            let inst = Inst::new(inst);
+            for (op, alloc) in func.inst_operands(inst).iter().zip(self.inst_allocs(inst)) {
+                if let Some(preg) = alloc.as_reg() {
+                    regs[op.vreg().vreg()].get_or_insert((op.vreg(), preg));
+                }
+            }
+        }
+        regs.into_iter().flatten().collect()
+    }
+
+    /// Get the timeline of vregs occupying `preg`, as `(from, to,
+    /// vreg)` ranges sorted by program point. Requires
+    /// `RegallocOptions::preg_timelines`; empty otherwise.
+    pub fn preg_timeline(&self, preg: PReg) -> Vec<(ProgPoint, ProgPoint, VReg)> {
+        let start = self
+            .preg_timelines
+            .partition_point(|&(p, _, _, _)| p < preg);
+        self.preg_timelines[start..]
+            .iter()
+            .take_while(|&&(p, _, _, _)| p == preg)
+            .map(|&(_, from, to, vreg)| (from, to, vreg))
+            .collect()
+    }
+
+    /// Get the edits that move, rematerialize or sink the value of
+    /// `vreg`, in order. Moves are only found with
+    /// `RegallocOptions::move_vregs`; without it, only the
+    /// `Edit::Remat`s and `Edit::Sink`s show up.
+    pub fn moves_for_vreg(&self, vreg: VReg) -> impl Iterator<Item = (ProgPoint, &Edit)> + '_ {
+        self.edits
+            .iter()
+            .enumerate()
+            .filter(move |&(i, (_, edit))| match *edit {
+                Edit::Move { .. } => self.move_vregs.get(i) == Some(&Some(vreg)),
+                Edit::Remat { vreg: v, .. } | Edit::Sink { vreg: v, .. } => v == vreg,
+                Edit::Reinterpret { .. } => false,
+            })
+            .map(|(_, (pos, edit))| (*pos, edit))
+    }
+
+    /// Get the edits inserted for `cause`, in order. Requires
+    /// `RegallocOptions::move_causes`; empty otherwise.
+    pub fn edits_with_cause(
+        &self,
+        cause: MoveCause,
+    ) -> impl Iterator<Item = (ProgPoint, &Edit)> + '_ {
+        self.edits_with_causes(move |c| c == cause)
+    }
+
+    /// Get the stores of values to their spillslots within blocks
+    /// (`MoveCause::Spill`), in order. Requires
+    /// `RegallocOptions::move_causes`; empty otherwise.
+    pub fn spill_edits(&self) -> impl Iterator<Item = (ProgPoint, &Edit)> + '_ {
+        self.edits_with_cause(MoveCause::Spill)
+    }
+
+    /// Get the loads of values from their spillslots within blocks
+    /// (`MoveCause::Reload`), in order. Requires
+    /// `RegallocOptions::move_causes`; empty otherwise.
+    pub fn reload_edits(&self) -> impl Iterator<Item = (ProgPoint, &Edit)> + '_ {
+        self.edits_with_cause(MoveCause::Reload)
+    }
+
+    /// Get the moves on CFG edges (`MoveCause::EdgeIn` and
+    /// `MoveCause::EdgeOut`), in order. Requires
+    /// `RegallocOptions::move_causes`; empty otherwise.
+    pub fn edge_move_edits(&self) -> impl Iterator<Item = (ProgPoint, &Edit)> + '_ {
+        self.edits_with_causes(|c| matches!(c, MoveCause::EdgeIn | MoveCause::EdgeOut))
+    }
+
+    fn edits_with_causes(
+        &self,
+        f: impl Fn(MoveCause) -> bool + 'static,
+    ) -> impl Iterator<Item = (ProgPoint, &Edit)> + '_ {
+        self.edits
+            .iter()
+            .zip(&self.move_causes)
+            .filter(move |&(_, &cause)| f(cause))
+            .map(|((pos, edit), _)| (*pos, edit))
+    }
+
+    /// Get the number of vregs of `class` live at `pos`, i.e. the
+    /// number of registers of that class the function needs there
+    /// to keep everything out of the stack. Requires
+    /// `RegallocOptions::pressure`; always 0 otherwise.
+    pub fn pressure_at(&self, pos: ProgPoint, class: RegClass) -> usize {
+        let next = self.pressure.partition_point(|&(point, _)| point <= pos);
+        match next.checked_sub(1) {
+            Some(i) => self.pressure[i].1[class as usize],
+            None => 0,
+        }
+    }
+
+    /// Get the allocatable registers of `class` that are free at
+    /// `pos`: no value lives in them, and no clobber, fixed-register
+    /// operand or edit at `pos` touches them, so a sequence inserted
+    /// at `pos` may use them as scratch. The allocator's own edits at
+    /// `pos` may still use a free register to break a move cycle, so
+    /// such a sequence must not be interleaved with them. Requires
+    /// `RegallocOptions::free_regs`; always empty otherwise.
+    pub fn free_regs_at(&self, pos: ProgPoint, class: RegClass) -> PRegSet {
+        let next = self.free_regs.partition_point(|&(point, _)| point <= pos);
+        let mut free = PRegSet::empty();
+        if let Some(i) = next.checked_sub(1) {
+            for preg in self.free_regs[i].1 {
+                if preg.class() == class {
+                    free.add(preg);
+                }
+            }
+        }
+        let start = self.edits.partition_point(|&(point, _)| point < pos);
+        for (_, edit) in self.edits[start..]
+            .iter()
+            .take_while(|&&(point, _)| point == pos)
+        {
+            let (from, to) = match *edit {
+                Edit::Move { from, to } => (from, to),
+                Edit::Remat { to, .. } | Edit::Sink { to, .. } => (Allocation::none(), to),
+                Edit::Reinterpret { alloc, .. } => (Allocation::none(), alloc),
+            };
+            for alloc in [from, to] {
+                if let Some(preg) = alloc.as_reg() {
+                    free.remove(preg);
+                }
+            }
+        }
+        free
+    }
+
+    /// Get the live ranges of `vreg` with their bundles, spillsets
+    /// and allocations, in program order.
+    #[cfg(feature = "debug-bundles")]
+    pub fn vreg_bundles(&self, vreg: VReg) -> &[VRegBundle] {
+        &self.vreg_bundles[vreg.vreg()]
+    }
+
+    /// Summarize which registers the allocation touches, for
+    /// shrink-wrapping prologue and epilogue saves. Returns one
+    /// `(preg, first, last, callee_saved)` entry per register that
+    /// appears in an operand allocation or an edit, sorted by
+    /// register, where `first` and `last` are the earliest and latest
+    /// program points (inclusive) at which it is read or written.
+    ///
+    /// `MachineEnv` has no explicit save convention; following the
+    /// usual setup, a register is reported as callee-saved if it is in
+    /// the class's `non_preferred_regs_by_class` list. `func` and
+    /// `env` must be the ones the output was computed for.
+    pub fn register_usage<F: Function>(
+        &self,
+        func: &F,
+        env: &MachineEnv,
+    ) -> Vec<(PReg, ProgPoint, ProgPoint, bool)> {
+        let mut spans: Vec<Option<(ProgPoint, ProgPoint)>> = alloc::vec![None; PReg::NUM_INDEX];
+        let mut touch = |alloc: Allocation, pos: ProgPoint| {
+            if let Some(preg) = alloc.as_reg() {
+                let span = spans[preg.index()].get_or_insert((pos, pos));
+                span.0 = core::cmp::min(span.0, pos);
+                span.1 = core::cmp::max(span.1, pos);
+            }
+        };
+        for i in 0..func.num_insts() {
+            let inst = Inst::new(i);
+            for (op, &alloc) in func.inst_operands(inst).iter().zip(self.inst_allocs(inst)) {
+                let pos = match op.pos() {
+                    OperandPos::Early => ProgPoint::before(inst),
+                    OperandPos::Late => ProgPoint::after(inst),
+                };
+                touch(alloc, pos);
+                if op.constraint() == OperandConstraint::RegPair {
+                    touch(ion::pair_halves(func, env, alloc, op.class()).1, pos);
+                }
+            }
+        }
+        for &(pos, ref edit) in &self.edits {
+            match *edit {
+                Edit::Move { from, to } => {
+                    touch(from, pos);
+                    touch(to, pos);
+                }
+                Edit::Remat { to, .. } | Edit::Sink { to, .. } => touch(to, pos),
+                Edit::Reinterpret { alloc, .. } => touch(alloc, pos),
+            }
+        }
+
+        spans
+            .iter()
+            .enumerate()
+            .filter_map(|(index, span)| {
+                let (first, last) = (*span)?;
+                let preg = PReg::from_index(index);
+                let callee_saved =
+                    env.non_preferred_regs_by_class[preg.class() as usize].contains(&preg);
+                Some((preg, first, last, callee_saved))
+            })
+            .collect()
+    }
+
+    /// Rename every spillslot in the output through `f`: the operand
+    /// allocations, the edits (including moves through the extra
+    /// slots the move resolver allocates), the stackmaps and the debug
+    /// locations. This lets a later frame-layout pass replace the
+    /// abstract slot indices with its own. `f` should be injective
+    /// over the slots in use; `num_spillslots` is left unchanged.
+    pub fn remap_spillslots(&mut self, f: impl Fn(SpillSlot) -> SpillSlot) {
+        let remap = |alloc: &mut Allocation| {
+            if let Some(slot) = alloc.as_stack() {
+                *alloc = Allocation::stack(f(slot));
+            }
+        };
+        self.allocs.iter_mut().for_each(remap);
+        for (_, edit) in &mut self.edits {
+            match edit {
+                Edit::Move { from, to } => {
+                    remap(from);
+                    remap(to);
+                }
+                Edit::Remat { to, .. } | Edit::Sink { to, .. } => remap(to),
+                Edit::Reinterpret { alloc, .. } => remap(alloc),
+            }
+        }
+        for (_, stackmap) in &mut self.stackmaps {
+            stackmap.iter_mut().for_each(remap);
+            stackmap.sort_unstable();
+        }
+        for (_, _, _, alloc) in &mut self.debug_locations {
+            remap(alloc);
+        }
+    }
+
+    /// Splice the allocation of an inlined callee into this output:
+    /// `callee`'s instructions are inserted right before `at` (or at
+    /// the end, if `at` is one past the last instruction), and
+    /// instructions from `at` on are renumbered after them. The
+    /// callee's spillslots are shifted up by `slot_offset`, its
+    /// registers renamed through `rename`, which must preserve the
+    /// register class, and its vregs (in `move_vregs`, `Edit::Remat`
+    /// and `Edit::Sink`) through `rename_vreg`, which maps them to
+    /// the vregs they became in the caller. It is up to the caller to
+    /// pick these so that the callee does not clobber anything live
+    /// across `at`.
+    ///
+    /// Allocations, edits, reuse copies, relaxed constraints, temp
+    /// allocations, stackmaps and debug locations are merged (the
+    /// callee's debug value labels are kept as they are, so they
+    /// should not overlap with the caller's), and so are move causes,
+    /// classes and vregs if both outputs record them.
+    /// `num_spillslots` and `spill_area_size` grow to cover the
+    /// callee's slots; every slot from the first secondary slot of
+    /// either output on counts as secondary. The remaining
+    /// diagnostics (`stats`, `hot_spills`, `preg_timelines`,
+    /// `pressure`, `free_regs`) still describe this output alone, in
+    /// its original numbering.
+    pub fn splice(
+        &mut self,
+        at: Inst,
+        callee: &Output,
+        slot_offset: usize,
+        rename: impl Fn(PReg) -> PReg,
+        rename_vreg: impl Fn(VReg) -> VReg,
+    ) {
+        let callee_insts = callee.inst_alloc_offsets.len();
+        debug_assert!(at.index() <= self.inst_alloc_offsets.len());
+        let inst_pos = |inst: Inst, shift: usize| Inst::new(inst.index() + shift);
+        let point =
+            |pos: ProgPoint, shift: usize| ProgPoint::new(inst_pos(pos.inst(), shift), pos.pos());
+        let shift_caller = |pos: ProgPoint| {
+            if pos.inst() >= at {
+                point(pos, callee_insts)
+            } else {
+                pos
+            }
+        };
+        let relocate = |alloc: Allocation| match alloc.kind() {
+            AllocationKind::Reg => {
+                let preg = rename(alloc.as_reg().unwrap());
+                debug_assert_eq!(preg.class(), alloc.as_reg().unwrap().class());
+                Allocation::reg(preg)
+            }
+            AllocationKind::Stack => Allocation::stack(SpillSlot::new(
+                alloc.as_stack().unwrap().index() + slot_offset,
+            )),
+            AllocationKind::None => alloc,
+        };
+
+        // Operand allocations: the callee's go in front of those of
+        // `at`.
+        let split = self
+            .inst_alloc_offsets
+            .get(at.index())
+            .map_or(self.allocs.len(), |&offset| offset as usize);
+        let tail = self.allocs.split_off(split);
+        self.allocs
+            .extend(callee.allocs.iter().map(|&alloc| relocate(alloc)));
+        let callee_allocs = callee.allocs.len() as u32;
+        let offsets_tail = self.inst_alloc_offsets.split_off(at.index());
+        self.inst_alloc_offsets.extend(
+            callee
+                .inst_alloc_offsets
+                .iter()
+                .map(|&offset| offset + split as u32),
+        );
+        self.inst_alloc_offsets
+            .extend(offsets_tail.iter().map(|&offset| offset + callee_allocs));
+        self.allocs.extend(tail);
+
+        // Edits: those of the callee sit between the caller's before
+        // and from `at`.
+        let before_at = ProgPoint::before(at);
+        let split = self.edits.partition_point(|&(pos, _)| pos < before_at);
+        fn splice_in_step<T: Copy>(
+            list: &mut Vec<T>,
+            edits: usize,
+            callee: &[T],
+            callee_edits: usize,
+            split: usize,
+        ) {
+            if list.len() == edits && callee.len() == callee_edits {
+                let tail = list.split_off(split);
+                list.extend_from_slice(callee);
+                list.extend(tail);
+            } else {
+                list.clear();
+            }
+        }
+        let (edits, callee_edits) = (self.edits.len(), callee.edits.len());
+        splice_in_step(
+            &mut self.move_causes,
+            edits,
+            &callee.move_causes,
+            callee_edits,
+            split,
+        );
+        splice_in_step(
+            &mut self.move_classes,
+            edits,
+            &callee.move_classes,
+            callee_edits,
+            split,
+        );
+        let callee_vregs: Vec<_> = callee
+            .move_vregs
+            .iter()
+            .map(|vreg| vreg.map(&rename_vreg))
+            .collect();
+        splice_in_step(
+            &mut self.move_vregs,
+            edits,
+            &callee_vregs,
+            callee_edits,
+            split,
+        );
+        let tail = self.edits.split_off(split);
+        self.edits
+            .extend(callee.edits.iter().map(|&(pos, ref edit)| {
+                let edit = match *edit {
+                    Edit::Move { from, to } => Edit::Move {
+                        from: relocate(from),
+                        to: relocate(to),
+                    },
+                    Edit::Remat { vreg, to } => Edit::Remat {
+                        vreg: rename_vreg(vreg),
+                        to: relocate(to),
+                    },
+                    Edit::Sink { inst, vreg, to } => Edit::Sink {
+                        inst: inst_pos(inst, at.index()),
+                        vreg: rename_vreg(vreg),
+                        to: relocate(to),
+                    },
+                    Edit::Reinterpret {
+                        alloc,
+                        from_class,
+                        to_class,
+                    } => Edit::Reinterpret {
+                        alloc: relocate(alloc),
+                        from_class,
+                        to_class,
+                    },
+                };
+                (point(pos, at.index()), edit)
+            }));
+        self.edits.extend(
+            tail.into_iter()
+                .map(|(pos, edit)| (shift_caller(pos), edit)),
+        );
+        // A sunk instruction of the caller may lie on either side of
+        // its `Edit::Sink`.
+        let callee_range = at.index()..at.index() + callee_insts;
+        for (pos, edit) in &mut self.edits {
+            if let Edit::Sink { inst, .. } = edit {
+                if *inst >= at && !callee_range.contains(&pos.inst().index()) {
+                    *inst = inst_pos(*inst, callee_insts);
+                }
+            }
+        }
+
+        let split = self.reuse_copies.partition_point(|&(inst, _, _)| inst < at);
+        let tail = self.reuse_copies.split_off(split);
+        self.reuse_copies.extend(
+            callee
+                .reuse_copies
+                .iter()
+                .map(|&(inst, input, output)| (inst_pos(inst, at.index()), input, output)),
+        );
+        self.reuse_copies.extend(
+            tail.into_iter()
+                .map(|(inst, input, output)| (inst_pos(inst, callee_insts), input, output)),
+        );
+
+        let split = self
+            .relaxed_constraints
+            .partition_point(|&(inst, _, _)| inst < at);
+        let tail = self.relaxed_constraints.split_off(split);
+        self.relaxed_constraints.extend(
+            callee
+                .relaxed_constraints
+                .iter()
+                .map(|&(inst, idx, kind)| (inst_pos(inst, at.index()), idx, kind)),
+        );
+        self.relaxed_constraints.extend(
+            tail.into_iter()
+                .map(|(inst, idx, kind)| (inst_pos(inst, callee_insts), idx, kind)),
+        );
+
+        let split = self.temp_allocs.partition_point(|&(inst, _)| inst < at);
+        let tail = self.temp_allocs.split_off(split);
+        self.temp_allocs.extend(
+            callee
+                .temp_allocs
+                .iter()
+                .map(|&(inst, alloc)| (inst_pos(inst, at.index()), relocate(alloc))),
+        );
+        self.temp_allocs.extend(
+            tail.into_iter()
+                .map(|(inst, alloc)| (inst_pos(inst, callee_insts), alloc)),
+        );
+
+        let split = self.stackmaps.partition_point(|&(inst, _)| inst < at);
+        let tail = self.stackmaps.split_off(split);
+        self.stackmaps
+            .extend(callee.stackmaps.iter().map(|(inst, allocs)| {
+                let mut allocs: Vec<_> = allocs.iter().map(|&alloc| relocate(alloc)).collect();
+                allocs.sort_unstable();
+                (inst_pos(*inst, at.index()), allocs)
+            }));
+        self.stackmaps.extend(
+            tail.into_iter()
+                .map(|(inst, allocs)| (inst_pos(inst, callee_insts), allocs)),
+        );
+
+        for (_, from, to, _) in &mut self.debug_locations {
+            *from = shift_caller(*from);
+            // A range that ends right at `at` does not cover the
+            // callee.
+            if *to > before_at {
+                *to = shift_caller(*to);
+            }
+        }
+        self.debug_locations
+            .extend(
+                callee
+                    .debug_locations
+                    .iter()
+                    .map(|&(label, from, to, alloc)| {
+                        (
+                            label,
+                            point(from, at.index()),
+                            point(to, at.index()),
+                            relocate(alloc),
+                        )
+                    }),
+            );
+        self.debug_locations
+            .sort_by_key(|&(label, from, _, _)| (label, from));
+
+        let unit = |out: &Output| match out.spill_area_size {
+            Some(size) if out.num_spillslots > 0 => Some(size / out.num_spillslots),
+            _ => None,
+        };
+        let unit = unit(self).or_else(|| unit(callee));
+        let caller_secondary = (self.num_secondary_spillslots > 0)
+            .then(|| self.num_spillslots - self.num_secondary_spillslots);
+        let callee_secondary = (callee.num_secondary_spillslots > 0)
+            .then(|| slot_offset + callee.num_spillslots - callee.num_secondary_spillslots);
+        let first_secondary = match (caller_secondary, callee_secondary) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.num_spillslots =
+            core::cmp::max(self.num_spillslots, slot_offset + callee.num_spillslots);
+        if let Some(first) = first_secondary {
+            self.num_secondary_spillslots = self.num_spillslots - first;
+        }
+        if let Some(unit) = unit {
+            self.spill_area_size = Some(self.num_spillslots * unit);
+        }
+    }
+
+    /// Returns an iterator over the edits at program points in
+    /// `[from, to)`, in order. Since `edits` is sorted this is just a
+    /// binary search and does not allocate.
+    ///
+    /// This is the natural way to interleave edits with instructions
+    /// while emitting code:
+    ///
+    /// ```
+    /// use regalloc2::{Edit, Function, Inst, Output, ProgPoint};
+    ///
+    /// fn emit<F: Function>(func: &F, out: &Output) {
+    ///     for i in 0..func.num_insts() {
+    ///         let inst = Inst::new(i);
+    ///         let next = Inst::new(i + 1);
+    ///         for (_, edit) in out.edits_in_range(ProgPoint::before(inst), ProgPoint::after(inst)) {
+    ///             if let Edit::Move { from, to } = edit {
+    ///                 // Emit a move from `from` to `to` before `inst`.
+    ///             }
+    ///         }
+    ///         // Emit `inst` using `out.inst_allocs(inst)`.
+    ///         for (_, edit) in out.edits_in_range(ProgPoint::after(inst), ProgPoint::before(next)) {
+    ///             if let Edit::Move { from, to } = edit {
+    ///                 // Emit a move from `from` to `to` after `inst`.
+    ///             }
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn edits_in_range(
+        &self,
+        from: ProgPoint,
+        to: ProgPoint,
+    ) -> impl Iterator<Item = (ProgPoint, &Edit)> + '_ {
+        let start = self.edits.partition_point(|&(pos, _)| pos < from);
+        let end = start + self.edits[start..].partition_point(|&(pos, _)| pos < to);
+        self.edits[start..end]
+            .iter()
+            .map(|(pos, edit)| (*pos, edit))
+    }
+
     /// Returns an iterator over the instructions and edits in a block, in
     /// order.
     pub fn block_insts_and_edits(&self, func: &impl Function, block: Block) -> OutputIter<'_> {
@@ -1458,6 +3108,267 @@ impl Output {
         let edits = &self.edits[edit_idx..];
         OutputIter { inst_range, edits }
     }
+
+    /// The moves the allocator placed on the CFG edge from `from` to
+    /// `to`, as the parallel-move resolver sequenced them (including
+    /// any cycle-breaking through a scratch location), with the
+    /// dependencies between them. Telling these apart from other
+    /// moves at the same point takes `RegallocOptions::move_causes`;
+    /// returns `None` if that was not set or there is no such edge.
+    pub fn edge_moves(&self, func: &impl Function, from: Block, to: Block) -> Option<EdgeMoves> {
+        if self.move_causes.len() != self.edits.len() || !func.block_succs(from).contains(&to) {
+            return None;
+        }
+        // Where the resolver puts the moves for this edge: at the end
+        // of `from` if `to` is a join, otherwise at the start of `to`.
+        let last = func.block_insns(from).last();
+        let from_outs = func.block_succs(from).len() + func.is_ret(last) as usize;
+        let to_ins = func.block_preds(to).len() + (to == func.entry_block()) as usize;
+        let (pos, cause) = if to_ins > 1 && from_outs <= 1 {
+            (ProgPoint::before(last), MoveCause::EdgeOut)
+        } else {
+            (
+                ProgPoint::before(func.block_insns(to).first()),
+                MoveCause::EdgeIn,
+            )
+        };
+
+        let start = self.edits.partition_point(|&(p, _)| p < pos);
+        let end = start + self.edits[start..].partition_point(|&(p, _)| p <= pos);
+        let is_edge = |i: usize| self.move_causes[i] == cause;
+        // A cycle among the edge's moves is broken by first saving one
+        // value to a scratch location, which carries no cause of its own.
+        let first = (start..end).find(|&i| is_edge(i)).map(|mut first| {
+            while first > start && self.move_causes[first - 1] == MoveCause::StackStackLowering {
+                first -= 1;
+            }
+            first
+        });
+        let last = (start..end).rev().find(|&i| is_edge(i));
+        let mut result = EdgeMoves::default();
+        let (first, last) = match (first, last) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Some(result),
+        };
+        for i in first..=last {
+            // Saves and restores of a borrowed scratch register sit
+            // among the moves they serve.
+            if !is_edge(i) && self.move_causes[i] != MoveCause::StackStackLowering {
+                continue;
+            }
+            let (from, to) = match self.edits[i].1 {
+                Edit::Move { from, to } => (from, to),
+                Edit::Remat { to, .. } | Edit::Sink { to, .. } => (Allocation::none(), to),
+                Edit::Reinterpret { .. } => continue,
+            };
+            let deps = result
+                .moves
+                .iter()
+                .enumerate()
+                .filter(|&(_, &(f, t))| t == from || t == to || f == to)
+                .map(|(j, _)| j)
+                .collect();
+            result.moves.push((from, to));
+            result.deps.push(deps);
+        }
+        Some(result)
+    }
+
+    /// Consume the output and yield the results for one block at a
+    /// time: its operand allocations, temp allocations, stackmaps and
+    /// edits.
+    /// Blocks come in the order of their instructions, which for a
+    /// function laid out in block order is just block order. The
+    /// memory of what was yielded is given back as the iteration
+    /// goes, so that a code generator emitting each block in turn
+    /// never holds much more than the rest of the output. The
+    /// diagnostics are dropped right away. `func` must be the
+    /// function the output was computed for.
+    pub fn into_blocks<F: Function>(self, func: &F) -> BlockOutputs<'_, F> {
+        let mut blocks: Vec<Block> = (0..func.num_blocks()).map(Block::new).collect();
+        blocks.sort_unstable_by_key(|&block| core::cmp::Reverse(func.block_insns(block).first()));
+        let Output {
+            mut allocs,
+            inst_alloc_offsets,
+            mut edits,
+            mut temp_allocs,
+            mut stackmaps,
+            ..
+        } = self;
+        allocs.reverse();
+        edits.reverse();
+        temp_allocs.reverse();
+        stackmaps.reverse();
+        BlockOutputs {
+            func,
+            blocks,
+            num_allocs: allocs.len(),
+            allocs,
+            inst_alloc_offsets,
+            edits,
+            temp_allocs,
+            stackmaps,
+        }
+    }
+
+    /// The whole function as it is to be emitted: every block in
+    /// order, with its instructions (and their allocations)
+    /// interleaved with the edits around them, as
+    /// `block_insts_and_edits` gives them.
+    pub fn instruction_stream(&self, func: &impl Function) -> Vec<StreamItem<'_>> {
+        let mut stream = Vec::with_capacity(func.num_insts() + self.edits.len());
+        for block in 0..func.num_blocks() {
+            stream.extend(self.block_insts_and_edits(func, Block::new(block)).map(
+                |item| match item {
+                    InstOrEdit::Inst(inst) => StreamItem::Inst(inst, self.inst_allocs(inst)),
+                    InstOrEdit::Edit(edit) => StreamItem::Edit(edit),
+                },
+            ));
+        }
+        stream
+    }
+
+    /// A human-readable listing of the allocation for triage: each
+    /// block with its params, each instruction with its operands and
+    /// the allocation of each (`imm` for an immediate), and the edits at its `Before` and
+    /// `After` points. `MachineEnv` carries no register names, so
+    /// registers print as `p3i` and the like; those that are
+    /// `MachineEnv::fixed_stack_slots` are marked as such.
+    pub fn display(&self, func: &impl Function, env: &MachineEnv) -> String {
+        use core::fmt::Write;
+
+        let alloc = |a: Allocation| match a.as_reg() {
+            Some(preg) if env.fixed_stack_slots.contains(&preg) => format!("{a}(stack)"),
+            _ => format!("{a}"),
+        };
+        let write_edits = |s: &mut String, from: ProgPoint, to: ProgPoint| {
+            for (pos, edit) in self.edits_in_range(from, to) {
+                let at = match pos.pos() {
+                    InstPosition::Before => "before",
+                    InstPosition::After => "after",
+                };
+                let what = match edit {
+                    Edit::Move { from, to } => format!("move {} -> {}", alloc(*from), alloc(*to)),
+                    Edit::Remat { vreg, to } => format!("remat {} -> {}", vreg, alloc(*to)),
+                    Edit::Sink { inst, vreg, to } => {
+                        format!("sink inst{} ({}) -> {}", inst.index(), vreg, alloc(*to))
+                    }
+                    Edit::Reinterpret {
+                        alloc: a,
+                        from_class,
+                        to_class,
+                    } => format!(
+                        "reinterpret {} {:?} -> {:?}",
+                        alloc(*a),
+                        from_class,
+                        to_class
+                    ),
+                };
+                let _ = writeln!(s, "    {at} inst{}: {what}", pos.inst().index());
+            }
+        };
+        let mut s = String::new();
+        for block in 0..func.num_blocks() {
+            let block = Block::new(block);
+            let params: Vec<_> = func
+                .block_params(block)
+                .iter()
+                .map(|v| format!("{v}"))
+                .collect();
+            let _ = writeln!(s, "block{}({}):", block.index(), params.join(", "));
+            for inst in func.block_insns(block).iter() {
+                write_edits(&mut s, ProgPoint::before(inst), ProgPoint::after(inst));
+                let operands: Vec<_> = func
+                    .inst_operands(inst)
+                    .iter()
+                    .zip(self.inst_allocs(inst))
+                    .enumerate()
+                    .map(|(slot, (op, a))| {
+                        if func.operand_is_immediate(inst, slot) {
+                            format!("[{op}] = imm")
+                        } else {
+                            format!("[{op}] = {}", alloc(*a))
+                        }
+                    })
+                    .collect();
+                let _ = write!(s, "  inst{}: {}", inst.index(), operands.join(", "));
+                let temps: Vec<_> = self.inst_temp_allocs(inst).map(alloc).collect();
+                if !temps.is_empty() {
+                    let _ = write!(s, "; temps {}", temps.join(", "));
+                }
+                if func.is_branch(inst) {
+                    let succs: Vec<_> = func
+                        .block_succs(block)
+                        .iter()
+                        .map(|b| format!("block{}", b.index()))
+                        .collect();
+                    let _ = write!(s, "; -> {}", succs.join(", "));
+                }
+                let _ = writeln!(s);
+                write_edits(
+                    &mut s,
+                    ProgPoint::after(inst),
+                    ProgPoint::before(inst.next()),
+                );
+            }
+        }
+        s
+    }
+}
+
+/// All registers clobbered by `inst`: its `Function::inst_clobbers`,
+/// plus `MachineEnv::call_clobbers` if it is a call.
+pub(crate) fn all_clobbers<F: Function>(f: &F, env: &MachineEnv, inst: Inst) -> PRegSet {
+    if f.is_call(inst) {
+        f.inst_clobbers(inst).union(env.call_clobbers)
+    } else {
+        f.inst_clobbers(inst)
+    }
+}
+
+/// The values that appear in fixed registers at the start of `block`
+/// without coming from a predecessor: the function's arguments (see
+/// `Function::entry_args`) for the entry block, or the values passed
+/// to a landing pad (see `Function::landing_pad_args`).
+pub(crate) fn block_entry_args<F: Function>(f: &F, block: Block) -> &[(VReg, PReg)] {
+    if block == f.entry_block() {
+        f.entry_args()
+    } else {
+        f.landing_pad_args(block)
+    }
+}
+
+/// The values defined only on the edge into `block` (see
+/// `Function::branch_edge_defs`), together with the predecessor the
+/// edge comes from. For the entry block and landing pads these are
+/// the `block_entry_args`, with `Block::invalid()` as the
+/// predecessor.
+pub(crate) fn block_edge_defs<F: Function>(
+    f: &F,
+    block: Block,
+) -> Option<(Block, &[(VReg, PReg)])> {
+    let args = block_entry_args(f, block);
+    if !args.is_empty() {
+        return Some((Block::invalid(), args));
+    }
+    if block == f.entry_block() {
+        return None;
+    }
+    let pred = match f.block_preds(block) {
+        &[pred] => pred,
+        _ => return None,
+    };
+    let last = f.block_insns(pred).last();
+    if !f.is_branch(last) {
+        return None;
+    }
+    let succ_idx = f.block_succs(pred).iter().position(|&s| s == block)?;
+    let defs = f.branch_edge_defs(pred, last, succ_idx);
+    if defs.is_empty() {
+        None
+    } else {
+        Some((pred, defs))
+    }
 }
 
 /// An error that prevents allocation.
@@ -1476,7 +3387,20 @@ pub enum RegAllocError {
     /// Invalid branch: operand count does not match sum of block
     /// params of successor blocks.
     Branch(Inst),
-    /// A VReg is live-in on entry; this is not allowed.
+    /// The branch defines values on an edge (see
+    /// `Function::branch_edge_defs`) to a successor that has other
+    /// predecessors or is the entry block or a landing pad, or into a
+    /// register of another class.
+    EdgeDef(Inst),
+    /// The function argument (see `Function::entry_args`) is passed
+    /// in a register of another class.
+    EntryArg(VReg),
+    /// The value passed to the given landing pad (see
+    /// `Function::landing_pad_args`) is in a register of another
+    /// class.
+    LandingPadArg(VReg, Block),
+    /// A VReg is live-in on entry, or to a landing pad without
+    /// predecessors; this is not allowed.
     EntryLivein,
     /// A branch has non-blockparam arg(s) and at least one of the
     /// successor blocks has more than one predecessor, forcing
@@ -1485,8 +3409,79 @@ pub enum RegAllocError {
     /// to avoid the situation.
     DisallowedBranchArg(Inst),
     /// Too many pinned VRegs + Reg-constrained Operands are live at
-    /// once, making allocation impossible.
+    /// once, making allocation impossible. This is also returned when
+    /// `RegallocOptions::available_override` leaves too few registers.
     TooManyLiveRegs,
+    /// The function has more blocks than can be represented
+    /// internally.
+    TooManyBlocks,
+    /// The function has more instructions than can be represented
+    /// internally.
+    TooManyInsts,
+    /// The function has more vregs than can be represented internally
+    /// (see `VReg::MAX`).
+    TooManyVRegs,
+    /// The vreg is mentioned with a different register class than
+    /// elsewhere in the function, or is constrained to a fixed
+    /// register of another class. `inst` may be `Inst::invalid()` if
+    /// this concerns a block param.
+    OperandClass(VReg, Inst),
+    /// Two operands of the instruction are constrained to the same
+    /// fixed register at overlapping positions.
+    FixedRegConflict(Inst, PReg),
+    /// A vreg of the given class is used, but the `MachineEnv` has no
+    /// allocatable registers of that class (or no aligned register
+    /// pair, if a vreg of the class needs one).
+    NoRegistersForClass(RegClass),
+    /// A vreg that lives in a register pair (see
+    /// `OperandConstraint::RegPair`) has an operand with a constraint
+    /// other than `Any` or `RegPair` at the given instruction.
+    RegPair(VReg, Inst),
+    /// An operand of the instruction is constrained to a register in
+    /// `MachineEnv::reserved_regs`.
+    ReservedReg(Inst, PReg),
+    /// An operand of the instruction is constrained to an allocatable
+    /// register that is not available in its block or at its position;
+    /// see `Function::block_available_regs` and
+    /// `Function::unavailable_ranges`.
+    UnavailableReg(Inst, PReg),
+    /// An operand of the instruction names a group that
+    /// `MachineEnv::reg_groups` does not declare, or whose registers
+    /// include none of the operand's class that are allocatable; see
+    /// `OperandConstraint::RegInGroup`.
+    RegGroup(Inst),
+    /// The `MachineEnv::slot_size_by_class` entry for the class is not
+    /// zero or a power of two.
+    SlotSize(RegClass),
+    /// The `MachineEnv::probe_order_by_class` entry for the class does
+    /// not list each of the class's preferred and non-preferred
+    /// registers exactly once.
+    ProbeOrder(RegClass),
+    /// `RegallocOptions::forbid_spilling` is set, or `vreg` may never
+    /// be spilled and cannot be rematerialized (see
+    /// `Function::never_spill`), but `vreg` would have to be spilled
+    /// from `at` onward because no register is free there.
+    OutOfRegisters { vreg: VReg, at: ProgPoint },
+    /// A vreg that may never be spilled (see `Function::never_spill`)
+    /// has an operand constrained to the stack at the instruction, or
+    /// is a reference vreg that may be rematerialized.
+    NeverSpill(VReg, Inst),
+    /// An operand of the instruction is an immediate (see
+    /// `Function::operand_is_immediate`) but is not an `Any` use at
+    /// `Early`, is of a register-pair vreg, or is the input of a
+    /// `Reuse` def.
+    Immediate(Inst),
+    /// `RegallocOptions::move_prio_order` puts the second group of
+    /// moves before the first, which must precede it; see
+    /// `InsertMovePrio::REQUIRED_ORDER`.
+    MovePrioOrder(InsertMovePrio, InsertMovePrio),
+    /// The input function failed validation; see
+    /// [`validate_function`].
+    Validation(ValidationError),
+    /// The call at this index in the calls passed to [`run_batch`]
+    /// names a function, instruction or vreg that does not exist, or
+    /// passes a value between vregs of different classes.
+    BatchCall(usize),
 }
 
 impl core::fmt::Display for RegAllocError {
@@ -1504,15 +3499,235 @@ pub fn run<F: Function>(
     env: &MachineEnv,
     options: &RegallocOptions,
 ) -> Result<Output, RegAllocError> {
-    ion::run(func, env, options.verbose_log, options.validate_ssa)
+    run_with_ctx(func, env, options, &mut Ctx::default())
+}
+
+/// Run the allocator with the buffers of `ctx`, which it keeps for
+/// the next call; see `Ctx`. The result is the same as `run`'s.
+pub fn run_with_ctx<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+    ctx: &mut Ctx,
+) -> Result<Output, RegAllocError> {
+    if options.validate {
+        validate_function(func)?;
+    }
+    ion::run(func, env, options, ctx)
+}
+
+/// Estimate register pressure without allocating: compute liveness
+/// and return the peak number of simultaneously live vregs of each
+/// class, indexed by `RegClass as usize`, with a register pair
+/// counting twice. This is the maximum of the curve that
+/// `RegallocOptions::pressure` reports in `Output::pressure`, at the
+/// cost of liveness analysis alone.
+pub fn max_pressure<F: Function>(func: &F, env: &MachineEnv) -> Result<[usize; 3], RegAllocError> {
+    ion::max_pressure(func, env)
 }
 
 /// Options for allocation.
 #[derive(Clone, Copy, Debug, Default)]
-pub struct RegallocOptions {
+pub struct RegallocOptions<'a> {
     /// Add extra verbosity to debug logs.
     pub verbose_log: bool,
 
     /// Run the SSA validator before allocating registers.
     pub validate_ssa: bool,
+
+    /// Run the full input validator (see [`validate_function`])
+    /// before allocating registers.
+    pub validate: bool,
+
+    /// If set, report spilled vregs with uses in blocks at least this
+    /// frequent in `Output::hot_spills`. See
+    /// `Function::block_frequency`.
+    pub hot_spill_threshold: Option<f32>,
+
+    /// Record which vregs occupy each register over time in
+    /// `Output::preg_timelines`.
+    pub preg_timelines: bool,
+
+    /// Compute the register pressure curve in `Output::pressure`.
+    pub pressure: bool,
+
+    /// Record the free registers over time in `Output::free_regs`.
+    pub free_regs: bool,
+
+    /// If set, only registers in this set are allocated: the
+    /// `MachineEnv`'s preferred, non-preferred and probe-order lists
+    /// are intersected with it for this run. Fixed-register operands
+    /// and the scratch registers are unaffected. Each register class
+    /// the function uses must keep at least one register, or
+    /// allocation fails with `RegAllocError::NoRegistersForClass`.
+    pub available_override: Option<PRegSet>,
+
+    /// Use a canonical spill model: a spilled vreg is stored to its
+    /// spillslot once, right after its def, and only ever reloaded
+    /// afterwards; the slot is never written again while the vreg is
+    /// live. This simplifies the edits at some cost in code quality.
+    /// Only applies to SSA functions, and not to vregs defined as
+    /// block parameters or by a branch.
+    pub spill_once: bool,
+
+    /// Favor code size when a value has to be split down to its uses:
+    /// consecutive register uses of a spilled vreg within one block
+    /// share a single reload before the first of them, and the value
+    /// stays in that register through the last, instead of being
+    /// reloaded at every use. This keeps registers occupied for
+    /// longer, so it can cost spills elsewhere.
+    pub reload_once_per_block: bool,
+
+    /// Let the input that an instruction's output reuses (see
+    /// `OperandConstraint::Reuse`) live anywhere at that instruction
+    /// instead of in a register, as the instruction only reads it
+    /// from the output's register. An input that is spilled and stays
+    /// live afterward is then reloaded straight into the output's
+    /// register rather than into a register of its own and copied
+    /// from there, which can also move where such inputs are split.
+    pub reload_reused_into_output: bool,
+
+    /// Require every vreg to stay in registers: if some value would
+    /// have to live on the stack at any point, allocation fails with
+    /// `RegAllocError::OutOfRegisters` instead of spilling it. Values
+    /// constrained to fixed stack slots are unaffected, and moves
+    /// may still use a stack slot to break a cycle when no scratch
+    /// register is available.
+    pub forbid_spilling: bool,
+
+    /// If set, the frame's fast spill area only holds this many
+    /// slots. Spillslots are numbered from 0 in it; any that do not
+    /// fit overflow into a secondary area numbered from this limit up
+    /// (see `Output::is_secondary_spillslot`). Spillslots only used
+    /// within cold blocks (see `Function::block_is_cold`) go to the
+    /// secondary area directly, leaving the primary one to the rest.
+    pub primary_spillslots: Option<usize>,
+
+    /// If set, the order in which the groups of moves that the
+    /// allocator inserts at one program point are emitted, in place
+    /// of the `Ord` of `InsertMovePrio`. Each group is still resolved
+    /// as one parallel move, and groups that compare equal keep their
+    /// default order. Only groups that do not depend on each other
+    /// may be reordered (e.g. a value must still be copied into its
+    /// first fixed register before being copied on from there; see
+    /// `InsertMovePrio::REQUIRED_ORDER`); allocation fails with
+    /// `RegAllocError::MovePrioOrder` otherwise.
+    pub move_prio_order: Option<fn(InsertMovePrio, InsertMovePrio) -> core::cmp::Ordering>,
+
+    /// Record the cause of each edit in `Output::move_causes`.
+    pub move_causes: bool,
+
+    /// Record the register class of each edit in
+    /// `Output::move_classes`.
+    pub move_classes: bool,
+
+    /// Record the vreg of each edit in `Output::move_vregs`.
+    pub move_vregs: bool,
+
+    /// Pack values into as few distinct registers as possible: when
+    /// probing for a free register, the allocator tries registers it
+    /// has already handed out before fresh ones (hints still come
+    /// first). This gives up some freedom to spread values out in
+    /// exchange for touching fewer registers, e.g. to cut save and
+    /// restore code. The result is reported in `Stats::regs_used`.
+    pub minimize_regs: bool,
+
+    /// Only coalesce two vregs (across a program move, a blockparam,
+    /// a reuse constraint or a `Function::coalesce_hints` pair) when
+    /// the merged bundle passes the Briggs or the George test of
+    /// graph-coloring allocators: it has fewer than K neighbors of
+    /// degree K or more, or each neighbor of one side either already
+    /// interferes with the other or has fewer than K neighbors, K
+    /// being the number of allocatable registers of the class. Such
+    /// a merge cannot make the interference graph harder to color,
+    /// so it trades some eliminated moves for fewer spills under
+    /// high register pressure. The refused merges are counted in
+    /// `Stats::conservative_coalesce_refusals`.
+    pub conservative_coalescing: bool,
+
+    /// Called for every spill and reload decision, in the order the
+    /// allocator makes them: once for each vreg of a bundle given its
+    /// spillslot, and once for each reload that survives redundant
+    /// move elimination. Nothing is tracked unless this is set.
+    pub spill_observer: Option<SpillObserver<'a>>,
+
+    /// Only count the edits, in `Stats::spill_edits`,
+    /// `Stats::reload_edits` and `Stats::move_edits`, instead of
+    /// storing them: `Output::edits` (and with it `move_causes`,
+    /// `move_classes` and `move_vregs`) stays empty. The counts are
+    /// the same as those of a full run, which makes this a cheap way
+    /// to compare alternatives, e.g. code layouts. Anything derived
+    /// from the edits, such as `Stats::regs_used`, only sees the
+    /// operand allocations.
+    pub count_edits_only: bool,
+
+    /// Let a spilled value live in a free register of another class,
+    /// e.g. an integer in a float register, instead of a spillslot,
+    /// if that register is free wherever the value is live and its
+    /// class's spillslots are at least as large. The spills and
+    /// reloads then show up as `Edit::Move`s between registers of
+    /// different classes, which the embedder must be able to emit.
+    /// Values that an instruction reads or writes in their spillslot
+    /// keep using one, as do register pairs and values whose
+    /// spillslot needs a larger alignment. The number of values
+    /// placed this way is reported in `Stats::cross_class_spills`.
+    pub cross_class_spills: bool,
+
+    /// When a `RegClass::Vector` register pair (see
+    /// `OperandConstraint::RegPair`) is spilled between two of its
+    /// uses in one block only because one of its registers is needed
+    /// in between, keep the other half in its register and store and
+    /// reload only the pressured half, through its half of the
+    /// spillslot. This is only done if the pair is in its spillslot
+    /// nowhere else, as the spillslot then holds it only in part,
+    /// so no debug location is reported for it there. Reference
+    /// vregs are always spilled whole, and the option is ignored with
+    /// `spill_once` or `reload_once_per_block`. The number of halves
+    /// kept in a register is reported in `Stats::partial_spills`.
+    pub partial_spills: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PReg, PRegSet, RegClass};
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_preg_set_algebra() {
+        let int = |i| PReg::new(i, RegClass::Int);
+        let float = |i| PReg::new(i, RegClass::Float);
+        let a = PRegSet::empty().with(int(0)).with(int(63)).with(float(1));
+        let b = PRegSet::empty().with(int(63)).with(float(1)).with(float(2));
+
+        assert!(PRegSet::empty().is_empty());
+        assert_eq!(a.len(), 3);
+        assert!(a.contains(int(63)) && !a.contains(float(63)));
+
+        assert_eq!(
+            a.union(b),
+            PRegSet::empty()
+                .with(int(0))
+                .with(int(63))
+                .with(float(1))
+                .with(float(2))
+        );
+        assert_eq!(
+            a.intersection(b),
+            PRegSet::empty().with(int(63)).with(float(1))
+        );
+        assert_eq!(a.difference(b), PRegSet::empty().with(int(0)));
+        assert!(a.difference(a).is_empty());
+
+        let mut c = a;
+        c.union_from(b);
+        assert_eq!(c, a.union(b));
+        c.remove(float(2));
+        c.remove(float(2));
+        assert_eq!(c, a);
+
+        // Iteration is in index order across classes.
+        let regs: Vec<PReg> = a.union(b).iter().collect();
+        assert_eq!(regs, [int(0), int(63), float(1), float(2)]);
+        assert_eq!(a.into_iter().count(), a.len());
+    }
 }
@@ -121,9 +121,21 @@ impl<T: Clone + Copy + Default + PartialEq> ParallelMoves<T> {
         // into that destination.
         self.parallel_moves.retain(|&mut (src, dst, _)| src != dst);
 
+        // Pure fan-out: one source copied to several destinations.
+        // Any write to the source would have been a self-move, so
+        // none of the moves can clobber it and each can be emitted
+        // directly. Since we sorted by destination, register
+        // destinations come before spillslots, which lets the
+        // scratch resolver source stack destinations from an
+        // already-loaded register.
+        let fan_out = self
+            .parallel_moves
+            .windows(2)
+            .all(|pair| pair[0].0 == pair[1].0);
+
         // Do any dests overlap sources? If not, we can also just
         // return the list.
-        if !self.sources_overlap_dests() {
+        if fan_out || !self.sources_overlap_dests() {
             return MoveVecWithScratch::NoScratch(self.parallel_moves);
         }
 
@@ -277,6 +289,17 @@ impl<T> MoveVecWithScratch<T> {
             MoveVecWithScratch::Scratch(..) => true,
         }
     }
+
+    /// How many cycles were broken through the scratch space? Each
+    /// cycle saves one value into the scratch space.
+    pub fn num_cycles(&self) -> usize {
+        match self {
+            MoveVecWithScratch::NoScratch(..) => 0,
+            MoveVecWithScratch::Scratch(moves) => {
+                moves.iter().filter(|(_, dst, _)| dst.is_none()).count()
+            }
+        }
+    }
 }
 
 /// Final stage of move resolution: finding or using scratch
@@ -300,7 +323,9 @@ impl<T> MoveVecWithScratch<T> {
 /// register. If unavailable, we create a new scratch stackslot to
 /// serve as a backup of one of the in-use registers, then borrow that
 /// register as the scratch register in the middle of stack-to-stack
-/// moves.
+/// moves. A stack-to-stack move whose source is still held in a
+/// register from an earlier load in the sequence is stored straight
+/// from that register instead.
 pub struct MoveAndScratchResolver<GetReg, GetStackSlot, IsStackAlloc>
 where
     GetReg: FnMut() -> Option<Allocation>,
@@ -319,6 +344,9 @@ where
     /// move sequence. Provided by caller and statically chosen. This is
     /// a very last-ditch option, so static choice is OK.
     pub borrowed_scratch_reg: PReg,
+    /// Whether stack-to-stack moves can be emitted directly, in which
+    /// case they are left unexpanded.
+    pub stack_to_stack_moves: bool,
 }
 
 impl<GetReg, GetStackSlot, IsStackAlloc> MoveAndScratchResolver<GetReg, GetStackSlot, IsStackAlloc>
@@ -341,10 +369,12 @@ where
             moves.without_scratch().unwrap()
         };
 
-        // Do we have any stack-to-stack moves? Fast return if not.
-        let stack_to_stack = moves
-            .iter()
-            .any(|&(src, dst, _)| self.is_stack_to_stack_move(src, dst));
+        // Do we have any stack-to-stack moves we must expand? Fast
+        // return if not.
+        let stack_to_stack = !self.stack_to_stack_moves
+            && moves
+                .iter()
+                .any(|&(src, dst, _)| self.is_stack_to_stack_move(src, dst));
         if !stack_to_stack {
             return moves;
         }
@@ -377,10 +407,26 @@ where
         let mut scratch_dirty = false;
         let mut save_dirty = true;
 
-        let mut result = smallvec![];
+        // A stack location and a register currently holding the same
+        // value, if any. A stack-to-stack move from that location can
+        // then be a single store from the register, e.g. when one
+        // spillslot fans out to several destinations.
+        let mut loaded: Option<(Allocation, Allocation)> = None;
+
+        let mut result: MoveVec<T> = smallvec![];
         for &(src, dst, data) in &moves {
+            let start = result.len();
+            let reuse = loaded.and_then(|(stack, reg)| (stack == src).then_some(reg));
             // Do we have a stack-to-stack move? If so, resolve.
-            if self.is_stack_to_stack_move(src, dst) {
+            if let (true, Some(reg)) = (self.is_stack_to_stack_move(src, dst), reuse) {
+                trace!(
+                    "scratch resolver: stack to stack: {:?} -> {:?} reusing {:?}",
+                    src,
+                    dst,
+                    reg
+                );
+                result.push((reg, dst, data));
+            } else if self.is_stack_to_stack_move(src, dst) {
                 trace!("scratch resolver: stack to stack: {:?} -> {:?}", src, dst);
 
                 // If the selected scratch register is stolen from the
@@ -434,6 +480,17 @@ where
                 }
                 result.push((src, dst, data));
             }
+
+            // Forget the loaded value if anything just overwrote either
+            // copy of it, then remember any fresh load from the stack.
+            for &(src, dst, _) in &result[start..] {
+                if loaded.is_some_and(|(stack, reg)| dst == stack || dst == reg) {
+                    loaded = None;
+                }
+                if (self.is_stack_alloc)(src) && !(self.is_stack_alloc)(dst) {
+                    loaded = Some((src, dst));
+                }
+            }
         }
 
         // Now that all the stack-to-stack moves are done, restore the
@@ -453,3 +510,72 @@ where
         (self.is_stack_alloc)(src) && (self.is_stack_alloc)(dst)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{RegClass, SpillSlot};
+
+    fn reg(i: usize) -> Allocation {
+        Allocation::reg(PReg::new(i, RegClass::Int))
+    }
+
+    fn stack(i: usize) -> Allocation {
+        Allocation::stack(SpillSlot::new(i))
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let mut moves = ParallelMoves::new();
+        for dst in [reg(4), stack(0), reg(2), reg(1), reg(3)] {
+            moves.add(reg(0), dst, ());
+        }
+        let resolved = moves.resolve().without_scratch().unwrap();
+        assert_eq!(resolved.len(), 5);
+        assert!(resolved.iter().all(|&(src, _, _)| src == reg(0)));
+    }
+
+    #[test]
+    fn test_stack_fan_out() {
+        // Spillslot 0 goes to two registers and two other spillslots.
+        // The stack destinations should be stored from one of the
+        // loaded registers rather than each going through a scratch
+        // register.
+        let mut moves = ParallelMoves::new();
+        for dst in [stack(2), reg(1), stack(1), reg(2)] {
+            moves.add(stack(0), dst, ());
+        }
+        let resolver = MoveAndScratchResolver {
+            find_free_reg: || None,
+            get_stackslot: || stack(10),
+            is_stack_alloc: |alloc: Allocation| alloc.is_stack(),
+            borrowed_scratch_reg: PReg::new(7, RegClass::Int),
+            stack_to_stack_moves: false,
+        };
+        let resolved = resolver.compute(moves.resolve());
+        assert_eq!(resolved.len(), 4);
+        for &(src, dst, _) in &resolved {
+            assert!(src == stack(0) || src == reg(1) || src == reg(2));
+            assert!(!(src.is_stack() && dst.is_stack()));
+        }
+    }
+
+    #[test]
+    fn test_num_cycles() {
+        let mut moves = ParallelMoves::new();
+        moves.add(reg(0), reg(1), ());
+        moves.add(reg(1), reg(2), ());
+        assert_eq!(moves.resolve().num_cycles(), 0);
+
+        // Two independent swaps plus a move out of one of them.
+        let mut moves = ParallelMoves::new();
+        moves.add(reg(0), reg(1), ());
+        moves.add(reg(1), reg(0), ());
+        moves.add(reg(2), reg(3), ());
+        moves.add(reg(3), reg(2), ());
+        moves.add(reg(3), reg(4), ());
+        let resolved = moves.resolve();
+        assert!(resolved.needs_scratch());
+        assert_eq!(resolved.num_cycles(), 2);
+    }
+}
@@ -27,6 +27,7 @@ struct InstData {
     op: InstOpcode,
     operands: Vec<Operand>,
     clobbers: PRegSet,
+    is_call: bool,
 }
 
 /// A wrapper around a `Function` and `MachineEnv` that can be serialized and
@@ -50,6 +51,7 @@ pub struct SerializableFunction {
     spillslot_size: Vec<usize>,
     multi_spillslot_named_by_last_slot: bool,
     allow_multiple_vreg_defs: bool,
+    is_ssa: bool,
 }
 
 impl SerializableFunction {
@@ -73,6 +75,7 @@ impl SerializableFunction {
                         op,
                         operands: func.inst_operands(inst).to_vec(),
                         clobbers: func.inst_clobbers(inst),
+                        is_call: func.is_call(inst),
                     }
                 })
                 .collect(),
@@ -119,6 +122,7 @@ impl SerializableFunction {
             .to_vec(),
             multi_spillslot_named_by_last_slot: func.multi_spillslot_named_by_last_slot(),
             allow_multiple_vreg_defs: func.allow_multiple_vreg_defs(),
+            is_ssa: func.is_ssa(),
         }
     }
 
@@ -177,6 +181,10 @@ impl Function for SerializableFunction {
         self.insts[insn.index()].clobbers
     }
 
+    fn is_call(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].is_call
+    }
+
     fn num_vregs(&self) -> usize {
         self.num_vregs
     }
@@ -196,6 +204,10 @@ impl Function for SerializableFunction {
     fn allow_multiple_vreg_defs(&self) -> bool {
         self.allow_multiple_vreg_defs
     }
+
+    fn is_ssa(&self) -> bool {
+        self.is_ssa
+    }
 }
 
 impl fmt::Debug for SerializableFunction {
@@ -227,6 +239,7 @@ impl fmt::Debug for SerializableFunction {
             "  allow_multiple_vreg_defs: {}\n",
             self.allow_multiple_vreg_defs()
         )?;
+        writeln!(f, "  is_ssa: {}", self.is_ssa())?;
         for (i, blockrange) in self.blocks.iter().enumerate() {
             let succs = self.block_succs[i]
                 .iter()
@@ -9,7 +9,7 @@ use alloc::vec;
 use hashbrown::HashSet;
 
 use crate::cfg::CFGInfo;
-use crate::{Block, Function, Inst, OperandKind, RegAllocError, VReg};
+use crate::{block_edge_defs, Block, Function, Inst, OperandKind, RegAllocError, VReg};
 
 pub fn validate_ssa<F: Function>(f: &F, cfginfo: &CFGInfo) -> Result<(), RegAllocError> {
     // For every block param and inst def, check that this is the only def.
@@ -28,6 +28,11 @@ pub fn validate_ssa<F: Function>(f: &F, cfginfo: &CFGInfo) -> Result<(), RegAllo
         for &param in f.block_params(block) {
             def(param, Inst::invalid())?;
         }
+        if let Some((_, defs)) = block_edge_defs(f, block) {
+            for &(vreg, _) in defs {
+                def(vreg, Inst::invalid())?;
+            }
+        }
         for inst in f.block_insns(block).iter() {
             for operand in f.inst_operands(inst) {
                 if let OperandKind::Def = operand.kind() {
@@ -46,6 +51,9 @@ pub fn validate_ssa<F: Function>(f: &F, cfginfo: &CFGInfo) -> Result<(), RegAllo
         let block = Block::new(block);
         local.clear();
         local.extend(f.block_params(block));
+        if let Some((_, defs)) = block_edge_defs(f, block) {
+            local.extend(defs.iter().map(|&(vreg, _)| vreg));
+        }
 
         for iix in f.block_insns(block).iter() {
             let operands = f.inst_operands(iix);
@@ -0,0 +1,580 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Small hand-built functions for unit tests.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{
+    checker::{check_output, CheckOutcome},
+    Allocation, Block, Edit, Function, Inst, InstRange, MachineEnv, Operand, Output, PReg, PRegSet,
+    ProgPoint, RegClass, RegallocOptions, VReg,
+};
+
+#[derive(Clone, Debug)]
+struct TestInst {
+    operands: Vec<Operand>,
+    clobbers: PRegSet,
+    soft_clobbers: PRegSet,
+    early_kills: PRegSet,
+    distinct_regs: bool,
+    temps: Vec<RegClass>,
+    is_call: bool,
+    is_branch: bool,
+    is_ret: bool,
+    is_move: bool,
+    is_safepoint: bool,
+    is_pure: bool,
+    latency: u32,
+}
+
+/// A `Function` built up block by block. Blocks must be added in
+/// layout order, and every block must end in `branch` or `ret`.
+#[derive(Clone, Debug, Default)]
+pub struct TestFunc {
+    insts: Vec<TestInst>,
+    blocks: Vec<InstRange>,
+    succs: Vec<Vec<Block>>,
+    preds: Vec<Vec<Block>>,
+    params: Vec<Vec<VReg>>,
+    branch_args: Vec<Vec<Vec<VReg>>>,
+    edge_defs: Vec<Vec<Vec<(VReg, PReg)>>>,
+    entry_args: Vec<(VReg, PReg)>,
+    landing_pad_args: Vec<Vec<(VReg, PReg)>>,
+    cold: Vec<bool>,
+    available_regs: Vec<Option<PRegSet>>,
+    split_hints: Vec<(VReg, ProgPoint)>,
+    coalesce_hints: Vec<(VReg, VReg)>,
+    preferred_regs: Vec<(Inst, usize, PReg)>,
+    class_views: Vec<(Inst, usize, RegClass)>,
+    immediates: Vec<(Inst, usize)>,
+    never_spill: Vec<(VReg, bool)>,
+    reference_vregs: Vec<VReg>,
+    allocation_hints: Vec<(VReg, PReg)>,
+    unavailable_ranges: Vec<(PReg, ProgPoint, ProgPoint)>,
+    num_vregs: usize,
+    non_ssa: bool,
+}
+
+impl TestFunc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a new block with the given params; subsequent
+    /// instructions are appended to it.
+    pub fn block(&mut self, params: &[VReg]) -> Block {
+        let block = Block::new(self.blocks.len());
+        let start = Inst::new(self.insts.len());
+        self.blocks.push(InstRange::new(start, start));
+        self.succs.push(vec![]);
+        self.preds.push(vec![]);
+        self.params.push(params.to_vec());
+        self.branch_args.push(vec![]);
+        self.edge_defs.push(vec![]);
+        self.landing_pad_args.push(vec![]);
+        self.cold.push(false);
+        self.available_regs.push(None);
+        for &param in params {
+            self.note_vreg(param);
+        }
+        block
+    }
+
+    /// Set the soft clobbers of `inst`; see
+    /// `Function::inst_soft_clobbers`.
+    pub fn set_soft_clobbers(&mut self, inst: Inst, soft_clobbers: PRegSet) {
+        self.insts[inst.index()].soft_clobbers = soft_clobbers;
+    }
+
+    /// Set the registers `inst` kills at its start; see
+    /// `Function::inst_early_kills`.
+    pub fn set_early_kills(&mut self, inst: Inst, kills: PRegSet) {
+        self.insts[inst.index()].early_kills = kills;
+    }
+
+    /// Require distinct registers for the operands of `inst`; see
+    /// `Function::inst_requires_distinct_regs`.
+    pub fn set_distinct_regs(&mut self, inst: Inst) {
+        self.insts[inst.index()].distinct_regs = true;
+    }
+
+    /// Set the scratch registers `inst` needs; see
+    /// `Function::inst_temps`.
+    pub fn set_temps(&mut self, inst: Inst, temps: &[RegClass]) {
+        self.insts[inst.index()].temps = temps.to_vec();
+    }
+
+    /// Set the values the branch ending `block` defines on its edge
+    /// to its `succ_idx`th successor; see `Function::branch_edge_defs`.
+    pub fn set_edge_defs(&mut self, block: Block, succ_idx: usize, defs: &[(VReg, PReg)]) {
+        for &(vreg, _) in defs {
+            self.note_vreg(vreg);
+        }
+        self.edge_defs[block.index()][succ_idx] = defs.to_vec();
+    }
+
+    /// Set the function's arguments; see `Function::entry_args`.
+    pub fn set_entry_args(&mut self, args: &[(VReg, PReg)]) {
+        for &(vreg, _) in args {
+            self.note_vreg(vreg);
+        }
+        self.entry_args = args.to_vec();
+    }
+
+    /// Make `block` a landing pad that receives `args`; see
+    /// `Function::landing_pad_args`.
+    pub fn set_landing_pad(&mut self, block: Block, args: &[(VReg, PReg)]) {
+        for &(vreg, _) in args {
+            self.note_vreg(vreg);
+        }
+        self.landing_pad_args[block.index()] = args.to_vec();
+    }
+
+    /// Set the preferred register of the operand in `slot` of
+    /// `inst`; see `Function::operand_preferred_reg`.
+    pub fn set_preferred_reg(&mut self, inst: Inst, slot: usize, preg: PReg) {
+        self.preferred_regs.push((inst, slot, preg));
+    }
+
+    /// Set the class view of the operand in `slot` of `inst`; see
+    /// `Function::operand_class_view`.
+    pub fn set_class_view(&mut self, inst: Inst, slot: usize, class: RegClass) {
+        self.class_views.push((inst, slot, class));
+    }
+
+    /// Make the operand in `slot` of `inst` an immediate; see
+    /// `Function::operand_is_immediate`.
+    pub fn set_immediate(&mut self, inst: Inst, slot: usize) {
+        self.immediates.push((inst, slot));
+    }
+
+    /// Restrict the registers available in `block`; see
+    /// `Function::block_available_regs`.
+    pub fn set_available_regs(&mut self, block: Block, regs: PRegSet) {
+        self.available_regs[block.index()] = Some(regs);
+    }
+
+    /// Set the split hints; see `Function::split_hints`.
+    pub fn set_split_hints(&mut self, mut hints: Vec<(VReg, ProgPoint)>) {
+        hints.sort_unstable();
+        self.split_hints = hints;
+    }
+
+    /// Set the coalesce hints; see `Function::coalesce_hints`.
+    pub fn set_coalesce_hints(&mut self, hints: &[(VReg, VReg)]) {
+        self.coalesce_hints = hints.to_vec();
+    }
+
+    /// Set the allocation hints; see `Function::allocation_hints`.
+    pub fn set_allocation_hints(&mut self, hints: &[(VReg, PReg)]) {
+        self.allocation_hints = hints.to_vec();
+        self.allocation_hints.sort_unstable();
+    }
+
+    /// Set the registers unavailable over ranges of program points;
+    /// see `Function::unavailable_ranges`.
+    pub fn set_unavailable_ranges(&mut self, ranges: &[(PReg, ProgPoint, ProgPoint)]) {
+        self.unavailable_ranges = ranges.to_vec();
+    }
+
+    /// Mark `block` as cold; see `Function::block_is_cold`.
+    pub fn set_cold(&mut self, block: Block) {
+        self.cold[block.index()] = true;
+    }
+
+    /// Forbid spilling `vreg`, rematerializing it instead if
+    /// `can_remat`; see `Function::never_spill`.
+    pub fn set_never_spill(&mut self, vreg: VReg, can_remat: bool) {
+        self.never_spill.push((vreg, can_remat));
+    }
+
+    /// Mark `inst` as pure; see `Function::inst_is_pure`.
+    pub fn set_pure(&mut self, inst: Inst) {
+        self.insts[inst.index()].is_pure = true;
+    }
+
+    /// Set the latency of `inst`; see `Function::inst_latency`.
+    pub fn set_latency(&mut self, inst: Inst, latency: u32) {
+        self.insts[inst.index()].latency = latency;
+    }
+
+    /// Declare the reference vregs; see `Function::is_reference_vreg`.
+    pub fn set_reference_vregs(&mut self, vregs: &[VReg]) {
+        self.reference_vregs = vregs.to_vec();
+    }
+
+    /// Make `inst` a safepoint; see `Function::requires_stackmap`.
+    pub fn set_safepoint(&mut self, inst: Inst) {
+        self.insts[inst.index()].is_safepoint = true;
+    }
+
+    /// Allow vregs to be defined more than once; see
+    /// `Function::is_ssa`.
+    pub fn set_non_ssa(&mut self) {
+        self.non_ssa = true;
+    }
+
+    /// Override the number of vregs, e.g. to make some out of range
+    /// for validation tests; see `Function::num_vregs`.
+    pub fn set_num_vregs(&mut self, num_vregs: usize) {
+        self.num_vregs = num_vregs;
+    }
+
+    /// Drop `pred` from the predecessors of `block` while keeping
+    /// `block` among its successors, for validation tests.
+    pub fn remove_pred(&mut self, block: Block, pred: Block) {
+        self.preds[block.index()].retain(|&b| b != pred);
+    }
+
+    fn note_vreg(&mut self, vreg: VReg) {
+        if vreg.vreg() != VReg::MAX {
+            self.num_vregs = core::cmp::max(self.num_vregs, vreg.vreg() + 1);
+        }
+    }
+
+    fn push(&mut self, inst: TestInst) -> Inst {
+        for op in &inst.operands {
+            self.note_vreg(op.vreg());
+        }
+        let idx = Inst::new(self.insts.len());
+        self.insts.push(inst);
+        let range = self.blocks.last_mut().unwrap();
+        let first = if range.len() == 0 { idx } else { range.first() };
+        *range = InstRange::new(first, idx.next());
+        idx
+    }
+
+    /// Append a plain instruction.
+    pub fn op(&mut self, operands: &[Operand]) -> Inst {
+        self.op_with_clobbers(operands, PRegSet::empty())
+    }
+
+    /// Append a plain instruction that also clobbers `clobbers`.
+    pub fn op_with_clobbers(&mut self, operands: &[Operand], clobbers: PRegSet) -> Inst {
+        self.push(TestInst {
+            operands: operands.to_vec(),
+            clobbers,
+            soft_clobbers: PRegSet::empty(),
+            early_kills: PRegSet::empty(),
+            distinct_regs: false,
+            temps: vec![],
+            is_call: false,
+            is_branch: false,
+            is_ret: false,
+            is_move: false,
+            is_safepoint: false,
+            is_pure: false,
+            latency: 1,
+        })
+    }
+
+    /// Append a call, which clobbers `MachineEnv::call_clobbers`; see
+    /// `Function::is_call`.
+    pub fn call(&mut self, operands: &[Operand]) -> Inst {
+        let inst = self.op(operands);
+        self.insts[inst.index()].is_call = true;
+        inst
+    }
+
+    /// Append a register-to-register copy; see `Function::is_move`.
+    pub fn mov(&mut self, dst: VReg, src: VReg) -> Inst {
+        self.push(TestInst {
+            operands: vec![Operand::reg_use(src), Operand::reg_def(dst)],
+            clobbers: PRegSet::empty(),
+            soft_clobbers: PRegSet::empty(),
+            early_kills: PRegSet::empty(),
+            distinct_regs: false,
+            temps: vec![],
+            is_call: false,
+            is_branch: false,
+            is_ret: false,
+            is_move: true,
+            is_safepoint: false,
+            is_pure: false,
+            latency: 1,
+        })
+    }
+
+    /// End the current block with a branch to `targets`, passing the
+    /// given blockparam args to each.
+    pub fn branch(&mut self, targets: &[(Block, &[VReg])]) -> Inst {
+        let from = Block::new(self.blocks.len() - 1);
+        for &(to, args) in targets {
+            self.succs[from.index()].push(to);
+            while self.preds.len() <= to.index() {
+                self.preds.push(vec![]);
+            }
+            self.preds[to.index()].push(from);
+            self.branch_args[from.index()].push(args.to_vec());
+            self.edge_defs[from.index()].push(vec![]);
+            for &arg in args {
+                self.note_vreg(arg);
+            }
+        }
+        self.push(TestInst {
+            operands: vec![],
+            clobbers: PRegSet::empty(),
+            soft_clobbers: PRegSet::empty(),
+            early_kills: PRegSet::empty(),
+            distinct_regs: false,
+            temps: vec![],
+            is_call: false,
+            is_branch: true,
+            is_ret: false,
+            is_move: false,
+            is_safepoint: false,
+            is_pure: false,
+            latency: 1,
+        })
+    }
+
+    /// End the current block with a return using `operands`.
+    pub fn ret(&mut self, operands: &[Operand]) -> Inst {
+        self.push(TestInst {
+            operands: operands.to_vec(),
+            clobbers: PRegSet::empty(),
+            soft_clobbers: PRegSet::empty(),
+            early_kills: PRegSet::empty(),
+            distinct_regs: false,
+            temps: vec![],
+            is_call: false,
+            is_branch: false,
+            is_ret: true,
+            is_move: false,
+            is_safepoint: false,
+            is_pure: false,
+            latency: 1,
+        })
+    }
+}
+
+impl Function for TestFunc {
+    fn num_insts(&self) -> usize {
+        self.insts.len()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn entry_block(&self) -> Block {
+        Block::new(0)
+    }
+
+    fn block_insns(&self, block: Block) -> InstRange {
+        self.blocks[block.index()]
+    }
+
+    fn block_succs(&self, block: Block) -> &[Block] {
+        &self.succs[block.index()]
+    }
+
+    fn block_preds(&self, block: Block) -> &[Block] {
+        &self.preds[block.index()]
+    }
+
+    fn block_params(&self, block: Block) -> &[VReg] {
+        &self.params[block.index()]
+    }
+
+    fn is_ret(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].is_ret
+    }
+
+    fn is_branch(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].is_branch
+    }
+
+    fn branch_blockparams(&self, block: Block, _insn: Inst, succ_idx: usize) -> &[VReg] {
+        &self.branch_args[block.index()][succ_idx]
+    }
+
+    fn branch_edge_defs(&self, block: Block, _insn: Inst, succ_idx: usize) -> &[(VReg, PReg)] {
+        &self.edge_defs[block.index()][succ_idx]
+    }
+
+    fn entry_args(&self) -> &[(VReg, PReg)] {
+        &self.entry_args
+    }
+
+    fn landing_pad_args(&self, block: Block) -> &[(VReg, PReg)] {
+        &self.landing_pad_args[block.index()]
+    }
+
+    fn inst_operands(&self, insn: Inst) -> &[Operand] {
+        &self.insts[insn.index()].operands
+    }
+
+    fn inst_clobbers(&self, insn: Inst) -> PRegSet {
+        self.insts[insn.index()].clobbers
+    }
+
+    fn inst_soft_clobbers(&self, insn: Inst) -> PRegSet {
+        self.insts[insn.index()].soft_clobbers
+    }
+
+    fn inst_early_kills(&self, insn: Inst) -> PRegSet {
+        self.insts[insn.index()].early_kills
+    }
+
+    fn operand_preferred_reg(&self, insn: Inst, slot: usize) -> Option<PReg> {
+        self.preferred_regs
+            .iter()
+            .find(|&&(i, s, _)| i == insn && s == slot)
+            .map(|&(_, _, preg)| preg)
+    }
+
+    fn operand_class_view(&self, insn: Inst, slot: usize) -> Option<RegClass> {
+        self.class_views
+            .iter()
+            .find(|&&(i, s, _)| i == insn && s == slot)
+            .map(|&(_, _, class)| class)
+    }
+
+    fn operand_is_immediate(&self, insn: Inst, slot: usize) -> bool {
+        self.immediates.contains(&(insn, slot))
+    }
+
+    fn inst_requires_distinct_regs(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].distinct_regs
+    }
+
+    fn inst_temps(&self, insn: Inst) -> &[RegClass] {
+        &self.insts[insn.index()].temps
+    }
+
+    fn is_call(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].is_call
+    }
+
+    fn is_move(&self, insn: Inst) -> Option<(VReg, VReg)> {
+        let inst = &self.insts[insn.index()];
+        if inst.is_move {
+            Some((inst.operands[0].vreg(), inst.operands[1].vreg()))
+        } else {
+            None
+        }
+    }
+
+    fn num_vregs(&self) -> usize {
+        self.num_vregs
+    }
+
+    fn block_is_cold(&self, block: Block) -> bool {
+        self.cold[block.index()]
+    }
+
+    fn block_available_regs(&self, block: Block) -> Option<PRegSet> {
+        self.available_regs[block.index()]
+    }
+
+    fn unavailable_ranges(&self) -> &[(PReg, ProgPoint, ProgPoint)] {
+        &self.unavailable_ranges
+    }
+
+    fn split_hints(&self) -> &[(VReg, ProgPoint)] {
+        &self.split_hints
+    }
+
+    fn coalesce_hints(&self) -> &[(VReg, VReg)] {
+        &self.coalesce_hints
+    }
+
+    fn allocation_hints(&self) -> &[(VReg, PReg)] {
+        &self.allocation_hints
+    }
+
+    fn is_reference_vreg(&self, vreg: VReg) -> bool {
+        self.reference_vregs.contains(&vreg)
+    }
+
+    fn requires_stackmap(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].is_safepoint
+    }
+
+    fn spillslot_size(&self, _regclass: RegClass) -> usize {
+        1
+    }
+
+    fn never_spill(&self, vreg: VReg) -> bool {
+        self.never_spill.iter().any(|&(v, _)| v == vreg)
+    }
+
+    fn can_remat(&self, vreg: VReg) -> bool {
+        self.never_spill
+            .iter()
+            .any(|&(v, remat)| v == vreg && remat)
+    }
+
+    fn inst_is_pure(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].is_pure
+    }
+
+    fn inst_latency(&self, insn: Inst) -> u32 {
+        self.insts[insn.index()].latency
+    }
+
+    fn is_ssa(&self) -> bool {
+        !self.non_ssa
+    }
+}
+
+/// An environment with `n` preferred integer registers `p0i..p{n-1}i`
+/// and no other registers.
+pub fn int_env(n: usize) -> MachineEnv {
+    MachineEnv {
+        preferred_regs_by_class: [
+            (0..n).map(|i| PReg::new(i, RegClass::Int)).collect(),
+            vec![],
+            vec![],
+        ],
+        non_preferred_regs_by_class: [vec![], vec![], vec![]],
+        probe_order_by_class: [None, None, None],
+        scratch_by_class: [None, None, None],
+        fixed_stack_slots: vec![],
+        stack_to_stack_moves: false,
+        reserved_regs: PRegSet::empty(),
+        slot_size_by_class: [0; 3],
+        call_clobbers: PRegSet::empty(),
+        reg_groups: vec![],
+    }
+}
+
+/// Shorthand for an integer vreg.
+pub fn vreg(i: usize) -> VReg {
+    VReg::new(i, RegClass::Int)
+}
+
+/// The source and destination of a move edit. Panics on the other
+/// edits, which only functions using `Function::never_spill`,
+/// `Function::inst_is_pure` or `Function::operand_class_view` produce.
+pub fn move_of(edit: &Edit) -> (Allocation, Allocation) {
+    match *edit {
+        Edit::Move { from, to } => (from, to),
+        Edit::Remat { .. } | Edit::Sink { .. } | Edit::Reinterpret { .. } => {
+            panic!("unexpected {:?}", edit)
+        }
+    }
+}
+
+/// Allocate `func` with default options and verify the result with
+/// the checker.
+pub fn run_and_check(func: &TestFunc, env: &MachineEnv) -> Output {
+    run_and_check_with(func, env, &RegallocOptions::default())
+}
+
+/// Allocate `func` with `options` and verify the result with the
+/// checker.
+pub fn run_and_check_with(func: &TestFunc, env: &MachineEnv, options: &RegallocOptions) -> Output {
+    let options = RegallocOptions {
+        validate: true,
+        ..*options
+    };
+    let out = crate::run(func, env, &options).expect("allocation failed");
+    assert_eq!(check_output(func, env, &out), CheckOutcome::Correct);
+    out
+}
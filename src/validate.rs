@@ -0,0 +1,481 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Up-front validation of `Function` inputs.
+//!
+//! The allocator proper assumes a well-formed input and will at best
+//! trip a debug assertion deep in the pipeline if that assumption is
+//! violated. `validate_function` checks the input ahead of time and
+//! reports the first problem it finds with the offending block or
+//! instruction.
+
+use alloc::vec;
+use hashbrown::{HashMap, HashSet};
+
+use crate::{
+    block_edge_defs, domtree, postorder, Block, Function, Inst, OperandConstraint, OperandKind,
+    OperandPos, RegAllocError, VReg,
+};
+
+/// A malformed-input error found by [`validate_function`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ValidationError {
+    /// The function has no blocks, or the entry block is out of
+    /// range or has block parameters.
+    EntryBlock(Block),
+    /// The block contains no instructions, or its instructions are
+    /// out of range.
+    BlockInsts(Block),
+    /// The block names a successor or predecessor that is out of range.
+    BlockOutOfRange(Block),
+    /// The edge from the first block to the second appears in the
+    /// successor list of the first but not in the predecessor list
+    /// of the second, or vice versa.
+    AsymmetricEdge(Block, Block),
+    /// The block does not end in a branch or ret, or contains a
+    /// branch or ret in the middle.
+    Terminator(Block),
+    /// The branch passes a different number of arguments to the given
+    /// successor than that block has block parameters.
+    BlockparamArity(Inst, Block),
+    /// A block parameter of the given block is out of range.
+    BlockparamOutOfRange(VReg, Block),
+    /// An operand or branch argument of the given inst is out of range.
+    VRegOutOfRange(VReg, Inst),
+    /// A branch argument of the given inst has a different register
+    /// class than the corresponding block parameter of the successor.
+    BlockparamClass(VReg, Inst),
+    /// The branch defines values on its edge to the given successor
+    /// (see `Function::branch_edge_defs`), but the successor has
+    /// other predecessors or is the entry block or a landing pad, or
+    /// a def's vreg is out of range or of another class than its
+    /// register.
+    EdgeDef(Inst, Block),
+    /// The function argument (see `Function::entry_args`) is out of
+    /// range or of another class than its register.
+    EntryArg(VReg),
+    /// The value passed to the given landing pad (see
+    /// `Function::landing_pad_args`) is out of range or of another
+    /// class than its register.
+    LandingPadArg(VReg, Block),
+    /// The operand at the given slot has a `Reuse` or `ReuseStack`
+    /// constraint that does not name a non-reusing `Use` operand of
+    /// the same class, or is not itself a `Def`. The input of a
+    /// `ReuseStack` def must also be an `Any` or `Stack` use.
+    Reuse(Inst, usize),
+    /// The vreg is defined more than once. `inst` may be
+    /// `Inst::invalid()` if this concerns a block param.
+    MultipleDefs(VReg, Inst),
+    /// The vreg is used at the given inst without a dominating def.
+    UseBeforeDef(VReg, Inst),
+    /// The inst both uses and redefines the vreg in a non-SSA
+    /// function, but not with an Early use and a Late def.
+    Redef(VReg, Inst),
+}
+
+impl core::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ValidationError {}
+
+impl From<ValidationError> for RegAllocError {
+    fn from(err: ValidationError) -> Self {
+        RegAllocError::Validation(err)
+    }
+}
+
+/// Check that `f` is a well-formed input to the allocator.
+///
+/// This checks CFG well-formedness (edges in range, predecessor and
+/// successor lists agree, blocks are properly terminated), blockparam
+/// arities and classes, operand vreg ranges, `Reuse` constraint
+/// consistency and, if the function claims to be in SSA form, that
+/// it is.
+pub fn validate_function<F: Function>(f: &F) -> Result<(), ValidationError> {
+    let num_blocks = f.num_blocks();
+    let num_insts = f.num_insts();
+    let num_vregs = f.num_vregs();
+
+    let entry = f.entry_block();
+    if num_blocks == 0 || entry.index() >= num_blocks || !f.block_params(entry).is_empty() {
+        return Err(ValidationError::EntryBlock(entry));
+    }
+    for &(vreg, preg) in f.entry_args() {
+        if vreg.vreg() >= num_vregs || vreg.class() != preg.class() {
+            return Err(ValidationError::EntryArg(vreg));
+        }
+    }
+
+    // Check the CFG itself before we touch any per-block data through
+    // the edges.
+    for block in 0..num_blocks {
+        let block = Block::new(block);
+        let insns = f.block_insns(block);
+        if insns.len() == 0 || insns.last().index() >= num_insts {
+            return Err(ValidationError::BlockInsts(block));
+        }
+        for &succ in f.block_succs(block) {
+            if succ.index() >= num_blocks {
+                return Err(ValidationError::BlockOutOfRange(block));
+            }
+            if !f.block_preds(succ).contains(&block) {
+                return Err(ValidationError::AsymmetricEdge(block, succ));
+            }
+        }
+        for &pred in f.block_preds(block) {
+            if pred.index() >= num_blocks {
+                return Err(ValidationError::BlockOutOfRange(block));
+            }
+            if !f.block_succs(pred).contains(&block) {
+                return Err(ValidationError::AsymmetricEdge(pred, block));
+            }
+        }
+        for &param in f.block_params(block) {
+            if param.vreg() >= num_vregs {
+                return Err(ValidationError::BlockparamOutOfRange(param, block));
+            }
+        }
+        if block != entry {
+            for &(vreg, preg) in f.landing_pad_args(block) {
+                if vreg.vreg() >= num_vregs || vreg.class() != preg.class() {
+                    return Err(ValidationError::LandingPadArg(vreg, block));
+                }
+            }
+        }
+    }
+
+    // Check terminators, blockparam arities and operands.
+    for block in 0..num_blocks {
+        let block = Block::new(block);
+        let insns = f.block_insns(block);
+        for inst in insns.iter() {
+            let is_term = f.is_branch(inst) || f.is_ret(inst);
+            if is_term != (inst == insns.last()) {
+                return Err(ValidationError::Terminator(block));
+            }
+
+            let operands = f.inst_operands(inst);
+            for (i, operand) in operands.iter().enumerate() {
+                if operand.as_fixed_nonallocatable().is_some() {
+                    continue;
+                }
+                if operand.vreg().vreg() >= num_vregs {
+                    return Err(ValidationError::VRegOutOfRange(operand.vreg(), inst));
+                }
+                if let OperandConstraint::Reuse(idx) | OperandConstraint::ReuseStack(idx) =
+                    operand.constraint()
+                {
+                    let ok = operand.kind() == OperandKind::Def
+                        && operands.get(idx).is_some_and(|input| {
+                            input.kind() == OperandKind::Use
+                                && input.class() == operand.class()
+                                && reuse_input_ok(operand.constraint(), input.constraint())
+                        });
+                    if !ok {
+                        return Err(ValidationError::Reuse(inst, i));
+                    }
+                }
+            }
+
+            if !f.is_ssa() {
+                // For each vreg used here, whether one of its uses is
+                // late.
+                let mut uses: HashMap<VReg, bool> = HashMap::new();
+                for op in operands.iter().filter(|op| op.kind() == OperandKind::Use) {
+                    *uses.entry(op.vreg()).or_insert(false) |= op.pos() == OperandPos::Late;
+                }
+                for def in operands.iter().filter(|op| op.kind() == OperandKind::Def) {
+                    let conflict = uses
+                        .get(&def.vreg())
+                        .is_some_and(|&late| late || def.pos() == OperandPos::Early);
+                    if conflict && def.as_fixed_nonallocatable().is_none() {
+                        return Err(ValidationError::Redef(def.vreg(), inst));
+                    }
+                }
+            }
+
+            if f.is_branch(inst) {
+                for (i, &succ) in f.block_succs(block).iter().enumerate() {
+                    let params_in = f.block_params(succ);
+                    let params_out = f.branch_blockparams(block, inst, i);
+                    if params_in.len() != params_out.len() {
+                        return Err(ValidationError::BlockparamArity(inst, succ));
+                    }
+                    for (&arg, &param) in params_out.iter().zip(params_in.iter()) {
+                        if arg.vreg() >= num_vregs {
+                            return Err(ValidationError::VRegOutOfRange(arg, inst));
+                        }
+                        if arg.class() != param.class() {
+                            return Err(ValidationError::BlockparamClass(arg, inst));
+                        }
+                    }
+                    let edge_defs = f.branch_edge_defs(block, inst, i);
+                    let edge_defs_ok = edge_defs.is_empty()
+                        || f.block_preds(succ).len() == 1
+                            && succ != f.entry_block()
+                            && f.landing_pad_args(succ).is_empty()
+                            && edge_defs.iter().all(|&(vreg, preg)| {
+                                vreg.vreg() < num_vregs && vreg.class() == preg.class()
+                            });
+                    if !edge_defs_ok {
+                        return Err(ValidationError::EdgeDef(inst, succ));
+                    }
+                }
+            }
+        }
+    }
+
+    if f.is_ssa() {
+        validate_ssa_form(f)?;
+    }
+
+    Ok(())
+}
+
+/// Whether a `Reuse` or `ReuseStack` def may name an input with the
+/// `input` constraint: the input must not reuse another operand, and
+/// a stack-reusing def's input must be allowed on the stack, since it
+/// is given the def's slot.
+pub(crate) fn reuse_input_ok(def: OperandConstraint, input: OperandConstraint) -> bool {
+    match input {
+        OperandConstraint::Reuse(_) | OperandConstraint::ReuseStack(_) => false,
+        OperandConstraint::Any | OperandConstraint::Stack(_) => true,
+        _ => !matches!(def, OperandConstraint::ReuseStack(_)),
+    }
+}
+
+/// Check that every vreg has exactly one def and that every use is
+/// dominated by that def. Assumes the CFG has already been validated.
+fn validate_ssa_form<F: Function>(f: &F) -> Result<(), ValidationError> {
+    let postorder = postorder::calculate(f.num_blocks(), f.entry_block(), |block| {
+        f.block_succs(block)
+    });
+    let idom = domtree::calculate(
+        f.num_blocks(),
+        |block| f.block_preds(block),
+        &postorder[..],
+        f.entry_block(),
+    );
+
+    let mut defined_in = vec![Block::invalid(); f.num_vregs()];
+    let mut defined_by = vec![Inst::invalid(); f.num_vregs()];
+    for block in 0..f.num_blocks() {
+        let block = Block::new(block);
+        let mut def = |vreg: VReg, inst: Inst| {
+            if defined_in[vreg.vreg()].is_valid() {
+                // Repeated defs within one inst are fine if the
+                // function opts into them.
+                if f.allow_multiple_vreg_defs()
+                    && inst.is_valid()
+                    && defined_by[vreg.vreg()] == inst
+                {
+                    return Ok(());
+                }
+                Err(ValidationError::MultipleDefs(vreg, inst))
+            } else {
+                defined_in[vreg.vreg()] = block;
+                defined_by[vreg.vreg()] = inst;
+                Ok(())
+            }
+        };
+        for &param in f.block_params(block) {
+            def(param, Inst::invalid())?;
+        }
+        if let Some((_, defs)) = block_edge_defs(f, block) {
+            for &(vreg, _) in defs {
+                def(vreg, Inst::invalid())?;
+            }
+        }
+        for inst in f.block_insns(block).iter() {
+            for operand in f.inst_operands(inst) {
+                if operand.kind() == OperandKind::Def && operand.as_fixed_nonallocatable().is_none()
+                {
+                    def(operand.vreg(), inst)?;
+                }
+            }
+        }
+    }
+
+    let mut local = HashSet::new();
+    for block in 0..f.num_blocks() {
+        let block = Block::new(block);
+        local.clear();
+        local.extend(f.block_params(block));
+        if let Some((_, defs)) = block_edge_defs(f, block) {
+            local.extend(defs.iter().map(|&(vreg, _)| vreg));
+        }
+
+        let check_use = |vreg: VReg, inst, local: &HashSet<VReg>| {
+            let def_block = defined_in[vreg.vreg()];
+            let ok = def_block.is_valid()
+                && if def_block == block {
+                    local.contains(&vreg)
+                } else {
+                    domtree::dominates(&idom[..], def_block, block)
+                };
+            if ok {
+                Ok(())
+            } else {
+                Err(ValidationError::UseBeforeDef(vreg, inst))
+            }
+        };
+
+        for inst in f.block_insns(block).iter() {
+            let operands = f.inst_operands(inst);
+            for operand in operands {
+                if operand.kind() == OperandKind::Use && operand.as_fixed_nonallocatable().is_none()
+                {
+                    check_use(operand.vreg(), inst, &local)?;
+                }
+            }
+            if f.is_branch(inst) {
+                for i in 0..f.block_succs(block).len() {
+                    for &arg in f.branch_blockparams(block, inst, i) {
+                        check_use(arg, inst, &local)?;
+                    }
+                }
+            }
+            for operand in operands {
+                if operand.kind() == OperandKind::Def {
+                    local.insert(operand.vreg());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{validate_function, ValidationError};
+    use crate::testing::{int_env, vreg, TestFunc};
+    use crate::{Block, Operand, RegAllocError, RegallocOptions};
+
+    /// A diamond whose join block takes a blockparam from both sides.
+    fn diamond() -> TestFunc {
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        f.branch(&[(Block::new(1), &[]), (Block::new(2), &[])]);
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(1)), Operand::reg_use(vreg(0))]);
+        f.branch(&[(Block::new(3), &[vreg(1)])]);
+        f.block(&[]);
+        f.branch(&[(Block::new(3), &[vreg(0)])]);
+        f.block(&[vreg(2)]);
+        f.ret(&[Operand::reg_use(vreg(2))]);
+        f
+    }
+
+    #[test]
+    fn test_well_formed() {
+        let f = diamond();
+        assert_eq!(validate_function(&f), Ok(()));
+        let options = RegallocOptions {
+            validate: true,
+            ..RegallocOptions::default()
+        };
+        assert!(crate::run(&f, &int_env(2), &options).is_ok());
+    }
+
+    #[test]
+    fn test_multiple_defs() {
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        let redef = f.op(&[Operand::reg_def(vreg(0))]);
+        f.ret(&[Operand::reg_use(vreg(0))]);
+        assert_eq!(
+            validate_function(&f),
+            Err(ValidationError::MultipleDefs(vreg(0), redef))
+        );
+        let options = RegallocOptions {
+            validate: true,
+            ..RegallocOptions::default()
+        };
+        assert!(matches!(
+            crate::run(&f, &int_env(2), &options),
+            Err(RegAllocError::Validation(ValidationError::MultipleDefs(v, inst)))
+                if v == vreg(0) && inst == redef
+        ));
+    }
+
+    #[test]
+    fn test_reuse() {
+        // The reused slot names the def itself rather than an input.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        let inst = f.op(&[
+            Operand::reg_use(vreg(0)),
+            Operand::reg_reuse_def(vreg(1), 1),
+        ]);
+        f.ret(&[Operand::reg_use(vreg(1))]);
+        assert_eq!(validate_function(&f), Err(ValidationError::Reuse(inst, 1)));
+
+        // A stack-reusing def whose input must be in a register; the
+        // allocator rejects it even without validation.
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        let inst = f.op(&[
+            Operand::reg_use(vreg(0)),
+            Operand::stack_reuse_def(vreg(1), 0),
+        ]);
+        f.ret(&[Operand::reg_use(vreg(1))]);
+        assert_eq!(validate_function(&f), Err(ValidationError::Reuse(inst, 1)));
+        assert!(matches!(
+            crate::run(&f, &int_env(2), &RegallocOptions::default()),
+            Err(RegAllocError::Validation(ValidationError::Reuse(i, 1))) if i == inst
+        ));
+    }
+
+    #[test]
+    fn test_asymmetric_edge() {
+        let mut f = diamond();
+        f.remove_pred(Block::new(3), Block::new(2));
+        assert_eq!(
+            validate_function(&f),
+            Err(ValidationError::AsymmetricEdge(
+                Block::new(2),
+                Block::new(3)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_blockparam_arity() {
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        let branch = f.branch(&[(Block::new(1), &[vreg(0), vreg(0)])]);
+        f.block(&[vreg(1)]);
+        f.ret(&[Operand::reg_use(vreg(1))]);
+        assert_eq!(
+            validate_function(&f),
+            Err(ValidationError::BlockparamArity(branch, Block::new(1)))
+        );
+    }
+
+    #[test]
+    fn test_vreg_out_of_range() {
+        let mut f = TestFunc::new();
+        f.block(&[]);
+        f.op(&[Operand::reg_def(vreg(0))]);
+        let inst = f.op(&[Operand::reg_def(vreg(1)), Operand::reg_use(vreg(0))]);
+        f.ret(&[Operand::reg_use(vreg(1))]);
+        f.set_num_vregs(1);
+        assert_eq!(
+            validate_function(&f),
+            Err(ValidationError::VRegOutOfRange(vreg(1), inst))
+        );
+    }
+}